@@ -0,0 +1,138 @@
+//! Capture the raw bytes sent and received on each connection.
+//!
+//! Each connection is written to its own file. Every read or write on the
+//! connection is recorded as a header line followed by the raw bytes:
+//!
+//! ```text
+//! >>> +0.000312s 78 bytes
+//! GET /foo HTTP/1.1
+//! ...
+//! <<< +0.000741s 38 bytes
+//! HTTP/1.1 200 OK
+//! ...
+//! ```
+//!
+//! `>>>` marks bytes received from the client and `<<<` bytes sent to it.
+
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The environment variable that enables capturing when the builder doesn't.
+pub(crate) const CAPTURE_DIR_ENV: &str = "HTTPTEST_CAPTURE_DIR";
+
+/// The directory to capture traffic into, if capturing is enabled.
+pub(crate) fn capture_dir(configured: Option<PathBuf>) -> Option<PathBuf> {
+    configured.or_else(|| std::env::var_os(CAPTURE_DIR_ENV).map(PathBuf::from))
+}
+
+/// A stream that copies everything read from and written to it into a file.
+pub(crate) struct CaptureStream<S> {
+    inner: S,
+    file: Option<File>,
+    started: Instant,
+}
+
+impl<S> CaptureStream<S> {
+    /// Capture the traffic on `inner` into a file in `dir`. If `dir` is None,
+    /// or the file can't be created, the stream is passed through untouched.
+    pub(crate) fn new(
+        inner: S,
+        dir: Option<&Path>,
+        server_addr: SocketAddr,
+        conn_id: u64,
+    ) -> CaptureStream<S> {
+        let file = dir.and_then(|dir| {
+            let path = dir.join(format!(
+                "httptest-{}-conn{}.log",
+                server_addr.port(),
+                conn_id
+            ));
+            File::create(&path)
+                .map_err(|e| log::warn!("unable to capture traffic to {:?}: {}", path, e))
+                .ok()
+        });
+        CaptureStream {
+            inner,
+            file,
+            started: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, direction: &str, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if let Some(file) = self.file.as_mut() {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let res = writeln!(file, "{} +{:.6}s {} bytes", direction, elapsed, bytes.len())
+                .and_then(|_| file.write_all(bytes))
+                .and_then(|_| file.write_all(b"\n"));
+            if let Err(e) = res {
+                log::warn!("unable to capture traffic: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CaptureStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            self.record(">>>", &buf.filled()[already_filled..]);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CaptureStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.record("<<<", &buf[..n]);
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.file.is_none() {
+            return Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        }
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        self.poll_write(cx, buf)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.file.is_none() && self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,145 @@
+//! Faults injected into responses at random.
+//!
+//! A [Chaos](struct.Chaos.html) configuration registered with
+//! `ServerBuilder::chaos` is applied to every response after the matching
+//! expectation has produced it, so clients can be soak tested against an
+//! unreliable server without changing any expectations. Faults are chosen
+//! using the server's random number generator, so a failing run can be
+//! reproduced by passing its seed to `ServerBuilder::rng_seed`.
+
+use crate::rng::Rng;
+use std::time::Duration;
+
+/// How often the server should inject faults into its responses.
+///
+/// ```
+/// use httptest::{Chaos, ServerBuilder};
+/// use std::time::Duration;
+///
+/// // Fail 10% of requests with a 500, reset the connection for another 5%,
+/// // and delay a quarter of responses by 200ms.
+/// let server = ServerBuilder::new()
+///     .chaos(
+///         Chaos::new()
+///             .error_rate(0.1)
+///             .reset_rate(0.05)
+///             .latency(0.25, Duration::from_millis(200)),
+///     )
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    error_rate: f64,
+    reset_rate: f64,
+    latency_rate: f64,
+    latency: Duration,
+}
+
+impl Chaos {
+    /// A configuration that injects no faults.
+    pub fn new() -> Self {
+        Chaos::default()
+    }
+
+    /// Replace this fraction of responses with a `500 Internal Server Error`.
+    ///
+    /// This function will panic if `rate` is not between 0 and 1.
+    pub fn error_rate(self, rate: f64) -> Self {
+        Chaos {
+            error_rate: check_rate(rate),
+            ..self
+        }
+    }
+
+    /// Close the connection instead of sending this fraction of responses.
+    ///
+    /// This function will panic if `rate` is not between 0 and 1.
+    pub fn reset_rate(self, rate: f64) -> Self {
+        Chaos {
+            reset_rate: check_rate(rate),
+            ..self
+        }
+    }
+
+    /// Delay this fraction of responses by `delay`. Delays are applied before
+    /// any other fault.
+    ///
+    /// This function will panic if `rate` is not between 0 and 1.
+    pub fn latency(self, rate: f64, delay: Duration) -> Self {
+        Chaos {
+            latency_rate: check_rate(rate),
+            latency: delay,
+            ..self
+        }
+    }
+}
+
+fn check_rate(rate: f64) -> f64 {
+    if !(0.0..=1.0).contains(&rate) {
+        panic!("chaos rate must be between 0 and 1, got {}", rate);
+    }
+    rate
+}
+
+// A fault to inject into a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    Error,
+    Reset,
+}
+
+// The faults to inject into a single response.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Injection {
+    pub(crate) delay: Option<Duration>,
+    pub(crate) fault: Option<Fault>,
+}
+
+impl Chaos {
+    // Decide the faults to inject into a response.
+    pub(crate) fn inject(&self, rng: &Rng) -> Injection {
+        // always draw the same number of values so each response's faults
+        // only depend on how many responses came before it.
+        let (latency, fault) = (rng.next_f64(), rng.next_f64());
+        let delay = (latency < self.latency_rate).then_some(self.latency);
+        let fault = if fault < self.error_rate {
+            Some(Fault::Error)
+        } else if fault < self.error_rate + self.reset_rate {
+            Some(Fault::Reset)
+        } else {
+            None
+        };
+        Injection { delay, fault }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject() {
+        let rng = Rng::new(7);
+        let chaos = Chaos::new();
+        assert!((0..100).all(|_| chaos.inject(&rng) == Injection::default()));
+
+        let chaos = Chaos::new().error_rate(1.0);
+        assert!((0..100).all(|_| chaos.inject(&rng).fault == Some(Fault::Error)));
+
+        // the same seed injects the same faults.
+        let chaos = Chaos::new()
+            .error_rate(0.3)
+            .reset_rate(0.3)
+            .latency(0.5, Duration::from_millis(1));
+        let (a, b) = (Rng::new(7), Rng::new(7));
+        let a: Vec<_> = (0..100).map(|_| chaos.inject(&a)).collect();
+        let b: Vec<_> = (0..100).map(|_| chaos.inject(&b)).collect();
+        assert_eq!(a, b);
+        for fault in [None, Some(Fault::Error), Some(Fault::Reset)] {
+            assert!(a.iter().any(|injection| injection.fault == fault));
+        }
+        assert!(a.iter().any(|injection| injection.delay.is_some()));
+        assert!(a.iter().any(|injection| injection.delay.is_none()));
+    }
+}
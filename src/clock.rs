@@ -0,0 +1,182 @@
+//! Clocks the server measures its delays with.
+//!
+//! Responses delayed by [delay_and_then](../responders/fn.delay_and_then.html),
+//! the pauses between streamed chunks and injected latency all sleep using the
+//! server's clock. By default that's the system clock, but a server built
+//! with `ServerBuilder::clock(ManualClock::new())` only lets time pass when
+//! the test advances it, so tests of client timeouts don't have to wait for
+//! real time to pass.
+//!
+//! ```
+//! use httptest::{clock::ManualClock, matchers::*, responders::*, Expectation, ServerBuilder};
+//! use std::time::Duration;
+//!
+//! # async fn example() {
+//! let clock = ManualClock::new();
+//! let server = ServerBuilder::new().clock(clock.clone()).run().unwrap();
+//! server.expect(
+//!     Expectation::matching(request::path("/slow"))
+//!         .respond_with(delay_and_then(Duration::from_secs(30), status_code(200))),
+//! );
+//! // ... the client sends a request to /slow ...
+//! clock.wait_for_sleepers(1).await;
+//! clock.advance(Duration::from_secs(30));
+//! // ... the client receives the response ...
+//! # }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The future returned by [Clock::sleep](trait.Clock.html#tymethod.sleep).
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// A source of time for the server's delays.
+pub trait Clock: Send + Sync {
+    /// Return a future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The system clock. Sleeps using `tokio::time::sleep`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves forward when [advance](#method.advance) is
+/// called.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<tokio::sync::watch::Sender<ManualTime>>);
+
+#[derive(Debug, Default)]
+struct ManualTime {
+    elapsed: Duration,
+    // the number of sleeps that haven't completed yet.
+    sleepers: usize,
+}
+
+impl ManualClock {
+    /// A clock that starts at zero.
+    pub fn new() -> Self {
+        ManualClock(Arc::new(tokio::sync::watch::Sender::new(
+            ManualTime::default(),
+        )))
+    }
+
+    /// Move the clock forward, completing any sleeps that are due.
+    pub fn advance(&self, duration: Duration) {
+        log::debug!("advancing manual clock by {:?}", duration);
+        self.0.send_modify(|time| time.elapsed += duration);
+    }
+
+    /// How far the clock has been advanced.
+    pub fn elapsed(&self) -> Duration {
+        self.0.borrow().elapsed
+    }
+
+    /// The number of sleeps waiting for the clock to advance.
+    pub fn sleepers(&self) -> usize {
+        self.0.borrow().sleepers
+    }
+
+    /// Wait until at least `n` sleeps are waiting for the clock to advance.
+    /// Advancing the clock before the server has started sleeping would
+    /// otherwise leave the sleep waiting for the full duration.
+    pub async fn wait_for_sleepers(&self, n: usize) {
+        let mut time = self.0.subscribe();
+        time.wait_for(|time| time.sleepers >= n)
+            .await
+            .expect("sender is owned by the clock");
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        // the deadline is fixed when the sleep is created, not when it's
+        // first polled.
+        let deadline = self.elapsed() + duration;
+        self.0.send_modify(|time| time.sleepers += 1);
+        let sleeper = Sleeper(self.clone());
+        let mut time = self.0.subscribe();
+        Box::pin(async move {
+            let _sleeper = sleeper;
+            time.wait_for(|time| time.elapsed >= deadline)
+                .await
+                .expect("sender is owned by the clock");
+        })
+    }
+}
+
+// Counts as a sleeper until the sleep completes or is dropped.
+struct Sleeper(ManualClock);
+
+impl Drop for Sleeper {
+    fn drop(&mut self) {
+        (self.0).0.send_modify(|time| time.sleepers -= 1);
+    }
+}
+
+// The server's clock, added to the extensions of every request so responders
+// can sleep with it.
+#[derive(Clone)]
+pub(crate) struct ServerClock(pub(crate) Arc<dyn Clock>);
+
+impl fmt::Debug for ServerClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerClock")
+    }
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        ServerClock(Arc::new(SystemClock))
+    }
+}
+
+// Sleep using the clock of the server that received the request.
+pub(crate) fn sleep<B>(req: &http::Request<B>, duration: Duration) -> Sleep {
+    match req.extensions().get::<ServerClock>() {
+        Some(clock) => clock.0.sleep(duration),
+        None => SystemClock.sleep(duration),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(2));
+        assert_eq!(2, clock.sleepers());
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+        assert_eq!(1, clock.sleepers());
+
+        clock.advance(Duration::from_secs(1));
+        assert!((&mut long).now_or_never().is_some());
+        assert_eq!(0, clock.sleepers());
+        assert_eq!(Duration::from_secs(2), clock.elapsed());
+    }
+}
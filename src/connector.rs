@@ -0,0 +1,89 @@
+//! Connect hyper clients to a Server without using the network.
+
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc::UnboundedSender;
+
+// The buffer size of each direction of an in-process connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A connector that routes every connection a client makes to a
+/// [Server](struct.Server.html) in-process, whatever the host in the uri.
+///
+/// Created by [Server::connector](struct.Server.html#method.connector).
+#[derive(Debug, Clone)]
+pub struct Connector {
+    connections: UnboundedSender<DuplexStream>,
+}
+
+impl Connector {
+    pub(crate) fn new(connections: UnboundedSender<DuplexStream>) -> Self {
+        Connector { connections }
+    }
+}
+
+impl tower_service::Service<http::Uri> for Connector {
+    type Response = InProcessStream;
+    type Error = std::io::Error;
+    type Future = futures::future::Ready<Result<InProcessStream, std::io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: http::Uri) -> Self::Future {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        let res = self
+            .connections
+            .send(server)
+            .map(|_| InProcessStream(TokioIo::new(client)))
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "server has shut down",
+                )
+            });
+        futures::future::ready(res)
+    }
+}
+
+/// The client end of a connection made by a [Connector](struct.Connector.html).
+#[derive(Debug)]
+pub struct InProcessStream(TokioIo<DuplexStream>);
+
+impl Connection for InProcessStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl hyper::rt::Read for InProcessStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl hyper::rt::Write for InProcessStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,67 @@
+//! Decompress request bodies before they're matched.
+
+use bytes::Bytes;
+use std::io::{self, Read};
+
+// Decode the body of `req` according to its `Content-Encoding`. When every
+// encoding is known the header is removed, the `Content-Length` updated and
+// the original encoding kept in the request's extensions for
+// `request::content_encoding`. Otherwise the request is left as it was.
+pub(crate) fn decompress(req: &mut http::Request<Bytes>) {
+    let header = req
+        .headers()
+        .get_all(http::header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let encodings: Vec<String> = header
+        .split(',')
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+    if encodings.is_empty() {
+        return;
+    }
+    // encodings are listed in the order they were applied.
+    let mut body = req.body().clone();
+    for encoding in encodings.iter().rev() {
+        body = match decode(encoding, &body) {
+            Ok(decoded) => decoded.into(),
+            Err(err) => {
+                log::debug!("unable to decompress {} request body: {}", encoding, err);
+                return;
+            }
+        };
+    }
+    let headers = req.headers_mut();
+    headers.remove(http::header::CONTENT_ENCODING);
+    if headers.contains_key(http::header::CONTENT_LENGTH) {
+        headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+    }
+    req.extensions_mut()
+        .insert(crate::server::DecodedContentEncoding(header));
+    *req.body_mut() = body;
+}
+
+fn decode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    match encoding {
+        "gzip" | "x-gzip" => flate2::read::MultiGzDecoder::new(body).read_to_end(&mut decoded),
+        // "deflate" is meant to be zlib, but some clients send raw deflate.
+        "deflate" => flate2::read::ZlibDecoder::new(body)
+            .read_to_end(&mut decoded)
+            .or_else(|_| {
+                decoded.clear();
+                flate2::read::DeflateDecoder::new(body).read_to_end(&mut decoded)
+            }),
+        "br" => brotli_decompressor::Decompressor::new(body, 4096).read_to_end(&mut decoded),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported encoding",
+            ))
+        }
+    }?;
+    Ok(decoded)
+}
@@ -0,0 +1,109 @@
+//! Diagnostic events delivered to a custom sink.
+//!
+//! The server describes how it handled each request through the `log` crate
+//! and, with the `tracing` feature, `tracing` events. Output from either is
+//! easy to lose to the test harness's output capturing or to a logger that
+//! was initialized too late, so the same diagnostics can also be delivered to
+//! a sink registered with `ServerBuilder::diagnostics`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A diagnostic event describing how the server handled a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiagnosticEvent {
+    /// A request was received.
+    RequestReceived {
+        /// The request's method and uri.
+        request: String,
+    },
+    /// A matcher was evaluated against a request. Evaluations are delivered
+    /// in the order the matchers were entered, so a composite matcher is
+    /// followed by its children.
+    MatcherEvaluated {
+        /// How deeply the matcher is nested within the expectation's matcher,
+        /// 0 for the expectation's matcher itself.
+        depth: usize,
+        /// The matcher's debug representation.
+        matcher: String,
+        /// Whether the matcher matched.
+        matched: bool,
+    },
+    /// A request matched an expectation.
+    ExpectationMatched {
+        /// The expectation's matcher.
+        matcher: String,
+    },
+    /// A request did not match any expectation.
+    UnexpectedRequest {
+        /// The request's method and uri.
+        request: String,
+        /// A description of the expectation that came closest to matching,
+        /// if any.
+        closest: Option<String>,
+    },
+    /// The server has been waiting on open connections to shutdown for
+    /// longer than its `shutdown_warning_after` duration.
+    ShutdownDelayed {
+        /// How long the server has been shutting down.
+        waited: Duration,
+        /// A description of the open connections and in-flight requests.
+        connections: String,
+    },
+}
+
+impl fmt::Display for DiagnosticEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiagnosticEvent::RequestReceived { request } => write!(f, "received {}", request),
+            DiagnosticEvent::MatcherEvaluated {
+                depth,
+                matcher,
+                matched,
+            } => write!(
+                f,
+                "{}{} {}",
+                "  ".repeat(*depth),
+                if *matched { "✅" } else { "❌" },
+                matcher
+            ),
+            DiagnosticEvent::ExpectationMatched { matcher } => {
+                write!(f, "matched expectation {}", matcher)
+            }
+            DiagnosticEvent::UnexpectedRequest { request, closest } => {
+                write!(f, "no expectation matched {}", request)?;
+                if let Some(closest) = closest {
+                    write!(f, "; closest expectation: {}", closest)?;
+                }
+                Ok(())
+            }
+            DiagnosticEvent::ShutdownDelayed {
+                waited,
+                connections,
+            } => write!(f, "shutting down for {:?}; {}", waited, connections),
+        }
+    }
+}
+
+type SinkFn = dyn Fn(&DiagnosticEvent) + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct Sink(Arc<SinkFn>);
+
+impl Sink {
+    pub(crate) fn new(f: impl Fn(&DiagnosticEvent) + Send + Sync + 'static) -> Sink {
+        Sink(Arc::new(f))
+    }
+
+    pub(crate) fn emit(&self, event: DiagnosticEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sink")
+    }
+}
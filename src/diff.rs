@@ -0,0 +1,337 @@
+//! Diffs used to describe why two values are not equal.
+
+use std::fmt;
+
+// Inputs whose differing lines would need a table with more than this many
+// cells are not diffed. Computing the diff takes time and memory proportional
+// to the product of the number of differing lines in each input, so this
+// bounds the table to 4MB.
+const MAX_CELLS: usize = 1 << 20;
+
+// Number of unchanged lines to show around each change.
+const CONTEXT: usize = 2;
+
+// Number of differences between two json values to list before summarizing
+// the rest.
+const MAX_JSON_DIFFERENCES: usize = 20;
+
+// Json values longer than this many characters are truncated when listed.
+const MAX_JSON_VALUE_LEN: usize = 60;
+
+/// Diff the pretty-printed Debug representations of `expected` and `actual`.
+/// Returns None if both fit on a single line, as they can be read directly.
+pub(crate) fn debug_diff<T>(expected: &T, actual: &T) -> Option<String>
+where
+    T: fmt::Debug + ?Sized,
+{
+    let expected = format!("{:#?}", expected);
+    let actual = format!("{:#?}", actual);
+    if !expected.contains('\n') && !actual.contains('\n') {
+        return None;
+    }
+    Some(line_diff(&expected, &actual))
+}
+
+/// List the differences between two json values, one per line, by the json
+/// pointer of where they occur. Object members are matched up by key and
+/// array elements by index. Returns None if the values are equal.
+pub(crate) fn json_diff(
+    expected: &serde_json::Value,
+    actual: &serde_json::Value,
+) -> Option<String> {
+    let mut differences = Vec::new();
+    walk_json(&mut String::new(), expected, actual, &mut differences);
+    if differences.is_empty() {
+        return None;
+    }
+    let mut out = String::from("json differs from expected:");
+    for difference in differences.iter().take(MAX_JSON_DIFFERENCES) {
+        out.push('\n');
+        out.push_str(difference);
+    }
+    if differences.len() > MAX_JSON_DIFFERENCES {
+        out.push_str(&format!(
+            "\n... and {} more",
+            differences.len() - MAX_JSON_DIFFERENCES
+        ));
+    }
+    Some(out)
+}
+
+fn walk_json(
+    path: &mut String,
+    expected: &serde_json::Value,
+    actual: &serde_json::Value,
+    differences: &mut Vec<String>,
+) {
+    use serde_json::Value;
+    let len = path.len();
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, e) in expected {
+                push_json_key(path, key);
+                match actual.get(key) {
+                    Some(a) => walk_json(path, e, a, differences),
+                    None => {
+                        differences.push(format!("{}: removed {}", pointer(path), short_json(e)))
+                    }
+                }
+                path.truncate(len);
+            }
+            for (key, a) in actual
+                .iter()
+                .filter(|(key, _)| !expected.contains_key(*key))
+            {
+                push_json_key(path, key);
+                differences.push(format!("{}: added {}", pointer(path), short_json(a)));
+                path.truncate(len);
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            for i in 0..expected.len().max(actual.len()) {
+                path.push_str(&format!("/{}", i));
+                match (expected.get(i), actual.get(i)) {
+                    (Some(e), Some(a)) => walk_json(path, e, a, differences),
+                    (Some(e), None) => {
+                        differences.push(format!("{}: removed {}", pointer(path), short_json(e)))
+                    }
+                    (None, Some(a)) => {
+                        differences.push(format!("{}: added {}", pointer(path), short_json(a)))
+                    }
+                    (None, None) => unreachable!(),
+                }
+                path.truncate(len);
+            }
+        }
+        (e, a) if e != a => differences.push(format!(
+            "{}: {} → {}",
+            pointer(path),
+            short_json(e),
+            short_json(a)
+        )),
+        _ => {}
+    }
+}
+
+// Append an object key to a json pointer, escaping it as described in RFC
+// 6901.
+fn push_json_key(path: &mut String, key: &str) {
+    path.push('/');
+    path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+fn short_json(value: &serde_json::Value) -> String {
+    let s = value.to_string();
+    match s.char_indices().nth(MAX_JSON_VALUE_LEN) {
+        Some((idx, _)) => format!("{}…", &s[..idx]),
+        None => s,
+    }
+}
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Produce a unified-style diff of the lines of `expected` and `actual`. Lines
+/// only in `expected` are prefixed with `-`, lines only in `actual` with `+`.
+/// Runs of unchanged lines away from any change are elided. If the inputs
+/// differ in too many lines to diff only the first differing lines are shown.
+pub(crate) fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // Lines common to the start and end of both inputs are unchanged, only
+    // the lines between them need to be diffed.
+    let prefix = expected
+        .iter()
+        .zip(actual.iter())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let (e, a) = (
+        &expected[prefix..expected.len() - suffix],
+        &actual[prefix..actual.len() - suffix],
+    );
+    if (e.len() + 1).saturating_mul(a.len() + 1) > MAX_CELLS {
+        let mut out = String::from("--- expected\n+++ actual");
+        for line in &expected[prefix.saturating_sub(CONTEXT)..prefix] {
+            out.push_str(&format!("\n {}", line));
+        }
+        out.push_str(&format!(
+            "\n-{}\n+{}\n ... {} more lines differ",
+            e[0],
+            a[0],
+            e.len() + a.len() - 2
+        ));
+        return out;
+    }
+
+    // lcs[i * width + j] is the length of the longest common subsequence of
+    // e[i..] and a[j..].
+    let width = a.len() + 1;
+    let mut lcs = vec![0u32; (e.len() + 1) * width];
+    for i in (0..e.len()).rev() {
+        for j in (0..a.len()).rev() {
+            lcs[i * width + j] = if e[i] == a[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<Line> = expected[..prefix].iter().map(|l| Line::Same(l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < e.len() || j < a.len() {
+        if i < e.len() && j < a.len() && e[i] == a[j] {
+            lines.push(Line::Same(e[i]));
+            i += 1;
+            j += 1;
+        } else if i < e.len()
+            && (j == a.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            lines.push(Line::Removed(e[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(a[j]));
+            j += 1;
+        }
+    }
+    lines.extend(
+        expected[expected.len() - suffix..]
+            .iter()
+            .map(|l| Line::Same(l)),
+    );
+
+    let is_change = |line: &Line| !matches!(line, Line::Same(_));
+    let mut out = String::from("--- expected\n+++ actual");
+    let mut elided = false;
+    for (idx, line) in lines.iter().enumerate() {
+        let lo = idx.saturating_sub(CONTEXT);
+        let hi = (idx + CONTEXT + 1).min(lines.len());
+        if !lines[lo..hi].iter().any(is_change) {
+            if !elided {
+                out.push_str("\n ...");
+                elided = true;
+            }
+            continue;
+        }
+        elided = false;
+        let (prefix, text) = match line {
+            Line::Same(text) => (' ', text),
+            Line::Removed(text) => ('-', text),
+            Line::Added(text) => ('+', text),
+        };
+        out.push('\n');
+        out.push(prefix);
+        out.push_str(text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_diff() {
+        let expected = "{\n  a: 1,\n  b: 2,\n  c: 3,\n  d: 4,\n  e: 5,\n  f: 6,\n}";
+        let actual = "{\n  a: 1,\n  b: 2,\n  c: 3,\n  d: 4,\n  e: 50,\n  f: 6,\n  g: 7,\n}";
+        assert_eq!(
+            line_diff(expected, actual),
+            "--- expected\n+++ actual\n ...\n   c: 3,\n   d: 4,\n-  e: 5,\n+  e: 50,\n   f: 6,\n+  g: 7,\n }"
+        );
+    }
+
+    #[test]
+    fn test_line_diff_removed() {
+        assert_eq!(
+            line_diff("a\nb\nc", "a\nc"),
+            "--- expected\n+++ actual\n a\n-b\n c"
+        );
+    }
+
+    #[test]
+    fn test_line_diff_too_large() {
+        let expected: String = (0..2000).map(|i| format!("{}\n", i)).collect();
+        let actual: String = (0..2000).map(|i| format!("{}\n", i * 2)).collect();
+        // Too many differing lines to diff, only the first are shown.
+        assert_eq!(
+            line_diff(&expected, &actual),
+            "--- expected\n+++ actual\n 0\n-1\n+2\n ... 3996 more lines differ"
+        );
+
+        // Large inputs that only differ in a few lines are still diffed.
+        let actual = expected.replace("\n1000\n", "\nchanged\n");
+        assert_eq!(
+            line_diff(&expected, &actual),
+            "--- expected\n+++ actual\n ...\n 998\n 999\n-1000\n+changed\n 1001\n 1002\n ..."
+        );
+    }
+
+    #[test]
+    fn test_json_diff() {
+        let expected = serde_json::json!({
+            "user": {"name": "alice", "roles": ["admin", "dev"]},
+            "a/b": 1,
+            "removed": true,
+        });
+        let actual = serde_json::json!({
+            "user": {"name": "bob", "roles": ["admin"], "age": 30},
+            "a/b": "1",
+        });
+        assert_eq!(
+            json_diff(&expected, &actual).unwrap(),
+            [
+                "json differs from expected:",
+                "/a~1b: 1 → \"1\"",
+                "/removed: removed true",
+                "/user/name: \"alice\" → \"bob\"",
+                "/user/roles/1: removed \"dev\"",
+                "/user/age: added 30",
+            ]
+            .join("\n")
+        );
+        assert!(json_diff(&expected, &expected.clone()).is_none());
+        assert_eq!(
+            json_diff(&serde_json::json!(1), &serde_json::json!([1])).unwrap(),
+            "json differs from expected:\n<root>: 1 → [1]"
+        );
+    }
+
+    #[test]
+    fn test_json_diff_large() {
+        let expected: serde_json::Value = (0..1000).collect();
+        let actual: serde_json::Value = (0..1000).map(|i| i * 2).collect();
+        let diff = json_diff(&expected, &actual).unwrap();
+        let lines: Vec<&str> = diff.lines().collect();
+        assert_eq!(lines.len(), 2 + MAX_JSON_DIFFERENCES);
+        assert_eq!(lines[1], "/1: 1 → 2");
+        assert_eq!(lines[lines.len() - 1], "... and 979 more");
+
+        let expected = serde_json::json!({ "text": "a".repeat(100) });
+        let actual = serde_json::json!({ "text": "" });
+        assert_eq!(
+            json_diff(&expected, &actual).unwrap(),
+            format!(
+                "json differs from expected:\n/text: \"{}… → \"\"",
+                "a".repeat(MAX_JSON_VALUE_LEN - 1)
+            )
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Register stubs from a directory of fixture files.
+//!
+//! Each file is named `<METHOD>__<path segments separated by __>.<ext>`, and
+//! becomes an expectation that matches requests with that method and path
+//! and responds with the file's contents. For example:
+//!
+//! * `GET__users__42.json` responds to `GET /users/42`.
+//! * `GET__.html` responds to `GET /`.
+//! * `DELETE__users__42@204.txt` responds to `DELETE /users/42` with a 204.
+//!
+//! The status is 200 unless the name ends with `@<status>`. The content type
+//! is chosen from the extension. Like other stubs, the expectations match any
+//! number of requests. Hidden files are ignored.
+//!
+//! ```no_run
+//! # use httptest::{fixtures, Server};
+//! let server = Server::run();
+//! for expectation in fixtures::load_dir("tests/fixtures/api").unwrap() {
+//!     server.expect(expectation);
+//! }
+//! ```
+
+use crate::matchers::{eq, request};
+use crate::responders::status_code;
+use crate::Expectation;
+use std::io;
+use std::path::Path;
+
+/// Load an expectation for every fixture file in `dir`, in file name order.
+pub fn load_dir(dir: impl AsRef<Path>) -> io::Result<Vec<Expectation>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_file() && !hidden {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    paths.into_iter().map(load).collect()
+}
+
+/// Load the expectation for a single fixture file.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Expectation> {
+    let path = path.as_ref();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let fixture = Fixture::parse(&file_name).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid fixture {}: {}", path.display(), err),
+        )
+    })?;
+    let body = std::fs::read(path)?;
+    Ok(
+        Expectation::matching(request::method_path(eq(fixture.method), eq(fixture.path)))
+            .times(..)
+            .respond_with(
+                status_code(fixture.status)
+                    .insert_header("content-type", fixture.content_type)
+                    .body(body),
+            ),
+    )
+}
+
+#[derive(Debug, PartialEq)]
+struct Fixture {
+    method: String,
+    path: String,
+    status: u16,
+    content_type: &'static str,
+}
+
+impl Fixture {
+    fn parse(file_name: &str) -> Result<Fixture, String> {
+        let invalid_name = || "expected METHOD__path__segments.ext".to_string();
+        let (stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) => (stem, ext),
+            None => (file_name, ""),
+        };
+        let (stem, status) = match stem.rsplit_once('@') {
+            Some((stem, status)) => {
+                let code = status
+                    .parse()
+                    .ok()
+                    .and_then(|code| http::StatusCode::from_u16(code).ok())
+                    .ok_or_else(|| format!("invalid status {:?}", status))?;
+                (stem, code.as_u16())
+            }
+            None => (stem, 200),
+        };
+        let (method, path) = stem.split_once("__").ok_or_else(invalid_name)?;
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(invalid_name());
+        }
+        Ok(Fixture {
+            method: method.to_string(),
+            path: format!("/{}", path.replace("__", "/")),
+            status,
+            content_type: content_type(ext),
+        })
+    }
+}
+
+fn content_type(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "xml" => "application/xml",
+        "csv" => "text/csv",
+        "js" => "application/javascript",
+        "css" => "text/css",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(Fixture {
+                method: "GET".to_string(),
+                path: "/users/42".to_string(),
+                status: 200,
+                content_type: "application/json",
+            }),
+            Fixture::parse("GET__users__42.json")
+        );
+        assert_eq!(
+            Ok(Fixture {
+                method: "DELETE".to_string(),
+                path: "/users/42".to_string(),
+                status: 204,
+                content_type: "text/plain",
+            }),
+            Fixture::parse("DELETE__users__42@204.txt")
+        );
+        assert_eq!(
+            Ok("/".to_string()),
+            Fixture::parse("GET__.html").map(|f| f.path)
+        );
+        assert!(Fixture::parse("README.md").is_err());
+        assert!(Fixture::parse("get__users.json").is_err());
+        assert_eq!(
+            Err("invalid status \"ok\"".to_string()),
+            Fixture::parse("GET__users@ok.json")
+        );
+        assert!(Fixture::parse("GET__users@1000.json").is_err());
+        assert!(Fixture::parse("GET__users@42.json").is_err());
+    }
+}
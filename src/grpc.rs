@@ -0,0 +1,287 @@
+//! Match and respond to unary gRPC calls.
+//!
+//! gRPC clients talk HTTP/2 to the server, which the server accepts without
+//! any configuration. A request is matched by the service and method it calls
+//! and by the message it sends, and answered with a message or an error
+//! status.
+//!
+//! Messages are passed to matchers and responders as their encoded bytes, so
+//! any protobuf implementation can be used to encode and decode them.
+//!
+//! ```
+//! use httptest::{grpc, matchers::*, Expectation};
+//!
+//! Expectation::matching(all_of![
+//!     grpc::call("helloworld.Greeter", "SayHello"),
+//!     grpc::message(&b"\x0a\x05world"[..]),
+//! ])
+//! .respond_with(grpc::unary(b"\x0a\x05hello".to_vec()));
+//!
+//! Expectation::matching(grpc::call("helloworld.Greeter", "SayGoodbye"))
+//!     .respond_with(grpc::status(grpc::Code::Unimplemented, "not here"));
+//! ```
+
+use crate::matchers::{matcher_name, ExecutionContext, Matcher};
+use crate::responders::{Responder, Trailers};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::convert::TryInto;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Match a call to the given service and method.
+///
+/// The service is the fully qualified name, including the package, e.g.
+/// `helloworld.Greeter`. Only `POST` requests with a gRPC content-type match.
+pub fn call<S, M>(service: S, method: M) -> Call<S, M> {
+    Call { service, method }
+}
+/// The `Call` matcher returned by [call()](fn.call.html)
+#[derive(Debug)]
+pub struct Call<S, M> {
+    service: S,
+    method: M,
+}
+impl<S, M, B> Matcher<http::Request<B>> for Call<S, M>
+where
+    S: Matcher<str>,
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let is_grpc = input
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .filter(|ct| ct.as_bytes().starts_with(b"application/grpc"))
+            .is_some();
+        if input.method() != http::Method::POST || !is_grpc {
+            return false;
+        }
+        let path = input.uri().path();
+        let (service, method) = match path.strip_prefix('/').and_then(|p| p.split_once('/')) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        ctx.chain(&mut self.service, service) && ctx.chain(&mut self.method, method)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Call")
+            .field("service", &matcher_name(&self.service))
+            .field("method", &matcher_name(&self.method))
+            .finish()
+    }
+}
+
+/// Extract the message of a unary call and pass its encoded bytes to the next
+/// mapper.
+///
+/// Requests that don't contain exactly one uncompressed message don't match.
+pub fn message<M>(inner: M) -> Message<M> {
+    Message(inner)
+}
+/// The `Message` mapper returned by [message()](fn.message.html)
+#[derive(Debug)]
+pub struct Message<M>(M);
+impl<M, B> Matcher<http::Request<B>> for Message<M>
+where
+    B: AsRef<[u8]>,
+    M: Matcher<[u8]>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        if ctx.body_unavailable() {
+            return true;
+        }
+        match decode_message(input.body().as_ref()) {
+            Some(msg) => ctx.chain(&mut self.0, msg),
+            None => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Message")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+// Decode a body consisting of a single uncompressed, length-prefixed message.
+fn decode_message(body: &[u8]) -> Option<&[u8]> {
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let msg = &body[5..];
+    if msg.len() != len {
+        return None;
+    }
+    Some(msg)
+}
+
+fn encode_message(msg: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(msg.len() + 5);
+    buf.put_u8(0);
+    buf.put_u32(msg.len() as u32);
+    buf.put_slice(msg);
+    buf.freeze()
+}
+
+/// A gRPC status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Code {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+/// respond to a unary call with the given encoded message and an `Ok` status.
+pub fn unary(message: impl Into<Bytes>) -> GrpcResponse {
+    GrpcResponse {
+        message: Some(message.into()),
+        code: Code::Ok,
+        status_message: String::new(),
+        metadata: http::HeaderMap::new(),
+    }
+}
+
+/// respond to a call with the given status and no message.
+pub fn status(code: Code, message: impl Into<String>) -> GrpcResponse {
+    GrpcResponse {
+        message: None,
+        code,
+        status_message: message.into(),
+        metadata: http::HeaderMap::new(),
+    }
+}
+
+/// The responder returned by [unary()](fn.unary.html) and
+/// [status()](fn.status.html)
+#[derive(Debug, Clone)]
+pub struct GrpcResponse {
+    message: Option<Bytes>,
+    code: Code,
+    status_message: String,
+    metadata: http::HeaderMap,
+}
+
+impl GrpcResponse {
+    /// Add a metadata entry to the response headers.
+    pub fn metadata<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: TryInto<http::header::HeaderName>,
+        K::Error: fmt::Debug,
+        V: TryInto<http::header::HeaderValue>,
+        V::Error: fmt::Debug,
+    {
+        self.metadata.append(
+            key.try_into().expect("invalid metadata key"),
+            value.try_into().expect("invalid metadata value"),
+        );
+        self
+    }
+
+    fn status_headers(&self) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("grpc-status", (self.code as u32).into());
+        if !self.status_message.is_empty() {
+            let encoded: String =
+                form_urlencoded::byte_serialize(self.status_message.as_bytes()).collect();
+            headers.insert(
+                "grpc-message",
+                encoded
+                    .replace('+', "%20")
+                    .parse()
+                    .expect("invalid grpc-message"),
+            );
+        }
+        headers
+    }
+}
+
+impl Responder for GrpcResponse {
+    fn respond<'a>(
+        &mut self,
+        _req: &'a http::Request<Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<Bytes>> + Send + 'a>> {
+        let mut resp = http::Response::new(Bytes::new());
+        *resp.headers_mut() = self.metadata.clone();
+        resp.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/grpc"),
+        );
+        match &self.message {
+            Some(message) => {
+                *resp.body_mut() = encode_message(message);
+                resp.extensions_mut()
+                    .insert(Trailers(self.status_headers()));
+            }
+            // without a message the status is sent in the headers, as a
+            // trailers-only response.
+            None => resp.headers_mut().extend(self.status_headers()),
+        }
+        Box::pin(async move { resp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchers::any;
+
+    fn grpc_request(path: &str, body: Bytes) -> http::Request<Bytes> {
+        http::Request::post(path)
+            .header("content-type", "application/grpc")
+            .body(body)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_call() {
+        let req = grpc_request("/helloworld.Greeter/SayHello", Bytes::new());
+        assert!(ExecutionContext::evaluate(
+            &mut call("helloworld.Greeter", "SayHello"),
+            &req
+        ));
+        assert!(!ExecutionContext::evaluate(
+            &mut call("helloworld.Greeter", "SayGoodbye"),
+            &req
+        ));
+
+        let req = http::Request::post("/helloworld.Greeter/SayHello")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(!ExecutionContext::evaluate(
+            &mut call("helloworld.Greeter", "SayHello"),
+            &req
+        ));
+    }
+
+    #[test]
+    fn test_message() {
+        let req = grpc_request("/s/m", encode_message(b"hello"));
+        assert!(ExecutionContext::evaluate(
+            &mut message(&b"hello"[..]),
+            &req
+        ));
+
+        // truncated or compressed messages don't match.
+        let req = grpc_request("/s/m", Bytes::from_static(b"\x00\x00\x00\x00\x05hel"));
+        assert!(!ExecutionContext::evaluate(&mut message(any()), &req));
+        let req = grpc_request("/s/m", Bytes::from_static(b"\x01\x00\x00\x00\x01h"));
+        assert!(!ExecutionContext::evaluate(&mut message(any()), &req));
+    }
+}
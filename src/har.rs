@@ -0,0 +1,281 @@
+//! Seed expectations from a [HAR](https://w3c.github.io/web-performance/specs/HAR/Overview.html)
+//! file.
+//!
+//! Each entry in the HAR becomes an expectation that matches requests with
+//! the entry's method, path and query and responds with the entry's response.
+//! The expectations match any number of requests, so a HAR can be used as a
+//! fixture without knowing exactly how many requests a test makes.
+//!
+//! Entries recorded for the same method, path and query share an expectation
+//! that responds with each of their responses in the order they were
+//! recorded, then keeps responding with the last. Entries with a status of 0,
+//! which browsers record for requests that never got a response, are skipped.
+//!
+//! ```no_run
+//! # use httptest::Server;
+//! let server = Server::run();
+//! server.expect_har("tests/fixtures/checkout.har").unwrap();
+//! ```
+
+use crate::matchers::{all_of, eq, request, Matcher};
+use crate::responders::Responder;
+use crate::Expectation;
+use base64::Engine;
+use bytes::Bytes;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+
+/// Read the HAR file at `path` and return an expectation for each entry.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Expectation>> {
+    parse(&std::fs::read(path)?)
+}
+
+/// Parse a HAR document and return an expectation for each entry.
+pub fn parse(har: &[u8]) -> io::Result<Vec<Expectation>> {
+    Ok(stubs(har)?
+        .into_iter()
+        .map(Stub::into_expectation)
+        .collect())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid HAR: {}", msg))
+}
+
+// The responses recorded for a request, in the order they were recorded.
+struct Stub {
+    method: String,
+    path: String,
+    query: String,
+    responses: Vec<http::Response<Bytes>>,
+}
+
+impl Stub {
+    fn matcher(&self) -> impl Matcher<http::Request<Bytes>> {
+        let matchers: Vec<Box<dyn Matcher<http::Request<Bytes>>>> = vec![
+            Box::new(request::method_path(
+                eq(self.method.clone()),
+                eq(self.path.clone()),
+            )),
+            Box::new(request::query(eq(self.query.clone()))),
+        ];
+        all_of(matchers)
+    }
+
+    fn into_expectation(self) -> Expectation {
+        Expectation::matching(self.matcher())
+            .times(..)
+            .respond_with(Replay {
+                responses: self.responses,
+                next: 0,
+            })
+    }
+}
+
+// Group the entries of a HAR document by request, in the order each request
+// was first recorded.
+fn stubs(har: &[u8]) -> io::Result<Vec<Stub>> {
+    let har: serde_json::Value = serde_json::from_slice(har)?;
+    let entries = har["log"]["entries"]
+        .as_array()
+        .ok_or_else(|| invalid("missing log.entries".to_string()))?;
+    let mut stubs: Vec<Stub> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = parse_entry(entry).map_err(|e| invalid(format!("entry {}: {}", i, e)))?;
+        let Some(stub) = entry else {
+            continue;
+        };
+        let same_request = |existing: &&mut Stub| {
+            existing.method == stub.method
+                && existing.path == stub.path
+                && existing.query == stub.query
+        };
+        match stubs.iter_mut().find(same_request) {
+            Some(existing) => existing.responses.extend(stub.responses),
+            None => stubs.push(stub),
+        }
+    }
+    Ok(stubs)
+}
+
+// Parse an entry into a stub with a single response. Returns None for
+// entries that never received a response.
+fn parse_entry(entry: &serde_json::Value) -> Result<Option<Stub>, String> {
+    let req = &entry["request"];
+    let method = req["method"].as_str().ok_or("missing request.method")?;
+    let url: http::Uri = req["url"]
+        .as_str()
+        .ok_or("missing request.url")?
+        .parse()
+        .map_err(|e| format!("invalid request.url: {}", e))?;
+
+    let resp = &entry["response"];
+    let status = resp["status"].as_u64().ok_or("missing response.status")?;
+    if status == 0 {
+        return Ok(None);
+    }
+    let status =
+        u16::try_from(status).map_err(|_| format!("invalid response.status {}", status))?;
+    let mut builder = http::Response::builder().status(status);
+    for header in resp["headers"].as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (header["name"].as_str(), header["value"].as_str()) else {
+            return Err("invalid response header".to_string());
+        };
+        // HAR content is recorded decoded, so the original framing and
+        // encoding no longer apply. Pseudo headers are from HTTP/2.
+        let skip = name.starts_with(':')
+            || ["content-length", "content-encoding", "transfer-encoding"]
+                .iter()
+                .any(|skipped| name.eq_ignore_ascii_case(skipped));
+        if !skip {
+            builder = builder.header(name, value);
+        }
+    }
+    let content = &resp["content"];
+    let text = content["text"].as_str().unwrap_or("");
+    let body = match content["encoding"].as_str() {
+        Some("base64") => base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .map_err(|e| format!("invalid base64 response.content.text: {}", e))?,
+        _ => text.as_bytes().to_vec(),
+    };
+    let response = builder
+        .body(Bytes::from(body))
+        .map_err(|e| format!("invalid response: {}", e))?;
+
+    Ok(Some(Stub {
+        method: method.to_string(),
+        path: url.path().to_string(),
+        query: url.query().unwrap_or("").to_string(),
+        responses: vec![response],
+    }))
+}
+
+// Responds with each response in turn, repeating the last once they've all
+// been used.
+#[derive(Debug)]
+struct Replay {
+    responses: Vec<http::Response<Bytes>>,
+    next: usize,
+}
+
+impl Responder for Replay {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<Bytes>> + Send + 'a>> {
+        let idx = self.next.min(self.responses.len() - 1);
+        self.next += 1;
+        self.responses[idx].respond(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchers::ExecutionContext;
+
+    fn entry(url: &str, status: u64, body: &str) -> serde_json::Value {
+        serde_json::json!({
+            "request": {"method": "GET", "url": url},
+            "response": {
+                "status": status,
+                "headers": [
+                    {"name": "content-type", "value": "text/plain"},
+                    {"name": "content-encoding", "value": "gzip"},
+                ],
+                "content": {"text": body},
+            },
+        })
+    }
+
+    async fn respond(stub: &mut Replay, req: &http::Request<Bytes>) -> (u16, Bytes) {
+        let resp = stub.respond(req).await;
+        (resp.status().as_u16(), resp.into_body())
+    }
+
+    #[tokio::test]
+    async fn test_parse() {
+        let har = serde_json::json!({
+            "log": {
+                "entries": [
+                    entry("https://example.com/foo?page=2", 200, "first"),
+                    entry("https://example.com/bar", 0, ""),
+                    entry("https://example.com/foo?page=2", 503, "second"),
+                    {
+                        "request": {"method": "GET", "url": "https://example.com/img"},
+                        "response": {
+                            "status": 200,
+                            "content": {"text": "iVBORw==", "encoding": "base64"},
+                        },
+                    },
+                ],
+            },
+        });
+        let mut stubs = stubs(har.to_string().as_bytes()).unwrap();
+        // the status 0 entry is skipped and the repeated request is grouped.
+        assert_eq!(2, stubs.len());
+
+        let img = stubs.pop().unwrap();
+        assert_eq!(&[0x89, b'P', b'N', b'G'][..], &img.responses[0].body()[..]);
+
+        let foo = stubs.pop().unwrap();
+        let req = http::Request::get("/foo?page=2")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(ExecutionContext::evaluate(&mut foo.matcher(), &req));
+        let other = http::Request::get("/foo?page=3")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(!ExecutionContext::evaluate(&mut foo.matcher(), &other));
+        let post = http::Request::post("/foo?page=2")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(!ExecutionContext::evaluate(&mut foo.matcher(), &post));
+        assert_eq!("text/plain", foo.responses[0].headers()["content-type"]);
+        assert!(!foo.responses[0].headers().contains_key("content-encoding"));
+
+        // the responses are replayed in order, then the last is repeated.
+        let mut replay = Replay {
+            responses: foo.responses,
+            next: 0,
+        };
+        assert_eq!(
+            (200, Bytes::from("first")),
+            respond(&mut replay, &req).await
+        );
+        assert_eq!(
+            (503, Bytes::from("second")),
+            respond(&mut replay, &req).await
+        );
+        assert_eq!(
+            (503, Bytes::from("second")),
+            respond(&mut replay, &req).await
+        );
+        assert_eq!(2, parse(har.to_string().as_bytes()).unwrap().len());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let err = parse(br#"{"log": {"entries": [{"request": {}}]}}"#)
+            .err()
+            .unwrap();
+        assert_eq!(
+            "invalid HAR: entry 0: missing request.method",
+            err.to_string()
+        );
+
+        let har = serde_json::json!({
+            "log": {"entries": [entry("https://example.com/foo", 65736, "")]},
+        });
+        let err = parse(har.to_string().as_bytes()).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(
+            "invalid HAR: entry 0: invalid response.status 65736",
+            err.to_string()
+        );
+    }
+}
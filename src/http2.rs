@@ -0,0 +1,309 @@
+//! Serve HTTP/2 with server push.
+//!
+//! hyper doesn't support server push, so when it's enabled HTTP/2
+//! connections are served using h2 directly and HTTP/1 connections are still
+//! handed to hyper. Responses carrying push promises, see
+//! [responders::push](../responders/fn.push.html), have them sent ahead of
+//! the response.
+
+use crate::responders::{PushPromise, PushPromises};
+use bytes::Bytes;
+use futures::future::FutureExt;
+use futures::stream::{FuturesUnordered, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Frame};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// What a client speaking HTTP/2 with prior knowledge sends first.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Determine whether the client is speaking HTTP/2, from the protocol
+// negotiated with ALPN or otherwise by reading the start of the connection.
+// The bytes read are replayed by the returned stream.
+pub(crate) async fn detect<S>(mut stream: S, alpn: Option<&str>) -> io::Result<(bool, Rewind<S>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut read = Vec::with_capacity(PREFACE.len());
+    if let Some(alpn) = alpn {
+        let rewind = Rewind {
+            prefix: Bytes::new(),
+            inner: stream,
+        };
+        return Ok((alpn == "h2", rewind));
+    }
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let mut buf = [0; PREFACE.len()];
+        let n = stream.read(&mut buf[..PREFACE.len() - read.len()]).await?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    let is_http2 = read == PREFACE;
+    Ok((
+        is_http2,
+        Rewind {
+            prefix: Bytes::from(read),
+            inner: stream,
+        },
+    ))
+}
+
+// A stream that replays the bytes read while detecting the protocol.
+pub(crate) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = std::cmp::min(self.prefix.len(), buf.remaining());
+            buf.put_slice(&self.prefix.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// The body of a request received over HTTP/2.
+pub(crate) struct RecvBody {
+    stream: h2::RecvStream,
+    data_done: bool,
+}
+
+impl Body for RecvBody {
+    type Data = Bytes;
+    type Error = h2::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, h2::Error>>> {
+        if !self.data_done {
+            match futures::ready!(self.stream.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    // let the client send more.
+                    let _ = self.stream.flow_control().release_capacity(data.len());
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => self.data_done = true,
+            }
+        }
+        match futures::ready!(self.stream.poll_trailers(cx)) {
+            Ok(Some(trailers)) => Poll::Ready(Some(Ok(Frame::trailers(trailers)))),
+            Ok(None) => Poll::Ready(None),
+            Err(err) => Poll::Ready(Some(Err(err))),
+        }
+    }
+}
+
+// Serve the requests made on an HTTP/2 connection until the client closes
+// it. Once `shutdown` completes the connection is shut down gracefully.
+pub(crate) async fn serve_connection<T, D, S, F>(io: T, shutdown: D, service: S)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    D: Future,
+    S: Fn(http::Request<RecvBody>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    let mut conn = match h2::server::handshake(io).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("http2 handshake failed: {}", err);
+            return;
+        }
+    };
+    let shutdown = shutdown.fuse();
+    futures::pin_mut!(shutdown);
+    let mut requests = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = conn.accept() => match accepted {
+                Some(Ok((req, respond))) => requests.push(serve_request(req, respond, &service)),
+                Some(Err(err)) => {
+                    log::debug!("http2 connection closed with error: {}", err);
+                    break;
+                }
+                None => break,
+            },
+            Some(_) = requests.next(), if !requests.is_empty() => {}
+            // let in-flight requests complete before closing the connection.
+            _ = &mut shutdown => conn.graceful_shutdown(),
+        }
+    }
+    while requests.next().await.is_some() {}
+}
+
+async fn serve_request<S, F>(
+    req: http::Request<h2::RecvStream>,
+    respond: h2::server::SendResponse<Bytes>,
+    service: &S,
+) where
+    S: Fn(http::Request<RecvBody>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    if let Err(err) = try_serve_request(req, respond, service).await {
+        log::debug!("http2 request failed: {}", err);
+    }
+}
+
+async fn try_serve_request<S, F>(
+    req: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    service: &S,
+) -> Result<(), BoxError>
+where
+    S: Fn(http::Request<RecvBody>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    let req = req.map(|stream| RecvBody {
+        stream,
+        data_done: false,
+    });
+    let resp = match service(req).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            respond.send_reset(h2::Reason::INTERNAL_ERROR);
+            return Err(err);
+        }
+    };
+    let (mut parts, body) = resp.into_parts();
+    remove_connection_headers(&mut parts.headers);
+
+    // promises are sent before the response that refers to them.
+    let mut pushes = Vec::new();
+    let promises = parts.extensions.remove::<PushPromises>();
+    for promise in promises.into_iter().flat_map(|promises| promises.0) {
+        let request = http::Request::get(promise.uri.clone()).body(())?;
+        match respond.push_request(request) {
+            Ok(pushed) => pushes.push(send_pushed(pushed, promise)),
+            // clients can refuse pushes.
+            Err(err) => log::debug!("unable to push {}: {}", promise.uri, err),
+        }
+    }
+    let end_of_stream = body.is_end_stream();
+    let stream = respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+    let send = async {
+        if !end_of_stream {
+            send_body(stream, body).await?;
+        }
+        Ok::<_, BoxError>(())
+    };
+    let (sent, pushed) = futures::join!(send, futures::future::join_all(pushes));
+    for result in pushed {
+        if let Err(err) = result {
+            log::debug!("http2 push failed: {}", err);
+        }
+    }
+    sent
+}
+
+async fn send_pushed(
+    mut pushed: h2::server::SendPushedResponse<Bytes>,
+    promise: PushPromise,
+) -> Result<(), BoxError> {
+    let mut response = http::Response::new(());
+    *response.status_mut() = promise.status;
+    *response.headers_mut() = promise.headers;
+    remove_connection_headers(response.headers_mut());
+    let end_of_stream = promise.body.is_empty();
+    let stream = pushed.send_response(response, end_of_stream)?;
+    if !end_of_stream {
+        let body = Full::new(promise.body).map_err(|never| match never {});
+        send_body(stream, body.boxed()).await?;
+    }
+    Ok(())
+}
+
+async fn send_body(
+    mut stream: h2::SendStream<Bytes>,
+    mut body: BoxBody<Bytes, BoxError>,
+) -> Result<(), BoxError> {
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                stream.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Err(err);
+            }
+        };
+        let mut data = match frame.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers)?;
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        // only send as much as the client's flow control window allows.
+        while !data.is_empty() {
+            stream.reserve_capacity(data.len());
+            let capacity = match futures::future::poll_fn(|cx| stream.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Err("stream closed by the client".into()),
+            };
+            let n = std::cmp::min(capacity, data.len());
+            stream.send_data(data.split_to(n), false)?;
+        }
+    }
+    stream.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+// Headers that are specific to HTTP/1 connections aren't allowed in HTTP/2.
+fn remove_connection_headers(headers: &mut http::HeaderMap) {
+    for name in [
+        http::header::CONNECTION,
+        http::header::TRANSFER_ENCODING,
+        http::header::UPGRADE,
+        http::header::HeaderName::from_static("keep-alive"),
+        http::header::HeaderName::from_static("proxy-connection"),
+    ] {
+        headers.remove(name);
+    }
+}
@@ -0,0 +1,157 @@
+//! Serve HTTP/3 over QUIC alongside HTTP/1 and HTTP/2.
+//!
+//! HTTP/3 is only served over TLS, so the server presents the same
+//! self-signed certificate it uses for TLS over TCP. Clients have to be told
+//! to trust it, see `Server::certificate_pem`.
+
+use crate::tls::Identity;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::stream::{FuturesUnordered, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// A UDP socket bound for HTTP/3, not yet serving.
+#[derive(Debug)]
+pub(crate) struct Http3 {
+    socket: UdpSocket,
+    config: quinn::ServerConfig,
+}
+
+impl Http3 {
+    // Bind to the same port as the TCP listener when it's free, otherwise any
+    // free port on the same ip.
+    pub(crate) fn bind(addr: SocketAddr, identity: &Arc<Identity>) -> io::Result<Self> {
+        let tls = identity.server_config(&[&rustls::version::TLS13], vec![b"h3".to_vec()])?;
+        let tls =
+            quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+        let config = quinn::ServerConfig::with_crypto(Arc::new(tls));
+
+        let socket = UdpSocket::bind(addr).or_else(|e| {
+            log::debug!("unable to bind udp {} for http3: {}", addr, e);
+            UdpSocket::bind(SocketAddr::new(addr.ip(), 0))
+        })?;
+        Ok(Http3 { socket, config })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Start accepting connections. Must be called from within a tokio
+    // runtime.
+    pub(crate) fn endpoint(self) -> io::Result<quinn::Endpoint> {
+        quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(self.config),
+            self.socket,
+            Arc::new(quinn::TokioRuntime),
+        )
+    }
+}
+
+// Serve the requests made on an HTTP/3 connection until the client closes
+// it.
+pub(crate) async fn serve_connection<S, F>(incoming: quinn::Incoming, service: S)
+where
+    S: Fn(http::Request<Full<Bytes>>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("http3 handshake failed: {}", err);
+            return;
+        }
+    };
+    let mut conn =
+        match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::debug!("http3 connection failed: {}", err);
+                return;
+            }
+        };
+    let mut requests = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = conn.accept() => match accepted {
+                Ok(Some(resolver)) => requests.push(serve_request(resolver, &service)),
+                Ok(None) => break,
+                Err(err) => {
+                    log::debug!("http3 connection closed with error: {}", err);
+                    break;
+                }
+            },
+            Some(_) = requests.next(), if !requests.is_empty() => {}
+        }
+    }
+    while requests.next().await.is_some() {}
+}
+
+async fn serve_request<S, F>(
+    resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>,
+    service: &S,
+) where
+    S: Fn(http::Request<Full<Bytes>>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    if let Err(err) = try_serve_request(resolver, service).await {
+        log::debug!("http3 request failed: {}", err);
+    }
+}
+
+async fn try_serve_request<S, F>(
+    resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>,
+    service: &S,
+) -> Result<(), BoxError>
+where
+    S: Fn(http::Request<Full<Bytes>>) -> F,
+    F: Future<Output = Result<http::Response<BoxBody<Bytes, BoxError>>, BoxError>>,
+{
+    let (req, mut stream) = resolver.resolve_request().await?;
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let (head, ()) = req.into_parts();
+    let req = http::Request::from_parts(head, Full::new(body.freeze()));
+
+    let resp = match service(req).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            // the TCP server closes the connection, only this request's
+            // stream is reset here.
+            stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+            return Err(err);
+        }
+    };
+    let (parts, mut body) = resp.into_parts();
+    stream
+        .send_response(http::Response::from_parts(parts, ()))
+        .await?;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Err(err);
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
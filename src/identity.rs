@@ -0,0 +1,232 @@
+//! Request identities.
+//!
+//! A request identity decides when two requests are "the same request", for
+//! example when counting how many times a client retried a request with
+//! [Server::retry_counts](../struct.Server.html#method.retry_counts).
+//!
+//! Identities are composed from the functions in this module using
+//! [and](trait.RequestIdentity.html#method.and). Any
+//! `FnMut(&http::Request<bytes::Bytes>) -> T` also works as an identity.
+//!
+//! ```
+//! use httptest::identity::{self, RequestIdentity};
+//!
+//! // Requests are the same if they have the same method, path and body.
+//! identity::method().and(identity::path()).and(identity::body_digest());
+//! ```
+
+use bytes::Bytes;
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+
+/// Computes the identity of a request. Requests with equal ids are the same
+/// request.
+pub trait RequestIdentity {
+    /// The identity of a request.
+    type Id: PartialEq + fmt::Debug;
+
+    /// Compute the identity of the request.
+    fn identity(&mut self, req: &http::Request<Bytes>) -> Self::Id;
+
+    /// Combine two identities. Requests are the same only if both identities
+    /// consider them the same.
+    fn and<B>(self, other: B) -> And<Self, B>
+    where
+        Self: Sized,
+        B: RequestIdentity,
+    {
+        And(self, other)
+    }
+}
+
+/// FnMut(&Request) -> T implements RequestIdentity.
+impl<F, T> RequestIdentity for F
+where
+    F: FnMut(&http::Request<Bytes>) -> T,
+    T: PartialEq + fmt::Debug,
+{
+    type Id = T;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> T {
+        self(req)
+    }
+}
+
+/// The `And` identity returned by [and()](trait.RequestIdentity.html#method.and)
+#[derive(Debug)]
+pub struct And<A, B>(A, B);
+impl<A, B> RequestIdentity for And<A, B>
+where
+    A: RequestIdentity,
+    B: RequestIdentity,
+{
+    type Id = (A::Id, B::Id);
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> Self::Id {
+        (self.0.identity(req), self.1.identity(req))
+    }
+}
+
+/// Identify requests by their method.
+pub fn method() -> Method {
+    Method
+}
+/// The `Method` identity returned by [method()](fn.method.html)
+#[derive(Debug)]
+pub struct Method;
+impl RequestIdentity for Method {
+    type Id = http::Method;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> http::Method {
+        req.method().clone()
+    }
+}
+
+/// Identify requests by their path, ignoring the query.
+pub fn path() -> Path {
+    Path
+}
+/// The `Path` identity returned by [path()](fn.path.html)
+#[derive(Debug)]
+pub struct Path;
+impl RequestIdentity for Path {
+    type Id = String;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> String {
+        req.uri().path().to_string()
+    }
+}
+
+/// Identify requests by their path and query.
+pub fn path_and_query() -> PathAndQuery {
+    PathAndQuery
+}
+/// The `PathAndQuery` identity returned by [path_and_query()](fn.path_and_query.html)
+#[derive(Debug)]
+pub struct PathAndQuery;
+impl RequestIdentity for PathAndQuery {
+    type Id = String;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> String {
+        req.uri()
+            .path_and_query()
+            .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string())
+    }
+}
+
+/// Identify requests by all the values of the named header.
+pub fn header(name: impl Into<String>) -> Header {
+    Header(name.into())
+}
+/// The `Header` identity returned by [header()](fn.header.html)
+#[derive(Debug)]
+pub struct Header(String);
+impl RequestIdentity for Header {
+    type Id = Vec<http::HeaderValue>;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> Self::Id {
+        req.headers()
+            .get_all(self.0.as_str())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Identify requests by a digest of their body. Unlike using the body itself,
+/// the digest is cheap to keep around for large bodies.
+pub fn body_digest() -> BodyDigest {
+    BodyDigest
+}
+/// The `BodyDigest` identity returned by [body_digest()](fn.body_digest.html)
+#[derive(Debug)]
+pub struct BodyDigest;
+impl RequestIdentity for BodyDigest {
+    type Id = Digest;
+
+    fn identity(&mut self, req: &http::Request<Bytes>) -> Digest {
+        Digest::of(req.body())
+    }
+}
+
+/// Identify requests by their method, path and query, and a digest of their
+/// body.
+pub fn method_uri_body() -> And<And<Method, PathAndQuery>, BodyDigest> {
+    method().and(path_and_query()).and(body_digest())
+}
+
+/// A SHA-256 digest of a byte string. Digests are stable across processes
+/// and Rust releases, so they can be written to files such as snapshots.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest {
+    len: usize,
+    hash: [u8; 32],
+}
+
+impl Digest {
+    /// Compute the digest of `bytes`.
+    pub fn of(bytes: &[u8]) -> Digest {
+        Digest {
+            len: bytes.len(),
+            hash: Sha256::digest(bytes).into(),
+        }
+    }
+}
+
+/// Formats the SHA-256 hash as lowercase hex.
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.hash {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest({} bytes, {})", self.len, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(uri: &str, body: &'static str) -> http::Request<Bytes> {
+        http::Request::post(uri)
+            .header("idempotency-key", "abc")
+            .body(Bytes::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_identities() {
+        let a = post("/foo?page=1", "body");
+        let b = post("/foo?page=2", "body");
+        let c = post("/foo?page=1", "other");
+
+        let mut id = path().and(body_digest());
+        assert_eq!(id.identity(&a), id.identity(&b));
+        assert_ne!(id.identity(&a), id.identity(&c));
+
+        let mut id = method_uri_body();
+        assert_eq!(id.identity(&a), id.identity(&post("/foo?page=1", "body")));
+        assert_ne!(id.identity(&a), id.identity(&b));
+        assert_ne!(id.identity(&a), id.identity(&c));
+
+        let mut id = header("idempotency-key");
+        assert_eq!(id.identity(&a), vec![http::HeaderValue::from_static("abc")]);
+
+        let mut id = |req: &http::Request<Bytes>| req.uri().query().map(str::to_string);
+        assert_eq!(Some("page=2".to_string()), id.identity(&b));
+    }
+
+    #[test]
+    fn test_digest_stable() {
+        assert_eq!(
+            "Digest(3 bytes, ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad)",
+            format!("{:?}", Digest::of(b"abc"))
+        );
+    }
+}
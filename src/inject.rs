@@ -0,0 +1,131 @@
+//! Write bytes to an HTTP/1 connection outside of hyper's framing.
+//!
+//! hyper never writes more of a body than its `Content-Length` allows, so
+//! responses that deliberately break the framing queue their extra bytes on
+//! the connection's [Injector](struct.Injector.html) instead. Queued bytes are
+//! written the next time hyper flushes the connection, after everything hyper
+//! has written so far. Responses that hyper can't write at all, such as ones
+//! with headers in a specific order, replace what hyper writes for them.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Queues bytes to be written to a connection. Added to the extensions of
+/// every HTTP/1 request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Injector(Arc<Mutex<Injected>>);
+
+#[derive(Debug, Default)]
+struct Injected {
+    // bytes to write on the next flush.
+    pending: Vec<u8>,
+    // while set, what hyper writes is discarded and this is written in its
+    // place on the next flush.
+    replacement: Option<Vec<u8>>,
+}
+
+impl Injector {
+    /// Write `bytes` once everything hyper has written so far is flushed.
+    pub(crate) fn inject(&self, bytes: &[u8]) {
+        self.lock().pending.extend_from_slice(bytes);
+    }
+
+    /// Discard what hyper writes until it next flushes, writing `bytes`
+    /// instead. Must be called just before handing hyper the response to
+    /// replace, hyper writes a response without flushing in between.
+    pub(crate) fn replace_response(&self, bytes: Vec<u8>) {
+        self.lock().replacement = Some(bytes);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Injected> {
+        self.0.lock().expect("mutex poisoned")
+    }
+}
+
+/// A stream that writes the bytes queued on its injector when flushed.
+pub(crate) struct InjectStream<S> {
+    inner: S,
+    injector: Injector,
+}
+
+impl<S: AsyncWrite + Unpin> InjectStream<S> {
+    pub(crate) fn new(inner: S) -> (InjectStream<S>, Injector) {
+        let injector = Injector::default();
+        let stream = InjectStream {
+            inner,
+            injector: injector.clone(),
+        };
+        (stream, injector)
+    }
+
+    fn poll_write_injected(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut injected = self.injector.lock();
+        if let Some(replacement) = injected.replacement.take() {
+            injected.pending.extend(replacement);
+        }
+        let pending = &mut injected.pending;
+        while !pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, pending) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(n)) => drop(pending.drain(..n)),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for InjectStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for InjectStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.injector.lock().replacement.is_some() {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.injector.lock().replacement.is_some() {
+            return Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()));
+        }
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    // hyper only flushes once its own buffer has been written, so injected
+    // bytes follow everything hyper wrote before they were queued.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_write_injected(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        futures::ready!(self.poll_write_injected(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
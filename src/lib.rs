@@ -66,12 +66,24 @@ verifies it has not exceeded it's expected number of requests. If the
 limit has been exceeded a 500 error is returned, if the limit has not been
 exceeded it uses the expectation's responder to respond to the request. If the
 request does not match any expectation a 500 error is returned.
+Expectations given a higher
+[priority](struct.ExpectationBuilder.html#method.priority) are checked before
+all those with a lower priority, regardless of the order they were added.
+
+The server can also be used as a client's HTTP proxy. Absolute-form requests
+(`GET http://example.com/foo`) are matched like any other request. When a
+`CONNECT` request is answered with a 2xx status the requests sent through the
+tunnel are served by the same expectations.
+[request::proxy_target](matchers/request/fn.proxy_target.html) matches the
+target the client asked the proxy for.
 
 When the server is Dropped it:
 * Stops running
 * Panics if
   * any expectation did not receive the expected number of requests
-  * a request was received that did not match any expectation
+  * a request was received that did not match any expectation. The panic
+    message includes the expectation that came closest to matching each
+    unexpected request to make typos easy to spot.
 
 Clients can determine the address and port the server is reachable at using
 [Server::addr](struct.Server.html#method.addr), or the helper methods
@@ -95,9 +107,14 @@ The [ServerPool](struct.ServerPool.html) allows limiting the number of
 servers that can be running concurrently while still allowing test cases to
 function independently.
 
+Each server also starts its own thread and runtime. To avoid that cost,
+[ServerBuilder::shared_runtime](struct.ServerBuilder.html#method.shared_runtime)
+runs servers on a single runtime shared by the whole process, and setting the
+`HTTPTEST_SHARED_RUNTIME=1` environment variable makes that the default.
+
 ### ServerPool example
 
-```
+```no_run
 # use httptest::ServerPool;
 // Create a server pool that will create at most 2 servers.
 static SERVER_POOL: ServerPool = ServerPool::new(2);
@@ -292,6 +309,68 @@ cycle![
     status_code(404),
 ];
 ```
+
+# Cargo features
+
+* `tls` - serve connections over TLS with a self-signed certificate. See
+  [ServerBuilder::tls](struct.ServerBuilder.html#method.tls).
+* `tracing` - instrument the server with [tracing](https://docs.rs/tracing)
+  spans and events. Each received request gets an `httptest.request` span
+  recording the method, path, the matched expectation and the response status.
+  Responders run within an `httptest.respond` span and matcher evaluation emits
+  trace level events.
+* `connector` - [Server::connector](struct.Server.html#method.connector)
+  connects hyper clients to the server in-process, without opening any
+  sockets.
+* `reqwest` - helpers to point a [reqwest](https://docs.rs/reqwest) client at
+  the server: [Server::reqwest_url](struct.Server.html#method.reqwest_url) and
+  [Server::reqwest_client](struct.Server.html#method.reqwest_client). With
+  `tls` also enabled,
+  [Server::trusted_client](struct.Server.html#method.trusted_client) trusts
+  the server's certificate.
+* `jwt` - [matchers::jwt](matchers/fn.jwt.html) matches the claims of
+  JSON Web Tokens, optionally verifying their signature, and
+  [responders::jwt_token](responders/fn.jwt_token.html) issues signed ones.
+* `oidc` - a fake OAuth2 / OpenID Connect identity provider. See the
+  [presets::oidc](presets/oidc/index.html) module.
+* `openapi` - validate requests and responses against an OpenAPI document.
+  See the [openapi](openapi/index.html) module.
+* `record` - forward unmatched requests to a real upstream and record the
+  exchanges so they can be replayed as expectations. See the
+  [record](record/index.html) module.
+* `yaml` - [responders::yaml_encoded](responders/fn.yaml_encoded.html)
+  responds with a yaml encoded body.
+* `http3` - serve HTTP/3 over QUIC as well. See
+  [ServerBuilder::http3](struct.ServerBuilder.html#method.http3). This is
+  experimental.
+* `http2-push` - [responders::push](responders/fn.push.html) pushes
+  responses to HTTP/2 clients. See
+  [ServerBuilder::http2_server_push](struct.ServerBuilder.html#method.http2_server_push).
+  This is experimental.
+* `msgpack` - [responders::msgpack_encoded](responders/fn.msgpack_encoded.html)
+  responds with a MessagePack encoded body.
+* `cbor` - [matchers::cbor_decoded](matchers/fn.cbor_decoded.html) matches
+  CBOR encoded bodies and
+  [responders::cbor_encoded](responders/fn.cbor_encoded.html) responds with
+  one.
+* `decompress` - decompress gzip, deflate and brotli request bodies before
+  matching them. See
+  [ServerBuilder::decompress_request_bodies](struct.ServerBuilder.html#method.decompress_request_bodies).
+* `digest-auth` - fake HTTP Digest authentication. See the
+  [presets::digest](presets/digest/index.html) module.
+* `grpc` - match and respond to unary gRPC calls. See the
+  [grpc](grpc/index.html) module.
+* `s3` - a fake S3-compatible object store. See the
+  [presets::s3](presets/s3/index.html) module.
+* `smol` - [runtime::SmolRuntime](runtime/struct.SmolRuntime.html) runs the
+  server on smol instead of tokio.
+* `soap` - match SOAP requests by action and XPath and respond with SOAP
+  envelopes. See the [soap](soap/index.html) module.
+* `webhook` - [responders::webhook](responders/fn.webhook.html) calls back
+  the client after responding.
+* `wiremock` - load WireMock stub mappings as expectations. See the
+  [wiremock](wiremock/index.html) module.
+
 !*/
 
 #![deny(missing_docs)]
@@ -320,6 +399,18 @@ macro_rules! any_of {
     ($($x:expr,)*) => ($crate::any_of![$($x),*]);
 }
 
+/// true if at least `n` of the provided matchers return true.
+///
+/// The macro exists to conveniently box a list of matchers and put them into a
+/// `Vec<Box<dyn Matcher>>`. The translation is:
+///
+/// `n_of![n; a, b] => n_of(n, vec![Box::new(a), Box::new(b)])`
+#[macro_export]
+macro_rules! n_of {
+    ($n:expr; $($x:expr),*) => ($crate::matchers::n_of($n, $crate::vec_of_boxes![$($x),*]));
+    ($n:expr; $($x:expr,)*) => ($crate::n_of![$n; $($x),*]);
+}
+
 /// a Responder that cycles through a list of responses.
 ///
 /// The macro exists to conveniently box a list of responders and put them into a
@@ -332,6 +423,86 @@ macro_rules! cycle {
     ($($x:expr,)*) => ($crate::cycle![$($x),*]);
 }
 
+/// a list of (matcher, responder) branches for
+/// [ExpectationBuilder::respond_with_map](struct.ExpectationBuilder.html#method.respond_with_map).
+///
+/// The macro exists to conveniently box the matchers and responders and put
+/// them into a `Vec<(Box<dyn Matcher>, Box<dyn Responder>)>`. The translation
+/// is:
+///
+/// `branches![a => x, b => y] => vec![(Box::new(a), Box::new(x)), (Box::new(b), Box::new(y))]`
+#[macro_export]
+macro_rules! branches {
+    ($($m:expr => $r:expr),*) => (std::vec![$((
+        std::boxed::Box::new($m) as std::boxed::Box<dyn $crate::matchers::Matcher<_>>,
+        std::boxed::Box::new($r) as std::boxed::Box<dyn $crate::responders::Responder>,
+    )),*]);
+    ($($m:expr => $r:expr,)*) => ($crate::branches![$($m => $r),*]);
+}
+
+/// Add an expectation to a server using a compact syntax for the common case
+/// of matching a method, path and headers and responding with a status and
+/// body. Returns the [ExpectationHandle](struct.ExpectationHandle.html).
+///
+/// ```text
+/// expect!(server, METHOD "/path?query"
+///     [with header "name" == "value"]*
+///     [with body "body"]
+///     => STATUS [json {...} | body expr])
+/// ```
+///
+/// The request must include the query parameters in the path, in any order.
+/// Header names are case insensitive. Like
+/// [Expectation::matching](struct.Expectation.html#method.matching), the
+/// expectation matches exactly one request.
+///
+/// ```no_run
+/// # use httptest::{expect, Server};
+/// let server = Server::run();
+/// expect!(server, GET "/foo?x=1"
+///     with header "accept" == "application/json"
+///     => 200 json {"ok": true});
+/// expect!(server, POST "/upload" with body "hello" => 201 body "created");
+/// expect!(server, DELETE "/foo" => 204);
+/// ```
+#[macro_export]
+macro_rules! expect {
+    ($server:expr, $method:ident $path:literal
+        $(with header $name:literal == $value:literal)*
+        $(with body $body:literal)?
+        => $status:literal $($response:tt)*) => {
+        $server.expect(
+            $crate::Expectation::matching($crate::all_of![
+                $crate::matchers::request::method(stringify!($method)),
+                $crate::macro_support::path_and_query($path),
+                $($crate::matchers::request::headers($crate::matchers::contains((
+                    $name.to_ascii_lowercase(),
+                    $value,
+                ))),)*
+                $($crate::matchers::request::body($body),)?
+            ])
+            .respond_with($crate::__expect_response!($status $($response)*)),
+        )
+    };
+}
+
+// hidden from docs because it's an implementation detail of expect!.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __expect_response {
+    ($status:literal) => {
+        $crate::responders::status_code($status)
+    };
+    ($status:literal json $json:tt) => {
+        $crate::responders::status_code($status)
+            .append_header("Content-Type", "application/json")
+            .body($crate::macro_support::serde_json::json!($json).to_string())
+    };
+    ($status:literal body $body:expr) => {
+        $crate::responders::status_code($status).body($body)
+    };
+}
+
 // hidden from docs because it's an implementation detail of the above macros.
 #[doc(hidden)]
 #[macro_export]
@@ -344,12 +515,58 @@ macro_rules! vec_of_boxes {
 pub use bytes;
 pub use http;
 
+mod capture;
+mod chaos;
+pub mod clock;
+#[cfg(feature = "connector")]
+mod connector;
+#[cfg(feature = "decompress")]
+mod decompress;
+mod diagnostics;
+mod diff;
+pub mod fixtures;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod har;
+#[cfg(feature = "http2-push")]
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+pub mod identity;
+mod inject;
 mod into_times;
+#[cfg(any(feature = "jwt", feature = "oidc"))]
+mod jwt;
+#[doc(hidden)]
+pub mod macro_support;
 pub mod matchers;
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod presets;
+#[cfg(feature = "record")]
+pub mod record;
 pub mod responders;
+mod rng;
+pub mod runtime;
 mod server;
 mod server_pool;
-
+mod snapshot;
+#[cfg(feature = "soap")]
+pub mod soap;
+#[cfg(feature = "tls")]
+mod tls;
+mod trace;
+#[cfg(feature = "wiremock")]
+pub mod wiremock;
+
+pub use chaos::Chaos;
+#[cfg(feature = "connector")]
+pub use connector::{Connector, InProcessStream};
+pub use diagnostics::DiagnosticEvent;
 pub use into_times::IntoTimes;
-pub use server::{Expectation, ExpectationBuilder, Server, ServerBuilder};
-pub use server_pool::{ServerHandle, ServerPool};
+pub use server::{
+    ConnectionInfo, ConnectionMetrics, Expectation, ExpectationBuilder, ExpectationGuard,
+    ExpectationHandle, ExpectationReport, SavedExpectations, Scope, Server, ServerBuilder,
+    UploadAction, UploadProgress, UrlBuilder, VerificationErrors, VerificationReport, WaitTimeout,
+};
+pub use server_pool::{PoolTimeout, ServerHandle, ServerPool};
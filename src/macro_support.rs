@@ -0,0 +1,24 @@
+//! Implementation details of the crate's macros. Not part of the public API.
+
+use crate::matchers::{all_of, contains, request, url_decoded, Matcher};
+use bytes::Bytes;
+
+pub use serde_json;
+
+/// Match the path and query of a `path?query` string. The request must
+/// include each query parameter, in any order.
+pub fn path_and_query(path_and_query: &str) -> impl Matcher<http::Request<Bytes>> {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path_and_query, ""),
+    };
+    let mut matchers: Vec<Box<dyn Matcher<http::Request<Bytes>>>> =
+        vec![Box::new(request::path(path.to_string()))];
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        matchers.push(Box::new(request::query(url_decoded(contains((
+            key.into_owned(),
+            value.into_owned(),
+        ))))));
+    }
+    all_of(matchers)
+}
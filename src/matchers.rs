@@ -3,6 +3,7 @@
 //! This module contains matchers for composing a set of operations. The result
 //! of the composition usually results in a boolean.
 
+use crate::diagnostics::DiagnosticEvent;
 use std::borrow::Borrow;
 use std::fmt;
 use std::marker::PhantomData;
@@ -13,7 +14,10 @@ use std::marker::PhantomData;
 pub use crate::all_of;
 #[doc(inline)]
 pub use crate::any_of;
+#[doc(inline)]
+pub use crate::n_of;
 
+pub mod multipart;
 pub mod request;
 
 /// An ExecutionContext tracks how Matchers are chained together. There is a
@@ -23,22 +27,79 @@ pub mod request;
 pub struct ExecutionContext {
     // Users outside this crate should not need to construct an ExecutionContext.
     stack_depth: usize,
+    // When true composite matchers evaluate every child rather than
+    // short-circuiting. Used when scoring how close an input came to matching.
+    exhaustive: bool,
+    // number of chained matchers evaluated and how many of them matched.
+    evaluated: usize,
+    matched: usize,
+    // descriptions of why matchers failed, only collected when exhaustive.
+    mismatches: Vec<String>,
+    // When true only the request head is available. Body matchers match
+    // without looking at the body and record that they needed it.
+    head_only: bool,
+    needs_body: bool,
+    // matcher evaluations in the order they were entered, only collected when
+    // there's a diagnostics sink to deliver them to.
+    events: Option<Vec<DiagnosticEvent>>,
 }
 
 impl ExecutionContext {
+    fn new(exhaustive: bool) -> Self {
+        ExecutionContext {
+            stack_depth: 0,
+            exhaustive,
+            evaluated: 0,
+            matched: 0,
+            mismatches: Vec::new(),
+            head_only: false,
+            needs_body: false,
+            events: None,
+        }
+    }
+
     /// Evaluate the given matcher with the provided input.
     pub fn evaluate<M, I>(matcher: &mut M, input: &I) -> bool
     where
         M: Matcher<I> + ?Sized,
         I: fmt::Debug + ?Sized,
     {
-        let mut ctx = ExecutionContext { stack_depth: 0 };
+        ExecutionContext::new(false).evaluate_inner(matcher, input)
+    }
+
+    /// Evaluate the matcher, also returning an event for every matcher
+    /// evaluated along the way.
+    pub(crate) fn evaluate_with_events<M, I>(
+        matcher: &mut M,
+        input: &I,
+    ) -> (bool, Vec<DiagnosticEvent>)
+    where
+        M: Matcher<I> + ?Sized,
+        I: fmt::Debug + ?Sized,
+    {
+        let mut ctx = ExecutionContext::new(false);
+        ctx.events = Some(Vec::new());
+        let x = ctx.evaluate_inner(matcher, input);
+        (x, ctx.events.unwrap_or_default())
+    }
+
+    fn evaluate_inner<M, I>(&mut self, matcher: &mut M, input: &I) -> bool
+    where
+        M: Matcher<I> + ?Sized,
+        I: fmt::Debug + ?Sized,
+    {
         log::debug!(
             "Matching {:?} with input: {:?}",
             matcher_name(matcher),
             input
         );
-        let x = matcher.matches(input, &mut ctx);
+        let event = self.start_event(matcher);
+        let x = matcher.matches(input, self);
+        if !x {
+            self.describe_unequal(matcher, input, crate::diff::debug_diff);
+        }
+        self.finish_event(event, x);
+        crate::trace::matcher_evaluated(0, &matcher_name(matcher), x);
         log::debug!(
             "┗━ {}",
             if x {
@@ -55,6 +116,21 @@ impl ExecutionContext {
     /// flow to provide better diagnostics about why a request did or did not
     /// match a composed set of matchers.
     pub fn chain<M, I>(&mut self, matcher: &mut M, input: &I) -> bool
+    where
+        M: Matcher<I> + ?Sized,
+        I: fmt::Debug + ?Sized,
+    {
+        self.chain_described(matcher, input, crate::diff::debug_diff)
+    }
+
+    // Like chain, but describes how the input differs from the value an eq
+    // matcher expected using the provided function.
+    pub(crate) fn chain_described<M, I>(
+        &mut self,
+        matcher: &mut M,
+        input: &I,
+        describe: fn(&I, &I) -> Option<String>,
+    ) -> bool
     where
         M: Matcher<I> + ?Sized,
         I: fmt::Debug + ?Sized,
@@ -68,7 +144,13 @@ impl ExecutionContext {
             matcher_name(matcher),
             input
         );
+        let event = self.start_event(matcher);
         let x = matcher.matches(input, self);
+        if !x {
+            self.describe_unequal(matcher, input, describe);
+        }
+        self.finish_event(event, x);
+        crate::trace::matcher_evaluated(self.stack_depth, &matcher_name(matcher), x);
         log::debug!(
             "{}┗━ {}",
             VerticalLines {
@@ -77,8 +159,134 @@ impl ExecutionContext {
             x
         );
         self.stack_depth -= 1;
+        self.evaluated += 1;
+        if x {
+            self.matched += 1;
+        }
         x
     }
+
+    // Add an event for the matcher about to be evaluated, returning its index
+    // so the result can be filled in once known.
+    fn start_event<M, I>(&mut self, matcher: &M) -> Option<usize>
+    where
+        M: Matcher<I> + ?Sized,
+        I: ?Sized,
+    {
+        let depth = self.stack_depth;
+        let events = self.events.as_mut()?;
+        events.push(DiagnosticEvent::MatcherEvaluated {
+            depth,
+            matcher: format!("{:?}", matcher_name(matcher)),
+            matched: false,
+        });
+        Some(events.len() - 1)
+    }
+
+    fn finish_event(&mut self, event: Option<usize>, x: bool) {
+        let event = event.and_then(|i| self.events.as_mut()?.get_mut(i));
+        if let Some(DiagnosticEvent::MatcherEvaluated { matched, .. }) = event {
+            *matched = x;
+        }
+    }
+
+    /// Determine how close the input came to matching. Composite matchers
+    /// evaluate all of their children instead of short-circuiting so that the
+    /// result reflects every sub-matcher.
+    pub(crate) fn closeness<M, I>(matcher: &mut M, input: &I) -> Closeness
+    where
+        M: Matcher<I> + ?Sized,
+        I: fmt::Debug + ?Sized,
+    {
+        let mut ctx = ExecutionContext::new(true);
+        if !matcher.matches(input, &mut ctx) {
+            ctx.describe_unequal(matcher, input, crate::diff::debug_diff);
+        }
+        Closeness {
+            matched: ctx.matched,
+            evaluated: ctx.evaluated,
+            mismatches: ctx.mismatches,
+        }
+    }
+
+    /// Determine whether the matcher could match a request with the given
+    /// head. Returns false only if the matcher doesn't match regardless of the
+    /// request body.
+    pub(crate) fn could_match_head<M, I>(matcher: &mut M, head: &I) -> bool
+    where
+        M: Matcher<I> + ?Sized,
+        I: fmt::Debug + ?Sized,
+    {
+        let mut ctx = ExecutionContext::new(false);
+        ctx.head_only = true;
+        let x = matcher.matches(head, &mut ctx);
+        x || ctx.needs_body
+    }
+
+    // Called by body matchers before looking at the body. Returns true if
+    // only the request head is being matched, in which case the body matcher
+    // should match without looking at the body.
+    pub(crate) fn body_unavailable(&mut self) -> bool {
+        if self.head_only {
+            self.needs_body = true;
+        }
+        self.head_only
+    }
+
+    // Describe how the input differs from the value the matcher expected it
+    // to equal, if it expects one.
+    fn describe_unequal<M, I>(
+        &mut self,
+        matcher: &M,
+        input: &I,
+        describe: fn(&I, &I) -> Option<String>,
+    ) where
+        M: Matcher<I> + ?Sized,
+        I: ?Sized,
+    {
+        if let Some(expected) = matcher.expected() {
+            self.mismatch(|| describe(expected, input));
+        }
+    }
+
+    // Record a description of why a matcher did not match. The description is
+    // only computed if it will be logged or reported.
+    fn mismatch(&mut self, describe: impl FnOnce() -> Option<String>) {
+        if !self.exhaustive && !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let description = match describe() {
+            Some(description) => description,
+            None => return,
+        };
+        log::debug!(
+            "{}{}",
+            VerticalLines {
+                num_lines: self.stack_depth + 1
+            },
+            description.replace(
+                '\n',
+                &format!(
+                    "\n{}",
+                    VerticalLines {
+                        num_lines: self.stack_depth + 1
+                    }
+                )
+            )
+        );
+        if self.exhaustive {
+            self.mismatches.push(description);
+        }
+    }
+}
+
+/// The number of chained matchers that matched an input out of the number that
+/// were evaluated, along with descriptions of the mismatches.
+#[derive(Debug, Clone)]
+pub(crate) struct Closeness {
+    pub(crate) matched: usize,
+    pub(crate) evaluated: usize,
+    pub(crate) mismatches: Vec<String>,
 }
 
 struct VerticalLines {
@@ -107,6 +315,12 @@ where
     /// formatted name of the mapper. This is used for debugging purposes and
     /// should typically look like a fmt::Debug representation.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result;
+
+    /// The value an input must be equal to for this matcher to match, if
+    /// there is one. Used to describe how a non-matching input differs.
+    fn expected(&self) -> Option<&IN> {
+        None
+    }
 }
 
 /// convenience function to print the Matcher::fmt representation of a mapper.
@@ -163,6 +377,14 @@ where
 
 /// true if the input is equal to value.
 ///
+/// When the values are not equal a description of how they differ is included
+/// in the debug log and in the closest expectation reported for unexpected
+/// requests. A `serde_json::Value` decoded by
+/// [json_decoded()](fn.json_decoded.html) lists each differing value by its
+/// json pointer, e.g. `/user/name: "alice" → "bob"`, along with any added or
+/// removed keys. Other values whose pretty-printed Debug representations span
+/// multiple lines are shown as a line diff of the two.
+///
 /// # Example
 ///
 /// ```
@@ -191,6 +413,10 @@ where
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         <Self as fmt::Debug>::fmt(self, f)
     }
+
+    fn expected(&self) -> Option<&IN> {
+        Some(self.0.borrow())
+    }
 }
 impl<T> fmt::Debug for Eq<T>
 where
@@ -201,6 +427,39 @@ where
     }
 }
 
+/// true if the input is equal to value, ignoring ASCII case.
+///
+/// Unlike `lowercase(eq(...))` the expected value is reported as it was
+/// written.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request with `content-type: application/json`,
+/// // `Application/JSON` or any other casing.
+/// request::headers(contains(("content-type", eq_nocase("application/json"))));
+/// ```
+pub fn eq_nocase(value: impl Into<String>) -> EqNoCase {
+    EqNoCase(value.into())
+}
+/// The `EqNoCase` mapper returned by [eq_nocase()](fn.eq_nocase.html)
+#[derive(Debug)]
+pub struct EqNoCase(String);
+impl<IN> Matcher<IN> for EqNoCase
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        self.0.as_bytes().eq_ignore_ascii_case(input.as_ref())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
 /// A &str is an implicit Eq mapper.
 impl<IN> Matcher<IN> for &str
 where
@@ -257,6 +516,72 @@ where
     }
 }
 
+/// A Vec<&str> is an implicit Eq mapper for lists of strings.
+///
+/// This allows `request::query_param_values("tag", vec!["a", "b"])`.
+impl<E> Matcher<[E]> for Vec<&str>
+where
+    E: AsRef<[u8]>,
+{
+    fn matches(&mut self, input: &[E], _ctx: &mut ExecutionContext) -> bool {
+        self.len() == input.len()
+            && self
+                .iter()
+                .zip(input)
+                .all(|(expected, actual)| expected.as_bytes() == actual.as_ref())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
+/// An http::Method is an implicit Eq mapper.
+///
+/// This allows `request::method(http::Method::POST)`.
+impl<IN> Matcher<IN> for http::Method
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        self.as_str().as_bytes() == input.as_ref()
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <str as fmt::Debug>::fmt(self.as_str(), f)
+    }
+}
+
+/// An http::header::HeaderName is an implicit Eq mapper.
+///
+/// This allows `request::headers(contains((http::header::AUTHORIZATION, "token")))`.
+impl<IN> Matcher<IN> for http::header::HeaderName
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        self.as_str().as_bytes() == input.as_ref()
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <str as fmt::Debug>::fmt(self.as_str(), f)
+    }
+}
+
+/// An http::header::HeaderValue is an implicit Eq mapper.
+impl<IN> Matcher<IN> for http::header::HeaderValue
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        self.as_bytes() == input.as_ref()
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
 /// Create a regex.
 ///
 /// This trait may panic if the regex failed to build.
@@ -315,6 +640,156 @@ where
     }
 }
 
+/// Match the input against the regex provided and pass its named capture
+/// groups to the next mapper, as a slice of key-value pairs. Groups that
+/// didn't participate in the match are left out. false if the regex doesn't
+/// match.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request to version 2 of the api.
+/// request::path(captures(r"^/v(?P<ver>\d+)/", contains(("ver", "2"))));
+///
+/// // A request matcher that matches a request for an item of a user.
+/// let mut m = request::path(captures(
+///     "^/users/(?P<user>[^/]+)/items/(?P<item>[^/]+)$",
+///     all_of![contains(("user", "jane")), contains(("item", matches(r"^\d+$")))],
+/// ));
+///
+/// # // Allow type inference to determine the request type.
+/// # ExecutionContext::evaluate(&mut m, &http::Request::get("/").body("").unwrap());
+/// ```
+pub fn captures<M>(value: impl IntoRegex, inner: M) -> Captures<M> {
+    Captures(value.into_regex(), inner)
+}
+/// The `Captures` mapper returned by [captures()](fn.captures.html)
+#[derive(Debug)]
+pub struct Captures<M>(regex::bytes::Regex, M);
+impl<IN, M> Matcher<IN> for Captures<M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<[KV<str, str>]>,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let Some(captures) = self.0.captures(input.as_ref()) else {
+            return false;
+        };
+        let groups: Vec<KV<str, str>> = self
+            .0
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                let value = captures.name(name)?;
+                Some(KV {
+                    k: name.to_string(),
+                    v: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                })
+            })
+            .collect();
+        ctx.chain(&mut self.1, &groups)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Captures")
+            .field(&self.0)
+            .field(&matcher_name(&self.1))
+            .finish()
+    }
+}
+
+/// true if the input starts with the value provided.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request to a path under "/api/".
+/// request::path(starts_with("/api/"));
+/// ```
+pub fn starts_with(value: impl Into<String>) -> StartsWith {
+    StartsWith(value.into())
+}
+/// The `StartsWith` mapper returned by [starts_with()](fn.starts_with.html)
+#[derive(Debug)]
+pub struct StartsWith(String);
+impl<IN> Matcher<IN> for StartsWith
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        input.as_ref().starts_with(self.0.as_bytes())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
+/// true if the input ends with the value provided.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request for a json file.
+/// request::path(ends_with(".json"));
+/// ```
+pub fn ends_with(value: impl Into<String>) -> EndsWith {
+    EndsWith(value.into())
+}
+/// The `EndsWith` mapper returned by [ends_with()](fn.ends_with.html)
+#[derive(Debug)]
+pub struct EndsWith(String);
+impl<IN> Matcher<IN> for EndsWith
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        input.as_ref().ends_with(self.0.as_bytes())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
+/// true if the value provided appears anywhere in the input.
+///
+/// Look at [contains()](fn.contains.html) to match an element of a list.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request body mentioning "foobar".
+/// request::body(contains_substring("foobar"));
+/// ```
+pub fn contains_substring(value: impl Into<String>) -> ContainsSubstring {
+    ContainsSubstring(value.into())
+}
+/// The `ContainsSubstring` mapper returned by [contains_substring()](fn.contains_substring.html)
+#[derive(Debug)]
+pub struct ContainsSubstring(String);
+impl<IN> Matcher<IN> for ContainsSubstring
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        use bstr::ByteSlice;
+        input.as_ref().contains_str(&self.0)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
 /// invert the result of the inner mapper.
 ///
 /// # Example
@@ -375,6 +850,15 @@ where
     IN: fmt::Debug + ?Sized,
 {
     fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        if ctx.exhaustive {
+            let num_matched = self
+                .0
+                .iter_mut()
+                .map(|mapper| ctx.chain(mapper.as_mut(), input))
+                .filter(|&x| x)
+                .count();
+            return num_matched == self.0.len();
+        }
         self.0
             .iter_mut()
             .all(|mapper| ctx.chain(mapper.as_mut(), input))
@@ -430,6 +914,15 @@ where
     IN: fmt::Debug + ?Sized,
 {
     fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        if ctx.exhaustive {
+            let num_matched = self
+                .0
+                .iter_mut()
+                .map(|mapper| ctx.chain(mapper.as_mut(), input))
+                .filter(|&x| x)
+                .count();
+            return num_matched > 0;
+        }
         self.0
             .iter_mut()
             .any(|mapper| ctx.chain(mapper.as_mut(), input))
@@ -447,7 +940,103 @@ where
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str("AnyOf")?;
         f.debug_list()
-            .entries(self.0.iter().map(|x| matcher_name(&**x)))
+            .entries(self.0.iter().map(|x| matcher_name(&**x)))
+            .finish()
+    }
+}
+
+/// true if at least `n` of the provided matchers return true. See the `n_of!`
+/// macro for convenient usage and
+/// [exactly()](struct.NOf.html#method.exactly) to require that no more than
+/// `n` match.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request satisfying two of the three
+/// // criteria.
+/// let mut m = n_of![2;
+///     request::method("POST"),
+///     request::path("/foo"),
+///     request::headers(contains(key("x-foobar"))),
+/// ];
+///
+/// # // Allow type inference to determine the request type.
+/// # ExecutionContext::evaluate(&mut m, &http::Request::get("/").body("").unwrap());
+/// ```
+pub fn n_of<IN>(n: usize, inner: Vec<Box<dyn Matcher<IN>>>) -> NOf<IN>
+where
+    IN: ?Sized,
+{
+    NOf {
+        n,
+        exact: false,
+        inner,
+    }
+}
+/// The `NOf` mapper returned by [n_of()](fn.n_of.html)
+pub struct NOf<IN>
+where
+    IN: ?Sized,
+{
+    n: usize,
+    exact: bool,
+    inner: Vec<Box<dyn Matcher<IN>>>,
+}
+
+impl<IN> NOf<IN>
+where
+    IN: ?Sized,
+{
+    /// Require exactly `n` of the matchers to return true.
+    pub fn exactly(self) -> Self {
+        NOf {
+            exact: true,
+            ..self
+        }
+    }
+}
+
+impl<IN> Matcher<IN> for NOf<IN>
+where
+    IN: fmt::Debug + ?Sized,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let mut num_matched = 0;
+        for mapper in self.inner.iter_mut() {
+            if num_matched >= self.n && !self.exact && !ctx.exhaustive {
+                break;
+            }
+            if ctx.chain(mapper.as_mut(), input) {
+                num_matched += 1;
+            }
+        }
+        if self.exact {
+            num_matched == self.n
+        } else {
+            num_matched >= self.n
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
+impl<IN> fmt::Debug for NOf<IN>
+where
+    IN: ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.exact {
+            write!(f, "ExactlyNOf({})", self.n)?;
+        } else {
+            write!(f, "NOf({})", self.n)?;
+        }
+        f.debug_list()
+            .entries(self.inner.iter().map(|x| matcher_name(&**x)))
             .finish()
     }
 }
@@ -519,6 +1108,112 @@ where
     }
 }
 
+impl<M> UrlDecoded<M> {
+    /// Parse PHP and Rails style bracketed keys into nested values, passing
+    /// a json value to the next mapper instead of key-value pairs. `a[b]=1`
+    /// decodes to `{"a": {"b": "1"}}` and `a[]=1&a[]=2` to
+    /// `{"a": ["1", "2"]}`. `a[][b]=1&a[][c]=2` adds both keys to one object
+    /// in the list, a new object is started when a key repeats. All values
+    /// are strings and when a key is repeated without brackets the last
+    /// value wins.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use httptest::matchers::*;
+    ///
+    /// // A request matcher that matches a form body of
+    /// // `user[name]=alice&user[roles][]=admin&user[roles][]=dev`.
+    /// request::body(url_decoded(eq(serde_json::json!({
+    ///     "user": {"name": "alice", "roles": ["admin", "dev"]},
+    /// }))).nested());
+    /// ```
+    pub fn nested(self) -> NestedUrlDecoded<M> {
+        NestedUrlDecoded(self.0)
+    }
+}
+
+/// The `NestedUrlDecoded` mapper returned by [UrlDecoded::nested()](struct.UrlDecoded.html#method.nested)
+#[derive(Debug)]
+pub struct NestedUrlDecoded<M>(M);
+impl<IN, M> Matcher<IN> for NestedUrlDecoded<M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<serde_json::Value>,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let mut decoded = serde_json::Value::Object(serde_json::Map::new());
+        for (k, v) in form_urlencoded::parse(input.as_ref()) {
+            assign(&mut decoded, &key_path(&k), v.into_owned());
+        }
+        ctx.chain(&mut self.0, &decoded)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("NestedUrlDecoded")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+// A step into a nested url decoded value.
+#[derive(Debug, PartialEq)]
+enum KeySegment {
+    Key(String),
+    // `[]`, append to a list.
+    Append,
+}
+
+// Split `a[b][]` into its segments. Keys with malformed brackets are taken
+// as they are.
+fn key_path(key: &str) -> Vec<KeySegment> {
+    let literal = || vec![KeySegment::Key(key.to_string())];
+    let (name, mut rest) = match key.find('[') {
+        Some(0) | None => return literal(),
+        Some(i) => key.split_at(i),
+    };
+    let mut path = vec![KeySegment::Key(name.to_string())];
+    while !rest.is_empty() {
+        let Some(end) = rest.find(']').filter(|_| rest.starts_with('[')) else {
+            return literal();
+        };
+        path.push(match &rest[1..end] {
+            "" => KeySegment::Append,
+            key => KeySegment::Key(key.to_string()),
+        });
+        rest = &rest[end + 1..];
+    }
+    path
+}
+
+fn assign(slot: &mut serde_json::Value, path: &[KeySegment], value: String) {
+    use serde_json::Value;
+    match path.split_first() {
+        None => *slot = Value::String(value),
+        Some((KeySegment::Key(key), rest)) => {
+            if !slot.is_object() {
+                *slot = Value::Object(serde_json::Map::new());
+            }
+            let map = slot.as_object_mut().expect("slot is an object");
+            assign(map.entry(key.as_str()).or_insert(Value::Null), rest, value);
+        }
+        Some((KeySegment::Append, rest)) => {
+            if !slot.is_array() {
+                *slot = Value::Array(Vec::new());
+            }
+            let list = slot.as_array_mut().expect("slot is an array");
+            let extend_last = match (list.last(), rest.first()) {
+                (Some(Value::Object(last)), Some(KeySegment::Key(key))) => !last.contains_key(key),
+                _ => false,
+            };
+            if !extend_last {
+                list.push(Value::Null);
+            }
+            assign(list.last_mut().expect("list isn't empty"), rest, value);
+        }
+    }
+}
+
 /// json decode the input and pass the resulting value to the inner mapper. If
 /// the input cannot be decoded a false value is returned.
 ///
@@ -546,14 +1241,14 @@ impl<IN, T, M> Matcher<IN> for JsonDecoded<T, M>
 where
     IN: AsRef<[u8]> + ?Sized,
     M: Matcher<T>,
-    T: serde::de::DeserializeOwned + fmt::Debug + Send,
+    T: serde::de::DeserializeOwned + fmt::Debug + Send + 'static,
 {
     fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
         let value: T = match serde_json::from_slice(input.as_ref()) {
             Ok(value) => value,
             Err(_) => return false,
         };
-        ctx.chain(&mut self.1, &value)
+        ctx.chain_described(&mut self.1, &value, describe_json::<T>)
     }
 
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -563,6 +1258,293 @@ where
     }
 }
 
+// Describe how a decoded json value differs from the value it was expected to
+// equal, structurally if it was decoded into a serde_json::Value.
+fn describe_json<T>(expected: &T, actual: &T) -> Option<String>
+where
+    T: fmt::Debug + 'static,
+{
+    let expected_json = (expected as &dyn std::any::Any).downcast_ref();
+    let actual_json = (actual as &dyn std::any::Any).downcast_ref();
+    match (expected_json, actual_json) {
+        (Some(expected), Some(actual)) => crate::diff::json_diff(expected, actual),
+        _ => crate::diff::debug_diff(expected, actual),
+    }
+}
+
+/// decode an `application/x-www-form-urlencoded` input into `T` and pass the
+/// resulting value to the inner mapper. If the input cannot be decoded a false
+/// value is returned.
+///
+/// # Example
+///
+/// ```rust
+/// use httptest::matchers::*;
+///
+/// #[derive(Debug, PartialEq, serde::Deserialize)]
+/// struct Login {
+///     user: String,
+///     remember: bool,
+/// }
+///
+/// // A request matcher that matches a body of `user=alice&remember=true`.
+/// request::body(form_decoded(eq(Login {
+///     user: "alice".to_string(),
+///     remember: true,
+/// })));
+/// ```
+pub fn form_decoded<T, M>(inner: M) -> FormDecoded<T, M>
+where
+    M: Matcher<T>,
+{
+    FormDecoded(PhantomData, inner)
+}
+/// The `FormDecoded` mapper returned by [form_decoded()](fn.form_decoded.html)
+#[derive(Debug)]
+pub struct FormDecoded<T, M>(PhantomData<T>, M);
+impl<IN, T, M> Matcher<IN> for FormDecoded<T, M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<T>,
+    T: serde::de::DeserializeOwned + fmt::Debug + Send,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let value: T = match serde_urlencoded::from_bytes(input.as_ref()) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        ctx.chain(&mut self.1, &value)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("FormDecoded")
+            .field(&matcher_name(&self.1))
+            .finish()
+    }
+}
+
+/// cbor decode the input and pass the resulting value to the inner mapper. If
+/// the input cannot be decoded a false value is returned.
+///
+/// # Example
+///
+/// ```rust
+/// use httptest::matchers::*;
+///
+/// request::body(cbor_decoded(eq(serde_json::json!({
+///     "foo": 1,
+/// }))));
+/// ```
+#[cfg(feature = "cbor")]
+pub fn cbor_decoded<T, M>(inner: M) -> CborDecoded<T, M>
+where
+    M: Matcher<T>,
+{
+    CborDecoded(PhantomData, inner)
+}
+/// The `CborDecoded` mapper returned by [cbor_decoded()](fn.cbor_decoded.html)
+#[cfg(feature = "cbor")]
+#[derive(Debug)]
+pub struct CborDecoded<T, M>(PhantomData<T>, M);
+#[cfg(feature = "cbor")]
+impl<IN, T, M> Matcher<IN> for CborDecoded<T, M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<T>,
+    T: serde::de::DeserializeOwned + fmt::Debug + Send,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let value: T = match ciborium::de::from_reader(input.as_ref()) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        ctx.chain(&mut self.1, &value)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CborDecoded")
+            .field(&matcher_name(&self.1))
+            .finish()
+    }
+}
+
+/// Decode a JSON Web Token and pass its claims to the next mapper. A `Bearer `
+/// prefix is ignored, so the input can be an `Authorization` header. If the
+/// input isn't a JWT a false value is returned.
+///
+/// The signature isn't checked unless a key is provided with
+/// [verify_hs256()](struct.Jwt.html#method.verify_hs256) or
+/// [verify_rs256()](struct.Jwt.html#method.verify_rs256).
+///
+/// # Example
+///
+/// ```rust
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request authorized by alice with a
+/// // token signed with the secret `s3cr3t`.
+/// request::headers(contains((
+///     "authorization",
+///     jwt(|claims: &serde_json::Value| claims["sub"] == "alice").verify_hs256("s3cr3t"),
+/// )));
+///
+/// // A request matcher that matches a token sent as a form field, using the
+/// // key id from the token's header.
+/// request::body(url_decoded(contains((
+///     "id_token",
+///     jwt(any()).header(|header: &serde_json::Value| header["kid"] == "key-1"),
+/// ))));
+/// ```
+#[cfg(feature = "jwt")]
+pub fn jwt<M>(inner: M) -> Jwt<M> {
+    Jwt {
+        inner,
+        header: None,
+        key: None,
+    }
+}
+/// The `Jwt` mapper returned by [jwt()](fn.jwt.html)
+#[cfg(feature = "jwt")]
+pub struct Jwt<M> {
+    inner: M,
+    header: Option<Box<dyn Matcher<serde_json::Value>>>,
+    key: Option<JwtKey>,
+}
+
+#[cfg(feature = "jwt")]
+#[derive(Debug)]
+enum JwtKey {
+    Hs256(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+#[cfg(feature = "jwt")]
+impl<M> Jwt<M> {
+    /// Also match the token's header with the provided mapper.
+    pub fn header(self, header: impl Matcher<serde_json::Value> + 'static) -> Self {
+        Jwt {
+            header: Some(Box::new(header)),
+            ..self
+        }
+    }
+
+    /// Only match tokens signed with HS256 using `secret`.
+    pub fn verify_hs256(self, secret: impl Into<Vec<u8>>) -> Self {
+        Jwt {
+            key: Some(JwtKey::Hs256(secret.into())),
+            ..self
+        }
+    }
+
+    /// Only match tokens signed with RS256 by the RSA key whose public
+    /// modulus and exponent are `n` and `e`, as big-endian bytes. These are
+    /// the base64url decoded `n` and `e` of a JWK.
+    pub fn verify_rs256(self, n: impl Into<Vec<u8>>, e: impl Into<Vec<u8>>) -> Self {
+        Jwt {
+            key: Some(JwtKey::Rs256 {
+                n: n.into(),
+                e: e.into(),
+            }),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl<IN, M> Matcher<IN> for Jwt<M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<serde_json::Value>,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        let Some((header, claims)) = decode_jwt(input.as_ref(), self.key.as_ref()) else {
+            return false;
+        };
+        let header_matches = match &mut self.header {
+            Some(matcher) => ctx.chain(matcher.as_mut(), &header),
+            None => true,
+        };
+        header_matches && ctx.chain(&mut self.inner, &claims)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl<M> fmt::Debug for Jwt<M>
+where
+    M: Matcher<serde_json::Value>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Jwt");
+        s.field("claims", &matcher_name(&self.inner));
+        if let Some(header) = &self.header {
+            s.field("header", &matcher_name(&**header));
+        }
+        match &self.key {
+            Some(JwtKey::Hs256(_)) => s.field("verify", &"HS256"),
+            Some(JwtKey::Rs256 { .. }) => s.field("verify", &"RS256"),
+            None => &mut s,
+        };
+        s.finish()
+    }
+}
+
+// Split a JWT into its decoded header and claims, checking its signature if
+// there's a key.
+#[cfg(feature = "jwt")]
+fn decode_jwt(
+    token: &[u8],
+    key: Option<&JwtKey>,
+) -> Option<(serde_json::Value, serde_json::Value)> {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    let token = std::str::from_utf8(token).ok()?.trim();
+    let token = match token.get(..7) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("bearer ") => token[7..].trim_start(),
+        _ => token,
+    };
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+    let decode = |part: &str| -> Option<serde_json::Value> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+    };
+    let header = decode(header)?;
+    let claims = decode(claims)?;
+    if let Some(key) = key {
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let signing_input = signing_input.as_bytes();
+        let verified = match key {
+            JwtKey::Hs256(secret) => {
+                header["alg"] == "HS256"
+                    && ring::hmac::verify(
+                        &ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret),
+                        signing_input,
+                        &signature,
+                    )
+                    .is_ok()
+            }
+            JwtKey::Rs256 { n, e } => {
+                header["alg"] == "RS256"
+                    && ring::signature::RsaPublicKeyComponents { n, e }
+                        .verify(
+                            &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+                            signing_input,
+                            &signature,
+                        )
+                        .is_ok()
+            }
+        };
+        if !verified {
+            log::debug!("jwt signature verification failed");
+            return None;
+        }
+    }
+    Some((header, claims))
+}
+
 /// lowercase the input and pass it to the next mapper.
 ///
 /// # Example
@@ -596,6 +1578,56 @@ where
     }
 }
 
+/// base64 decode the input and pass it to the next mapper. Both the standard
+/// and the url safe alphabets are accepted, with or without padding. If the
+/// input cannot be decoded a false value is returned.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request with a base64 encoded json header.
+/// request::headers(contains((
+///     "x-payload",
+///     base64_decoded(json_decoded(eq(serde_json::json!({"foo": 1})))),
+/// )));
+/// ```
+pub fn base64_decoded<M>(inner: M) -> Base64Decoded<M> {
+    Base64Decoded(inner)
+}
+/// The `Base64Decoded` mapper returned by [base64_decoded()](fn.base64_decoded.html)
+#[derive(Debug)]
+pub struct Base64Decoded<M>(M);
+impl<IN, M> Matcher<IN> for Base64Decoded<M>
+where
+    IN: AsRef<[u8]> + ?Sized,
+    M: Matcher<[u8]>,
+{
+    fn matches(&mut self, input: &IN, ctx: &mut ExecutionContext) -> bool {
+        use base64::engine::{general_purpose, DecodePaddingMode, GeneralPurpose};
+        use base64::{alphabet, Engine};
+        const CONFIG: general_purpose::GeneralPurposeConfig =
+            general_purpose::GeneralPurposeConfig::new()
+                .with_decode_padding_mode(DecodePaddingMode::Indifferent);
+        const STANDARD: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, CONFIG);
+        const URL_SAFE: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, CONFIG);
+
+        let input = input.as_ref().trim_ascii();
+        let decoded = match STANDARD.decode(input).or_else(|_| URL_SAFE.decode(input)) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        ctx.chain(&mut self.0, decoded.as_slice())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Base64Decoded")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
 // Fn(T) -> bool implements Matcher<T>
 impl<IN, F> Matcher<IN> for F
 where
@@ -803,6 +1835,7 @@ where
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -823,6 +1856,15 @@ mod tests {
         assert_eq!(true, eval(&mut c, "foo"));
     }
 
+    #[test]
+    fn test_eq_nocase() {
+        let mut c = eq_nocase("Foo");
+        assert_eq!(true, eval(&mut c, "foo"));
+        assert_eq!(true, eval(&mut c, "FOO"));
+        assert_eq!(false, eval(&mut c, "foobar"));
+        assert_eq!("EqNoCase(\"Foo\")", format!("{:?}", c));
+    }
+
     #[test]
     fn test_matches() {
         // regex from str
@@ -885,6 +1927,134 @@ mod tests {
         assert_eq!(false, eval(&mut c, "baz"));
     }
 
+    #[test]
+    fn test_captures() {
+        let mut c = captures(r"^/v(?P<ver>\d+)/(?P<a>a)?", contains(("ver", "2")));
+        assert_eq!(true, eval(&mut c, "/v2/foo"));
+        assert_eq!(false, eval(&mut c, "/v3/foo"));
+        assert_eq!(false, eval(&mut c, "/foo"));
+
+        let mut c = captures(r"^/v(?P<ver>\d+)/(?P<a>a)?", eq(vec![KV::new("ver", "2")]));
+        assert_eq!(true, eval(&mut c, "/v2/foo"));
+        assert_eq!(false, eval(&mut c, "/v2/a"));
+    }
+
+    #[test]
+    fn test_substrings() {
+        let mut c = starts_with("foo");
+        assert_eq!(true, eval(&mut c, "foobar"));
+        assert_eq!(false, eval(&mut c, "barfoo"));
+        assert_eq!("StartsWith(\"foo\")", format!("{:?}", c));
+
+        let mut c = ends_with("foo");
+        assert_eq!(true, eval(&mut c, "barfoo"));
+        assert_eq!(false, eval(&mut c, "foobar"));
+
+        let mut c = contains_substring("o.b");
+        assert_eq!(true, eval(&mut c, "foo.bar"));
+        assert_eq!(false, eval(&mut c, "foobar"));
+        assert_eq!(true, eval(&mut c, b"\xffo.b".as_ref()));
+    }
+
+    #[test]
+    fn test_n_of() {
+        let mut c = n_of![2; matches("foo"), matches("bar"), matches("baz")];
+        assert_eq!(true, eval(&mut c, "foobar"));
+        assert_eq!(true, eval(&mut c, "foobarbaz"));
+        assert_eq!(false, eval(&mut c, "foo"));
+
+        let mut c = n_of![2; matches("foo"), matches("bar"), matches("baz")].exactly();
+        assert_eq!(true, eval(&mut c, "foobar"));
+        assert_eq!(false, eval(&mut c, "foobarbaz"));
+        assert_eq!(false, eval(&mut c, "foo"));
+        assert!(format!("{:?}", c).starts_with("ExactlyNOf(2)[Matches("));
+    }
+
+    #[test]
+    fn test_closeness() {
+        let mut c = all_of![matches("foo"), matches("bar"), matches("baz")];
+        let closeness = ExecutionContext::closeness(&mut c, "foobar");
+        assert_eq!(2, closeness.matched);
+        assert_eq!(3, closeness.evaluated);
+
+        let mut c = any_of![matches("foo"), matches("bar")];
+        let closeness = ExecutionContext::closeness(&mut c, "bar");
+        assert_eq!(1, closeness.matched);
+        assert_eq!(2, closeness.evaluated);
+    }
+
+    #[test]
+    fn test_eq_mismatch_diff() {
+        let mut c = json_decoded(eq(serde_json::json!({
+            "foo": 1,
+            "bar": 99,
+        })));
+        let closeness = ExecutionContext::closeness(&mut c, r#"{"foo": 1, "bar": 100}"#);
+        assert_eq!(
+            closeness.mismatches,
+            vec!["json differs from expected:\n/bar: 99 → 100".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_eq_mismatch_nested_json() {
+        let mut c = json_decoded(eq(serde_json::json!({
+            "user": {"name": "alice", "tags": ["a", "b"]},
+            "id": 1,
+        })));
+        let closeness = ExecutionContext::closeness(
+            &mut c,
+            r#"{"user": {"name": "bob", "tags": ["a"], "admin": true}}"#,
+        );
+        assert_eq!(
+            closeness.mismatches,
+            vec![[
+                "json differs from expected:",
+                "/id: removed 1",
+                "/user/name: \"alice\" → \"bob\"",
+                "/user/tags/1: removed \"b\"",
+                "/user/admin: added true",
+            ]
+            .join("\n")]
+        );
+    }
+
+    #[test]
+    fn test_eq_mismatch_debug_diff() {
+        let mut c = json_decoded::<Vec<i32>, _>(eq(vec![1, 2, 3]));
+        let closeness = ExecutionContext::closeness(&mut c, "[1, 5, 3]");
+        assert_eq!(
+            closeness.mismatches,
+            vec![
+                "--- expected\n+++ actual\n [\n     1,\n-    2,\n+    5,\n     3,\n ]".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eq_input_not_debug() {
+        #[derive(PartialEq)]
+        struct NotDebug(&'static str);
+        struct Expected(NotDebug);
+        impl Borrow<NotDebug> for Expected {
+            fn borrow(&self) -> &NotDebug {
+                &self.0
+            }
+        }
+        impl fmt::Debug for Expected {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "Expected({:?})", (self.0).0)
+            }
+        }
+
+        let mut c = eq(Expected(NotDebug("a\nb")));
+        let mut ctx = ExecutionContext::new(true);
+        assert!(c.matches(&NotDebug("a\nb"), &mut ctx));
+        assert!(!c.matches(&NotDebug("a\nc"), &mut ctx));
+        // Without a Debug input there's nothing to diff against.
+        assert!(ctx.mismatches.is_empty());
+    }
+
     #[test]
     fn test_url_decoded() {
         let expected = vec![KV::new("key 1", "value 1"), KV::new("key2", "")];
@@ -896,6 +2066,31 @@ mod tests {
         assert_eq!(true, eval(&mut c, &req));
     }
 
+    #[test]
+    fn test_nested_url_decoded() {
+        let mut c = url_decoded(eq(serde_json::json!({
+            "a": {"b": "1", "c": ["2", "3"]},
+            "items": [{"id": "1", "name": "x"}, {"id": "2"}],
+            "plain": "last",
+            "[odd": "4",
+            "odd[": "5",
+        })))
+        .nested();
+        assert_eq!(
+            true,
+            eval(
+                &mut c,
+                "a[b]=1&a[c][]=2&a%5Bc%5D%5B%5D=3\
+                 &items[][id]=1&items[][name]=x&items[][id]=2\
+                 &plain=first&plain=last&[odd=4&odd[=5"
+            )
+        );
+        assert_eq!(
+            true,
+            eval(&mut url_decoded(eq(serde_json::json!({}))).nested(), "")
+        );
+    }
+
     #[test]
     fn test_json_decoded() {
         let mut c = json_decoded(eq(serde_json::json!({
@@ -907,6 +2102,83 @@ mod tests {
         assert_eq!(false, eval(&mut c, r#"{"foo": 1, "bar": 100}"#));
     }
 
+    #[test]
+    fn test_form_decoded() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Login {
+            user: String,
+            remember: bool,
+        }
+        let mut c = form_decoded(eq(Login {
+            user: "alice smith".to_string(),
+            remember: true,
+        }));
+        assert_eq!(true, eval(&mut c, "user=alice+smith&remember=true"));
+        assert_eq!(true, eval(&mut c, "remember=true&user=alice%20smith"));
+        assert_eq!(false, eval(&mut c, "user=alice+smith&remember=false"));
+        assert_eq!(false, eval(&mut c, "user=alice+smith&remember=maybe"));
+    }
+
+    #[test]
+    fn test_base64_decoded() {
+        let mut c = base64_decoded(&b"\xfb\xff?"[..]);
+        assert_eq!(true, eval(&mut c, "+/8/"));
+        assert_eq!(true, eval(&mut c, "-_8_"));
+        let mut c = base64_decoded("ab");
+        assert_eq!(true, eval(&mut c, "YWI="));
+        assert_eq!(true, eval(&mut c, "YWI"));
+        assert_eq!(true, eval(&mut c, " YWI=\n"));
+        assert_eq!(false, eval(&mut c, "YWM="));
+        assert_eq!(false, eval(&mut c, "not base64!"));
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn test_jwt() {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let claims = serde_json::json!({"sub": "alice"});
+        let token = crate::jwt::sign_hs256(b"s3cr3t", &claims);
+        let is_alice = |claims: &serde_json::Value| claims["sub"] == "alice";
+        assert!(eval(&mut jwt(is_alice), token.as_str()));
+        assert!(eval(
+            &mut jwt(is_alice),
+            format!("Bearer {}", token).as_str()
+        ));
+        assert!(eval(
+            &mut jwt(is_alice).verify_hs256("s3cr3t"),
+            token.as_str()
+        ));
+        assert!(!eval(
+            &mut jwt(is_alice).verify_hs256("wrong"),
+            token.as_str()
+        ));
+        assert!(eval(
+            &mut jwt(any()).header(|h: &serde_json::Value| h["alg"] == "HS256"),
+            token.as_str()
+        ));
+        assert!(!eval(&mut jwt(any()), "not.a.jwt"));
+
+        let key = crate::jwt::SigningKey::insecure_test_key();
+        let token = key.sign_rs256(&claims);
+        let jwks = key.jwks();
+        let component = |name: &str| {
+            URL_SAFE_NO_PAD
+                .decode(jwks["keys"][0][name].as_str().unwrap())
+                .unwrap()
+        };
+        assert!(eval(
+            &mut jwt(is_alice).verify_rs256(component("n"), component("e")),
+            token.as_str()
+        ));
+        // the algorithm has to match the key.
+        assert!(!eval(
+            &mut jwt(is_alice).verify_hs256(component("n")),
+            token.as_str()
+        ));
+    }
+
     #[test]
     fn test_lowercase() {
         let mut c = lowercase(matches("foo"));
@@ -917,6 +2189,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::manual_is_multiple_of)]
     fn test_fn_mapper() {
         let mut c = |input: &u64| input % 2 == 0;
         assert_eq!(true, eval(&mut c, &6));
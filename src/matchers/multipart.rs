@@ -0,0 +1,276 @@
+//! Matchers that extract information from the parts of multipart bodies.
+//!
+//! Parts are found with
+//! [request::multipart_file](../request/fn.multipart_file.html).
+
+use super::{matcher_name, ExecutionContext, Matcher};
+use bstr::ByteSlice;
+use std::fmt;
+
+/// A part of a `multipart/form-data` body.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Part {
+    /// The name of the form field the part is for.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The filename of an uploaded file, None if the part isn't a file.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The `Content-Type` of the part, if it has one.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The contents of the part.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+impl fmt::Debug for Part {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Part")
+            .field("name", &self.name)
+            .field("filename", &self.filename)
+            .field("content_type", &self.content_type)
+            .field("body", &self.body.as_bstr())
+            .finish()
+    }
+}
+
+/// Extract the filename of the part and pass it to the next mapper. It's
+/// empty if the part isn't a file.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A part matcher that matches png files.
+/// multipart::filename(matches(r"\.png$"));
+/// ```
+pub fn filename<M>(inner: M) -> Filename<M> {
+    Filename(inner)
+}
+/// The `Filename` mapper returned by [filename()](fn.filename.html)
+#[derive(Debug)]
+pub struct Filename<M>(M);
+impl<M> Matcher<Part> for Filename<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &Part, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, input.filename().unwrap_or(""))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Filename")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the `Content-Type` of the part and pass it to the next mapper.
+/// It's empty if the part doesn't have one.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A part matcher that matches png images.
+/// multipart::content_type("image/png");
+/// ```
+pub fn content_type<M>(inner: M) -> ContentType<M> {
+    ContentType(inner)
+}
+/// The `ContentType` mapper returned by [content_type()](fn.content_type.html)
+#[derive(Debug)]
+pub struct ContentType<M>(M);
+impl<M> Matcher<Part> for ContentType<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &Part, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, input.content_type().unwrap_or(""))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ContentType")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the contents of the part and pass them to the next mapper.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A part matcher that matches non-empty parts.
+/// multipart::bytes(len(|n: &usize| *n > 0));
+/// ```
+pub fn bytes<M>(inner: M) -> Bytes<M> {
+    Bytes(inner)
+}
+/// The `Bytes` mapper returned by [bytes()](fn.bytes.html)
+#[derive(Debug)]
+pub struct Bytes<M>(M);
+impl<M> Matcher<Part> for Bytes<M>
+where
+    M: Matcher<bstr::BStr>,
+{
+    fn matches(&mut self, input: &Part, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, input.body().as_bstr())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Bytes")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+// Split a `multipart/form-data` body into its parts, using the boundary from
+// `content_type`. Returns None if the body isn't multipart or is malformed.
+pub(crate) fn parse(content_type: &str, body: &[u8]) -> Option<Vec<Part>> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    let boundary = parameters(params)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))?
+        .1;
+    let delimiter = format!("\r\n--{}", boundary);
+
+    // the preamble before the first delimiter is ignored. The leading CRLF
+    // is optional for the first delimiter.
+    let mut rest = match body.find(&delimiter[2..]) {
+        Some(start) => &body[start + delimiter.len() - 2..],
+        None => return None,
+    };
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        // skip any padding after the delimiter.
+        let line_end = rest.find(b"\r\n")?;
+        rest = &rest[line_end + 2..];
+        let end = rest.find(&delimiter)?;
+        parts.push(parse_part(&rest[..end])?);
+        rest = &rest[end + delimiter.len()..];
+    }
+}
+
+fn parse_part(part: &[u8]) -> Option<Part> {
+    let (head, body) = if part.starts_with(b"\r\n") {
+        (&b""[..], &part[2..])
+    } else {
+        let end = part.find(b"\r\n\r\n")?;
+        (&part[..end], &part[end + 4..])
+    };
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in head.split_str("\r\n") {
+        let line = line.to_str().ok()?;
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(value.trim());
+        } else if name.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+    let (_, params) = disposition?.split_once(';')?;
+    let params = parameters(params);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    Some(Part {
+        name: param("name")?,
+        filename: param("filename"),
+        content_type,
+        body: body.to_vec(),
+    })
+}
+
+// Parse `; name=value` parameters, where values may be quoted.
+fn parameters(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        let name: String = chars
+            .by_ref()
+            .skip_while(|c| *c == ';' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect();
+        if name.is_empty() {
+            return params;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            // skip to the next parameter.
+            chars.by_ref().take_while(|c| *c != ';').for_each(drop);
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let body = b"preamble\r\n\
+                     --XyZ\r\n\
+                     Content-Disposition: form-data; name=\"title\"\r\n\
+                     \r\n\
+                     hello\r\n\
+                     --XyZ\r\n\
+                     Content-Disposition: form-data; name=\"avatar\"; filename=\"a;b \\\"c\\\".png\"\r\n\
+                     Content-Type: image/png\r\n\
+                     \r\n\
+                     \x89PNG\r\n\r\n\
+                     --XyZ--\r\n";
+        let parts = parse("multipart/form-data; boundary=\"XyZ\"", body).unwrap();
+        assert_eq!(2, parts.len());
+        assert_eq!("title", parts[0].name());
+        assert_eq!(None, parts[0].filename());
+        assert_eq!(b"hello", parts[0].body());
+        assert_eq!("avatar", parts[1].name());
+        assert_eq!(Some("a;b \"c\".png"), parts[1].filename());
+        assert_eq!(Some("image/png"), parts[1].content_type());
+        assert_eq!(b"\x89PNG\r\n", parts[1].body());
+
+        assert_eq!(None, parse("application/json", body));
+        assert_eq!(None, parse("multipart/form-data; boundary=other", body));
+    }
+}
@@ -4,6 +4,18 @@ use super::{matcher_name, ExecutionContext, Matcher, KV};
 use std::fmt;
 
 /// Extract the method from the HTTP request and pass it to the next mapper.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a `POST` request.
+/// request::method("POST");
+///
+/// // The same, using the typed method from the `http` crate.
+/// request::method(http::Method::POST);
+/// ```
 pub fn method<M>(inner: M) -> Method<M> {
     Method(inner)
 }
@@ -25,6 +37,46 @@ where
     }
 }
 
+/// Matches requests made with any of the provided methods.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches read-only requests.
+/// request::method_in(["GET", "HEAD", "OPTIONS"]);
+///
+/// // The same, using the typed methods from the `http` crate.
+/// request::method_in([http::Method::GET, http::Method::HEAD, http::Method::OPTIONS]);
+/// ```
+pub fn method_in<I>(methods: I) -> MethodIn
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    MethodIn(
+        methods
+            .into_iter()
+            .map(|method| method.as_ref().to_string())
+            .collect(),
+    )
+}
+/// The `MethodIn` matcher returned by [method_in()](fn.method_in.html)
+#[derive(Debug)]
+pub struct MethodIn(Vec<String>);
+impl<B> Matcher<http::Request<B>> for MethodIn {
+    fn matches(&mut self, input: &http::Request<B>, _ctx: &mut ExecutionContext) -> bool {
+        self.0
+            .iter()
+            .any(|method| method == input.method().as_str())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Self as fmt::Debug>::fmt(self, f)
+    }
+}
+
 /// Extract the path from the HTTP request and pass it to the next mapper.
 ///
 /// # Example
@@ -57,6 +109,90 @@ where
     }
 }
 
+/// Extract the path from the HTTP request, percent-decoded, and pass it to the
+/// next mapper. Unlike [path()](fn.path.html) this matches the logical path
+/// without hand-encoding it. `+` is left as is.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request to `/a%20b`.
+/// request::decoded_path("/a b");
+/// ```
+pub fn decoded_path<M>(inner: M) -> DecodedPath<M> {
+    DecodedPath(inner)
+}
+/// The `DecodedPath` mapper returned by [decoded_path()](fn.decoded_path.html)
+#[derive(Debug)]
+pub struct DecodedPath<M>(M);
+impl<M, B> Matcher<http::Request<B>> for DecodedPath<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, percent_decode(input.uri().path()).as_str())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("DecodedPath")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Split the path of the HTTP request into its percent-decoded segments and
+/// pass them to the next mapper. Escaped slashes (`%2F`) stay within their
+/// segment. The path `/` has no segments and a trailing slash adds an empty
+/// segment.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request to `/users/jane%20doe`.
+/// request::path_segments(vec!["users", "jane doe"]);
+///
+/// // A request matcher that matches a path three segments long.
+/// request::path_segments(len(eq(3)));
+///
+/// // A request matcher that matches a path with a uuid as the third segment.
+/// request::path_segments(|segments: &[String]| {
+///     segments.get(2).map_or(false, |s| {
+///         regex::Regex::new("^[0-9a-f]{8}(-[0-9a-f]{4}){3}-[0-9a-f]{12}$")
+///             .unwrap()
+///             .is_match(s)
+///     })
+/// });
+/// ```
+pub fn path_segments<M>(inner: M) -> PathSegments<M> {
+    PathSegments(inner)
+}
+/// The `PathSegments` mapper returned by [path_segments()](fn.path_segments.html)
+#[derive(Debug)]
+pub struct PathSegments<M>(M);
+impl<M, B> Matcher<http::Request<B>> for PathSegments<M>
+where
+    M: Matcher<[String]>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let path = input.uri().path();
+        let segments: Vec<String> = match path.strip_prefix('/').unwrap_or(path) {
+            "" => Vec::new(),
+            path => path.split('/').map(percent_decode).collect(),
+        };
+        ctx.chain(&mut self.0, segments.as_slice())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PathSegments")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
 /// Extract the query from the HTTP request and pass it to the next mapper.
 ///
 /// # Example
@@ -88,6 +224,88 @@ where
     }
 }
 
+/// Extract every value of the query parameter `name`, in the order they
+/// appear, and pass them to the next mapper. Unlike `contains` this can tell
+/// when a repeated parameter is missing a value. Use
+/// [unordered()](struct.QueryParamValues.html#method.unordered) when the
+/// order doesn't matter.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request with the query `tag=a&tag=b`.
+/// request::query_param_values("tag", vec!["a", "b"]);
+///
+/// // A request matcher that matches a request with exactly two `tag` parameters.
+/// request::query_param_values("tag", len(eq(2)));
+/// ```
+pub fn query_param_values<M>(name: impl Into<String>, inner: M) -> QueryParamValues<M> {
+    QueryParamValues {
+        name: name.into(),
+        inner,
+        unordered: false,
+    }
+}
+/// The `QueryParamValues` mapper returned by [query_param_values()](fn.query_param_values.html)
+#[derive(Debug)]
+pub struct QueryParamValues<M> {
+    name: String,
+    inner: M,
+    unordered: bool,
+}
+
+impl<M> QueryParamValues<M> {
+    /// Sort the values before passing them to the next mapper, so they match
+    /// in whatever order they were sent. Expected values need to be sorted
+    /// too.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use httptest::matchers::*;
+    ///
+    /// // Matches both `tag=a&tag=b` and `tag=b&tag=a`.
+    /// request::query_param_values("tag", vec!["a", "b"]).unordered();
+    /// ```
+    pub fn unordered(self) -> Self {
+        QueryParamValues {
+            unordered: true,
+            ..self
+        }
+    }
+}
+
+impl<M, B> Matcher<http::Request<B>> for QueryParamValues<M>
+where
+    M: Matcher<[String]>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let query = input.uri().query().unwrap_or("");
+        let mut values: Vec<String> = form_urlencoded::parse(query.as_bytes())
+            .filter(|(k, _)| *k == self.name)
+            .map(|(_, v)| v.into_owned())
+            .collect();
+        if self.unordered {
+            values.sort();
+        }
+        ctx.chain(&mut self.inner, values.as_slice())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.unordered {
+            "QueryParamValues(unordered)"
+        } else {
+            "QueryParamValues"
+        };
+        f.debug_tuple(name)
+            .field(&self.name)
+            .field(&matcher_name(&self.inner))
+            .finish()
+    }
+}
+
 /// Extract the headers from the HTTP request and pass the sequence to the next
 /// mapper.
 ///
@@ -101,6 +319,9 @@ where
 ///
 /// // A request matcher that matches a request with the header `x-foobar` with any value.
 /// request::headers(contains(key("x-foobar")));
+///
+/// // Typed header names from the `http` crate work too.
+/// request::headers(contains((http::header::AUTHORIZATION, "Bearer token")));
 /// ```
 pub fn headers<M>(inner: M) -> Headers<M> {
     Headers(inner)
@@ -161,6 +382,9 @@ where
 {
     fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
         use bstr::ByteSlice;
+        if ctx.body_unavailable() {
+            return true;
+        }
         ctx.chain(&mut self.0, input.body().as_ref().as_bstr())
     }
 
@@ -169,6 +393,145 @@ where
     }
 }
 
+/// Find the files uploaded as the form field `name` in a
+/// `multipart/form-data` body and pass each to the next mapper, matching if
+/// any of them match. The parts can be inspected with the mappers in the
+/// [multipart](../multipart/index.html) module. Doesn't match requests
+/// without such a file.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a non-empty png uploaded as the avatar.
+/// request::multipart_file("avatar", all_of![
+///     multipart::filename(matches(r"\.png$")),
+///     multipart::content_type("image/png"),
+///     multipart::bytes(len(|n: &usize| *n > 0)),
+/// ]);
+/// ```
+pub fn multipart_file<M>(name: impl Into<String>, inner: M) -> MultipartFile<M> {
+    MultipartFile {
+        name: name.into(),
+        inner,
+    }
+}
+/// The `MultipartFile` mapper returned by [multipart_file()](fn.multipart_file.html)
+#[derive(Debug)]
+pub struct MultipartFile<M> {
+    name: String,
+    inner: M,
+}
+
+impl<M, B> Matcher<http::Request<B>> for MultipartFile<M>
+where
+    B: AsRef<[u8]>,
+    M: Matcher<super::multipart::Part>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        if ctx.body_unavailable() {
+            return true;
+        }
+        let content_type = input
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let Some(parts) = super::multipart::parse(content_type, input.body().as_ref()) else {
+            return false;
+        };
+        let name = &self.name;
+        let inner = &mut self.inner;
+        parts
+            .iter()
+            .filter(|part| part.name() == name && part.filename().is_some())
+            .any(|part| ctx.chain(inner, part))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MultipartFile")
+            .field(&self.name)
+            .field(&matcher_name(&self.inner))
+            .finish()
+    }
+}
+
+/// Matches requests whose body was sent using chunked transfer encoding.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a streamed request body.
+/// request::is_chunked();
+/// ```
+pub fn is_chunked() -> IsChunked {
+    IsChunked
+}
+/// The `IsChunked` matcher returned by [is_chunked()](fn.is_chunked.html)
+#[derive(Debug)]
+pub struct IsChunked;
+impl<B> Matcher<http::Request<B>> for IsChunked {
+    fn matches(&mut self, input: &http::Request<B>, _ctx: &mut ExecutionContext) -> bool {
+        // chunked must be the final coding applied to the body.
+        let last = input
+            .headers()
+            .get_all(http::header::TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .last();
+        matches!(last, Some(coding) if coding.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("IsChunked")
+    }
+}
+
+/// Extract the Content-Length the client declared and pass it to the next
+/// mapper. Doesn't match requests without a valid Content-Length header, such
+/// as those using chunked transfer encoding.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a request declaring an 11 byte body.
+/// request::content_length(eq(11));
+/// ```
+pub fn content_length<M>(inner: M) -> ContentLength<M> {
+    ContentLength(inner)
+}
+/// The `ContentLength` mapper returned by [content_length()](fn.content_length.html)
+#[derive(Debug)]
+pub struct ContentLength<M>(M);
+impl<M, B> Matcher<http::Request<B>> for ContentLength<M>
+where
+    M: Matcher<u64>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        match input
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+        {
+            Some(len) => ctx.chain(&mut self.0, &len),
+            None => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ContentLength")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
 /// A convenience matcher for both method and path. Extracts a bolean true if the method and path both match.
 ///
 /// `method_path(a, b) == all_of![method(a), path(b)]`
@@ -208,6 +571,270 @@ where
     }
 }
 
+/// Extract the scheme from the HTTP request and pass it to the next mapper.
+///
+/// Only requests with an absolute-form target (`GET http://example.com/foo`),
+/// as sent to proxies, and HTTP/2 requests carry a scheme. It's empty for
+/// other requests.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches requests for https urls.
+/// request::scheme("https");
+/// ```
+pub fn scheme<M>(inner: M) -> Scheme<M> {
+    Scheme(inner)
+}
+/// The `Scheme` mapper returned by [scheme()](fn.scheme.html)
+#[derive(Debug)]
+pub struct Scheme<M>(M);
+impl<M, B> Matcher<http::Request<B>> for Scheme<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, input.uri().scheme_str().unwrap_or(""))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Scheme")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the full request target from the HTTP request and pass it to the
+/// next mapper.
+///
+/// For absolute-form targets this includes the scheme and authority
+/// (`http://example.com/foo?bar`). Requests sent directly to the server only
+/// have the path and query (`/foo?bar`).
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches a proxied request for http://example.com/foo.
+/// request::absolute_uri("http://example.com/foo");
+/// ```
+pub fn absolute_uri<M>(inner: M) -> AbsoluteUri<M> {
+    AbsoluteUri(inner)
+}
+/// The `AbsoluteUri` mapper returned by [absolute_uri()](fn.absolute_uri.html)
+#[derive(Debug)]
+pub struct AbsoluteUri<M>(M);
+impl<M, B> Matcher<http::Request<B>> for AbsoluteUri<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        ctx.chain(&mut self.0, input.uri().to_string().as_str())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AbsoluteUri")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the target a proxy client asked for and pass it to the next mapper.
+///
+/// This is the authority of an absolute-form request (`GET
+/// http://example.com/foo`), of a `CONNECT` request, or of the `CONNECT`
+/// tunnel a request was sent through. It's empty for requests sent directly
+/// to the server.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches requests proxied to example.com on port 443.
+/// request::proxy_target("example.com:443");
+/// ```
+pub fn proxy_target<M>(inner: M) -> ProxyTarget<M> {
+    ProxyTarget(inner)
+}
+/// The `ProxyTarget` mapper returned by [proxy_target()](fn.proxy_target.html)
+#[derive(Debug)]
+pub struct ProxyTarget<M>(M);
+impl<M, B> Matcher<http::Request<B>> for ProxyTarget<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let target = match input.extensions().get::<crate::server::TunnelTarget>() {
+            Some(tunnel) => tunnel.0.as_str(),
+            None => input.uri().authority().map_or("", |a| a.as_str()),
+        };
+        ctx.chain(&mut self.0, target)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ProxyTarget")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the ALPN protocol negotiated on the connection the request was
+/// received on and pass it to the next mapper. It's empty unless the request
+/// was received over TLS and the client and server agreed on a protocol.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches requests from clients that negotiated HTTP/2.
+/// request::alpn_protocol("h2");
+/// ```
+pub fn alpn_protocol<M>(inner: M) -> AlpnProtocol<M> {
+    AlpnProtocol(inner)
+}
+/// The `AlpnProtocol` mapper returned by [alpn_protocol()](fn.alpn_protocol.html)
+#[derive(Debug)]
+pub struct AlpnProtocol<M>(M);
+impl<M, B> Matcher<http::Request<B>> for AlpnProtocol<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let protocol = input
+            .extensions()
+            .get::<crate::server::NegotiatedProtocol>()
+            .map_or("", |protocol| protocol.0.as_str());
+        ctx.chain(&mut self.0, protocol)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AlpnProtocol")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the `Content-Encoding` the request was sent with and pass it to
+/// the next mapper. For requests decompressed by
+/// [ServerBuilder::decompress_request_bodies](../../struct.ServerBuilder.html#method.decompress_request_bodies)
+/// this is the encoding before decompression. It's empty when the request
+/// has no `Content-Encoding`, and multiple encodings are joined with `, `.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+///
+/// // A request matcher that matches gzip compressed requests.
+/// request::content_encoding("gzip");
+/// ```
+pub fn content_encoding<M>(inner: M) -> ContentEncoding<M> {
+    ContentEncoding(inner)
+}
+/// The `ContentEncoding` mapper returned by [content_encoding()](fn.content_encoding.html)
+#[derive(Debug)]
+pub struct ContentEncoding<M>(M);
+impl<M, B> Matcher<http::Request<B>> for ContentEncoding<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let encoding = match input
+            .extensions()
+            .get::<crate::server::DecodedContentEncoding>()
+        {
+            Some(decoded) => decoded.0.clone(),
+            None => input
+                .headers()
+                .get_all(http::header::CONTENT_ENCODING)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        ctx.chain(&mut self.0, encoding.as_str())
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ContentEncoding")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Extract the time between the previous request the server received and
+/// this one and pass it to the next mapper. Doesn't match the first request
+/// the server receives.
+///
+/// # Example
+///
+/// ```
+/// use httptest::matchers::*;
+/// use std::time::Duration;
+///
+/// // A request matcher that matches requests sent at least 500ms after the
+/// // one before.
+/// request::since_previous(|d: &Duration| *d >= Duration::from_millis(500));
+/// ```
+pub fn since_previous<M>(inner: M) -> SincePrevious<M> {
+    SincePrevious(inner)
+}
+/// The `SincePrevious` mapper returned by [since_previous()](fn.since_previous.html)
+#[derive(Debug)]
+pub struct SincePrevious<M>(M);
+impl<M, B> Matcher<http::Request<B>> for SincePrevious<M>
+where
+    M: Matcher<std::time::Duration>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let since_previous = input
+            .extensions()
+            .get::<crate::server::RequestTiming>()
+            .and_then(|timing| timing.since_previous);
+        match since_previous {
+            Some(since_previous) => ctx.chain(&mut self.0, &since_previous),
+            None => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SincePrevious")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+// Decode the %XX escapes in a path. Unlike form decoding, `+` is left as is.
+// Escapes that aren't followed by two hex digits are kept.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(&[hi, lo]) => hex(hi).zip(hex(lo)).map(|(hi, lo)| hi << 4 | lo),
+            _ => None,
+        };
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -259,6 +886,15 @@ mod tests {
         assert!(eval(&mut method("POST"), &req));
     }
 
+    #[test]
+    fn test_method_typed() {
+        let req = http::Request::post("https://example.com/foobar")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut method(http::Method::POST), &req));
+        assert!(!eval(&mut method(http::Method::GET), &req));
+    }
+
     #[test]
     fn test_headers() {
         use bstr::{ByteSlice, B};
@@ -283,6 +919,30 @@ mod tests {
         assert!(eval(&mut headers(eq(expected)), &req));
     }
 
+    #[test]
+    fn test_headers_typed() {
+        let req = http::Request::get("https://example.com/path")
+            .header(hyper::header::HOST, "example.com")
+            .header(hyper::header::CONTENT_LENGTH, "101")
+            .body("")
+            .unwrap();
+        assert!(eval(
+            &mut headers(contains((hyper::header::HOST, "example.com"))),
+            &req
+        ));
+        assert!(eval(
+            &mut headers(contains((
+                hyper::header::CONTENT_LENGTH,
+                hyper::header::HeaderValue::from_static("101")
+            ))),
+            &req
+        ));
+        assert!(!eval(
+            &mut headers(contains(key(hyper::header::AUTHORIZATION))),
+            &req
+        ));
+    }
+
     #[test]
     fn test_body() {
         let req = http::Request::get("https://example.com/foo")
@@ -311,4 +971,185 @@ mod tests {
         assert!(!eval(&mut method_path("GET", "/foobar"), &req));
         assert!(!eval(&mut method_path("POST", "/"), &req));
     }
+
+    #[test]
+    fn test_method_path_typed() {
+        let req = http::Request::post("https://example.com/foobar")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut method_path(http::Method::POST, "/foobar"), &req));
+        assert!(!eval(&mut method_path(http::Method::GET, "/foobar"), &req));
+    }
+
+    #[test]
+    fn test_framing() {
+        let req = http::Request::post("/upload")
+            .header("Transfer-Encoding", "gzip, chunked")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut is_chunked(), &req));
+        assert!(!eval(&mut content_length(any()), &req));
+
+        let req = http::Request::post("/upload")
+            .header("Content-Length", "11")
+            .body("hello world")
+            .unwrap();
+        assert!(!eval(&mut is_chunked(), &req));
+        assert!(eval(&mut content_length(eq(11)), &req));
+        assert!(!eval(&mut content_length(eq(12)), &req));
+    }
+
+    #[test]
+    fn test_scheme_and_absolute_uri() {
+        let req = http::Request::get("http://example.com/foo?bar")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut scheme("http"), &req));
+        assert!(eval(&mut absolute_uri("http://example.com/foo?bar"), &req));
+
+        let req = http::Request::get("/foo?bar").body("").unwrap();
+        assert!(eval(&mut scheme(""), &req));
+        assert!(eval(&mut absolute_uri("/foo?bar"), &req));
+    }
+
+    #[test]
+    fn test_proxy_target() {
+        let req = http::Request::get("http://example.com/foo")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut proxy_target("example.com"), &req));
+
+        let req = http::Request::connect("example.com:443").body("").unwrap();
+        assert!(eval(&mut proxy_target("example.com:443"), &req));
+
+        let mut req = http::Request::get("/foo").body("").unwrap();
+        assert!(eval(&mut proxy_target(""), &req));
+        req.extensions_mut().insert(crate::server::TunnelTarget(
+            "example.com:443".parse().unwrap(),
+        ));
+        assert!(eval(&mut proxy_target("example.com:443"), &req));
+    }
+
+    #[test]
+    fn test_alpn_protocol() {
+        let mut req = http::Request::get("/foo").body("").unwrap();
+        assert!(eval(&mut alpn_protocol(""), &req));
+        req.extensions_mut()
+            .insert(crate::server::NegotiatedProtocol("h2".to_string()));
+        assert!(eval(&mut alpn_protocol("h2"), &req));
+        assert!(!eval(&mut alpn_protocol("http/1.1"), &req));
+    }
+
+    #[test]
+    fn test_method_in() {
+        let mut c = method_in(["GET", "HEAD"]);
+        assert!(eval(&mut c, &http::Request::get("/").body("").unwrap()));
+        assert!(eval(&mut c, &http::Request::head("/").body("").unwrap()));
+        assert!(!eval(&mut c, &http::Request::post("/").body("").unwrap()));
+        let mut c = method_in(vec![http::Method::PUT]);
+        assert!(eval(&mut c, &http::Request::put("/").body("").unwrap()));
+    }
+
+    #[test]
+    fn test_decoded_path() {
+        let req = http::Request::get("/a%20b/c%2Fd+e").body("").unwrap();
+        assert!(eval(&mut decoded_path("/a b/c/d+e"), &req));
+        assert!(!eval(&mut path("/a b/c/d+e"), &req));
+        // invalid escapes are kept.
+        let req = http::Request::get("/100%zz").body("").unwrap();
+        assert!(eval(&mut decoded_path("/100%zz"), &req));
+    }
+
+    #[test]
+    fn test_path_segments() {
+        let req = http::Request::get("/users/jane%20doe/a%2Fb+c/")
+            .body("")
+            .unwrap();
+        assert!(eval(
+            &mut path_segments(vec!["users", "jane doe", "a/b+c", ""]),
+            &req
+        ));
+        assert!(eval(&mut path_segments(contains("jane doe")), &req));
+        let req = http::Request::get("/").body("").unwrap();
+        assert!(eval(&mut path_segments(len(eq(0))), &req));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("a/b c+d", percent_decode("a%2Fb%20c+d"));
+        assert_eq!("\u{e9}", percent_decode("%c3%A9"));
+        // only two hex digits make an escape.
+        assert_eq!("%+1%-1%1", percent_decode("%+1%-1%1"));
+        assert_eq!("%zz%", percent_decode("%zz%"));
+    }
+
+    #[test]
+    fn test_query_param_values() {
+        let req = http::Request::get("/foo?tag=b&other=x&tag=a%20b")
+            .body("")
+            .unwrap();
+        assert!(eval(&mut query_param_values("tag", vec!["b", "a b"]), &req));
+        assert!(!eval(
+            &mut query_param_values("tag", vec!["a b", "b"]),
+            &req
+        ));
+        assert!(eval(
+            &mut query_param_values("tag", vec!["a b", "b"]).unordered(),
+            &req
+        ));
+        assert!(!eval(&mut query_param_values("tag", vec!["b"]), &req));
+        assert!(eval(&mut query_param_values("missing", len(eq(0))), &req));
+    }
+
+    #[test]
+    fn test_multipart_file() {
+        let body = "--b\r\n\
+                    Content-Disposition: form-data; name=\"avatar\"\r\n\
+                    \r\n\
+                    not a file\r\n\
+                    --b\r\n\
+                    Content-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\n\
+                    Content-Type: image/png\r\n\
+                    \r\n\
+                    png\r\n\
+                    --b--\r\n";
+        let req = http::Request::post("/upload")
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body(body)
+            .unwrap();
+        assert!(eval(
+            &mut multipart_file(
+                "avatar",
+                all_of![
+                    multipart::filename(matches(r"\.png$")),
+                    multipart::content_type("image/png"),
+                    multipart::bytes(len(|n: &usize| *n > 0)),
+                ]
+            ),
+            &req
+        ));
+        // only files are matched.
+        assert!(!eval(
+            &mut multipart_file("avatar", multipart::bytes("not a file")),
+            &req
+        ));
+        assert!(!eval(&mut multipart_file("other", any()), &req));
+        let req = http::Request::post("/upload").body(body).unwrap();
+        assert!(!eval(&mut multipart_file("avatar", any()), &req));
+    }
+
+    #[test]
+    fn test_content_encoding() {
+        let mut req = http::Request::get("/foo").body("").unwrap();
+        assert!(eval(&mut content_encoding(""), &req));
+        req.headers_mut()
+            .append("content-encoding", "gzip".parse().unwrap());
+        req.headers_mut()
+            .append("content-encoding", "br".parse().unwrap());
+        assert!(eval(&mut content_encoding("gzip, br"), &req));
+        // the encoding before decompression takes precedence.
+        req.extensions_mut()
+            .insert(crate::server::DecodedContentEncoding("deflate".to_string()));
+        assert!(eval(&mut content_encoding("deflate"), &req));
+    }
 }
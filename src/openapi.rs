@@ -0,0 +1,589 @@
+//! Validate traffic against an [OpenAPI](https://spec.openapis.org/oas/v3.1.0)
+//! document.
+//!
+//! A server started with
+//! [ServerBuilder::validate_against](../struct.ServerBuilder.html#method.validate_against)
+//! checks every request it receives, and every response its responders
+//! produce, against the spec. Violations fail verification, which catches
+//! mocks that have drifted from the real API.
+//!
+//! ```no_run
+//! use httptest::{openapi::OpenApi, ServerBuilder};
+//!
+//! let server = ServerBuilder::new()
+//!     .validate_against(OpenApi::load("openapi.json").unwrap())
+//!     .run()
+//!     .unwrap();
+//! ```
+//!
+//! Requests are checked for a matching path and method, the presence and type
+//! of path, query and header parameters, and the content type and schema of
+//! the body. Responses are checked for a documented status code and the
+//! content type and schema of the body. Only json bodies are checked against
+//! schemas. Schemas support `$ref`, `type`, `nullable`, `enum`, `properties`,
+//! `required`, `additionalProperties`, `items`, `allOf`, `anyOf`, `oneOf`,
+//! the numeric and length bounds, and `pattern`.
+
+use crate::matchers::request::percent_decode;
+use bytes::Bytes;
+use serde_json::Value;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+// The name and value of each path parameter.
+type PathParams = Vec<(String, String)>;
+
+/// A parsed OpenAPI document.
+pub struct OpenApi {
+    doc: Value,
+    // path prefixes from the document's servers, e.g. `/v1`.
+    base_paths: Vec<String>,
+}
+
+impl OpenApi {
+    /// Parse a json OpenAPI document.
+    pub fn from_json(doc: &[u8]) -> io::Result<OpenApi> {
+        let doc: Value = serde_json::from_slice(doc)?;
+        if !doc["paths"].is_object() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid OpenAPI document: missing paths",
+            ));
+        }
+        let base_paths = doc["servers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|server| server["url"].as_str())
+            .filter_map(|url| {
+                let path = url
+                    .parse::<http::Uri>()
+                    .ok()?
+                    .path()
+                    .trim_end_matches('/')
+                    .to_string();
+                Some(path).filter(|path| !path.is_empty())
+            })
+            .collect();
+        Ok(OpenApi { doc, base_paths })
+    }
+
+    /// Read the json OpenAPI document at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<OpenApi> {
+        OpenApi::from_json(&std::fs::read(path)?)
+    }
+
+    // Check an exchange against the spec and return the violations found.
+    pub(crate) fn validate(
+        &self,
+        req: &http::Request<Bytes>,
+        resp: &http::Response<Bytes>,
+    ) -> Vec<String> {
+        let mut violations = Vec::new();
+        self.validate_exchange(req, resp, &mut violations);
+        let prefix = format!("{} {}", req.method(), req.uri());
+        violations
+            .into_iter()
+            .map(|violation| format!("{}: {}", prefix, violation))
+            .collect()
+    }
+
+    fn validate_exchange(
+        &self,
+        req: &http::Request<Bytes>,
+        resp: &http::Response<Bytes>,
+        violations: &mut Vec<String>,
+    ) {
+        let Some((path_item, path_params)) = self.find_path(req.uri().path()) else {
+            violations.push("path is not in the spec".to_string());
+            return;
+        };
+        let method = req.method().as_str().to_lowercase();
+        let Some(operation) = path_item.get(method.as_str()) else {
+            violations.push(format!("method {} is not allowed", req.method()));
+            return;
+        };
+
+        // operation parameters override path parameters with the same name
+        // and location.
+        let mut params: Vec<&Value> = Vec::new();
+        for param in operation["parameters"]
+            .as_array()
+            .into_iter()
+            .chain(path_item["parameters"].as_array())
+            .flatten()
+        {
+            let param = self.resolve(param);
+            if !params
+                .iter()
+                .any(|p| p["name"] == param["name"] && p["in"] == param["in"])
+            {
+                params.push(param);
+            }
+        }
+        let query: Vec<(String, String)> =
+            form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+        for param in params {
+            let name = param["name"].as_str().unwrap_or("");
+            let location = param["in"].as_str().unwrap_or("");
+            let values: Vec<String> = match location {
+                "path" => path_params
+                    .iter()
+                    .filter(|(k, _)| k == name)
+                    .map(|(_, v)| v.clone())
+                    .collect(),
+                "query" => query
+                    .iter()
+                    .filter(|(k, _)| k == name)
+                    .map(|(_, v)| v.clone())
+                    .collect(),
+                "header" => req
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .collect(),
+                _ => continue,
+            };
+            if values.is_empty() {
+                if param["required"] == Value::Bool(true) {
+                    violations.push(format!("missing required {} parameter {}", location, name));
+                }
+                continue;
+            }
+            let schema = self.resolve(&param["schema"]);
+            let is_array = schema["type"] == "array";
+            let value = if is_array {
+                Value::Array(
+                    values
+                        .iter()
+                        .map(|v| parameter_value(v, &schema["items"]))
+                        .collect(),
+                )
+            } else {
+                parameter_value(&values[0], schema)
+            };
+            let path = format!("{} parameter {}", location, name);
+            self.validate_schema(&value, schema, &path, violations);
+        }
+
+        match operation.get("requestBody").map(|body| self.resolve(body)) {
+            Some(body) => {
+                if req.body().is_empty() {
+                    if body["required"] == Value::Bool(true) {
+                        violations.push("missing required request body".to_string());
+                    }
+                } else {
+                    self.validate_body(
+                        "request body",
+                        req.headers(),
+                        req.body(),
+                        &body["content"],
+                        violations,
+                    );
+                }
+            }
+            None if !req.body().is_empty() => {
+                violations.push("request body is not allowed".to_string());
+            }
+            None => {}
+        }
+
+        let responses = &operation["responses"];
+        let status = resp.status().as_u16().to_string();
+        let range = format!("{}XX", &status[..1]);
+        let response = [status.as_str(), range.as_str(), "default"]
+            .iter()
+            .find_map(|key| responses.get(key));
+        match response.map(|response| self.resolve(response)) {
+            None => violations.push(format!("response status {} is not in the spec", status)),
+            Some(response) if !resp.body().is_empty() => {
+                self.validate_body(
+                    "response body",
+                    resp.headers(),
+                    resp.body(),
+                    &response["content"],
+                    violations,
+                );
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Find the path item for `path` and the values of its path parameters.
+    // Paths without parameters take precedence over templated paths.
+    fn find_path(&self, path: &str) -> Option<(&Value, PathParams)> {
+        let mut candidates = vec![path];
+        candidates.extend(
+            self.base_paths
+                .iter()
+                .filter_map(|base| path.strip_prefix(base.as_str()))
+                .filter(|path| path.starts_with('/')),
+        );
+        let mut best: Option<(usize, &Value, PathParams)> = None;
+        for path in candidates {
+            let segments: Vec<&str> = path.split('/').collect();
+            for (template, item) in self.doc["paths"].as_object().into_iter().flatten() {
+                let template_segments: Vec<&str> = template.split('/').collect();
+                if template_segments.len() != segments.len() {
+                    continue;
+                }
+                let mut params = Vec::new();
+                let matched = template_segments.iter().zip(&segments).all(|(t, s)| {
+                    match t.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+                        Some(name) if !s.is_empty() => {
+                            params.push((name.to_string(), percent_decode(s)));
+                            true
+                        }
+                        Some(_) => false,
+                        None => t == s,
+                    }
+                });
+                let better = match &best {
+                    Some((n, _, _)) => params.len() < *n,
+                    None => true,
+                };
+                if matched && better {
+                    best = Some((params.len(), self.resolve(item), params));
+                }
+            }
+        }
+        best.map(|(_, item, params)| (item, params))
+    }
+
+    fn validate_body(
+        &self,
+        what: &str,
+        headers: &http::HeaderMap,
+        body: &[u8],
+        content: &Value,
+        violations: &mut Vec<String>,
+    ) {
+        let Some(content) = content.as_object() else {
+            return;
+        };
+        let content_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        let main_type = format!("{}/*", essence.split('/').next().unwrap_or(""));
+        let media_type = [essence.as_str(), main_type.as_str(), "*/*"]
+            .iter()
+            .find_map(|key| content.get(*key));
+        let Some(media_type) = media_type else {
+            violations.push(format!(
+                "{} content type {:?} is not in the spec",
+                what, content_type
+            ));
+            return;
+        };
+        if !essence.ends_with("json") || media_type.get("schema").is_none() {
+            return;
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(value) => self.validate_schema(&value, &media_type["schema"], what, violations),
+            Err(e) => violations.push(format!("{} is not valid json: {}", what, e)),
+        }
+    }
+
+    fn validate_schema(
+        &self,
+        value: &Value,
+        schema: &Value,
+        path: &str,
+        violations: &mut Vec<String>,
+    ) {
+        let schema = self.resolve(schema);
+        if value.is_null() && schema["nullable"] == Value::Bool(true) {
+            return;
+        }
+        if let Some(types) = schema.get("type") {
+            // 3.1 allows a list of types.
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                ty => ty.as_str().into_iter().collect(),
+            };
+            if !types.iter().any(|ty| has_type(value, ty)) {
+                violations.push(format!(
+                    "{}: expected {}, got {}",
+                    path,
+                    types.join(" or "),
+                    value
+                ));
+                return;
+            }
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                violations.push(format!("{}: {} is not one of {:?}", path, value, allowed));
+            }
+        }
+        for sub in schema["allOf"].as_array().into_iter().flatten() {
+            self.validate_schema(value, sub, path, violations);
+        }
+        let matching = |key: &str| {
+            schema[key].as_array().map(|subs| {
+                subs.iter()
+                    .filter(|sub| {
+                        let mut sub_violations = Vec::new();
+                        self.validate_schema(value, sub, path, &mut sub_violations);
+                        sub_violations.is_empty()
+                    })
+                    .count()
+            })
+        };
+        if matching("anyOf") == Some(0) {
+            violations.push(format!("{}: {} matches none of anyOf", path, value));
+        }
+        if let Some(n) = matching("oneOf").filter(|n| *n != 1) {
+            violations.push(format!("{}: {} matches {} of oneOf", path, value, n));
+        }
+
+        match value {
+            Value::Object(object) => {
+                for required in schema["required"].as_array().into_iter().flatten() {
+                    let name = required.as_str().unwrap_or("");
+                    if !object.contains_key(name) {
+                        violations.push(format!("{}: missing required property {}", path, name));
+                    }
+                }
+                let properties = &schema["properties"];
+                for (name, property) in object {
+                    let property_path = format!("{}.{}", path, name);
+                    match (properties.get(name), &schema["additionalProperties"]) {
+                        (Some(property_schema), _) => self.validate_schema(
+                            property,
+                            property_schema,
+                            &property_path,
+                            violations,
+                        ),
+                        (None, Value::Bool(false)) => {
+                            violations.push(format!("{}: property is not allowed", property_path))
+                        }
+                        (None, additional) if additional.is_object() => {
+                            self.validate_schema(property, additional, &property_path, violations)
+                        }
+                        (None, _) => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                check_bound(
+                    path,
+                    "items",
+                    items.len() as f64,
+                    schema,
+                    "minItems",
+                    "maxItems",
+                    violations,
+                );
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        let item_path = format!("{}[{}]", path, i);
+                        self.validate_schema(item, item_schema, &item_path, violations);
+                    }
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count() as f64;
+                check_bound(
+                    path,
+                    "length",
+                    len,
+                    schema,
+                    "minLength",
+                    "maxLength",
+                    violations,
+                );
+                if let Some(pattern) = schema["pattern"].as_str() {
+                    match regex::Regex::new(pattern) {
+                        Ok(regex) if !regex.is_match(s) => violations.push(format!(
+                            "{}: {:?} does not match pattern {:?}",
+                            path, s, pattern
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or(0.0);
+                check_bound(path, "value", n, schema, "minimum", "maximum", violations);
+            }
+            Value::Bool(_) | Value::Null => {}
+        }
+    }
+
+    // Follow a local `$ref`, e.g. `#/components/schemas/Pet`.
+    fn resolve<'a>(&'a self, mut value: &'a Value) -> &'a Value {
+        // bound the number of references followed in case of a cycle.
+        for _ in 0..32 {
+            let Some(reference) = value["$ref"].as_str() else {
+                return value;
+            };
+            let Some(pointer) = reference.strip_prefix('#') else {
+                return value;
+            };
+            match self.doc.pointer(pointer) {
+                Some(target) => value = target,
+                None => return value,
+            }
+        }
+        value
+    }
+}
+
+impl fmt::Debug for OpenApi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OpenApi")
+            .field("title", &self.doc["info"]["title"])
+            .field("version", &self.doc["info"]["version"])
+            .finish()
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn check_bound(
+    path: &str,
+    what: &str,
+    n: f64,
+    schema: &Value,
+    min: &str,
+    max: &str,
+    violations: &mut Vec<String>,
+) {
+    if let Some(min) = schema[min].as_f64().filter(|min| n < *min) {
+        violations.push(format!("{}: {} {} is less than {}", path, what, n, min));
+    }
+    if let Some(max) = schema[max].as_f64().filter(|max| n > *max) {
+        violations.push(format!("{}: {} {} is greater than {}", path, what, n, max));
+    }
+}
+
+// Parameters arrive as strings. Convert them to the json type the schema
+// expects so they can be validated like a body.
+fn parameter_value(raw: &str, schema: &Value) -> Value {
+    let parsed = match schema["type"].as_str() {
+        Some("integer") | Some("number") | Some("boolean") => serde_json::from_str(raw).ok(),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> OpenApi {
+        let doc = serde_json::json!({
+            "openapi": "3.0.3",
+            "servers": [{"url": "https://api.example.com/v1"}],
+            "paths": {
+                "/pets/{id}": {
+                    "parameters": [{
+                        "name": "id", "in": "path", "required": true,
+                        "schema": {"type": "integer"},
+                    }],
+                    "get": {
+                        "responses": {
+                            "200": {
+                                "content": {
+                                    "application/json": {
+                                        "schema": {"$ref": "#/components/schemas/Pet"},
+                                    },
+                                },
+                            },
+                            "4XX": {},
+                        },
+                    },
+                },
+                "/pets/mine": {"get": {"responses": {"200": {}}}},
+            },
+            "components": {
+                "schemas": {
+                    "Pet": {
+                        "type": "object",
+                        "required": ["name"],
+                        "properties": {
+                            "name": {"type": "string", "minLength": 1},
+                            "tags": {"type": "array", "items": {"type": "string"}},
+                        },
+                    },
+                },
+            },
+        });
+        OpenApi::from_json(doc.to_string().as_bytes()).unwrap()
+    }
+
+    fn get(uri: &str) -> http::Request<Bytes> {
+        http::Request::get(uri).body(Bytes::new()).unwrap()
+    }
+
+    fn json_response(status: u16, body: &str) -> http::Response<Bytes> {
+        http::Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Bytes::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let spec = spec();
+        let ok = json_response(200, r#"{"name": "rex", "tags": ["good"]}"#);
+        assert_eq!(Vec::<String>::new(), spec.validate(&get("/v1/pets/1"), &ok));
+        assert_eq!(Vec::<String>::new(), spec.validate(&get("/pets/1"), &ok));
+        assert_eq!(
+            Vec::<String>::new(),
+            spec.validate(&get("/pets/mine"), &json_response(200, ""))
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            spec.validate(&get("/pets/1"), &json_response(404, ""))
+        );
+
+        assert_eq!(
+            vec![
+                "GET /pets/abc: path parameter id: expected integer, got \"abc\"",
+                "GET /pets/abc: response body: missing required property name",
+                "GET /pets/abc: response body.tags[0]: expected string, got 1",
+            ],
+            spec.validate(&get("/pets/abc"), &json_response(200, r#"{"tags": [1]}"#))
+        );
+        assert_eq!(
+            vec!["GET /cats: path is not in the spec"],
+            spec.validate(&get("/cats"), &ok)
+        );
+        assert_eq!(
+            vec!["GET /pets/1: response status 500 is not in the spec"],
+            spec.validate(&get("/pets/1"), &json_response(500, ""))
+        );
+    }
+
+    #[test]
+    fn test_path_params_percent_decoded() {
+        let spec = spec();
+        let (_, params) = spec.find_path("/pets/a%26b%3Dc+d%2F").unwrap();
+        assert_eq!(vec![("id".to_string(), "a&b=c+d/".to_string())], params);
+    }
+}
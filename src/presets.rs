@@ -0,0 +1,18 @@
+//! Ready-made fakes of common protocols.
+//!
+//! A preset bundles the matchers, responders and state needed to fake a
+//! protocol that takes more than one request to play out.
+
+#[cfg(feature = "digest-auth")]
+pub mod digest;
+pub mod health;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+pub use health::health_checks;
+#[cfg(feature = "oidc")]
+pub use oidc::oidc;
+#[cfg(feature = "s3")]
+pub use s3::s3;
@@ -0,0 +1,301 @@
+//! HTTP Digest authentication.
+//!
+//! A [DigestAuth](struct.DigestAuth.html) holds the credentials a client is
+//! expected to use and the nonces handed out to it. Requests without valid
+//! credentials are answered with a challenge, after which the client retries
+//! with an `Authorization` header computed from the challenge's nonce.
+//!
+//! ```
+//! use httptest::{matchers::*, presets::digest::DigestAuth, responders::*, Expectation, Server};
+//!
+//! let server = Server::run();
+//! let digest = DigestAuth::new("example", "alice", "secret");
+//! server.expect(
+//!     Expectation::matching(all_of![
+//!         request::method_path("GET", "/protected"),
+//!         digest.authorized(),
+//!     ])
+//!     .times(..)
+//!     .respond_with(status_code(200)),
+//! );
+//! server.expect(
+//!     Expectation::matching(all_of![
+//!         request::method_path("GET", "/protected"),
+//!         not(digest.authorized()),
+//!     ])
+//!     .times(..)
+//!     .respond_with(digest.challenge()),
+//! );
+//! ```
+
+use crate::matchers::{ExecutionContext, Matcher};
+use crate::responders::Responder;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The hash algorithm used to compute digests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// `MD5`, the default.
+    Md5,
+    /// `SHA-256`
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn hash(self, data: &str) -> String {
+        let digest = match self {
+            Algorithm::Md5 => Md5::digest(data.as_bytes()).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Credentials a client must authenticate with and the nonces it has been
+/// challenged with.
+///
+/// Clones share the same nonces.
+#[derive(Debug, Clone)]
+pub struct DigestAuth {
+    realm: String,
+    username: String,
+    password: String,
+    algorithm: Algorithm,
+    nonces: Arc<Mutex<Nonces>>,
+}
+
+#[derive(Debug, Default)]
+struct Nonces {
+    issued: HashSet<String>,
+    next: u64,
+}
+
+impl DigestAuth {
+    /// Expect the client to authenticate as `username` with `password` in
+    /// the given realm.
+    pub fn new(realm: &str, username: &str, password: &str) -> Self {
+        DigestAuth {
+            realm: realm.to_owned(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+            algorithm: Algorithm::Md5,
+            nonces: Arc::new(Mutex::new(Nonces::default())),
+        }
+    }
+
+    /// Use the given hash algorithm. Defaults to `MD5`.
+    pub fn algorithm(self, algorithm: Algorithm) -> Self {
+        DigestAuth { algorithm, ..self }
+    }
+
+    /// A responder that answers with a `401 Unauthorized` carrying a new
+    /// challenge.
+    pub fn challenge(&self) -> Challenge {
+        Challenge(self.clone())
+    }
+
+    /// A matcher that matches requests whose `Authorization` header holds
+    /// valid credentials for a nonce issued by [challenge](#method.challenge).
+    pub fn authorized(&self) -> Authorized {
+        Authorized(self.clone())
+    }
+
+    fn issue_nonce(&self) -> String {
+        let mut nonces = self.nonces.lock().unwrap_or_else(|e| e.into_inner());
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let nonce = self
+            .algorithm
+            .hash(&format!("{}:{}:{:?}", self.realm, nonces.next, now));
+        nonces.next += 1;
+        nonces.issued.insert(nonce.clone());
+        nonce
+    }
+
+    fn is_authorized<B>(&self, req: &http::Request<B>) -> bool {
+        let params = match req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Digest "))
+        {
+            Some(params) => parse_params(params),
+            None => return false,
+        };
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let (Some(username), Some(realm), Some(nonce), Some(uri), Some(response)) = (
+            param("username"),
+            param("realm"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) else {
+            return false;
+        };
+        let request_uri = req
+            .uri()
+            .path_and_query()
+            .map_or(req.uri().path(), |pq| pq.as_str());
+        if username != self.username || realm != self.realm || uri != request_uri {
+            return false;
+        }
+        let issued = {
+            let nonces = self.nonces.lock().unwrap_or_else(|e| e.into_inner());
+            nonces.issued.contains(nonce)
+        };
+        if !issued {
+            return false;
+        }
+        let ha1 = self.algorithm.hash(&format!(
+            "{}:{}:{}",
+            self.username, self.realm, self.password
+        ));
+        let ha2 = self.algorithm.hash(&format!("{}:{}", req.method(), uri));
+        let expected = match (param("qop"), param("nc"), param("cnonce")) {
+            (Some(qop), Some(nc), Some(cnonce)) => self.algorithm.hash(&format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, nonce, nc, cnonce, qop, ha2
+            )),
+            _ => self.algorithm.hash(&format!("{}:{}:{}", ha1, nonce, ha2)),
+        };
+        response.eq_ignore_ascii_case(&expected)
+    }
+}
+
+// Parse the comma separated `name=value` pairs of a Digest header. Values may
+// be quoted.
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = s.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let name = name.trim().to_owned();
+        let after = after.trim_start();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (
+                    quoted[..end].to_owned(),
+                    quoted.get(end + 1..).unwrap_or(""),
+                )
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_owned(), &after[end..])
+            }
+        };
+        params.push((name, value));
+        rest = after.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+/// The responder returned by [DigestAuth::challenge](struct.DigestAuth.html#method.challenge)
+#[derive(Debug)]
+pub struct Challenge(DigestAuth);
+
+impl Responder for Challenge {
+    fn respond<'a>(
+        &mut self,
+        _req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let nonce = self.0.issue_nonce();
+        let challenge = format!(
+            r#"Digest realm="{}", qop="auth", algorithm={}, nonce="{}""#,
+            self.0.realm,
+            self.0.algorithm.name(),
+            nonce
+        );
+        let resp = http::Response::builder()
+            .status(http::StatusCode::UNAUTHORIZED)
+            .header(http::header::WWW_AUTHENTICATE, challenge)
+            .body(hyper::body::Bytes::new())
+            .unwrap();
+        Box::pin(async move { resp })
+    }
+}
+
+/// The matcher returned by [DigestAuth::authorized](struct.DigestAuth.html#method.authorized)
+#[derive(Debug)]
+pub struct Authorized(DigestAuth);
+
+impl<B> Matcher<http::Request<B>> for Authorized {
+    fn matches(&mut self, input: &http::Request<B>, _ctx: &mut ExecutionContext) -> bool {
+        self.0.is_authorized(input)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DigestAuthorized(realm: {:?}, username: {:?})",
+            self.0.realm, self.0.username
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(
+            vec![
+                ("username".to_owned(), "Mufasa".to_owned()),
+                ("uri".to_owned(), "/dir/index.html?a=1,2".to_owned()),
+                ("qop".to_owned(), "auth".to_owned()),
+                ("nc".to_owned(), "00000001".to_owned()),
+            ],
+            parse_params(
+                r#"username="Mufasa", uri="/dir/index.html?a=1,2", qop=auth, nc=00000001"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_authorized() {
+        // the example from RFC 7616, section 3.9.1.
+        let digest = DigestAuth::new("http-auth@example.org", "Mufasa", "Circle of Life");
+        let nonce = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+        digest
+            .nonces
+            .lock()
+            .unwrap()
+            .issued
+            .insert(nonce.to_owned());
+        let req = http::Request::get("/dir/index.html")
+            .header(
+                "Authorization",
+                format!(
+                    r#"Digest username="Mufasa", realm="http-auth@example.org", uri="/dir/index.html", algorithm=MD5, nonce="{}", nc=00000001, cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth, response="8ca523f5e9506fed4657c9700eebdbec", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+                    nonce
+                ),
+            )
+            .body("")
+            .unwrap();
+        assert!(ExecutionContext::evaluate(&mut digest.authorized(), &req));
+
+        // nonces that weren't issued are rejected.
+        let digest = DigestAuth::new("http-auth@example.org", "Mufasa", "Circle of Life");
+        assert!(!ExecutionContext::evaluate(&mut digest.authorized(), &req));
+    }
+}
@@ -0,0 +1,65 @@
+//! Health check probes.
+//!
+//! [install](struct.HealthChecks.html#method.install) allows any number of
+//! `GET` requests to the health check paths, `/healthz` and `/readyz` by
+//! default, answering them with `200 OK`. They never fail verification, so
+//! probes from sidecars and load balancers don't trip the unexpected request
+//! failure.
+//!
+//! ```
+//! use httptest::{presets, Server};
+//!
+//! let server = Server::run();
+//! presets::health_checks().path("/livez").install(&server);
+//! ```
+
+use crate::matchers::{any_of, request, Matcher};
+use crate::responders::status_code;
+use crate::{ExpectationHandle, Server};
+
+/// Allow health check probes. See the [module docs](index.html).
+pub fn health_checks() -> HealthChecks {
+    HealthChecks {
+        paths: vec!["/healthz".to_owned(), "/readyz".to_owned()],
+    }
+}
+
+/// Configures the paths health checks are answered on. Returned by
+/// [presets::health_checks()](../fn.health_checks.html).
+#[derive(Debug, Clone)]
+pub struct HealthChecks {
+    paths: Vec<String>,
+}
+
+impl HealthChecks {
+    /// Answer health checks on `path` as well.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Answer health checks on `paths` instead of the default paths.
+    pub fn paths<I>(self, paths: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        HealthChecks {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Allow the health checks on the server. The returned handle counts the
+    /// probes received.
+    pub fn install(self, server: &Server) -> ExpectationHandle {
+        let paths = self
+            .paths
+            .into_iter()
+            .map(|path| Box::new(path) as Box<dyn Matcher<str>>)
+            .collect();
+        server.allow(
+            request::method_path("GET", any_of(paths)),
+            status_code(200).body("ok"),
+        )
+    }
+}
@@ -0,0 +1,350 @@
+//! A fake OAuth2 / OpenID Connect identity provider.
+//!
+//! [install](struct.Oidc.html#method.install) adds expectations to a server
+//! for the endpoints a client needs to discover the provider, obtain tokens
+//! and verify them:
+//!
+//! * `GET /.well-known/openid-configuration` - the discovery document.
+//! * `GET /jwks` - the key set tokens are signed with.
+//! * `GET /authorize` - redirects to the `redirect_uri` with a code, as if the
+//!   user logged in.
+//! * `POST /token` - issues an access token and an id token for any grant.
+//!
+//! Tokens are JWTs signed with RS256. Unless another key is given with
+//! [signing_key](struct.Oidc.html#method.signing_key) they're signed with a
+//! key published in httptest's source, so anyone can sign tokens with it.
+//! Never trust it outside of tests.
+//!
+//! ```
+//! use httptest::{presets, Server};
+//!
+//! let server = Server::run();
+//! let provider = presets::oidc()
+//!     .subject("alice")
+//!     .claim("email", "alice@example.com")
+//!     .install(&server);
+//! // configure the client under test with provider.issuer()
+//! # let _ = provider.issuer();
+//! ```
+
+use crate::jwt::SigningKey;
+use crate::matchers::request;
+use crate::responders::{json_encoded, Responder};
+use crate::{Expectation, Server};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Create a fake identity provider. See the [module docs](index.html).
+pub fn oidc() -> Oidc {
+    Oidc {
+        subject: "httptest-user".to_owned(),
+        audience: None,
+        token_lifetime: Duration::from_secs(3600),
+        claims: Map::new(),
+        signing_key: SigningKey::insecure_test_key(),
+    }
+}
+
+/// Configures the tokens a fake identity provider issues. Returned by
+/// [presets::oidc()](../fn.oidc.html).
+#[derive(Debug, Clone)]
+pub struct Oidc {
+    subject: String,
+    audience: Option<String>,
+    token_lifetime: Duration,
+    claims: Map<String, Value>,
+    signing_key: SigningKey,
+}
+
+impl Oidc {
+    /// The `sub` claim of issued tokens. Defaults to `httptest-user`.
+    pub fn subject(self, subject: impl Into<String>) -> Self {
+        Oidc {
+            subject: subject.into(),
+            ..self
+        }
+    }
+
+    /// The `aud` claim of issued tokens. Defaults to the `client_id` of the
+    /// token request.
+    pub fn audience(self, audience: impl Into<String>) -> Self {
+        Oidc {
+            audience: Some(audience.into()),
+            ..self
+        }
+    }
+
+    /// How long issued tokens are valid for. Defaults to an hour.
+    pub fn token_lifetime(self, token_lifetime: Duration) -> Self {
+        Oidc {
+            token_lifetime,
+            ..self
+        }
+    }
+
+    /// Add a claim to issued tokens, replacing any claim with the same name.
+    pub fn claim(mut self, name: impl Into<String>, value: impl serde::Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("failed to serialize claim");
+        self.claims.insert(name.into(), value);
+        self
+    }
+
+    /// Sign tokens with the RSA private key in `pem`, a PEM encoded PKCS#8 key
+    /// (`BEGIN PRIVATE KEY`), instead of the insecure built-in one.
+    ///
+    /// This function will panic if `pem` doesn't contain a valid key.
+    pub fn signing_key(self, pem: &str) -> Self {
+        Oidc {
+            signing_key: SigningKey::from_pem(pem).expect("invalid signing key"),
+            ..self
+        }
+    }
+
+    /// Add the provider's endpoints to the server. They match any number of
+    /// requests.
+    pub fn install(self, server: &Server) -> OidcProvider {
+        let provider = OidcProvider {
+            issuer: server.url_str("").trim_end_matches('/').to_owned(),
+            config: Arc::new(self),
+        };
+        let issuer = &provider.issuer;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/.well-known/openid-configuration",
+            ))
+            .times(..)
+            .respond_with(json_encoded(json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+                "response_types_supported": ["code"],
+                "subject_types_supported": ["public"],
+                "id_token_signing_alg_values_supported": ["RS256"],
+                "grant_types_supported": [
+                    "authorization_code",
+                    "client_credentials",
+                    "password",
+                    "refresh_token",
+                ],
+            }))),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/jwks"))
+                .times(..)
+                .respond_with(json_encoded(provider.config.signing_key.jwks())),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/authorize"))
+                .times(..)
+                .respond_with(Authorize),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/token"))
+                .times(..)
+                .respond_with(TokenEndpoint(provider.clone())),
+        );
+        provider
+    }
+}
+
+/// A fake identity provider installed on a server. Returned by
+/// [Oidc::install](struct.Oidc.html#method.install).
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    issuer: String,
+    config: Arc<Oidc>,
+}
+
+impl OidcProvider {
+    /// The issuer url, which clients discover the provider from.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Sign a token with the configured claims, overridden by `claims`.
+    ///
+    /// This is useful for handing a client a token directly, for example an
+    /// expired one.
+    pub fn sign(&self, claims: Value) -> String {
+        let mut all = self.default_claims(None);
+        if let Value::Object(claims) = claims {
+            all.extend(claims);
+        }
+        self.config.signing_key.sign_rs256(&Value::Object(all))
+    }
+
+    fn default_claims(&self, client_id: Option<&str>) -> Map<String, Value> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let audience = self
+            .config
+            .audience
+            .as_deref()
+            .or(client_id)
+            .unwrap_or("httptest");
+        let mut claims = Map::new();
+        claims.insert("iss".to_owned(), json!(self.issuer));
+        claims.insert("sub".to_owned(), json!(self.config.subject));
+        claims.insert("aud".to_owned(), json!(audience));
+        claims.insert("iat".to_owned(), json!(now));
+        claims.insert(
+            "exp".to_owned(),
+            json!(now + self.config.token_lifetime.as_secs()),
+        );
+        claims.extend(self.config.claims.clone());
+        claims
+    }
+}
+
+// Redirects back to the client with a code. The code carries the client id
+// and nonce so the token endpoint can put them in the id token.
+struct Authorize;
+
+impl Responder for Authorize {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let params: Vec<(String, String)> =
+            form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let resp = match param("redirect_uri") {
+            Some(redirect_uri) => {
+                let code = URL_SAFE_NO_PAD.encode(
+                    json!({"client_id": param("client_id"), "nonce": param("nonce")}).to_string(),
+                );
+                let mut location = form_urlencoded::Serializer::new(String::new());
+                location.append_pair("code", &code);
+                if let Some(state) = param("state") {
+                    location.append_pair("state", state);
+                }
+                let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+                http::Response::builder()
+                    .status(http::StatusCode::FOUND)
+                    .header(
+                        http::header::LOCATION,
+                        format!("{}{}{}", redirect_uri, separator, location.finish()),
+                    )
+                    .body(hyper::body::Bytes::new())
+            }
+            None => http::Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(hyper::body::Bytes::from_static(b"missing redirect_uri")),
+        };
+        let resp = resp.unwrap();
+        Box::pin(async move { resp })
+    }
+}
+
+// Issues tokens for any grant.
+struct TokenEndpoint(OidcProvider);
+
+impl Responder for TokenEndpoint {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let params: Vec<(String, String)> =
+            form_urlencoded::parse(req.body()).into_owned().collect();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let code: Value = param("code")
+            .and_then(|code| URL_SAFE_NO_PAD.decode(code).ok())
+            .and_then(|code| serde_json::from_slice(&code).ok())
+            .unwrap_or(Value::Null);
+        let client_id = param("client_id")
+            .or_else(|| code["client_id"].as_str().map(str::to_owned))
+            .or_else(|| basic_auth_user(req));
+
+        let claims = self.0.default_claims(client_id.as_deref());
+        let signing_key = &self.0.config.signing_key;
+        let access_token = signing_key.sign_rs256(&Value::Object(claims.clone()));
+        let mut id_claims = claims;
+        if let Some(nonce) = code["nonce"].as_str() {
+            id_claims.insert("nonce".to_owned(), json!(nonce));
+        }
+        let id_token = signing_key.sign_rs256(&Value::Object(id_claims));
+        let body = json!({
+            "access_token": access_token,
+            "id_token": id_token,
+            "refresh_token": "httptest-refresh-token",
+            "token_type": "Bearer",
+            "expires_in": self.0.config.token_lifetime.as_secs(),
+        });
+        let resp = http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(hyper::body::Bytes::from(body.to_string()))
+            .unwrap();
+        Box::pin(async move { resp })
+    }
+}
+
+// The user of an `Authorization: Basic` header, which clients use to send
+// their client id.
+fn basic_auth_user<B>(req: &http::Request<B>) -> Option<String> {
+    let credentials = req
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+    let user = credentials.split(':').next()?;
+    Some(
+        form_urlencoded::parse(user.as_bytes())
+            .map(|(k, _)| k)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{self, RsaPublicKeyComponents};
+
+    #[test]
+    fn test_sign_verifies_with_jwks() {
+        let key = SigningKey::insecure_test_key();
+        let token = key.sign_rs256(&json!({"sub": "alice"}));
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let jwks = key.jwks();
+        let key = &jwks["keys"][0];
+        let public = RsaPublicKeyComponents {
+            n: URL_SAFE_NO_PAD.decode(key["n"].as_str().unwrap()).unwrap(),
+            e: URL_SAFE_NO_PAD.decode(key["e"].as_str().unwrap()).unwrap(),
+        };
+        public
+            .verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                signing_input.as_bytes(),
+                &URL_SAFE_NO_PAD.decode(signature).unwrap(),
+            )
+            .unwrap();
+
+        let claims = signing_input.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(json!({"sub": "alice"}), claims);
+    }
+}
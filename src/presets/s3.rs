@@ -0,0 +1,477 @@
+//! A fake S3-compatible object store.
+//!
+//! [install](struct.S3.html#method.install) adds an expectation to a server
+//! that handles requests the way S3 would, storing objects in memory. It
+//! supports path-style requests (`/bucket/key`) for:
+//!
+//! * `PutObject`, `GetObject`, `HeadObject` and `DeleteObject`
+//! * `CreateBucket` and `ListObjectsV2`
+//! * `CreateMultipartUpload`, `UploadPart`, `CompleteMultipartUpload` and
+//!   `AbortMultipartUpload`
+//!
+//! Buckets don't have to be created before they're used. Request signatures,
+//! including those of presigned urls, are not checked. Other operations are
+//! answered with `501 Not Implemented`.
+//!
+//! The expectation matches any request with a lower priority than the
+//! default, so expectations added to the server take precedence over it. The
+//! requests it handled are available from
+//! [S3Store::operations](struct.S3Store.html#method.operations).
+//!
+//! ```
+//! use httptest::{presets, Server};
+//!
+//! let server = Server::run();
+//! let store = presets::s3()
+//!     .object("my-bucket", "config.json", "{}")
+//!     .install(&server);
+//! // point the client under test at server.url("/")
+//! assert_eq!(Some("{}".into()), store.object("my-bucket", "config.json"));
+//! ```
+
+use crate::matchers::any;
+use crate::matchers::request::percent_decode;
+use crate::responders::Responder;
+use crate::{Expectation, Server};
+use bytes::Bytes;
+use md5::{Digest, Md5};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+/// Create a fake object store. See the [module docs](index.html).
+pub fn s3() -> S3 {
+    S3 {
+        state: State::default(),
+    }
+}
+
+/// Configures the initial contents of a fake object store. Returned by
+/// [presets::s3()](../fn.s3.html).
+#[derive(Debug)]
+pub struct S3 {
+    state: State,
+}
+
+impl S3 {
+    /// Add an object to the store.
+    pub fn object(mut self, bucket: &str, key: &str, body: impl Into<Bytes>) -> Self {
+        self.state.put(bucket, key, body.into(), None);
+        self
+    }
+
+    /// Add the store to the server.
+    pub fn install(self, server: &Server) -> S3Store {
+        let store = S3Store {
+            state: Arc::new(Mutex::new(self.state)),
+        };
+        server.expect(
+            Expectation::matching(any())
+                .times(..)
+                .priority(-1)
+                .respond_with(store.clone()),
+        );
+        store
+    }
+}
+
+/// An S3 operation handled by the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+#[allow(missing_docs)]
+pub enum Operation {
+    PutObject {
+        bucket: String,
+        key: String,
+    },
+    GetObject {
+        bucket: String,
+        key: String,
+    },
+    HeadObject {
+        bucket: String,
+        key: String,
+    },
+    DeleteObject {
+        bucket: String,
+        key: String,
+    },
+    CreateBucket {
+        bucket: String,
+    },
+    ListObjects {
+        bucket: String,
+        prefix: String,
+    },
+    CreateMultipartUpload {
+        bucket: String,
+        key: String,
+    },
+    UploadPart {
+        bucket: String,
+        key: String,
+        part_number: u32,
+    },
+    CompleteMultipartUpload {
+        bucket: String,
+        key: String,
+    },
+    AbortMultipartUpload {
+        bucket: String,
+        key: String,
+    },
+}
+
+/// A fake object store installed on a server. Returned by
+/// [S3::install](struct.S3.html#method.install).
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    state: Arc<Mutex<State>>,
+}
+
+impl S3Store {
+    /// The body of an object, if it exists.
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Bytes> {
+        let state = self.lock();
+        let object = state.buckets.get(bucket)?.get(key)?;
+        Some(object.body.clone())
+    }
+
+    /// The keys of the objects in a bucket, in lexicographic order.
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let state = self.lock();
+        state
+            .buckets
+            .get(bucket)
+            .map(|objects| objects.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The operations the store handled, in the order they were received.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.lock().operations.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: BTreeMap<String, BTreeMap<String, Object>>,
+    uploads: BTreeMap<String, Upload>,
+    next_upload_id: u64,
+    operations: Vec<Operation>,
+}
+
+#[derive(Debug, Clone)]
+struct Object {
+    body: Bytes,
+    etag: String,
+    content_type: Option<http::HeaderValue>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    bucket: String,
+    key: String,
+    parts: BTreeMap<u32, Bytes>,
+}
+
+impl State {
+    fn put(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        body: Bytes,
+        content_type: Option<http::HeaderValue>,
+    ) {
+        let etag = format!("\"{}\"", hex(&Md5::digest(&body)));
+        self.buckets.entry(bucket.to_owned()).or_default().insert(
+            key.to_owned(),
+            Object {
+                body,
+                etag,
+                content_type,
+            },
+        );
+    }
+
+    fn handle(&mut self, req: &http::Request<Bytes>) -> http::Response<Bytes> {
+        let path = percent_decode(req.uri().path());
+        let (bucket, key) = match path.trim_start_matches('/').split_once('/') {
+            Some((bucket, key)) => (bucket.to_owned(), key.to_owned()),
+            None => (path.trim_start_matches('/').to_owned(), String::new()),
+        };
+        let query: BTreeMap<String, String> =
+            form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+        if bucket.is_empty() {
+            return error(501, "NotImplemented", "ListBuckets is not supported");
+        }
+        let upload_id = query.get("uploadId");
+        let method = req.method();
+
+        if key.is_empty() {
+            return match *method {
+                http::Method::PUT => {
+                    self.buckets.entry(bucket.clone()).or_default();
+                    self.operations.push(Operation::CreateBucket { bucket });
+                    response(200).body(Bytes::new()).unwrap()
+                }
+                http::Method::GET => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let body = self.list(&bucket, &prefix);
+                    self.operations
+                        .push(Operation::ListObjects { bucket, prefix });
+                    xml(body)
+                }
+                _ => error(501, "NotImplemented", "unsupported bucket operation"),
+            };
+        }
+
+        match (method.clone(), upload_id) {
+            (http::Method::PUT, Some(upload_id)) => {
+                let part_number = match query.get("partNumber").and_then(|n| n.parse().ok()) {
+                    Some(part_number) => part_number,
+                    None => return error(400, "InvalidArgument", "invalid partNumber"),
+                };
+                let upload = match self.uploads.get_mut(upload_id) {
+                    Some(upload) => upload,
+                    None => return error(404, "NoSuchUpload", "no such upload"),
+                };
+                upload.parts.insert(part_number, req.body().clone());
+                let etag = format!("\"{}\"", hex(&Md5::digest(req.body())));
+                self.operations.push(Operation::UploadPart {
+                    bucket,
+                    key,
+                    part_number,
+                });
+                response(200)
+                    .header(http::header::ETAG, etag)
+                    .body(Bytes::new())
+                    .unwrap()
+            }
+            (http::Method::PUT, None) => {
+                let content_type = req.headers().get(http::header::CONTENT_TYPE).cloned();
+                self.put(&bucket, &key, req.body().clone(), content_type);
+                let etag = self.buckets[&bucket][&key].etag.clone();
+                self.operations.push(Operation::PutObject { bucket, key });
+                response(200)
+                    .header(http::header::ETAG, etag)
+                    .body(Bytes::new())
+                    .unwrap()
+            }
+            (http::Method::GET, _) | (http::Method::HEAD, _) => {
+                let object = self
+                    .buckets
+                    .get(&bucket)
+                    .and_then(|objects| objects.get(&key))
+                    .cloned();
+                self.operations.push(if method == http::Method::GET {
+                    Operation::GetObject { bucket, key }
+                } else {
+                    Operation::HeadObject { bucket, key }
+                });
+                let object = match object {
+                    Some(object) => object,
+                    None => return error(404, "NoSuchKey", "the specified key does not exist"),
+                };
+                let mut resp = response(200)
+                    .header(http::header::ETAG, object.etag)
+                    .header(http::header::CONTENT_LENGTH, object.body.len());
+                if let Some(content_type) = object.content_type {
+                    resp = resp.header(http::header::CONTENT_TYPE, content_type);
+                }
+                resp.body(object.body).unwrap()
+            }
+            (http::Method::DELETE, Some(upload_id)) => {
+                self.uploads.remove(upload_id);
+                self.operations
+                    .push(Operation::AbortMultipartUpload { bucket, key });
+                response(204).body(Bytes::new()).unwrap()
+            }
+            (http::Method::DELETE, None) => {
+                if let Some(objects) = self.buckets.get_mut(&bucket) {
+                    objects.remove(&key);
+                }
+                self.operations
+                    .push(Operation::DeleteObject { bucket, key });
+                response(204).body(Bytes::new()).unwrap()
+            }
+            (http::Method::POST, None) if query.contains_key("uploads") => {
+                let upload_id = format!("httptest-upload-{}", self.next_upload_id);
+                self.next_upload_id += 1;
+                self.uploads.insert(
+                    upload_id.clone(),
+                    Upload {
+                        bucket: bucket.clone(),
+                        key: key.clone(),
+                        parts: BTreeMap::new(),
+                    },
+                );
+                let body = format!(
+                    "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    escape(&bucket),
+                    escape(&key),
+                    upload_id
+                );
+                self.operations
+                    .push(Operation::CreateMultipartUpload { bucket, key });
+                xml(body)
+            }
+            (http::Method::POST, Some(upload_id)) => {
+                let upload = match self.uploads.remove(upload_id) {
+                    Some(upload) => upload,
+                    None => return error(404, "NoSuchUpload", "no such upload"),
+                };
+                let mut body = bytes::BytesMut::new();
+                for part in upload.parts.values() {
+                    body.extend_from_slice(part);
+                }
+                self.put(&upload.bucket, &upload.key, body.freeze(), None);
+                let etag = self.buckets[&upload.bucket][&upload.key].etag.clone();
+                let body = format!(
+                    "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                    escape(&bucket),
+                    escape(&key),
+                    escape(&etag)
+                );
+                self.operations
+                    .push(Operation::CompleteMultipartUpload { bucket, key });
+                xml(body)
+            }
+            _ => error(501, "NotImplemented", "unsupported object operation"),
+        }
+    }
+
+    fn list(&self, bucket: &str, prefix: &str) -> String {
+        let mut contents = String::new();
+        let mut count = 0;
+        if let Some(objects) = self.buckets.get(bucket) {
+            for (key, object) in objects.range(prefix.to_owned()..) {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                count += 1;
+                contents.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>1970-01-01T00:00:00.000Z</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    escape(key),
+                    escape(&object.etag),
+                    object.body.len()
+                ));
+            }
+        }
+        format!(
+            "<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+            escape(bucket),
+            escape(prefix),
+            count,
+            contents
+        )
+    }
+}
+
+impl Responder for S3Store {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<Bytes>> + Send + 'a>> {
+        let resp = self.lock().handle(req);
+        Box::pin(async move { resp })
+    }
+}
+
+fn response(status: u16) -> http::response::Builder {
+    http::Response::builder().status(status)
+}
+
+fn xml(body: String) -> http::Response<Bytes> {
+    response(200)
+        .header(http::header::CONTENT_TYPE, "application/xml")
+        .body(Bytes::from(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}",
+            body
+        )))
+        .unwrap()
+}
+
+fn error(status: u16, code: &str, message: &str) -> http::Response<Bytes> {
+    let mut resp = xml(format!(
+        "<Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, message
+    ));
+    *resp.status_mut() = http::StatusCode::from_u16(status).unwrap();
+    resp
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, uri: &str, body: &'static str) -> http::Request<Bytes> {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Bytes::from_static(body.as_bytes()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_multipart_upload() {
+        let mut state = State::default();
+        let resp = state.handle(&request("POST", "/bucket/big%20file?uploads", ""));
+        assert_eq!(200, resp.status());
+        let body = String::from_utf8(resp.body().to_vec()).unwrap();
+        assert!(body.contains("<UploadId>httptest-upload-0</UploadId>"));
+
+        let resp = state.handle(&request(
+            "PUT",
+            "/bucket/big%20file?partNumber=2&uploadId=httptest-upload-0",
+            "world",
+        ));
+        assert_eq!(200, resp.status());
+        state.handle(&request(
+            "PUT",
+            "/bucket/big%20file?partNumber=1&uploadId=httptest-upload-0",
+            "hello ",
+        ));
+        let resp = state.handle(&request(
+            "POST",
+            "/bucket/big%20file?uploadId=httptest-upload-0",
+            "<CompleteMultipartUpload/>",
+        ));
+        assert_eq!(200, resp.status());
+        assert_eq!(
+            Bytes::from_static(b"hello world"),
+            state.buckets["bucket"]["big file"].body
+        );
+    }
+
+    #[test]
+    fn test_list_objects() {
+        let mut state = State::default();
+        state.put("bucket", "a/1", Bytes::new(), None);
+        state.put("bucket", "a/2", Bytes::new(), None);
+        state.put("bucket", "b/1", Bytes::new(), None);
+        let resp = state.handle(&request("GET", "/bucket?list-type=2&prefix=a%2F", ""));
+        let body = String::from_utf8(resp.body().to_vec()).unwrap();
+        assert!(body.contains("<KeyCount>2</KeyCount>"));
+        assert!(body.contains("<Key>a/1</Key>"));
+        assert!(!body.contains("<Key>b/1</Key>"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Record requests forwarded to a real upstream and replay them later.
+//!
+//! A server started with
+//! [ServerBuilder::forward_unmatched_to](../struct.ServerBuilder.html#method.forward_unmatched_to)
+//! forwards every request that doesn't match an expectation to the upstream
+//! and records the request and the upstream's response. The recordings can be
+//! saved to a file and turned back into expectations for offline runs.
+//!
+//! ```no_run
+//! use httptest::{record, Server, ServerBuilder};
+//! # fn run_the_code_under_test(_: &Server) {}
+//!
+//! // record once against the real service.
+//! let server = ServerBuilder::new()
+//!     .forward_unmatched_to("http://api.example.com".parse().unwrap())
+//!     .run()
+//!     .unwrap();
+//! run_the_code_under_test(&server);
+//! record::save("tests/recordings/api.json", &server.recordings()).unwrap();
+//!
+//! // replay forever.
+//! let server = Server::run();
+//! for recording in record::load("tests/recordings/api.json").unwrap() {
+//!     server.expect(recording.to_expectation());
+//! }
+//! run_the_code_under_test(&server);
+//! ```
+
+use crate::matchers::{all_of, eq, request, Matcher};
+use crate::server::{copy_request, headers_json};
+use crate::Expectation;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A request forwarded to the upstream and the response it returned.
+#[derive(Debug)]
+pub struct Recording {
+    request: http::Request<Bytes>,
+    response: http::Response<Bytes>,
+}
+
+impl Recording {
+    /// The request that was forwarded.
+    pub fn request(&self) -> &http::Request<Bytes> {
+        &self.request
+    }
+
+    /// The response returned by the upstream.
+    pub fn response(&self) -> &http::Response<Bytes> {
+        &self.response
+    }
+
+    /// An expectation that matches requests with the recorded method, path,
+    /// query and body, and responds with the recorded response. It matches
+    /// any number of requests so replaying a request more than once is
+    /// allowed.
+    pub fn to_expectation(&self) -> Expectation {
+        let uri = self.request.uri();
+        let matchers: Vec<Box<dyn Matcher<http::Request<Bytes>>>> = vec![
+            Box::new(request::method_path(
+                eq(self.request.method().to_string()),
+                eq(uri.path().to_string()),
+            )),
+            Box::new(request::query(eq(uri.query().unwrap_or("").to_string()))),
+            Box::new(request::body(eq(bstr::BString::from(
+                self.request.body().to_vec(),
+            )))),
+        ];
+        Expectation::matching(all_of(matchers))
+            .times(..)
+            .respond_with(copy_response(&self.response))
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "request": {
+                "method": self.request.method().as_str(),
+                "uri": self.request.uri().to_string(),
+                "headers": headers_json(self.request.headers()),
+                "body": body_to_json(self.request.body()),
+            },
+            "response": {
+                "status": self.response.status().as_u16(),
+                "headers": headers_json(self.response.headers()),
+                "body": body_to_json(self.response.body()),
+            },
+        })
+    }
+
+    fn from_json(value: &serde_json::Value) -> Option<Recording> {
+        let req = &value["request"];
+        let mut request = http::Request::builder()
+            .method(req["method"].as_str()?)
+            .uri(req["uri"].as_str()?);
+        *request.headers_mut()? = headers_from_json(&req["headers"])?;
+        let request = request.body(body_from_json(&req["body"])?).ok()?;
+
+        let resp = &value["response"];
+        let mut response = http::Response::builder().status(resp["status"].as_u64()? as u16);
+        *response.headers_mut()? = headers_from_json(&resp["headers"])?;
+        let response = response.body(body_from_json(&resp["body"])?).ok()?;
+        Some(Recording { request, response })
+    }
+}
+
+/// Save recordings to `path` as json.
+pub fn save(path: impl AsRef<Path>, recordings: &[Recording]) -> std::io::Result<()> {
+    let recordings: Vec<_> = recordings.iter().map(Recording::to_json).collect();
+    let json = serde_json::to_string_pretty(&recordings)?;
+    std::fs::write(path, json)
+}
+
+/// Load recordings previously saved with [save](fn.save.html).
+pub fn load(path: impl AsRef<Path>) -> std::io::Result<Vec<Recording>> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid recording");
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    json.as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|recording| Recording::from_json(recording).ok_or_else(invalid))
+        .collect()
+}
+
+// Forwards requests to the upstream and records the exchanges. Recordings
+// survive verify_and_clear.
+#[derive(Debug, Clone)]
+pub(crate) struct Recorder {
+    upstream: http::Uri,
+    client: Client<HttpConnector, Full<Bytes>>,
+    recordings: Arc<Mutex<Vec<Recording>>>,
+}
+
+impl Recorder {
+    pub(crate) fn new(upstream: http::Uri) -> std::io::Result<Self> {
+        // the client has no tls connector, so https upstreams can't be
+        // reached.
+        if upstream.scheme() != Some(&http::uri::Scheme::HTTP) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unsupported upstream {}, only http is supported", upstream),
+            ));
+        }
+        Ok(Recorder {
+            upstream,
+            client: Client::builder(hyper_util::rt::TokioExecutor::new()).build_http(),
+            recordings: Arc::default(),
+        })
+    }
+
+    pub(crate) fn recordings(&self) -> Vec<Recording> {
+        self.recordings
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .map(|recording| Recording {
+                request: copy_request(&recording.request),
+                response: copy_response(&recording.response),
+            })
+            .collect()
+    }
+
+    // Forward the request to the upstream. Failures to reach the upstream
+    // result in a 502 and aren't recorded.
+    pub(crate) async fn forward(&self, req: http::Request<Bytes>) -> http::Response<Bytes> {
+        match self.try_forward(&req).await {
+            Ok(response) => {
+                let recorded = Recording {
+                    request: req,
+                    response: copy_response(&response),
+                };
+                self.recordings
+                    .lock()
+                    .expect("mutex poisoned")
+                    .push(recorded);
+                response
+            }
+            Err(err) => {
+                log::warn!("failed to forward request to {}: {}", self.upstream, err);
+                http::Response::builder()
+                    .status(http::StatusCode::BAD_GATEWAY)
+                    .body(Bytes::from(format!("failed to forward request: {}", err)))
+                    .unwrap()
+            }
+        }
+    }
+
+    async fn try_forward(
+        &self,
+        req: &http::Request<Bytes>,
+    ) -> Result<http::Response<Bytes>, Box<dyn std::error::Error + Send + Sync>> {
+        let base = self.upstream.path().trim_end_matches('/');
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        let mut parts = self.upstream.clone().into_parts();
+        parts.path_and_query = Some(format!("{}{}", base, path_and_query).parse()?);
+        let mut forwarded = http::Request::builder()
+            .method(req.method().clone())
+            .uri(http::Uri::from_parts(parts)?);
+        let headers = forwarded.headers_mut().unwrap();
+        *headers = req.headers().clone();
+        remove_framing(headers);
+        let forwarded = forwarded.body(Full::new(req.body().clone()))?;
+
+        log::debug!("forwarding request to upstream: {:?}", forwarded);
+        let (mut head, body) = self.client.request(forwarded).await?.into_parts();
+        let body = body.collect().await?.to_bytes();
+        remove_framing(&mut head.headers);
+        Ok(http::Response::from_parts(head, body))
+    }
+}
+
+// The forwarded messages have their own host and framing.
+fn remove_framing(headers: &mut http::HeaderMap) {
+    headers.remove(http::header::HOST);
+    headers.remove(http::header::TRANSFER_ENCODING);
+    headers.remove(http::header::CONNECTION);
+}
+
+fn copy_response(resp: &http::Response<Bytes>) -> http::Response<Bytes> {
+    let mut builder = http::Response::builder().status(resp.status());
+    *builder.headers_mut().unwrap() = resp.headers().clone();
+    builder.body(resp.body().clone()).unwrap()
+}
+
+fn headers_from_json(value: &serde_json::Value) -> Option<http::HeaderMap> {
+    let mut headers = http::HeaderMap::new();
+    for header in value.as_array()? {
+        let name = http::HeaderName::from_bytes(header[0].as_str()?.as_bytes()).ok()?;
+        let value = http::HeaderValue::from_str(header[1].as_str()?).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+// Bodies are stored as a string when they're valid utf8 and as an array of
+// bytes otherwise.
+fn body_to_json(body: &Bytes) -> serde_json::Value {
+    match std::str::from_utf8(body) {
+        Ok(body) => body.into(),
+        Err(_) => body.to_vec().into(),
+    }
+}
+
+fn body_from_json(value: &serde_json::Value) -> Option<Bytes> {
+    match value {
+        serde_json::Value::String(body) => Some(Bytes::from(body.clone())),
+        value => serde_json::from_value::<Vec<u8>>(value.clone())
+            .ok()
+            .map(Bytes::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let recording = Recording {
+            request: http::Request::post("/foo?page=1")
+                .header("x-foo", "bar")
+                .body(Bytes::from_static(&[0xff, 0x00]))
+                .unwrap(),
+            response: http::Response::builder()
+                .status(201)
+                .header("content-type", "text/plain")
+                .body(Bytes::from("created"))
+                .unwrap(),
+        };
+        let loaded = Recording::from_json(&recording.to_json()).unwrap();
+        assert_eq!(recording.to_json(), loaded.to_json());
+        assert_eq!(&[0xff, 0x00][..], &loaded.request().body()[..]);
+    }
+
+    #[test]
+    fn test_https_upstream_rejected() {
+        let err = Recorder::new("https://api.example.com".parse().unwrap()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert!(Recorder::new("http://api.example.com".parse().unwrap()).is_ok());
+    }
+}
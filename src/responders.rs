@@ -29,6 +29,12 @@ pub trait Responder: Send {
         &mut self,
         req: &'a http::Request<bytes::Bytes>,
     ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>>;
+
+    /// For responders that delegate to a list of inner responders, the number
+    /// of times each inner responder was used. Returns None by default.
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        None
+    }
 }
 
 /// Convenient ResponseBuilder that implements Responder.
@@ -70,12 +76,176 @@ impl<B> ResponseBuilder<B> {
         self
     }
 
+    /// Add a header line that's sent exactly as given, in the order it was
+    /// added, after the headers set by the other methods. The case of `name`
+    /// is preserved and repeated names are sent as separate lines, even when
+    /// interleaved with other headers. This is useful for testing how a
+    /// client handles duplicate `Set-Cookie` or `Vary` headers.
+    ///
+    /// Over HTTP/1 the response head is written by httptest rather than
+    /// hyper, so hyper's `date` header isn't added. Over HTTP/2 and HTTP/3
+    /// the headers are sent lowercased, grouped by name.
+    ///
+    /// This function will panic if `name` or `value` is not a valid header
+    /// name or value.
+    pub fn verbatim_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let (name, value) = (name.into(), value.into());
+        http::header::HeaderName::from_bytes(name.as_bytes()).expect("invalid header name");
+        http::header::HeaderValue::from_str(&value).expect("invalid header value");
+        let extensions = self.0.extensions_mut();
+        match extensions.get_mut::<VerbatimHeaders>() {
+            Some(verbatim) => verbatim.0.push((name, value)),
+            None => drop(extensions.insert(VerbatimHeaders(vec![(name, value)]))),
+        }
+        self
+    }
+
+    /// Send `reason` in place of the canonical reason phrase for the status
+    /// code, e.g. `HTTP/1.1 299 Custom Warning`. Reason phrases are only sent
+    /// over HTTP/1, HTTP/2 and HTTP/3 don't have them.
+    ///
+    /// This function will panic if `reason` contains characters that aren't
+    /// allowed in a reason phrase, such as newlines.
+    pub fn reason_phrase(mut self, reason: impl Into<String>) -> Self {
+        let reason: hyper::ext::ReasonPhrase =
+            reason.into().try_into().expect("invalid reason phrase");
+        self.0.extensions_mut().insert(reason);
+        self
+    }
+
+    /// Set the `Cache-Control` header to the given directives, replacing any
+    /// already set.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use httptest::responders::*;
+    /// use std::time::Duration;
+    ///
+    /// // Cache-Control: max-age=60, stale-while-revalidate=30
+    /// status_code(200).cache_control(
+    ///     CacheControl::new()
+    ///         .max_age(Duration::from_secs(60))
+    ///         .stale_while_revalidate(Duration::from_secs(30)),
+    /// );
+    /// ```
+    pub fn cache_control(self, cache_control: CacheControl) -> Self {
+        self.insert_header(http::header::CACHE_CONTROL, cache_control.to_string())
+    }
+
+    /// Send an `Expires` header for `duration` after the response is sent.
+    pub fn expires_in(mut self, duration: Duration) -> Self {
+        self.0.extensions_mut().insert(ExpiresIn(duration));
+        self
+    }
+
     /// Set the body of the header.
     pub fn body<B2>(self, body: B2) -> ResponseBuilder<B2> {
         ResponseBuilder(self.0.map(|_| body))
     }
 }
 
+// Inserted into the extensions of a response to tell the server to send the
+// header lines exactly as given.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct VerbatimHeaders(pub(crate) Vec<(String, String)>);
+
+// Inserted into the extensions of a response to have it sent with an
+// Expires header relative to when it's sent.
+#[derive(Debug, Clone, Copy)]
+struct ExpiresIn(Duration);
+
+/// The directives of a `Cache-Control` header. See
+/// [ResponseBuilder::cache_control](struct.ResponseBuilder.html#method.cache_control).
+#[derive(Debug, Clone, Default)]
+pub struct CacheControl(Vec<String>);
+
+impl CacheControl {
+    /// No directives.
+    pub fn new() -> Self {
+        CacheControl::default()
+    }
+
+    /// `max-age`, the response is fresh for `age`.
+    pub fn max_age(self, age: Duration) -> Self {
+        self.seconds("max-age", age)
+    }
+
+    /// `s-maxage`, the response is fresh in shared caches for `age`.
+    pub fn s_maxage(self, age: Duration) -> Self {
+        self.seconds("s-maxage", age)
+    }
+
+    /// `no-cache`, the response must be revalidated before it's reused.
+    pub fn no_cache(self) -> Self {
+        self.directive("no-cache")
+    }
+
+    /// `no-store`, the response must not be stored.
+    pub fn no_store(self) -> Self {
+        self.directive("no-store")
+    }
+
+    /// `must-revalidate`, the response must not be reused once stale
+    /// without revalidating it.
+    pub fn must_revalidate(self) -> Self {
+        self.directive("must-revalidate")
+    }
+
+    /// `proxy-revalidate`, like `must-revalidate` for shared caches.
+    pub fn proxy_revalidate(self) -> Self {
+        self.directive("proxy-revalidate")
+    }
+
+    /// `public`, the response may be stored by shared caches.
+    pub fn public(self) -> Self {
+        self.directive("public")
+    }
+
+    /// `private`, the response must not be stored by shared caches.
+    pub fn private(self) -> Self {
+        self.directive("private")
+    }
+
+    /// `no-transform`, intermediaries must not transform the response.
+    pub fn no_transform(self) -> Self {
+        self.directive("no-transform")
+    }
+
+    /// `immutable`, the response won't change while it's fresh.
+    pub fn immutable(self) -> Self {
+        self.directive("immutable")
+    }
+
+    /// `stale-while-revalidate`, a stale response may be reused for
+    /// `duration` while it's revalidated in the background.
+    pub fn stale_while_revalidate(self, duration: Duration) -> Self {
+        self.seconds("stale-while-revalidate", duration)
+    }
+
+    /// `stale-if-error`, a stale response may be reused for `duration` when
+    /// revalidating it fails.
+    pub fn stale_if_error(self, duration: Duration) -> Self {
+        self.seconds("stale-if-error", duration)
+    }
+
+    /// Add any other directive, such as `max-stale=10`, as given.
+    pub fn directive(mut self, directive: impl Into<String>) -> Self {
+        self.0.push(directive.into());
+        self
+    }
+
+    fn seconds(self, name: &str, duration: Duration) -> Self {
+        self.directive(format!("{}={}", name, duration.as_secs()))
+    }
+}
+
+impl fmt::Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
+
 /// respond with the provided status code and an empty body.
 pub fn status_code(code: u16) -> ResponseBuilder<&'static str> {
     ResponseBuilder(
@@ -112,6 +282,189 @@ where
         .body(serde_urlencoded::to_string(&data).expect("failed to serialize body"))
 }
 
+/// respond with a body that is the yaml encoding of data.
+///
+/// The status code will be `200` and the content-type will be
+/// `application/yaml`.
+#[cfg(feature = "yaml")]
+pub fn yaml_encoded<T>(data: T) -> ResponseBuilder<String>
+where
+    T: serde::Serialize,
+{
+    status_code(200)
+        .append_header("Content-Type", "application/yaml")
+        .body(serde_yaml::to_string(&data).expect("failed to serialize body"))
+}
+
+/// respond with a body that is the MessagePack encoding of data.
+///
+/// Structs are encoded as maps keyed by field name. The status code will be
+/// `200` and the content-type will be `application/msgpack`.
+#[cfg(feature = "msgpack")]
+pub fn msgpack_encoded<T>(data: T) -> ResponseBuilder<Vec<u8>>
+where
+    T: serde::Serialize,
+{
+    status_code(200)
+        .append_header("Content-Type", "application/msgpack")
+        .body(rmp_serde::to_vec_named(&data).expect("failed to serialize body"))
+}
+
+/// respond with a body that is the CBOR encoding of data.
+///
+/// The status code will be `200` and the content-type will be
+/// `application/cbor`.
+#[cfg(feature = "cbor")]
+pub fn cbor_encoded<T>(data: T) -> ResponseBuilder<Vec<u8>>
+where
+    T: serde::Serialize,
+{
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(&data, &mut body).expect("failed to serialize body");
+    status_code(200)
+        .append_header("Content-Type", "application/cbor")
+        .body(body)
+}
+
+/// Responder that issues signed JSON Web Tokens.
+#[cfg(feature = "jwt")]
+#[derive(Debug, Clone)]
+pub struct JwtToken {
+    claims: serde_json::Map<String, serde_json::Value>,
+    lifetime: Duration,
+    hs256_secret: Option<Vec<u8>>,
+    rs256_key: crate::jwt::SigningKey,
+}
+
+/// respond like an OAuth2 token endpoint, with a freshly signed JWT as the
+/// `access_token`.
+///
+/// Tokens are signed with RS256, which consumers can verify using the key set
+/// served by [jwks()](fn.jwks.html). Unless another key is given with
+/// [rs256_key()](struct.JwtToken.html#method.rs256_key) the signing key is
+/// one published in httptest's source, so anyone can sign tokens with it.
+/// Never trust it outside of tests. Use
+/// [hs256()](struct.JwtToken.html#method.hs256) to sign with a shared secret
+/// instead. Each token has `iat` and `exp`
+/// claims, expiring after an hour unless set with
+/// [expires_in()](struct.JwtToken.html#method.expires_in).
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+/// use std::time::Duration;
+///
+/// // respond with a token for alice that expires after 5 minutes.
+/// jwt_token()
+///     .claim("sub", "alice")
+///     .claim("scope", "read write")
+///     .expires_in(Duration::from_secs(300));
+/// ```
+#[cfg(feature = "jwt")]
+pub fn jwt_token() -> JwtToken {
+    JwtToken {
+        claims: serde_json::Map::new(),
+        lifetime: Duration::from_secs(3600),
+        hs256_secret: None,
+        rs256_key: crate::jwt::SigningKey::insecure_test_key(),
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl JwtToken {
+    /// Add a claim to issued tokens, replacing any claim with the same name.
+    pub fn claim(mut self, name: impl Into<String>, value: impl serde::Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("failed to serialize claim");
+        self.claims.insert(name.into(), value);
+        self
+    }
+
+    /// How long issued tokens are valid for.
+    pub fn expires_in(self, lifetime: Duration) -> Self {
+        JwtToken { lifetime, ..self }
+    }
+
+    /// Sign tokens with RS256 using the RSA private key in `pem`, a PEM
+    /// encoded PKCS#8 key (`BEGIN PRIVATE KEY`), instead of the insecure
+    /// built-in one. Serve its key set with [jwks_for()](fn.jwks_for.html).
+    ///
+    /// This function will panic if `pem` doesn't contain a valid key.
+    pub fn rs256_key(self, pem: &str) -> Self {
+        JwtToken {
+            rs256_key: crate::jwt::SigningKey::from_pem(pem).expect("invalid signing key"),
+            ..self
+        }
+    }
+
+    /// Sign tokens with HS256 using `secret` instead of with RS256.
+    pub fn hs256(self, secret: impl Into<Vec<u8>>) -> Self {
+        JwtToken {
+            hs256_secret: Some(secret.into()),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl Responder for JwtToken {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut claims = serde_json::Map::new();
+        claims.insert("iat".to_owned(), now.into());
+        claims.insert("exp".to_owned(), (now + self.lifetime.as_secs()).into());
+        claims.extend(self.claims.clone());
+        let claims = serde_json::Value::Object(claims);
+        let token = match &self.hs256_secret {
+            Some(secret) => crate::jwt::sign_hs256(secret, &claims),
+            None => self.rs256_key.sign_rs256(&claims),
+        };
+        json_encoded(serde_json::json!({
+            "access_token": token,
+            "token_type": "Bearer",
+            "expires_in": self.lifetime.as_secs(),
+        }))
+        .respond(req)
+    }
+}
+
+/// respond with the JSON Web Key Set that verifies the RS256 tokens issued by
+/// [jwt_token()](fn.jwt_token.html) with the insecure built-in key.
+///
+/// # Example
+///
+/// ```
+/// use httptest::{matchers::*, responders::*, Expectation, Server};
+///
+/// let server = Server::run();
+/// server.expect(
+///     Expectation::matching(request::method_path("GET", "/.well-known/jwks.json"))
+///         .times(..)
+///         .respond_with(jwks()),
+/// );
+/// ```
+#[cfg(feature = "jwt")]
+pub fn jwks() -> ResponseBuilder<String> {
+    json_encoded(crate::jwt::SigningKey::insecure_test_key().jwks())
+}
+
+/// respond with the JSON Web Key Set that verifies the RS256 tokens signed
+/// with the private key in `pem` by
+/// [rs256_key()](struct.JwtToken.html#method.rs256_key).
+///
+/// This function will panic if `pem` doesn't contain a valid key.
+#[cfg(feature = "jwt")]
+pub fn jwks_for(pem: &str) -> ResponseBuilder<String> {
+    let key = crate::jwt::SigningKey::from_pem(pem).expect("invalid signing key");
+    json_encoded(key.jwks())
+}
+
 impl<B> Responder for ResponseBuilder<B>
 where
     B: Clone + Into<hyper::body::Bytes> + Send + fmt::Debug,
@@ -143,13 +496,776 @@ impl<R: Responder> Responder for Delay<R> {
         req: &'a http::Request<bytes::Bytes>,
     ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
         let resp = self.and_then.respond(req);
-        let delay = self.delay;
+        let sleep = crate::clock::sleep(req, self.delay);
 
         Box::pin(async move {
-            tokio::time::sleep(delay).await;
+            sleep.await;
             resp.await
         })
     }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+/// Responder that calls back the client after responding.
+#[cfg(feature = "webhook")]
+pub struct Webhook<F, R: Responder> {
+    callback_url: F,
+    method: http::Method,
+    headers: http::HeaderMap,
+    payload: hyper::body::Bytes,
+    delay: Duration,
+    and_then: R,
+}
+
+/// respond with the given responder, then send a request to the url
+/// `callback_url` extracts from the matched request. If it returns None no
+/// request is sent.
+///
+/// The callback is a `POST` with an empty body by default, sent in the
+/// background once the response has been produced. Its result is ignored.
+/// Callbacks are sent over plain `http` on the server's
+/// [runtime](../runtime/index.html).
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+/// use std::time::Duration;
+///
+/// // accept the job, then report its completion to the url in the
+/// // request's `callback_url` field a second later.
+/// webhook(callback_url_from_json("/callback_url"), status_code(202))
+///     .json_payload(serde_json::json!({"status": "done"}))
+///     .delay(Duration::from_secs(1));
+/// ```
+#[cfg(feature = "webhook")]
+pub fn webhook<F, R>(callback_url: F, and_then: R) -> Webhook<F, R>
+where
+    F: FnMut(&http::Request<bytes::Bytes>) -> Option<String> + Send,
+    R: Responder,
+{
+    Webhook {
+        callback_url,
+        method: http::Method::POST,
+        headers: http::HeaderMap::new(),
+        payload: hyper::body::Bytes::new(),
+        delay: Duration::from_secs(0),
+        and_then,
+    }
+}
+
+/// Extract a callback url from the string at the given [JSON
+/// pointer](https://tools.ietf.org/html/rfc6901) in a json request body. For
+/// use with [webhook()](fn.webhook.html).
+#[cfg(feature = "webhook")]
+pub fn callback_url_from_json(
+    pointer: &str,
+) -> impl FnMut(&http::Request<bytes::Bytes>) -> Option<String> + Send {
+    let pointer = pointer.to_owned();
+    move |req| {
+        let body: serde_json::Value = serde_json::from_slice(req.body()).ok()?;
+        Some(body.pointer(&pointer)?.as_str()?.to_owned())
+    }
+}
+
+#[cfg(feature = "webhook")]
+impl<F, R: Responder> Webhook<F, R> {
+    /// Send the callback with the given method.
+    pub fn method(self, method: http::Method) -> Self {
+        Webhook { method, ..self }
+    }
+
+    /// Add a header to the callback.
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: TryInto<http::HeaderName>,
+        K::Error: fmt::Debug,
+        V: TryInto<http::HeaderValue>,
+        V::Error: fmt::Debug,
+    {
+        self.headers.append(
+            name.try_into().expect("invalid header name"),
+            value.try_into().expect("invalid header value"),
+        );
+        self
+    }
+
+    /// Send the given body with the callback.
+    pub fn payload(self, payload: impl Into<hyper::body::Bytes>) -> Self {
+        Webhook {
+            payload: payload.into(),
+            ..self
+        }
+    }
+
+    /// Send the json encoding of data with the callback, with a content-type
+    /// of `application/json`.
+    pub fn json_payload<T: serde::Serialize>(self, data: T) -> Self {
+        let payload = serde_json::to_string(&data).expect("failed to serialize payload");
+        self.header("Content-Type", "application/json")
+            .payload(payload)
+    }
+
+    /// Wait for the given duration after responding before sending the
+    /// callback.
+    pub fn delay(self, delay: Duration) -> Self {
+        Webhook { delay, ..self }
+    }
+}
+
+#[cfg(feature = "webhook")]
+impl<F, R> Responder for Webhook<F, R>
+where
+    F: FnMut(&http::Request<bytes::Bytes>) -> Option<String> + Send,
+    R: Responder,
+{
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let callback = (self.callback_url)(req).and_then(|url| {
+            let mut builder = http::Request::builder()
+                .method(self.method.clone())
+                .uri(url);
+            *builder.headers_mut()? = self.headers.clone();
+            builder
+                .body(http_body_util::Full::new(self.payload.clone()))
+                .map_err(|err| log::debug!("invalid webhook callback: {}", err))
+                .ok()
+        });
+        let resp = self.and_then.respond(req);
+        let delay = self.delay;
+
+        Box::pin(async move {
+            let resp = resp.await;
+            if let Some(callback) = callback {
+                let sleep = crate::clock::sleep(req, delay);
+                // spawn on the server's runtime, which may not be tokio.
+                let runtime = crate::runtime::of(req);
+                runtime.0.clone().spawn(Box::pin(async move {
+                    sleep.await;
+                    log::debug!("sending webhook callback: {:?}", callback);
+                    if let Err(err) = send_callback(&runtime, callback).await {
+                        log::debug!("webhook callback failed: {}", err);
+                    }
+                }));
+            }
+            resp
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+// Send a webhook callback over a connection made by `runtime`.
+#[cfg(feature = "webhook")]
+async fn send_callback(
+    runtime: &crate::runtime::ServerRuntime,
+    mut callback: http::Request<http_body_util::Full<hyper::body::Bytes>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use std::net::ToSocketAddrs;
+
+    let uri = callback.uri().clone();
+    if uri.scheme() != Some(&http::uri::Scheme::HTTP) {
+        return Err(format!("unsupported callback url: {}", uri).into());
+    }
+    let authority = uri.authority().ok_or("callback url has no host")?;
+    // ipv6 hosts are bracketed in urls.
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let addr = (host, authority.port_u16().unwrap_or(80))
+        .to_socket_addrs()?
+        .next()
+        .ok_or("callback host has no addresses")?;
+    let io = runtime.0.connect(addr).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    runtime.0.spawn(Box::pin(async move {
+        if let Err(err) = conn.await {
+            log::debug!("webhook callback connection failed: {}", err);
+        }
+    }));
+    // send the origin-form of the url, with the authority as the host.
+    *callback.uri_mut() = uri.path_and_query().map_or("/", |p| p.as_str()).parse()?;
+    callback
+        .headers_mut()
+        .entry(http::header::HOST)
+        .or_insert(authority.as_str().parse()?);
+    sender.send_request(callback).await?;
+    Ok(())
+}
+
+/// Responder that cuts off the body of the embedded response.
+pub struct TruncateBody<R: Responder> {
+    at: usize,
+    and_then: R,
+}
+
+/// respond with the given responder, but close the connection after writing
+/// `n` bytes of the response body.
+///
+/// The response headers, including `Content-Length`, describe the complete
+/// body, so the client sees the response end unexpectedly. This is useful
+/// for testing how a client detects a partial download or resumes an
+/// interrupted one. Any body can be truncated, including streamed and
+/// generated bodies. A chunked body is cut off before its final chunk.
+/// Bodies no longer than `n` bytes are sent unchanged.
+pub fn truncate_after<R: Responder>(n: usize, and_then: R) -> TruncateBody<R> {
+    TruncateBody { at: n, and_then }
+}
+
+/// Equivalent to [truncate_after](fn.truncate_after.html).
+#[deprecated(note = "use truncate_after")]
+pub fn truncate_body_at<R: Responder>(at: usize, and_then: R) -> TruncateBody<R> {
+    truncate_after(at, and_then)
+}
+
+// Inserted into the extensions of a response to tell the server to truncate
+// the body.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TruncateBodyAt(pub(crate) usize);
+
+impl<R: Responder> Responder for TruncateBody<R> {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let resp = self.and_then.respond(req);
+        let at = self.at;
+
+        Box::pin(async move {
+            let mut resp = resp.await;
+            resp.extensions_mut().insert(TruncateBodyAt(at));
+            resp
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+/// Responder that declares a Content-Length that differs from the body.
+pub struct DeclareContentLength<R: Responder> {
+    len: u64,
+    and_then: R,
+}
+
+/// respond with the given responder, but declare a `Content-Length` of `len`
+/// regardless of the length of the body.
+///
+/// When the body is shorter than `len` the connection is closed once the
+/// body has been sent, so the client receives fewer bytes than declared.
+/// When the body is longer, all of it is sent anyway and the bytes beyond
+/// `len` are left on the connection where the client would expect the next
+/// response. This is useful for testing that a client detects framing
+/// violations rather than silently truncating data.
+///
+/// Bodies longer than `len` are only sent in full over HTTP/1. Over HTTP/2
+/// and HTTP/3 the bytes beyond `len` are dropped.
+pub fn declare_content_length<R: Responder>(len: u64, and_then: R) -> DeclareContentLength<R> {
+    DeclareContentLength { len, and_then }
+}
+
+// Inserted into the extensions of a response to tell the server which
+// Content-Length to declare.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeclaredContentLength(pub(crate) u64);
+
+impl<R: Responder> Responder for DeclareContentLength<R> {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let resp = self.and_then.respond(req);
+        let len = self.len;
+
+        Box::pin(async move {
+            let mut resp = resp.await;
+            resp.extensions_mut().insert(DeclaredContentLength(len));
+            resp
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+/// How the end of a response body is indicated on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// A `Content-Length` header with the length of the body.
+    ContentLength,
+    /// `Transfer-Encoding: chunked`.
+    Chunked,
+    /// Neither, the body ends when the connection is closed.
+    CloseDelimited,
+}
+
+/// Responder that sends the body of the embedded response with the given
+/// framing.
+pub struct ForceFraming<R: Responder> {
+    framing: Framing,
+    and_then: R,
+}
+
+/// respond with the given responder, framing the body as `framing`
+/// regardless of its size or how it's produced.
+///
+/// Bodies are sent with a `Content-Length` when their size is known and
+/// chunked otherwise, so this is useful for covering each of a client's
+/// framing code paths. Bodies sent with a `Content-Length` or close-delimited
+/// are produced in full before being sent, and close-delimited responses
+/// always close the connection.
+///
+/// `Chunked` and `CloseDelimited` only apply over HTTP/1.1, HTTP/1.0 clients
+/// can't receive chunked bodies and HTTP/2 and HTTP/3 frame bodies
+/// themselves.
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+///
+/// force_framing(Framing::Chunked, status_code(200).body("hello"));
+/// ```
+pub fn force_framing<R: Responder>(framing: Framing, and_then: R) -> ForceFraming<R> {
+    ForceFraming { framing, and_then }
+}
+
+// Inserted into the extensions of a response to tell the server how to frame
+// the body.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BodyFraming(pub(crate) Framing);
+
+impl<R: Responder> Responder for ForceFraming<R> {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let resp = self.and_then.respond(req);
+        let framing = self.framing;
+
+        Box::pin(async move {
+            let mut resp = resp.await;
+            resp.extensions_mut().insert(BodyFraming(framing));
+            resp
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+/// Responder that pushes a response to the client over HTTP/2.
+#[cfg(feature = "http2-push")]
+pub struct Push<P: Responder, R: Responder> {
+    path: http::uri::PathAndQuery,
+    pushed: P,
+    and_then: R,
+}
+
+/// respond with the given responder, first promising the client a `GET` of
+/// `path` and pushing the response of `pushed` for it.
+///
+/// `pushed` is called with the matched request. Pushes are only sent by
+/// servers built with
+/// [ServerBuilder::http2_server_push](../struct.ServerBuilder.html#method.http2_server_push)
+/// to HTTP/2 clients that haven't disabled them, other clients only receive
+/// the response of `and_then`. This is useful for testing that a client
+/// either consumes or refuses pushed streams.
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+///
+/// // push the stylesheet along with the page.
+/// push(
+///     "/style.css",
+///     status_code(200).body("body {}"),
+///     status_code(200).body("<html></html>"),
+/// );
+/// ```
+#[cfg(feature = "http2-push")]
+pub fn push<P, R>(path: &str, pushed: P, and_then: R) -> Push<P, R>
+where
+    P: Responder,
+    R: Responder,
+{
+    Push {
+        path: path.parse().expect("invalid push path"),
+        pushed,
+        and_then,
+    }
+}
+
+// Inserted into the extensions of a response to tell the server which
+// requests to promise, and the responses to push for them.
+#[cfg(feature = "http2-push")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PushPromises(pub(crate) Vec<PushPromise>);
+
+// A `GET` of `uri` promised to the client, and the response pushed for it.
+#[cfg(feature = "http2-push")]
+#[derive(Debug, Clone)]
+pub(crate) struct PushPromise {
+    pub(crate) uri: http::Uri,
+    pub(crate) status: http::StatusCode,
+    pub(crate) headers: http::HeaderMap,
+    pub(crate) body: hyper::body::Bytes,
+}
+
+#[cfg(feature = "http2-push")]
+impl<P: Responder, R: Responder> Responder for Push<P, R> {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        // the promised request is for the same origin as the matched one.
+        let mut uri = http::Uri::builder();
+        if let (Some(scheme), Some(authority)) = (req.uri().scheme(), req.uri().authority()) {
+            uri = uri.scheme(scheme.clone()).authority(authority.clone());
+        }
+        let uri = uri.path_and_query(self.path.clone()).build().unwrap();
+        let pushed = self.pushed.respond(req);
+        let resp = self.and_then.respond(req);
+
+        Box::pin(async move {
+            let (parts, body) = pushed.await.into_parts();
+            let promise = PushPromise {
+                uri,
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            };
+            let mut resp = resp.await;
+            // pushes from nested responders are sent first.
+            let extensions = resp.extensions_mut();
+            match extensions.get_mut::<PushPromises>() {
+                Some(promises) => promises.0.push(promise),
+                None => drop(extensions.insert(PushPromises(vec![promise]))),
+            }
+            resp
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+/// Responder that answers conditional requests.
+#[derive(Debug)]
+pub struct Conditional<R: Responder> {
+    last_modified: Option<std::time::SystemTime>,
+    and_then: R,
+}
+
+/// respond with the given responder, or with a `304 Not Modified` when the
+/// request's validators show the client already has the response.
+///
+/// Successful responses are sent with an `ETag` computed from the body,
+/// unless they already have one. A `GET` or `HEAD` whose `If-None-Match`
+/// lists the ETag receives a `304` with an empty body, as does one whose
+/// `If-Modified-Since` isn't earlier than the `Last-Modified` of the
+/// response. `If-Modified-Since` is ignored when `If-None-Match` is present.
+/// Other methods with a matching `If-None-Match` receive a
+/// `412 Precondition Failed`. The ETag is a SHA-256 digest of the body, so
+/// it's the same across processes.
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+/// use std::time::{Duration, SystemTime};
+///
+/// conditional(status_code(200).body("hello"))
+///     .last_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+/// ```
+pub fn conditional<R: Responder>(and_then: R) -> Conditional<R> {
+    Conditional {
+        last_modified: None,
+        and_then,
+    }
+}
+
+impl<R: Responder> Conditional<R> {
+    /// Send a `Last-Modified` of `time` with responses that don't already
+    /// have one.
+    pub fn last_modified(self, time: std::time::SystemTime) -> Self {
+        Conditional {
+            last_modified: Some(time),
+            ..self
+        }
+    }
+}
+
+impl<R: Responder> Responder for Conditional<R> {
+    fn respond<'a>(
+        &mut self,
+        req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let resp = self.and_then.respond(req);
+        let last_modified = self.last_modified;
+
+        Box::pin(async move {
+            let mut resp = resp.await;
+            if !resp.status().is_success() {
+                return resp;
+            }
+            if !resp.headers().contains_key(http::header::ETAG) {
+                let etag = etag_of(resp.body());
+                resp.headers_mut().insert(http::header::ETAG, etag);
+            }
+            if let Some(time) = last_modified {
+                resp.headers_mut()
+                    .entry(http::header::LAST_MODIFIED)
+                    .or_insert_with(|| {
+                        let date = httpdate::fmt_http_date(time);
+                        date.try_into().expect("http dates are valid header values")
+                    });
+            }
+            match evaluate_preconditions(req, resp.headers()) {
+                Some(status) => not_modified(status, resp),
+                None => resp,
+            }
+        })
+    }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.and_then.branch_hit_counts()
+    }
+}
+
+fn etag_of(body: &[u8]) -> http::HeaderValue {
+    format!("\"{}\"", crate::identity::Digest::of(body))
+        .try_into()
+        .expect("etags are valid header values")
+}
+
+// The status to respond with instead of the response, if the request's
+// preconditions aren't met. See RFC 9110 section 13.2.2.
+fn evaluate_preconditions(
+    req: &http::Request<bytes::Bytes>,
+    resp_headers: &http::HeaderMap,
+) -> Option<http::StatusCode> {
+    let safe = req.method() == http::Method::GET || req.method() == http::Method::HEAD;
+    let if_none_match = req.headers().get_all(http::header::IF_NONE_MATCH);
+    if if_none_match.iter().next().is_some() {
+        let etag = resp_headers
+            .get(http::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .unwrap_or_default();
+        let matched = if_none_match
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == "*" || weak_eq(tag.trim(), etag));
+        return match (matched, safe) {
+            (false, _) => None,
+            (true, true) => Some(http::StatusCode::NOT_MODIFIED),
+            (true, false) => Some(http::StatusCode::PRECONDITION_FAILED),
+        };
+    }
+    if !safe {
+        return None;
+    }
+    let date = |headers: &http::HeaderMap, name| {
+        let value = headers.get(name)?.to_str().ok()?;
+        httpdate::parse_http_date(value).ok()
+    };
+    let if_modified_since = date(req.headers(), http::header::IF_MODIFIED_SINCE)?;
+    let last_modified = date(resp_headers, http::header::LAST_MODIFIED)?;
+    if last_modified <= if_modified_since {
+        Some(http::StatusCode::NOT_MODIFIED)
+    } else {
+        None
+    }
+}
+
+// Weak comparison ignores whether either etag is weak.
+fn weak_eq(a: &str, b: &str) -> bool {
+    !b.is_empty() && a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// Replace the response with an empty one with `status`, keeping only the
+// headers a 304 is sent with.
+fn not_modified(
+    status: http::StatusCode,
+    resp: http::Response<hyper::body::Bytes>,
+) -> http::Response<hyper::body::Bytes> {
+    let mut not_modified = http::Response::new(hyper::body::Bytes::new());
+    *not_modified.status_mut() = status;
+    if status == http::StatusCode::NOT_MODIFIED {
+        for name in [
+            http::header::CACHE_CONTROL,
+            http::header::CONTENT_LOCATION,
+            http::header::DATE,
+            http::header::ETAG,
+            http::header::EXPIRES,
+            http::header::LAST_MODIFIED,
+            http::header::VARY,
+        ] {
+            for value in resp.headers().get_all(&name) {
+                not_modified
+                    .headers_mut()
+                    .append(name.clone(), value.clone());
+            }
+        }
+    }
+    not_modified
+}
+
+/// Responder that streams a sequence of json values as newline-delimited json.
+#[derive(Debug)]
+pub struct NdjsonStream {
+    lines: Vec<String>,
+    line_delay: Duration,
+}
+
+/// respond with a body that streams each value as a line of json.
+///
+/// The status code will be `200` and the content-type will be
+/// `application/x-ndjson`. The body is sent using chunked encoding, one line
+/// per chunk.
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+/// use std::time::Duration;
+///
+/// // respond with two lines, waiting 100ms before each one.
+/// ndjson_stream(vec![
+///     serde_json::json!({"type": "ADDED"}),
+///     serde_json::json!({"type": "DELETED"}),
+/// ])
+/// .line_delay(Duration::from_millis(100));
+/// ```
+pub fn ndjson_stream<I, T>(values: I) -> NdjsonStream
+where
+    I: IntoIterator<Item = T>,
+    T: serde::Serialize,
+{
+    let lines = values
+        .into_iter()
+        .map(|value| {
+            let mut line = serde_json::to_string(&value).expect("failed to serialize line");
+            line.push('\n');
+            line
+        })
+        .collect();
+    NdjsonStream {
+        lines,
+        line_delay: Duration::from_secs(0),
+    }
+}
+
+impl NdjsonStream {
+    /// Wait for the given duration before sending each line.
+    pub fn line_delay(self, line_delay: Duration) -> Self {
+        NdjsonStream { line_delay, ..self }
+    }
+}
+
+// Inserted into the extensions of a response to tell the server to send the
+// body in chunks. Each chunk is described by its end offset in the body and
+// how long to wait before sending it.
+#[derive(Debug, Clone)]
+pub(crate) struct StreamChunks(pub(crate) Vec<(usize, Duration)>);
+
+// Inserted into the extensions of a response to tell the server to send
+// trailers after the body.
+#[derive(Debug, Clone)]
+pub(crate) struct Trailers(pub(crate) http::HeaderMap);
+
+impl Responder for NdjsonStream {
+    fn respond<'a>(
+        &mut self,
+        _req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let mut chunks = Vec::with_capacity(self.lines.len());
+        let mut end = 0;
+        for line in &self.lines {
+            end += line.len();
+            chunks.push((end, self.line_delay));
+        }
+        let mut resp = http::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/x-ndjson")
+            .body(hyper::body::Bytes::from(self.lines.concat()))
+            .unwrap();
+        resp.extensions_mut().insert(StreamChunks(chunks));
+        Box::pin(async move { resp })
+    }
+}
+
+/// Responder that streams a body generated from a repeating pattern.
+#[derive(Debug)]
+pub struct GeneratedBody {
+    len: u64,
+    pattern: hyper::body::Bytes,
+}
+
+/// respond with a body of `len` bytes made up of `pattern` repeated, without
+/// holding the body in memory. Useful for testing how a client handles very
+/// large downloads.
+///
+/// The status code will be `200`, the content-type will be
+/// `application/octet-stream` and the content-length will be `len`.
+///
+/// This function will panic if `pattern` is empty.
+///
+/// # Example
+///
+/// ```
+/// use httptest::responders::*;
+///
+/// // respond with 4GiB of "0123456789".
+/// generated_body(4 << 30, "0123456789");
+/// ```
+pub fn generated_body(len: u64, pattern: impl Into<hyper::body::Bytes>) -> GeneratedBody {
+    let pattern = pattern.into();
+    if pattern.is_empty() {
+        panic!("empty pattern provided to generated_body");
+    }
+    GeneratedBody { len, pattern }
+}
+
+// Inserted into the extensions of a response to tell the server to send a
+// body of the given length made up of the pattern repeated.
+#[derive(Debug, Clone)]
+pub(crate) struct Generated {
+    pub(crate) len: u64,
+    pub(crate) pattern: hyper::body::Bytes,
+}
+
+impl Responder for GeneratedBody {
+    fn respond<'a>(
+        &mut self,
+        _req: &'a http::Request<bytes::Bytes>,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        let mut resp = http::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", self.len)
+            .body(hyper::body::Bytes::new())
+            .unwrap();
+        resp.extensions_mut().insert(Generated {
+            len: self.len,
+            pattern: self.pattern.clone(),
+        });
+        Box::pin(async move { resp })
+    }
 }
 
 impl<B> Responder for http::Response<B>
@@ -168,6 +1284,21 @@ where
         let mut builder = http::Response::builder();
         builder = builder.status(self.status()).version(self.version());
         *builder.headers_mut().unwrap() = self.headers().clone();
+        if let Some(reason) = self.extensions().get::<hyper::ext::ReasonPhrase>() {
+            builder = builder.extension(reason.clone());
+        }
+        if let Some(verbatim) = self.extensions().get::<VerbatimHeaders>() {
+            builder = builder.extension(verbatim.clone());
+        }
+        if let Some(ExpiresIn(duration)) = self.extensions().get::<ExpiresIn>() {
+            let expires = httpdate::fmt_http_date(std::time::SystemTime::now() + *duration);
+            builder.headers_mut().unwrap().insert(
+                http::header::EXPIRES,
+                expires
+                    .try_into()
+                    .expect("http dates are valid header values"),
+            );
+        }
         let resp = builder.body(self.body().clone().into()).unwrap();
 
         Box::pin(_respond(resp))
@@ -185,21 +1316,30 @@ where
         req: &'a http::Request<bytes::Bytes>,
     ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
         let mut f = self.clone();
-        Box::pin(async move { tokio::task::block_in_place(|| f()).respond(req).await })
+        Box::pin(async move { tokio::task::block_in_place(&mut f).respond(req).await })
     }
 }
 
 /// Cycle through the provided list of responders.
-pub fn cycle(responders: Vec<Box<dyn Responder>>) -> impl Responder {
+///
+/// The number of times each responder was used is reported by
+/// `branch_hit_counts`.
+pub fn cycle(responders: Vec<Box<dyn Responder>>) -> Cycle {
     if responders.is_empty() {
         panic!("empty vector provided to cycle");
     }
-    Cycle { idx: 0, responders }
+    let hit_counts = vec![0; responders.len()];
+    Cycle {
+        idx: 0,
+        responders,
+        hit_counts,
+    }
 }
 /// The `Cycle` responder returned by [cycle()](fn.cycle.html)
 pub struct Cycle {
     idx: usize,
     responders: Vec<Box<dyn Responder>>,
+    hit_counts: Vec<usize>,
 }
 
 impl Responder for Cycle {
@@ -209,9 +1349,39 @@ impl Responder for Cycle {
     ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
         let idx = self.idx;
         self.idx = (self.idx + 1) % self.responders.len();
+        self.hit_counts[idx] += 1;
         self.responders[idx].respond(req)
     }
+
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        Some(self.hit_counts.clone())
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_hit_counts() {
+        let req = http::Request::new(bytes::Bytes::new());
+        let mut responder = crate::cycle![status_code(200), status_code(404), status_code(500)];
+        assert_eq!(responder.branch_hit_counts(), Some(vec![0, 0, 0]));
+        for _ in 0..4 {
+            drop(responder.respond(&req));
+        }
+        assert_eq!(responder.branch_hit_counts(), Some(vec![2, 1, 1]));
+        assert_eq!(
+            delay_and_then(Duration::from_secs(1), responder).branch_hit_counts(),
+            Some(vec![2, 1, 1])
+        );
+    }
+
+    #[test]
+    fn test_etag_of() {
+        assert_eq!(
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\"",
+            etag_of(b"hello")
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! The random number generator behind the server's randomized behavior.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+
+// A small, fast generator that's good enough for testing. It's seeded once
+// per server so a run can be reproduced from the seed it logs.
+#[derive(Debug)]
+pub(crate) struct Rng {
+    seed: u64,
+    state: Mutex<u64>,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng {
+            seed,
+            state: Mutex::new(seed),
+        }
+    }
+
+    pub(crate) fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // splitmix64
+    pub(crate) fn next_u64(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // A value in [0, 1).
+    pub(crate) fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Rng {
+    // Seeded from the randomly keyed hasher std uses for HashMaps.
+    fn default() -> Self {
+        Rng::new(RandomState::new().build_hasher().finish())
+    }
+}
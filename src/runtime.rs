@@ -0,0 +1,262 @@
+//! Async runtimes the server can run on.
+//!
+//! By default every server starts its own tokio runtime on a background
+//! thread, or runs on a tokio runtime shared by the whole process when
+//! `ServerBuilder::shared_runtime` is set. A server built with
+//! `ServerBuilder::runtime` instead runs on the given
+//! [Runtime](trait.Runtime.html): the runtime accepts the server's
+//! connections, runs its tasks and measures its delays, so tests on another
+//! executor don't need a tokio runtime or an extra thread.
+//!
+//! With the `smol` feature enabled, [SmolRuntime](struct.SmolRuntime.html)
+//! runs the server on smol. Other runtimes are supported by implementing
+//! [Runtime](trait.Runtime.html).
+
+use crate::clock::{Clock, Sleep};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A task spawned by the server.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The future returned by [Listener::accept](trait.Listener.html#tymethod.accept).
+pub type Accept<'a> =
+    Pin<Box<dyn Future<Output = io::Result<(Box<dyn Connection>, SocketAddr)>> + Send + 'a>>;
+
+/// The future returned by [Runtime::connect](trait.Runtime.html#method.connect).
+pub type Connect = Pin<Box<dyn Future<Output = io::Result<Box<dyn Connection>>> + Send>>;
+
+/// An async runtime the server runs on.
+pub trait Runtime: Send + Sync + 'static {
+    /// Run `task` in the background.
+    fn spawn(&self, task: Task);
+
+    /// Accept connections on `listener`. The listener is already bound and
+    /// has been set to non-blocking.
+    fn listen(&self, listener: std::net::TcpListener) -> io::Result<Box<dyn Listener>>;
+
+    /// Return a future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    /// Connect to `addr` to send the server's own requests, like the
+    /// callbacks of a [webhook](../responders/fn.webhook.html). By default
+    /// connecting is unsupported.
+    fn connect(&self, addr: SocketAddr) -> Connect {
+        let _ = addr;
+        Box::pin(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the runtime doesn't support connecting",
+            ))
+        })
+    }
+}
+
+/// Accepts the connections made to the server.
+pub trait Listener: Send {
+    /// Wait for the next connection.
+    fn accept(&mut self) -> Accept<'_>;
+}
+
+/// A connection accepted by a [Listener](trait.Listener.html).
+///
+/// Implemented for anything implementing hyper's IO traits. Streams
+/// implementing the `futures` IO traits can be adapted with
+/// `smol_hyper::rt::FuturesIo`, and tokio streams with
+/// `hyper_util::rt::TokioIo`.
+pub trait Connection: hyper::rt::Read + hyper::rt::Write + Send + Unpin {}
+
+impl<T> Connection for T where T: hyper::rt::Read + hyper::rt::Write + Send + Unpin {}
+
+// Runs on a tokio runtime. Without a handle it uses the runtime it's called
+// from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TokioRuntime(pub(crate) Option<tokio::runtime::Handle>);
+
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: Task) {
+        match &self.0 {
+            Some(handle) => drop(handle.spawn(task)),
+            None => drop(tokio::spawn(task)),
+        }
+    }
+
+    fn listen(&self, listener: std::net::TcpListener) -> io::Result<Box<dyn Listener>> {
+        let _guard = self.0.as_ref().map(|handle| handle.enter());
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        Ok(Box::new(TokioListener(listener)))
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        // the timer is registered with the runtime when the sleep is created.
+        let _guard = self.0.as_ref().map(|handle| handle.enter());
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Connect {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let stream: Box<dyn Connection> = Box::new(hyper_util::rt::TokioIo::new(stream));
+            Ok(stream)
+        })
+    }
+}
+
+struct TokioListener(tokio::net::TcpListener);
+
+impl Listener for TokioListener {
+    fn accept(&mut self) -> Accept<'_> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.0.accept().await?;
+            let stream: Box<dyn Connection> = Box::new(hyper_util::rt::TokioIo::new(stream));
+            Ok((stream, peer_addr))
+        })
+    }
+}
+
+/// Runs the server on smol.
+///
+/// ```
+/// use httptest::{runtime::SmolRuntime, ServerBuilder};
+///
+/// let server = ServerBuilder::new()
+///     .runtime(SmolRuntime::new())
+///     .run()
+///     .unwrap();
+/// ```
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Default)]
+pub struct SmolRuntime {
+    executor: Option<Arc<smol::Executor<'static>>>,
+}
+
+#[cfg(feature = "smol")]
+impl SmolRuntime {
+    /// Spawn the server's tasks onto smol's global executor.
+    pub fn new() -> Self {
+        SmolRuntime::default()
+    }
+
+    /// Spawn the server's tasks onto `executor`. The executor must be run
+    /// for the server to make progress. When the executor is run on the
+    /// thread that drops the server, stop the server with
+    /// [Server::shutdown_async](../struct.Server.html#method.shutdown_async)
+    /// first, as dropping it would block the executor the server is waiting
+    /// on.
+    pub fn with_executor(executor: Arc<smol::Executor<'static>>) -> Self {
+        SmolRuntime {
+            executor: Some(executor),
+        }
+    }
+}
+
+#[cfg(feature = "smol")]
+impl Runtime for SmolRuntime {
+    fn spawn(&self, task: Task) {
+        match &self.executor {
+            Some(executor) => executor.spawn(task).detach(),
+            None => smol::spawn(task).detach(),
+        }
+    }
+
+    fn listen(&self, listener: std::net::TcpListener) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(SmolListener(smol::Async::new(listener)?)))
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let timer = smol::Timer::after(duration);
+        Box::pin(async move {
+            timer.await;
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Connect {
+        Box::pin(async move {
+            let stream = smol::net::TcpStream::connect(addr).await?;
+            let stream: Box<dyn Connection> = Box::new(smol_hyper::rt::FuturesIo::new(stream));
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(feature = "smol")]
+struct SmolListener(smol::Async<std::net::TcpListener>);
+
+#[cfg(feature = "smol")]
+impl Listener for SmolListener {
+    fn accept(&mut self) -> Accept<'_> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.0.accept().await?;
+            let stream: Box<dyn Connection> = Box::new(smol_hyper::rt::FuturesIo::new(stream));
+            Ok((stream, peer_addr))
+        })
+    }
+}
+
+// The runtime a server runs on. Spawns hyper's tasks and, unless a clock was
+// given, measures the server's delays.
+#[derive(Clone)]
+pub(crate) struct ServerRuntime(pub(crate) Arc<dyn Runtime>);
+
+impl ServerRuntime {
+    pub(crate) fn tokio(handle: tokio::runtime::Handle) -> Self {
+        ServerRuntime(Arc::new(TokioRuntime(Some(handle))))
+    }
+
+    // Wait for `future` to complete for up to `timeout`, returning None if it
+    // didn't.
+    pub(crate) async fn timeout<F: Future>(
+        &self,
+        timeout: Duration,
+        future: F,
+    ) -> Option<F::Output> {
+        let sleep = self.0.sleep(timeout);
+        futures::pin_mut!(future);
+        match futures::future::select(future, sleep).await {
+            futures::future::Either::Left((output, _)) => Some(output),
+            futures::future::Either::Right(_) => None,
+        }
+    }
+}
+
+// The runtime of the server that received `req`, or the current tokio
+// runtime when `req` wasn't received by a server.
+#[cfg(feature = "webhook")]
+pub(crate) fn of<B>(req: &http::Request<B>) -> ServerRuntime {
+    req.extensions()
+        .get::<ServerRuntime>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+impl fmt::Debug for ServerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerRuntime")
+    }
+}
+
+impl Default for ServerRuntime {
+    fn default() -> Self {
+        ServerRuntime(Arc::new(TokioRuntime::default()))
+    }
+}
+
+impl Clock for ServerRuntime {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.0.sleep(duration)
+    }
+}
+
+impl<F> hyper::rt::Executor<F> for ServerRuntime
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, future: F) {
+        self.0.spawn(Box::pin(future));
+    }
+}
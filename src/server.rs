@@ -1,26 +1,70 @@
+use crate::capture::{self, CaptureStream};
+use crate::chaos::{self, Chaos};
+use crate::clock::{Clock, ServerClock};
+use crate::diagnostics::{self, DiagnosticEvent};
+use crate::identity::RequestIdentity;
+use crate::inject::{InjectStream, Injector};
 use crate::matchers::{matcher_name, ExecutionContext, Matcher};
-use crate::responders::Responder;
+use crate::responders::{
+    BodyFraming, DeclaredContentLength, Framing, Generated, Responder, StreamChunks, Trailers,
+    TruncateBodyAt, VerbatimHeaders,
+};
+use crate::rng::Rng;
+use crate::runtime::{Connection, Runtime, ServerRuntime};
+use crate::snapshot::Snapshot;
+use crate::trace;
 use futures::future::FutureExt;
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::body::Frame;
 use hyper::service::service_fn;
 use hyper_util::{rt::TokioIo, server::conn::auto::Builder};
-use std::convert::Infallible;
+use once_cell::sync::OnceCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
 use std::fmt;
 use std::future::Future;
+use std::io::Write;
 use std::net::{SocketAddr, TcpListener};
-use std::ops::{Bound, RangeBounds};
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // type alias for a request that has read a complete body into memory.
-type FullRequest = http::Request<hyper::body::Bytes>;
+pub(crate) type FullRequest = http::Request<hyper::body::Bytes>;
 
 /// The Server
 #[derive(Debug)]
 pub struct Server {
     trigger_shutdown: Option<tokio::sync::watch::Sender<bool>>,
+    // None when running on the shared runtime or a given runtime.
     join_handle: Option<std::thread::JoinHandle<()>>,
+    // disconnected when the server exits.
+    thread_exited: mpsc::Receiver<()>,
+    // closed when the server exits, for waiting without blocking the thread.
+    exited: tokio::sync::watch::Receiver<()>,
+    shutdown_warning_after: Duration,
+    report_all_failures: bool,
+    // the runtime the server runs on.
+    runtime: ServerRuntime,
+    // connections made without the network, see Server::connector.
+    #[cfg_attr(not(feature = "connector"), allow(dead_code))]
+    in_process: tokio::sync::mpsc::UnboundedSender<tokio::io::DuplexStream>,
+    json_report: Option<JsonReportWriter>,
+    snapshot: Option<Snapshot>,
     addr: SocketAddr,
+    // used in urls instead of the ip address when set.
+    hostname: Option<String>,
+    // whether connections to the server use TLS.
+    #[cfg(feature = "tls")]
+    tls: bool,
+    #[cfg(feature = "tls")]
+    identity: Option<Arc<crate::tls::Identity>>,
+    #[cfg(feature = "http3")]
+    http3_addr: Option<SocketAddr>,
     state: ServerState,
 }
 
@@ -43,15 +87,60 @@ impl Server {
     /// If the server is listening on port 1234.
     ///
     /// `server.url("/foo?q=1") == "http://localhost:1234/foo?q=1"`
+    ///
+    /// The url uses the [hostname](struct.ServerBuilder.html#method.hostname)
+    /// if one was set, otherwise the server's IP address.
     pub fn url(&self, path_and_query: &str) -> http::Uri {
         hyper::Uri::builder()
-            .scheme("http")
-            .authority(self.addr.to_string().as_str())
+            .scheme(self.scheme())
+            .authority(self.authority().as_str())
             .path_and_query(path_and_query)
             .build()
             .unwrap()
     }
 
+    /// Build a url to the server with a query string, percent-encoding the
+    /// query parameters.
+    ///
+    /// ```
+    /// # use httptest::Server;
+    /// let server = Server::run();
+    /// let url = server
+    ///     .url_builder("/search")
+    ///     .query("q", "rust & tokio")
+    ///     .query("page", 2)
+    ///     .build();
+    /// assert_eq!(Some("q=rust+%26+tokio&page=2"), url.query());
+    /// ```
+    pub fn url_builder(&self, path: &str) -> UrlBuilder {
+        UrlBuilder {
+            authority: self.authority(),
+            path: path.to_string(),
+            query: Vec::new(),
+        }
+    }
+
+    // The scheme used in urls to the server.
+    fn scheme(&self) -> &'static str {
+        #[cfg(feature = "tls")]
+        if self.tls {
+            return "https";
+        }
+        "http"
+    }
+
+    // The host and port used in urls to the server.
+    fn authority(&self) -> String {
+        self.authority_with_port(self.addr.port())
+    }
+
+    fn authority_with_port(&self, port: u16) -> String {
+        match &self.hostname {
+            Some(hostname) => format!("{}:{}", hostname, port),
+            None => SocketAddr::new(self.addr.ip(), port).to_string(),
+        }
+    }
+
     /// Get a fully formed url to the servers address as a String.
     ///
     /// `server.url_str(foo)  == server.url(foo).to_string()`
@@ -59,306 +148,4095 @@ impl Server {
         self.url(path_and_query).to_string()
     }
 
+    /// Get a fully formed https url to the server's HTTP/3 address.
+    ///
+    /// This function will panic if the server wasn't built with
+    /// [ServerBuilder::http3](struct.ServerBuilder.html#method.http3).
+    #[cfg(feature = "http3")]
+    pub fn https_url(&self, path_and_query: &str) -> http::Uri {
+        let addr = self
+            .http3_addr()
+            .expect("the server wasn't built with ServerBuilder::http3");
+        hyper::Uri::builder()
+            .scheme("https")
+            .authority(self.authority_with_port(addr.port()).as_str())
+            .path_and_query(path_and_query)
+            .build()
+            .unwrap()
+    }
+
+    /// The UDP address HTTP/3 is served on, if it's enabled. This is usually
+    /// the same as [addr](#method.addr).
+    #[cfg(feature = "http3")]
+    pub fn http3_addr(&self) -> Option<SocketAddr> {
+        self.http3_addr
+    }
+
+    /// The DER encoding of the self-signed certificate presented to clients,
+    /// if TLS or HTTP/3 is enabled.
+    #[cfg(feature = "tls")]
+    pub fn certificate_der(&self) -> Option<Vec<u8>> {
+        self.identity
+            .as_ref()
+            .map(|identity| identity.certificate().der)
+    }
+
+    /// The PEM encoding of the self-signed certificate presented to clients,
+    /// if TLS or HTTP/3 is enabled. Clients must trust it to connect.
+    #[cfg(feature = "tls")]
+    pub fn certificate_pem(&self) -> Option<String> {
+        self.identity
+            .as_ref()
+            .map(|identity| identity.certificate().pem)
+    }
+
+    /// Replace the server's certificate with a newly generated self-signed
+    /// certificate. Connections that are already established are unaffected,
+    /// new connections are presented the new certificate. This is useful for
+    /// testing how a client handles certificate changes on reconnect.
+    ///
+    /// This function will panic if neither TLS nor HTTP/3 is enabled.
+    #[cfg(feature = "tls")]
+    pub fn rotate_certificate(&self) {
+        self.tls_identity()
+            .rotate()
+            .expect("failed to generate a certificate");
+    }
+
+    /// Replace the server's certificate with a PEM encoded certificate chain
+    /// and private key. Like [rotate_certificate](#method.rotate_certificate)
+    /// only new connections are presented the new certificate.
+    ///
+    /// This function will panic if neither TLS nor HTTP/3 is enabled.
+    #[cfg(feature = "tls")]
+    pub fn set_certificate(&self, cert_chain_pem: &str, key_pem: &str) -> std::io::Result<()> {
+        self.tls_identity().replace(cert_chain_pem, key_pem)
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_identity(&self) -> &crate::tls::Identity {
+        self.identity
+            .as_deref()
+            .expect("the server doesn't use TLS or HTTP/3")
+    }
+
+    /// The requests forwarded to the upstream set with
+    /// [ServerBuilder::forward_unmatched_to](struct.ServerBuilder.html#method.forward_unmatched_to)
+    /// and the responses it returned, in the order they completed.
+    #[cfg(feature = "record")]
+    pub fn recordings(&self) -> Vec<crate::record::Recording> {
+        self.state
+            .recorder
+            .as_ref()
+            .map_or_else(Vec::new, |recorder| recorder.recordings())
+    }
+
+    /// Get a fully formed url to the servers address as a `reqwest::Url`.
+    #[cfg(feature = "reqwest")]
+    pub fn reqwest_url(&self, path_and_query: &str) -> reqwest::Url {
+        reqwest::Url::parse(&self.url_str(path_and_query)).unwrap()
+    }
+
+    /// Get a reqwest client that resolves `hostname` to the server's address,
+    /// for testing code that talks to a fixed hostname.
+    ///
+    /// ```
+    /// # use httptest::Server;
+    /// let server = Server::run();
+    /// let client = server.reqwest_client("api.example.com");
+    /// let url = format!("http://api.example.com:{}/foo", server.addr().port());
+    /// ```
+    #[cfg(feature = "reqwest")]
+    pub fn reqwest_client(&self, hostname: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .resolve(hostname, self.addr)
+            .build()
+            .expect("failed to build reqwest client")
+    }
+
+    /// Get a reqwest client that trusts the server's self-signed certificate
+    /// and resolves `hostname` to the server's address. The certificate is
+    /// valid for `localhost`, the server's IP address and the
+    /// [hostname](struct.ServerBuilder.html#method.hostname) the server was
+    /// built with.
+    ///
+    /// This function will panic if neither TLS nor HTTP/3 is enabled.
+    ///
+    /// ```
+    /// # use httptest::ServerBuilder;
+    /// let server = ServerBuilder::new().tls(true).run().unwrap();
+    /// let client = server.trusted_client("localhost");
+    /// let url = format!("https://localhost:{}/foo", server.addr().port());
+    /// ```
+    #[cfg(all(feature = "reqwest", feature = "tls"))]
+    pub fn trusted_client(&self, hostname: &str) -> reqwest::Client {
+        let der = self
+            .certificate_der()
+            .expect("trusted_client requires TLS or HTTP/3 to be enabled");
+        let certificate =
+            reqwest::Certificate::from_der(&der).expect("the server's certificate is valid");
+        reqwest::Client::builder()
+            .add_root_certificate(certificate)
+            .resolve(hostname, self.addr)
+            .build()
+            .expect("failed to build reqwest client")
+    }
+
+    /// Get a connector that connects hyper clients to this server without
+    /// using the network. Every connection made through it reaches this
+    /// server regardless of the host in the request uri.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// use hyper_util::client::legacy::Client;
+    /// use hyper_util::rt::TokioExecutor;
+    ///
+    /// let server = Server::run();
+    /// let client: Client<_, http_body_util::Empty<bytes::Bytes>> =
+    ///     Client::builder(TokioExecutor::new()).build(server.connector());
+    /// ```
+    #[cfg(feature = "connector")]
+    pub fn connector(&self) -> crate::Connector {
+        crate::Connector::new(self.in_process.clone())
+    }
+
     /// Add a new expectation to the server.
-    pub fn expect(&self, expectation: Expectation) {
+    ///
+    /// The returned handle can be used to observe the progress of the
+    /// expectation before it's verified.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// let token = server.expect(
+    ///     Expectation::matching(request::path("/token"))
+    ///         .times(0..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// assert_eq!(token.hit_count(), 0);
+    /// ```
+    pub fn expect(&self, expectation: Expectation) -> ExpectationHandle {
         log::debug!("expectation added: {:?}", expectation);
-        self.state.push_expectation(expectation);
+        self.state.push_expectation(expectation)
+    }
+
+    /// Allow requests matching `matcher` any number of times, including none,
+    /// responding with `responder`. Unlike an expectation, an allowance never
+    /// fails verification and is left out of verification reports, which
+    /// suits background traffic like health checks, telemetry pings and
+    /// favicon requests. Allowances are matched like expectations.
+    ///
+    /// ```
+    /// # use httptest::{Server, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// server.allow(request::method_path("GET", "/favicon.ico"), status_code(404));
+    /// ```
+    pub fn allow(
+        &self,
+        matcher: impl Matcher<FullRequest> + 'static,
+        responder: impl Responder + 'static,
+    ) -> ExpectationHandle {
+        let mut expectation = Expectation::matching(matcher)
+            .times(..)
+            .respond_with(responder);
+        expectation.allowed = true;
+        log::debug!("allowance added: {:?}", expectation);
+        self.state.push_expectation(expectation)
+    }
+
+    /// Add an expectation for each entry in the HAR file at `path`. See the
+    /// [har](har/index.html) module for how entries are matched.
+    pub fn expect_har(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        for expectation in crate::har::load(path)? {
+            self.expect(expectation);
+        }
+        Ok(())
+    }
+
+    /// Start a scope for a phase of a test. Expectations added through the
+    /// returned scope are verified and removed from the server when the scope
+    /// is dropped, leaving other expectations in place.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// {
+    ///     let login = server.scope();
+    ///     login.expect(
+    ///         Expectation::matching(request::path("/login"))
+    ///             .times(0..)
+    ///             .respond_with(status_code(200)),
+    ///     );
+    ///     // ... exercise the login flow ...
+    /// } // expectations added through `login` are verified here.
+    /// ```
+    pub fn scope(&self) -> Scope<'_> {
+        Scope {
+            server: self,
+            ids: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Group the requests received since the server started or was last
+    /// verified by `identity`, and return how many times each was retried,
+    /// i.e. the number of requests with that identity beyond the first. Groups
+    /// are returned in the order they were first received.
+    ///
+    /// See the [identity](identity/index.html) module for ways to identify
+    /// requests.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// use httptest::identity::{self, RequestIdentity};
+    /// # async fn example(server: Server) {
+    /// // ... the client uploads a document and retries on failure ...
+    /// let retries = server.retry_counts(identity::path().and(identity::body_digest()));
+    /// assert_eq!(retries.len(), 1);
+    /// assert_eq!(retries[0].1, 2);
+    /// # }
+    /// ```
+    pub fn retry_counts<I>(&self, mut identity: I) -> Vec<(I::Id, usize)>
+    where
+        I: RequestIdentity,
+    {
+        let state = self.state.lock().expect("mutex poisoned");
+        let mut groups: Vec<(I::Id, usize)> = Vec::new();
+        for exchange in state.exchanges.iter() {
+            let id = identity.identity(&exchange.request);
+            match groups.iter_mut().find(|(group, _)| *group == id) {
+                Some((_, retries)) => *retries += 1,
+                None => groups.push((id, 0)),
+            }
+        }
+        groups
+    }
+
+    /// When each request was received since the server started or was last
+    /// verified, in the order they were received. Includes requests that
+    /// didn't match any expectation.
+    pub fn request_times(&self) -> Vec<Instant> {
+        let state = self.state.lock().expect("mutex poisoned");
+        state.request_times.clone()
+    }
+
+    /// The seed of the random number generator behind the server's
+    /// randomized behavior. See
+    /// [ServerBuilder::rng_seed](struct.ServerBuilder.html#method.rng_seed).
+    pub fn rng_seed(&self) -> u64 {
+        self.state.rng.seed()
+    }
+
+    /// Describe the current state of the server as json. This includes the
+    /// expectations with their hit counts, the unexpected requests, and every
+    /// request received along with the response sent since the server started
+    /// or was last verified. The document has the same format as the reports
+    /// written by [ServerBuilder::json_report](struct.ServerBuilder.html#method.json_report).
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// server.expect(Expectation::matching(any()).times(..).respond_with(status_code(200)));
+    /// let state = server.dump_state_json();
+    /// assert_eq!(state["expectations"][0]["hit_count"], 0);
+    /// assert_eq!(state["exchanges"].as_array().unwrap().len(), 0);
+    /// ```
+    pub fn dump_state_json(&self) -> serde_json::Value {
+        let mut state = self.state.lock().expect("mutex poisoned").to_json();
+        state["addr"] = self.addr.to_string().into();
+        state
+    }
+
+    // Describe why the server can't be reused for another test, if it can't.
+    pub(crate) fn health_problem(&self) -> Option<String> {
+        if !self.is_running() {
+            return Some("the server has stopped running".to_string());
+        }
+        match self.state.connections.in_flight_count() {
+            0 => None,
+            n => Some(format!("{} requests are still in flight", n)),
+        }
+    }
+
+    /// Counters for the connections the server has accepted. Useful for
+    /// checking that a client reuses connections rather than opening one per
+    /// request.
+    ///
+    /// Unlike expectations, the counters aren't reset by
+    /// [verify_and_clear](#method.verify_and_clear).
+    pub fn connection_metrics(&self) -> ConnectionMetrics {
+        self.state.connections.metrics()
+    }
+
+    /// Wait up to `timeout` for every expectation to receive the minimum
+    /// number of requests it expects, then verify and clear the expectations
+    /// like [verify_and_clear](#method.verify_and_clear). Returns as soon as
+    /// the minimums are reached.
+    ///
+    /// This is useful when the client sends requests from a background task
+    /// that may still be running when the test finishes.
+    pub async fn verify_with_timeout(&mut self, timeout: Duration) {
+        let wait = self.state.wait_for_min_hits();
+        let _ = self.state.runtime.timeout(timeout, wait).await;
+        self.verify_and_clear();
+    }
+
+    /// Blocking version of
+    /// [verify_with_timeout](#method.verify_with_timeout) for use outside of
+    /// an async context. The requests being waited on must be sent from
+    /// another thread.
+    pub fn verify_with_timeout_blocking(&mut self, timeout: Duration) {
+        let (done_tx, done_rx) = mpsc::channel();
+        let state = self.state.clone();
+        // wait on the server's runtime, the calling thread may not have one.
+        let runtime = self.runtime.clone();
+        self.runtime.0.spawn(Box::pin(async move {
+            let _ = runtime.timeout(timeout, state.wait_for_min_hits()).await;
+            let _ = done_tx.send(());
+        }));
+        let _ = done_rx.recv();
+        self.verify_and_clear();
+    }
+
+    /// Stop the server. It stops accepting connections and closes existing
+    /// connections once their in-flight requests complete. Returns once the
+    /// server has stopped.
+    ///
+    /// Unlike dropping the server, expectations are not verified so the
+    /// server's state can still be inspected. They're verified when the
+    /// server is dropped. Calling shutdown more than once has no effect.
+    ///
+    /// This blocks the calling thread. A server on a
+    /// [runtime](struct.ServerBuilder.html#method.runtime) that's driven by
+    /// the calling thread can't stop while it's blocked, so use
+    /// [shutdown_async](#method.shutdown_async) before dropping the server.
+    pub fn shutdown(&mut self) {
+        // drop the trigger_shutdown channel to tell the server to shutdown.
+        // Then wait for the shutdown to complete.
+        if self.trigger_shutdown.take().is_none() {
+            return;
+        }
+        let shutdown_started = Instant::now();
+        if let Err(mpsc::RecvTimeoutError::Timeout) =
+            self.thread_exited.recv_timeout(self.shutdown_warning_after)
+        {
+            self.shutdown_delayed(shutdown_started.elapsed());
+        }
+        match self.join_handle.take() {
+            Some(join_handle) => {
+                let _ = join_handle.join();
+            }
+            // on the shared runtime there's no thread to join, so keep
+            // waiting for the server's task to exit.
+            None => {
+                let _ = self.thread_exited.recv();
+            }
+        }
+    }
+
+    /// Like [shutdown](#method.shutdown), but waits for the server to stop
+    /// without blocking the thread. This is needed for a server on a
+    /// [runtime](struct.ServerBuilder.html#method.runtime) driven by the
+    /// calling thread, where dropping the server would otherwise deadlock.
+    ///
+    /// ```
+    /// # #[cfg(feature = "smol")]
+    /// # {
+    /// use httptest::{runtime::SmolRuntime, ServerBuilder};
+    /// use std::sync::Arc;
+    ///
+    /// let executor = Arc::new(smol::Executor::new());
+    /// smol::block_on(executor.run(async {
+    ///     let mut server = ServerBuilder::new()
+    ///         .runtime(SmolRuntime::with_executor(executor.clone()))
+    ///         .run()
+    ///         .unwrap();
+    ///     // ... use the server ...
+    ///     server.shutdown_async().await;
+    /// }));
+    /// # }
+    /// ```
+    pub async fn shutdown_async(&mut self) {
+        if self.trigger_shutdown.take().is_none() {
+            return;
+        }
+        let shutdown_started = Instant::now();
+        let mut exited = self.exited.clone();
+        let exited_in_time = self
+            .runtime
+            .timeout(self.shutdown_warning_after, exited.changed())
+            .await;
+        if exited_in_time.is_none() {
+            self.shutdown_delayed(shutdown_started.elapsed());
+            // the sender is only ever dropped, never used to send.
+            let _ = exited.changed().await;
+        }
+        if let Some(join_handle) = self.join_handle.take() {
+            // the server's thread is exiting once the server loop has.
+            let _ = join_handle.join();
+        }
+    }
+
+    // The server is waiting on connections to finish. Tell the user what it's
+    // waiting on. Write directly to stderr rather than using eprintln! so the
+    // output is not held back by the test harness's output capturing while
+    // the test appears hung.
+    fn shutdown_delayed(&self, waited: Duration) {
+        let connections = self.state.connections.to_string();
+        let msg = format!(
+            "httptest: server at {} has been shutting down for {:?}; {}",
+            self.addr, waited, connections
+        );
+        log::warn!("{}", msg);
+        let _ = writeln!(std::io::stderr(), "{}", msg);
+        self.state.diagnose(|| DiagnosticEvent::ShutdownDelayed {
+            waited,
+            connections,
+        });
+    }
+
+    /// Whether the server is still running. A server stops running when it's
+    /// [shutdown](#method.shutdown) or if it fails.
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.thread_exited.try_recv(),
+            Err(mpsc::TryRecvError::Empty)
+        )
     }
 
     /// Verify all registered expectations. Panic if any are not met, then clear
     /// all expectations leaving the server running in a clean state.
     pub fn verify_and_clear(&mut self) {
+        self.verify_and_take();
+    }
+
+    /// Like [verify_and_clear](#method.verify_and_clear), but returns a
+    /// report of every expectation and how many requests it received when
+    /// verification passes.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let mut server = Server::run();
+    /// server.expect(
+    ///     Expectation::matching(request::path("/health"))
+    ///         .times(..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// let report = server.verify_report();
+    /// println!("{}", report);
+    /// assert_eq!(0, report.expectations()[0].hit_count());
+    /// ```
+    pub fn verify_report(&mut self) -> VerificationReport {
+        VerificationReport::new(&self.verify_and_take())
+    }
+
+    /// Save the server's current expectations so they can be reapplied with
+    /// [restore_expectations](#method.restore_expectations), such as to reset
+    /// a shared set of baseline expectations between parts of a test.
+    ///
+    /// The saved expectations share their matchers and responders with the
+    /// originals, so responders that change as they respond, like `cycle`,
+    /// pick up where they left off.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let mut server = Server::run();
+    /// server.expect(
+    ///     Expectation::matching(request::path("/health"))
+    ///         .times(..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// let baseline = server.save_expectations();
+    /// for case in 0..3 {
+    ///     // ... add expectations and run the case ...
+    ///     server.verify_and_clear();
+    ///     server.restore_expectations(&baseline);
+    /// }
+    /// ```
+    pub fn save_expectations(&self) -> SavedExpectations {
+        let inner = self.state.lock().expect("mutex poisoned");
+        SavedExpectations {
+            expectations: inner.expected.iter().map(Expectation::share).collect(),
+            branch_hits: inner
+                .expected
+                .iter()
+                .map(|expectation| expectation.responder().branch_hits())
+                .collect(),
+            orderings: inner.orderings.clone(),
+        }
+    }
+
+    /// Replace the server's expectations with those saved by
+    /// [save_expectations](#method.save_expectations). The restored
+    /// expectations start with no requests received. Handles to the saved
+    /// expectations refer to the restored ones.
+    pub fn restore_expectations(&self, saved: &SavedExpectations) {
+        self.restore(saved, false);
+    }
+
+    /// Like [restore_expectations](#method.restore_expectations), but the
+    /// restored expectations keep the requests they'd received when they were
+    /// saved.
+    pub fn restore_expectations_with_hits(&self, saved: &SavedExpectations) {
+        self.restore(saved, true);
+    }
+
+    fn restore(&self, saved: &SavedExpectations, with_hits: bool) {
+        log::debug!("restoring {} expectations", saved.expectations.len());
+        {
+            let mut inner = self.state.lock().expect("mutex poisoned");
+            inner.expected = saved
+                .expectations
+                .iter()
+                .zip(saved.branch_hits.iter())
+                .map(|(expectation, branch_hits)| {
+                    let mut expectation = expectation.share();
+                    // the responder is shared with the saved expectation, so
+                    // its branches are reset to the counts they had when
+                    // saved.
+                    if let Some((hit_counts, unmatched)) = branch_hits {
+                        expectation.responder().set_branch_hits(if with_hits {
+                            (hit_counts.clone(), *unmatched)
+                        } else {
+                            (vec![0; hit_counts.len()], 0)
+                        });
+                    }
+                    if !with_hits {
+                        expectation.hit_count = 0;
+                        expectation.hit_times.clear();
+                    }
+                    expectation
+                })
+                .collect();
+            inner.orderings = saved.orderings.clone();
+        }
+        // wake anyone waiting on an expectation's hit count.
+        self.state.hits.send_modify(|_| {});
+    }
+
+    // Verify all registered expectations, panicking if any are not met, and
+    // return the state they were cleared from.
+    fn verify_and_take(&mut self) -> ServerStateInner {
         let state = {
             let mut state = self.state.lock().expect("mutex poisoned");
             std::mem::take(&mut *state) // reset server to default state.
         };
+        if let Some(json_report) = self.json_report.as_mut() {
+            json_report.write(&self.addr, &state);
+        }
+        if let Some(snapshot) = self.snapshot.as_mut() {
+            for exchange in &state.exchanges {
+                snapshot.record(&exchange.request);
+            }
+        }
         if std::thread::panicking() {
             // If the test is already panicking don't double panic on drop.
-            return;
+            return state;
         }
-        for expectation in state.expected.iter() {
-            if !hit_count_is_valid(expectation.times, expectation.hit_count) {
-                panic!(
-                    "Unexpected number of requests for matcher '{:?}'; received {}; expected {}",
-                    matcher_name(&*expectation.matcher),
-                    expectation.hit_count,
-                    RangeDisplay(expectation.times),
-                );
+        report_failures(
+            state.failures(),
+            self.report_all_failures,
+            self.state.on_verification_failure.as_ref(),
+        );
+        state
+    }
+}
+
+/// Builds a url to a server, percent-encoding its query parameters.
+///
+/// Created by [Server::url_builder](struct.Server.html#method.url_builder).
+#[derive(Debug, Clone)]
+pub struct UrlBuilder {
+    authority: String,
+    // may already include a query, which the parameters are appended to.
+    path: String,
+    query: Vec<(String, String)>,
+}
+
+impl UrlBuilder {
+    /// Append a query parameter.
+    pub fn query(mut self, name: impl fmt::Display, value: impl fmt::Display) -> UrlBuilder {
+        self.query.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Build the url.
+    ///
+    /// Panics if the path is not a valid url path.
+    pub fn build(self) -> http::Uri {
+        let mut path_and_query = self.path;
+        if !self.query.is_empty() {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&self.query)
+                .finish();
+            match path_and_query.find('?') {
+                Some(i) if i + 1 < path_and_query.len() => path_and_query.push('&'),
+                Some(_) => {}
+                None => path_and_query.push('?'),
             }
+            path_and_query.push_str(&query);
         }
-        if !state.unexpected_requests.is_empty() {
-            panic!(
-                "received the following unexpected requests:\n{:#?}",
-                &state.unexpected_requests
-            );
+        http::Uri::builder()
+            .scheme("http")
+            .authority(self.authority.as_str())
+            .path_and_query(path_and_query)
+            .build()
+            .unwrap()
+    }
+}
+
+/// A group of expectations that are verified together when dropped.
+///
+/// Created by [Server::scope](struct.Server.html#method.scope).
+#[derive(Debug)]
+pub struct Scope<'a> {
+    server: &'a Server,
+    ids: Mutex<Vec<u64>>,
+}
+
+impl Scope<'_> {
+    /// Add a new expectation to the server that is verified when the scope is
+    /// dropped.
+    pub fn expect(&self, expectation: Expectation) -> ExpectationHandle {
+        let handle = self.server.expect(expectation);
+        self.ids.lock().expect("mutex poisoned").push(handle.id);
+        handle
+    }
+}
+
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        let ids = std::mem::take(&mut *self.ids.lock().unwrap_or_else(|e| e.into_inner()));
+        let expectations = {
+            let mut state = self.server.state.lock().unwrap_or_else(|e| e.into_inner());
+            let (scoped, rest) = std::mem::take(&mut state.expected)
+                .into_iter()
+                .partition(|expectation| ids.contains(&expectation.id));
+            state.expected = rest;
+            scoped
+        };
+        if std::thread::panicking() {
+            return;
         }
+        let failures = expectations
+            .iter()
+            .filter_map(Expectation::verification_error)
+            .collect();
+        report_failures(
+            failures,
+            self.server.report_all_failures,
+            self.server.state.on_verification_failure.as_ref(),
+        );
     }
 }
 
 impl Drop for Server {
     fn drop(&mut self) {
-        // drop the trigger_shutdown channel to tell the server to shutdown.
-        // Then wait for the shutdown to complete.
-        self.trigger_shutdown = None;
-        let _ = self.join_handle.take().unwrap().join();
+        self.shutdown();
+        // don't follow the report written when the server was last verified
+        // with an empty one.
+        let written = self.json_report.as_ref().map(|report| report.written);
+        if written == Some(true) && self.state.lock().expect("mutex poisoned").is_empty() {
+            self.json_report = None;
+        }
         self.verify_and_clear();
+        // the snapshot covers every request received over the server's
+        // lifetime, so it's only checked once the server is gone.
+        if let Some(failure) = self.snapshot.as_ref().and_then(Snapshot::check) {
+            if !std::thread::panicking() {
+                report_failures(
+                    vec![failure],
+                    false,
+                    self.state.on_verification_failure.as_ref(),
+                );
+            }
+        }
     }
 }
 
-async fn process_request(
+// The environment variable that runs servers on the shared runtime by default.
+const SHARED_RUNTIME_ENV: &str = "HTTPTEST_SHARED_RUNTIME";
+
+// The runtime shared by servers started with ServerBuilder::shared_runtime.
+fn shared_runtime() -> std::io::Result<&'static tokio::runtime::Handle> {
+    static RUNTIME: OnceCell<tokio::runtime::Runtime> = OnceCell::new();
+    RUNTIME
+        .get_or_try_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .thread_name("httptest-shared")
+                .enable_all()
+                .build()
+        })
+        .map(|runtime| runtime.handle())
+}
+
+// Everything needed to serve a connection accepted by the server.
+#[derive(Clone)]
+struct ServeConnection {
     state: ServerState,
-    req: hyper::Request<hyper::body::Incoming>,
-) -> hyper::Result<http::Response<BoxBody<hyper::body::Bytes, Infallible>>> {
-    // read the full body into memory prior to handing it to matchers.
-    let (head, body) = req.into_parts();
-    let bytes = body.collect().await.unwrap().to_bytes();
-    let req = http::Request::from_parts(head, bytes);
+    shutdown_received: tokio::sync::watch::Receiver<bool>,
+    capture_dir: Option<PathBuf>,
+    addr: SocketAddr,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    #[cfg(feature = "http2-push")]
+    http2_server_push: bool,
+}
 
-    log::debug!("Received Request: {:?}", req);
-    let resp = on_req(state, req).await;
+// A connection's stream of bytes, after any TLS has been removed.
+trait Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin {}
 
-    let (parts, body) = resp.into_parts();
-    let body = Full::new(body).boxed();
-    let resp = hyper::Response::from_parts(parts, body);
+impl<T> Stream for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin {}
 
-    log::debug!("Sending Response: {:?}", resp);
-    hyper::Result::Ok(resp)
-}
+impl ServeConnection {
+    async fn run(mut self, stream: Box<dyn Connection>, peer_addr: SocketAddr) {
+        let conn = self.state.connections.opened(peer_addr);
+        let info = ConnectionInfo {
+            id: conn.id,
+            peer_addr,
+        };
+        if let Some(hook) = &self.state.connection_accepted {
+            (hook.0)(&info);
+        }
+        match self.handshake(Box::new(TokioIo::new(stream))).await {
+            Ok((stream, protocol)) => self.serve(stream, info, protocol).await,
+            Err(err) => log::debug!("TLS handshake failed: {}", err),
+        }
+        drop(conn);
+        if let Some(hook) = &self.state.connection_closed {
+            (hook.0)(&info);
+        }
+    }
 
-async fn on_req(state: ServerState, req: FullRequest) -> http::Response<hyper::body::Bytes> {
-    let response_future = {
-        let mut state = state.lock().expect("mutex poisoned");
-        // Iterate over expectations in reverse order. Expectations are
-        // evaluated most recently added first.
-        match state.find_expectation(&req) {
-            Some(expectation) => {
-                log::debug!("found matcher: {:?}", matcher_name(&*expectation.matcher));
-                expectation.hit_count += 1;
-                if !times_exceeded(expectation.times.1, expectation.hit_count) {
-                    Some(expectation.responder.respond(&req))
-                } else {
-                    Some(times_error(
-                        &*expectation.matcher as &dyn Matcher<FullRequest>,
-                        expectation.times,
-                        expectation.hit_count,
-                    ))
+    // Complete the TLS handshake if the server uses TLS, returning the
+    // decrypted stream and the negotiated ALPN protocol.
+    #[cfg(feature = "tls")]
+    async fn handshake(
+        &self,
+        stream: Box<dyn Stream>,
+    ) -> std::io::Result<(Box<dyn Stream>, Option<NegotiatedProtocol>)> {
+        let Some(acceptor) = &self.tls else {
+            return Ok((stream, None));
+        };
+        let stream = acceptor.accept(stream).await?;
+        let protocol = stream
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(|protocol| NegotiatedProtocol(String::from_utf8_lossy(protocol).into_owned()));
+        Ok((Box::new(stream), protocol))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn handshake(
+        &self,
+        stream: Box<dyn Stream>,
+    ) -> std::io::Result<(Box<dyn Stream>, Option<NegotiatedProtocol>)> {
+        Ok((stream, None))
+    }
+
+    async fn serve(
+        &mut self,
+        stream: Box<dyn Stream>,
+        info: ConnectionInfo,
+        protocol: Option<NegotiatedProtocol>,
+    ) {
+        let stream = CaptureStream::new(stream, self.capture_dir.as_deref(), self.addr, info.id);
+        #[cfg(feature = "http2-push")]
+        let stream: Box<dyn Stream> = if self.http2_server_push {
+            let alpn = protocol.as_ref().map(|protocol| protocol.0.as_str());
+            let detected = tokio::select! {
+                detected = crate::http2::detect(stream, alpn) => detected,
+                _ = self.shutdown_received.changed().fuse() => return,
+            };
+            match detected {
+                Ok((true, stream)) => return self.serve_http2(stream, info, protocol).await,
+                Ok((false, stream)) => Box::new(stream),
+                Err(err) => {
+                    log::debug!("unable to read from the connection: {}", err);
+                    return;
                 }
             }
-            None => {
-                log::debug!("no matcher found for request: {:?}", req);
-                state.unexpected_requests.push(req);
-                None
+        } else {
+            Box::new(stream)
+        };
+        let (stream, injector) = InjectStream::new(stream);
+        let (tunnels, mut tunnel_requests) = tokio::sync::mpsc::unbounded_channel();
+        let tunnels = Tunnels(tunnels);
+        let state = self.state.clone();
+        let service = service_fn(move |mut req: http::Request<hyper::body::Incoming>| {
+            if let Some(protocol) = &protocol {
+                req.extensions_mut().insert(protocol.clone());
+            }
+            if req.version() <= http::Version::HTTP_11 {
+                req.extensions_mut().insert(injector.clone());
+            }
+            req.extensions_mut().insert(tunnels.clone());
+            let state = state.clone();
+            let span = trace::request_span(info.id, &req);
+            trace::instrument(process_request(state, info, req), span)
+        });
+        let builder = Builder::new(self.state.runtime.clone());
+        let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+        tokio::pin!(connection);
+
+        tokio::select! {
+            _ = connection.as_mut() => {}
+            _ = self.shutdown_received.changed().fuse() => {
+                // let in-flight requests complete before closing the
+                // connection.
+                connection.as_mut().graceful_shutdown();
+                let _ = connection.as_mut().await;
+                return;
+            }
+        };
+        while let Ok((target, on_upgrade)) = tunnel_requests.try_recv() {
+            self.serve_tunnel(info, target, on_upgrade).await;
+        }
+    }
+
+    // Serve the requests a client sends through a CONNECT tunnel as though
+    // they were sent to the server directly. The tunnel is served as part of
+    // the connection it was opened on, so it's closed on shutdown and the
+    // server waits for it like any other connection. The future is boxed
+    // because tunnels opened within the tunnel are served recursively.
+    fn serve_tunnel(
+        &mut self,
+        info: ConnectionInfo,
+        target: http::uri::Authority,
+        on_upgrade: hyper::upgrade::OnUpgrade,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    log::debug!("CONNECT tunnel to {} failed: {}", target, err);
+                    return;
+                }
+            };
+            log::debug!("serving CONNECT tunnel to {}", target);
+            let (tunnels, mut tunnel_requests) = tokio::sync::mpsc::unbounded_channel();
+            let tunnels = Tunnels(tunnels);
+            let state = self.state.clone();
+            let service = service_fn(move |mut req: http::Request<hyper::body::Incoming>| {
+                req.extensions_mut().insert(TunnelTarget(target.clone()));
+                req.extensions_mut().insert(tunnels.clone());
+                let span = trace::request_span(info.id, &req);
+                trace::instrument(process_request(state.clone(), info, req), span)
+            });
+            let builder = Builder::new(self.state.runtime.clone());
+            let connection = builder.serve_connection_with_upgrades(upgraded, service);
+            tokio::pin!(connection);
+
+            tokio::select! {
+                result = connection.as_mut() => {
+                    if let Err(err) = result {
+                        log::debug!("CONNECT tunnel closed with error: {}", err);
+                    }
+                }
+                _ = self.shutdown_received.changed().fuse() => {
+                    connection.as_mut().graceful_shutdown();
+                    let _ = connection.as_mut().await;
+                    return;
+                }
+            };
+            while let Ok((target, on_upgrade)) = tunnel_requests.try_recv() {
+                self.serve_tunnel(info, target, on_upgrade).await;
+            }
+        })
+    }
+}
+
+#[cfg(feature = "http2-push")]
+impl ServeConnection {
+    // Serve HTTP/2 without hyper, which doesn't support server push.
+    async fn serve_http2<S: Stream>(
+        &mut self,
+        stream: S,
+        info: ConnectionInfo,
+        protocol: Option<NegotiatedProtocol>,
+    ) {
+        let state = self.state.clone();
+        let shutdown = self.shutdown_received.changed();
+        crate::http2::serve_connection(stream, shutdown, move |mut req| {
+            if let Some(protocol) = &protocol {
+                req.extensions_mut().insert(protocol.clone());
+            }
+            let span = trace::request_span(info.id, &req);
+            trace::instrument(process_request(state.clone(), info, req), span)
+        })
+        .await;
+    }
+}
+
+#[cfg(feature = "http3")]
+impl ServeConnection {
+    // Accept HTTP/3 connections until the server shuts down. The connections
+    // are closed on shutdown rather than waiting for in-flight requests.
+    async fn run_http3(mut self, endpoint: quinn::Endpoint) {
+        let mut connections = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                incoming = endpoint.accept() => match incoming {
+                    Some(incoming) => connections.push(self.clone().serve_http3(incoming)),
+                    None => break,
+                },
+                Some(_) = futures::StreamExt::next(&mut connections), if !connections.is_empty() => {}
+                _ = self.shutdown_received.changed().fuse() => break,
             }
         }
-    };
-    if let Some(f) = response_future {
-        f.await
-    } else {
-        http::Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body("No matcher found".into())
-            .unwrap()
+        drop(connections);
+        endpoint.close(0u32.into(), b"server shutting down");
+    }
+
+    async fn serve_http3(self, incoming: quinn::Incoming) {
+        let peer_addr = incoming.remote_address();
+        let conn = self.state.connections.opened(peer_addr);
+        let info = ConnectionInfo {
+            id: conn.id,
+            peer_addr,
+        };
+        if let Some(hook) = &self.state.connection_accepted {
+            (hook.0)(&info);
+        }
+        let state = self.state.clone();
+        crate::http3::serve_connection(incoming, move |mut req| {
+            req.extensions_mut()
+                .insert(NegotiatedProtocol("h3".to_string()));
+            let span = trace::request_span(info.id, &req);
+            trace::instrument(process_request(state.clone(), info, req), span)
+        })
+        .await;
+        drop(conn);
+        if let Some(hook) = &self.state.connection_closed {
+            (hook.0)(&info);
+        }
+    }
+}
+
+// Pass the failures to the failure handler if there is one, otherwise panic
+// with the first of the failures, or all of them if `report_all` is set.
+fn report_failures(failures: Vec<String>, report_all: bool, handler: Option<&FailureHook>) {
+    if failures.is_empty() {
+        return;
+    }
+    let errors = VerificationErrors {
+        failures,
+        report_all,
+    };
+    match handler {
+        Some(handler) => (handler.0)(errors),
+        None => panic!("{}", errors),
+    }
+}
+
+/// The reasons verification failed. Passed to the
+/// [on_verification_failure](struct.ServerBuilder.html#method.on_verification_failure)
+/// hook.
+///
+/// The `Display` implementation renders the same message the server would
+/// otherwise panic with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationErrors {
+    failures: Vec<String>,
+    report_all: bool,
+}
+
+impl VerificationErrors {
+    /// A description of each failure, such as an unmet expectation or an
+    /// unexpected request.
+    pub fn failures(&self) -> &[String] {
+        &self.failures
+    }
+}
+
+impl fmt::Display for VerificationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.report_all && self.failures.len() > 1 {
+            write!(f, "{} verification failures:", self.failures.len())?;
+            for (i, failure) in self.failures.iter().enumerate() {
+                write!(f, "\n{}. {}", i + 1, failure.trim_end())?;
+            }
+            return Ok(());
+        }
+        match self.failures.first() {
+            Some(failure) => f.write_str(failure),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for VerificationErrors {}
+
+type FailureFn = dyn Fn(VerificationErrors) + Send + Sync;
+
+#[derive(Clone)]
+struct FailureHook(Arc<FailureFn>);
+
+impl fmt::Debug for FailureHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FailureHook")
+    }
+}
+
+async fn process_request<B>(
+    state: ServerState,
+    conn: ConnectionInfo,
+    mut req: hyper::Request<B>,
+) -> Result<http::Response<BoxBody<hyper::body::Bytes, BoxError>>, BoxError>
+where
+    B: hyper::body::Body<Data = hyper::body::Bytes> + Unpin,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let _in_flight = state
+        .connections
+        .request_started(conn.id, format!("{} {}", req.method(), req.uri()));
+    let injector = req.extensions_mut().remove::<Injector>();
+    let tunnels = req.extensions_mut().remove::<Tunnels>();
+    req.extensions_mut().insert(conn);
+    req.extensions_mut().insert(state.request_received());
+    req.extensions_mut().insert(state.clock.clone());
+    req.extensions_mut().insert(state.runtime.clone());
+    let tunnel = match (req.method(), req.uri().authority()) {
+        (&http::Method::CONNECT, Some(target)) => {
+            Some((target.clone(), hyper::upgrade::on(&mut req)))
+        }
+        _ => None,
+    };
+    // read the full body into memory prior to handing it to matchers, unless
+    // no expectation could match regardless of the body.
+    let (head, mut body) = req.into_parts();
+    let skip_body = state.match_before_body && !state.head_could_match(&head);
+    let mut bytes = bytes::BytesMut::new();
+    // bytes read that haven't been waited for yet when reading slowly.
+    let mut unpaid = 0;
+    if !skip_body {
+        while let Some(frame) = body.frame().await {
+            let Ok(mut chunk) = frame?.into_data() else {
+                continue;
+            };
+            // stop at exactly abort_after_bytes, splitting the chunk that
+            // crosses it.
+            let abort = match state.abort_after_bytes {
+                Some(n) if bytes.len() + chunk.len() >= n => {
+                    chunk.truncate(n - bytes.len());
+                    true
+                }
+                _ => false,
+            };
+            bytes.extend_from_slice(&chunk);
+            if let Some(upload_progress) = &state.upload_progress {
+                let progress = UploadProgress {
+                    request: &head,
+                    bytes_received: bytes.len(),
+                    chunk_len: chunk.len(),
+                };
+                if let UploadAction::Abort = (upload_progress.0)(&progress) {
+                    log::debug!("aborting request after {} bytes", bytes.len());
+                    return Err(RequestAborted.into());
+                }
+            }
+            if abort {
+                log::debug!("aborting request after {} bytes", bytes.len());
+                return Err(RequestAborted.into());
+            }
+            if let Some((rate, interval)) = state.slow_body_read {
+                // the next frame isn't polled for until the bytes read so far
+                // have been paid for, so the client sees backpressure.
+                unpaid += chunk.len();
+                let intervals = unpaid / rate;
+                unpaid %= rate;
+                if intervals > 0 {
+                    state.clock.0.sleep(interval * intervals as u32).await;
+                }
+            }
+        }
+    }
+    let mut req = http::Request::from_parts(head, bytes.freeze());
+    #[cfg(feature = "decompress")]
+    if state.decompress_request_bodies {
+        crate::decompress::decompress(&mut req);
+    }
+    for map_request in &state.map_request {
+        (map_request.0)(&mut req);
+    }
+
+    log::debug!("Received Request: {:?}", req);
+    state.diagnose(|| DiagnosticEvent::RequestReceived {
+        request: format!("{} {}", req.method(), req.uri()),
+    });
+    if let Some(sink) = &state.request_sink {
+        // the receiver may have been dropped, which is fine.
+        let _ = sink.0.send(copy_request(&req));
+    }
+    let logged_req = copy_request(&req);
+    let (req_method, req_version) = (req.method().clone(), req.version());
+    let resp = if skip_body {
+        log::debug!("no matcher can match the request head, skipped reading the body");
+        state.record_unexpected(req);
+        no_matcher_response()
+    } else {
+        // A panicking matcher or responder would otherwise be swallowed by
+        // the connection task. Record it so that it's reported on
+        // verification.
+        match AssertUnwindSafe(on_req(state.clone(), req))
+            .catch_unwind()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(payload) => {
+                let msg = state.record_panic(payload);
+                http::Response::builder()
+                    .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(hyper::body::Bytes::from(format!(
+                        "server panicked: {}",
+                        msg
+                    )))
+                    .unwrap()
+            }
+        }
+    };
+    let mut resp = resp;
+    for map_response in &state.map_response {
+        (map_response.0)(&logged_req, &mut resp);
+    }
+    #[cfg(feature = "openapi")]
+    if let Some(openapi) = &state.openapi {
+        let violations = openapi.validate(&logged_req, &resp);
+        if !violations.is_empty() {
+            log::debug!("OpenAPI violations: {:?}", violations);
+            let mut inner = state.lock().unwrap_or_else(|e| e.into_inner());
+            inner.spec_violations.extend(violations);
+        }
+    }
+    let resp = match &state.chaos {
+        Some(chaos) => {
+            let injection = chaos.inject(&state.rng);
+            if let Some(delay) = injection.delay {
+                log::debug!("chaos: delaying response by {:?}", delay);
+                state.clock.0.sleep(delay).await;
+            }
+            match injection.fault {
+                Some(chaos::Fault::Error) => {
+                    log::debug!("chaos: replacing response with a 500");
+                    http::Response::builder()
+                        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                        .body("chaos: injected failure".into())
+                        .unwrap()
+                }
+                Some(chaos::Fault::Reset) => {
+                    log::debug!("chaos: resetting the connection");
+                    return Err(RequestAborted.into());
+                }
+                None => resp,
+            }
+        }
+        None => resp,
+    };
+    let mut resp = resp;
+    state.add_default_headers(&logged_req, &mut resp);
+    state.log_exchange(logged_req, &resp);
+
+    if let (Some(tunnel), Some(tunnels)) = (tunnel, tunnels) {
+        if resp.status().is_success() {
+            // served by the connection once the response has been sent.
+            let _ = tunnels.0.send(tunnel);
+        }
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = match parts.extensions.remove::<StreamChunks>() {
+        // the chunks no longer describe the body if a map_response hook
+        // changed its length, so it's sent whole.
+        Some(StreamChunks(chunks)) if chunks.last().map(|(end, _)| *end) == Some(body.len()) => {
+            // send each chunk as its own frame after its delay. Without a
+            // Content-Length the body is sent using chunked encoding.
+            let mut start = 0;
+            let frames: Vec<_> = chunks
+                .into_iter()
+                .map(|(end, delay)| {
+                    let chunk = body.slice(start..end);
+                    start = end;
+                    (chunk, delay)
+                })
+                .collect();
+            let clock = state.clock.clone();
+            let frames = futures::stream::StreamExt::then(
+                futures::stream::iter(frames),
+                move |(chunk, delay)| {
+                    let sleep = clock.0.sleep(delay);
+                    async move {
+                        sleep.await;
+                        Ok::<_, BoxError>(Frame::data(chunk))
+                    }
+                },
+            );
+            StreamBody::new(frames).boxed()
+        }
+        _ => match parts.extensions.remove::<Generated>() {
+            Some(generated) => generated_body(generated),
+            None => Full::new(body).map_err(|never| match never {}).boxed(),
+        },
+    };
+    let body = match parts.extensions.remove::<TruncateBodyAt>() {
+        Some(TruncateBodyAt(at)) => {
+            // advertise the full length when it's known, so the client sees
+            // fewer bytes than promised.
+            if let Some(len) = hyper::body::Body::size_hint(&body).exact() {
+                parts
+                    .headers
+                    .entry(http::header::CONTENT_LENGTH)
+                    .or_insert_with(|| len.into());
+            }
+            truncated_body(body, at)
+        }
+        None => body,
+    };
+    let body = match parts.extensions.remove::<DeclaredContentLength>() {
+        Some(DeclaredContentLength(len)) => {
+            parts
+                .headers
+                .insert(http::header::CONTENT_LENGTH, len.into());
+            mislabeled_body(body, len, injector.clone()).await?
+        }
+        None => body,
+    };
+    let body = match parts.extensions.remove::<Trailers>() {
+        Some(Trailers(trailers)) => body.with_trailers(async { Some(Ok(trailers)) }).boxed(),
+        None => body,
+    };
+    let framing = parts
+        .extensions
+        .remove::<BodyFraming>()
+        .map(|framing| framing.0);
+    let body = match framing {
+        Some(Framing::ContentLength) => {
+            let body = body.collect().await?.to_bytes();
+            parts
+                .headers
+                .insert(http::header::CONTENT_LENGTH, body.len().into());
+            Full::new(body).map_err(|never| match never {}).boxed()
+        }
+        Some(Framing::Chunked) if injector.is_some() => {
+            // hyper uses chunked encoding when the header is set, the body's
+            // size is hidden so that it doesn't add a Content-Length.
+            parts.headers.remove(http::header::CONTENT_LENGTH);
+            parts.headers.insert(
+                http::header::TRANSFER_ENCODING,
+                http::header::HeaderValue::from_static("chunked"),
+            );
+            StreamBody::new(http_body_util::BodyStream::new(body)).boxed()
+        }
+        Some(Framing::CloseDelimited) if injector.is_some() => {
+            parts.headers.remove(http::header::CONTENT_LENGTH);
+            parts.headers.remove(http::header::TRANSFER_ENCODING);
+            parts.headers.insert(
+                http::header::CONNECTION,
+                http::header::HeaderValue::from_static("close"),
+            );
+            body
+        }
+        _ => body,
+    };
+    let close_delimited = framing == Some(Framing::CloseDelimited);
+    let verbatim = match parts.extensions.remove::<VerbatimHeaders>() {
+        Some(VerbatimHeaders(verbatim)) => Some(verbatim),
+        // hyper can't send a close-delimited body over HTTP/1.1.
+        None if close_delimited => Some(Vec::new()),
+        None => None,
+    };
+    let (parts, body) = match (verbatim, injector) {
+        (Some(verbatim), Some(injector)) => {
+            let head = VerbatimHead {
+                method: req_method,
+                version: req_version,
+                verbatim,
+                close_delimited,
+            };
+            verbatim_response(&injector, head, parts, body).await?
+        }
+        (Some(verbatim), None) => {
+            for (name, value) in verbatim {
+                parts.headers.append(
+                    http::header::HeaderName::from_bytes(name.as_bytes())?,
+                    http::header::HeaderValue::from_str(&value)?,
+                );
+            }
+            (parts, body)
+        }
+        (None, _) => (parts, body),
+    };
+    let resp = hyper::Response::from_parts(parts, body);
+
+    log::debug!("Sending Response: {:?}", resp);
+    trace::record_status(resp.status());
+    Ok(resp)
+}
+
+// Stream the generated body in frames of the pattern repeated, so that only
+// a single frame's worth of the body is ever held in memory.
+fn generated_body(generated: Generated) -> BoxBody<hyper::body::Bytes, BoxError> {
+    const FRAME_SIZE: usize = 64 * 1024;
+    let Generated { len, pattern } = generated;
+    // every frame but the last is a whole number of patterns so each one
+    // picks up where the previous one left off.
+    let frame = pattern.repeat((FRAME_SIZE / pattern.len()).max(1));
+    let frame = hyper::body::Bytes::from(frame);
+    let frames = futures::stream::unfold(len, move |remaining| {
+        let frame = frame.clone();
+        async move {
+            if remaining == 0 {
+                return None;
+            }
+            let n = std::cmp::min(frame.len() as u64, remaining) as usize;
+            Some((
+                Ok::<_, BoxError>(Frame::data(frame.slice(..n))),
+                remaining - n as u64,
+            ))
+        }
+    });
+    StreamBody::new(frames).boxed()
+}
+
+// Forward the first `at` bytes of `body`, then fail the body so the
+// connection is closed. Bodies no longer than `at` are forwarded unchanged.
+fn truncated_body(
+    body: BoxBody<hyper::body::Bytes, BoxError>,
+    at: usize,
+) -> BoxBody<hyper::body::Bytes, BoxError> {
+    enum Truncating {
+        Forwarding(BoxBody<hyper::body::Bytes, BoxError>, usize),
+        Truncated,
+        Done,
+    }
+    let frames =
+        futures::stream::unfold(Truncating::Forwarding(body, at), move |state| async move {
+            let (mut body, remaining) = match state {
+                Truncating::Forwarding(body, remaining) => (body, remaining),
+                Truncating::Truncated => {
+                    // yield so the truncated portion is flushed to the client
+                    // before the error closes the connection.
+                    tokio::task::yield_now().await;
+                    let err: BoxError = Box::new(ResponseTruncated(at));
+                    return Some((Err(err), Truncating::Done));
+                }
+                Truncating::Done => return None,
+            };
+            let frame = match body.frame().await? {
+                Ok(frame) => frame,
+                Err(err) => return Some((Err(err), Truncating::Done)),
+            };
+            match frame.into_data() {
+                Ok(data) if data.len() > remaining => {
+                    log::debug!("truncating response body after {} bytes", at);
+                    let frame = Frame::data(data.slice(..remaining));
+                    Some((Ok(frame), Truncating::Truncated))
+                }
+                Ok(data) => {
+                    let remaining = remaining - data.len();
+                    Some((
+                        Ok(Frame::data(data)),
+                        Truncating::Forwarding(body, remaining),
+                    ))
+                }
+                Err(frame) => Some((Ok(frame), Truncating::Forwarding(body, remaining))),
+            }
+        });
+    StreamBody::new(frames).boxed()
+}
+
+// Send `body` with a Content-Length of `len`. A body shorter than `len` is
+// failed after it's sent, which closes the connection. The bytes of a longer
+// body beyond `len` are written by `injector` after hyper has written the
+// first `len` bytes, or dropped when there's no injector.
+async fn mislabeled_body(
+    body: BoxBody<hyper::body::Bytes, BoxError>,
+    len: u64,
+    injector: Option<Injector>,
+) -> Result<BoxBody<hyper::body::Bytes, BoxError>, BoxError> {
+    // injects the bytes beyond the declared length when hyper drops the body,
+    // which it does without polling for more once `len` bytes are written.
+    struct InjectOnDrop(Option<Injector>, hyper::body::Bytes);
+
+    impl Drop for InjectOnDrop {
+        fn drop(&mut self) {
+            if let (Some(injector), false) = (&self.0, self.1.is_empty()) {
+                log::debug!("writing {} bytes beyond the content-length", self.1.len());
+                injector.inject(&self.1);
+            }
+        }
+    }
+
+    let body = body.collect().await?.to_bytes();
+    let at = std::cmp::min(len, body.len() as u64) as usize;
+    let extra = InjectOnDrop(injector, body.slice(at..));
+    let mut frames: Vec<Result<_, BoxError>> = Vec::new();
+    if at > 0 {
+        frames.push(Ok(Frame::data(body.slice(..at))));
+    }
+    if at as u64 != len {
+        frames.push(Err(Box::new(ResponseTruncated(at))));
+    }
+    // yield before each frame so the body is flushed to the client before an
+    // error closes the connection.
+    let frames = futures::stream::StreamExt::then(futures::stream::iter(frames), move |frame| {
+        let _extra = &extra;
+        async {
+            tokio::task::yield_now().await;
+            frame
+        }
+    });
+    Ok(StreamBody::new(frames).boxed())
+}
+
+// What's needed to write a response head with verbatim header lines.
+struct VerbatimHead {
+    method: http::Method,
+    version: http::Version,
+    verbatim: Vec<(String, String)>,
+    // whether the body is ended by closing the connection.
+    close_delimited: bool,
+}
+
+// Write the response ourselves, so the verbatim header lines are sent exactly
+// as given and in order, after the headers in `parts`, and so the body can be
+// close-delimited. hyper is handed an empty response in its place, which the
+// injector discards.
+async fn verbatim_response(
+    injector: &Injector,
+    head: VerbatimHead,
+    parts: http::response::Parts,
+    body: BoxBody<hyper::body::Bytes, BoxError>,
+) -> Result<(http::response::Parts, BoxBody<hyper::body::Bytes, BoxError>), BoxError> {
+    let body = body.collect().await?.to_bytes();
+    let reason = match parts.extensions.get::<hyper::ext::ReasonPhrase>() {
+        Some(reason) => reason.as_bytes(),
+        None => parts.status.canonical_reason().unwrap_or("").as_bytes(),
+    };
+    let version = match head.version {
+        http::Version::HTTP_10 => "HTTP/1.0",
+        _ => "HTTP/1.1",
+    };
+    let mut raw = format!("{} {} ", version, parts.status.as_str()).into_bytes();
+    raw.extend_from_slice(reason);
+    raw.extend_from_slice(b"\r\n");
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes()))
+        .chain(
+            head.verbatim
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_bytes())),
+        );
+    let mut framed = false;
+    for (name, value) in headers {
+        framed |= name.eq_ignore_ascii_case(b"content-length")
+            || name.eq_ignore_ascii_case(b"transfer-encoding");
+        raw.extend_from_slice(name);
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(value);
+        raw.extend_from_slice(b"\r\n");
+    }
+    let has_body = !(head.method == http::Method::HEAD
+        || parts.status.is_informational()
+        || parts.status == http::StatusCode::NO_CONTENT
+        || parts.status == http::StatusCode::NOT_MODIFIED);
+    if !framed && has_body && !head.close_delimited {
+        raw.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+    }
+    raw.extend_from_slice(b"\r\n");
+    if has_body {
+        raw.extend_from_slice(&body);
+    }
+    log::debug!("writing response head with verbatim headers");
+    injector.replace_response(raw);
+
+    // hyper decides whether to keep the connection alive from the response it
+    // writes.
+    let mut replaced = http::Response::new(());
+    *replaced.status_mut() = parts.status;
+    let verbatim_connection = head
+        .verbatim
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .map(|(_, value)| http::header::HeaderValue::from_str(value));
+    for connection in parts
+        .headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .cloned()
+        .map(Ok)
+        .chain(verbatim_connection)
+    {
+        replaced
+            .headers_mut()
+            .append(http::header::CONNECTION, connection?);
+    }
+    let body = Full::new(hyper::body::Bytes::new())
+        .map_err(|never| match never {})
+        .boxed();
+    Ok((replaced.into_parts().0, body))
+}
+
+// When a request was received and how long after the request before it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestTiming {
+    pub(crate) received_at: Instant,
+    pub(crate) since_previous: Option<Duration>,
+}
+
+// The ALPN protocol negotiated on the connection a request was received on.
+#[derive(Debug, Clone)]
+pub(crate) struct NegotiatedProtocol(pub(crate) String);
+
+// The Content-Encoding of a request before its body was decompressed.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodedContentEncoding(pub(crate) String);
+
+// The target of the CONNECT tunnel a request was received through.
+#[derive(Debug, Clone)]
+pub(crate) struct TunnelTarget(pub(crate) http::uri::Authority);
+
+// Passes the CONNECT tunnels accepted by process_request back to the task
+// serving the connection they were requested on.
+#[derive(Clone)]
+struct Tunnels(
+    tokio::sync::mpsc::UnboundedSender<(http::uri::Authority, hyper::upgrade::OnUpgrade)>,
+);
+
+// Returning an error from the service closes the connection.
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+struct RequestAborted;
+
+impl fmt::Display for RequestAborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("request aborted while reading the body")
+    }
+}
+
+impl std::error::Error for RequestAborted {}
+
+#[derive(Debug)]
+struct ResponseTruncated(usize);
+
+impl fmt::Display for ResponseTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "response body truncated at {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for ResponseTruncated {}
+
+async fn on_req(state: ServerState, req: FullRequest) -> http::Response<hyper::body::Bytes> {
+    #[cfg(feature = "record")]
+    let recorder = state.recorder.clone();
+    // Matchers are evaluated without holding the state lock so that slow
+    // matchers don't hold up other connections or calls to expect().
+    let candidates = state.lock().expect("mutex poisoned").candidates();
+    let matched = candidates
+        .into_iter()
+        .find_map(|(id, mut matcher)| state.evaluate(&mut matcher, &req).then_some((id, matcher)));
+    // Only hold the lock to count the hit. The expectation may have been
+    // cleared or deactivated while its matcher was evaluated.
+    let hit = matched.and_then(|(id, matcher)| {
+        let mut inner = state.lock().expect("mutex poisoned");
+        let expectation = inner
+            .expected
+            .iter_mut()
+            .find(|expectation| expectation.id == id && expectation.active)?;
+        log::debug!("found matcher: {:?}", matcher_name(&matcher));
+        trace::record_expectation(&matcher_name(&matcher));
+        state.diagnose(|| DiagnosticEvent::ExpectationMatched {
+            matcher: format!("{:?}", matcher_name(&matcher)),
+        });
+        expectation.hit_count += 1;
+        if let Some(timing) = req.extensions().get::<RequestTiming>() {
+            expectation.hit_times.push(timing.received_at);
+        }
+        let on_match = expectation.on_match.clone();
+        let result = if !times_exceeded(expectation.times.1, expectation.hit_count) {
+            Ok(expectation.responder.clone())
+        } else {
+            Err(times_error(
+                &matcher,
+                expectation.times,
+                expectation.hit_count,
+            ))
+        };
+        Some((on_match, result))
+    });
+    // call the hook without holding the lock so it's free to use the server.
+    let hit = hit.map(|(on_match, result)| {
+        if let Some(on_match) = on_match {
+            (on_match.0)(&req);
+        }
+        result
+    });
+    let response_future = match hit {
+        Some(Ok(responder)) => {
+            let mut responder = responder.lock().unwrap_or_else(|e| e.into_inner());
+            Some(responder.respond(&req))
+        }
+        Some(Err(times_error)) => Some(times_error),
+        #[cfg(feature = "record")]
+        None if recorder.is_some() => {
+            log::debug!("forwarding unmatched request: {:?}", req);
+            let recorder = recorder.unwrap();
+            let forward: Pin<Box<dyn Future<Output = _> + Send>> =
+                Box::pin(async move { recorder.forward(req).await });
+            Some(forward)
+        }
+        None => {
+            log::debug!("no matcher found for request: {:?}", req);
+            state.record_unexpected(req);
+            None
+        }
+    };
+    if let Some(f) = response_future {
+        // wake anyone waiting on the expectation's hit count.
+        state.hits.send_modify(|_| {});
+        trace::instrument(f, trace::respond_span()).await
+    } else {
+        no_matcher_response()
+    }
+}
+
+fn no_matcher_response() -> http::Response<hyper::body::Bytes> {
+    http::Response::builder()
+        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+        .body("No matcher found".into())
+        .unwrap()
+}
+
+fn times_exceeded(end_bound: Bound<usize>, hit_count: usize) -> bool {
+    match end_bound {
+        Bound::Included(limit) if hit_count > limit => true,
+        Bound::Excluded(limit) if hit_count >= limit => true,
+        _ => false,
+    }
+}
+
+// The minimum number of requests an expectation expects.
+fn min_hits(times: (Bound<usize>, Bound<usize>)) -> usize {
+    match times.0 {
+        Bound::Included(min) => min,
+        Bound::Excluded(min) => min + 1,
+        Bound::Unbounded => 0,
+    }
+}
+
+fn hit_count_is_valid(bounds: (Bound<usize>, Bound<usize>), hit_count: usize) -> bool {
+    bounds.contains(&hit_count)
+}
+
+/// An expectation to be asserted by the server.
+pub struct Expectation {
+    // assigned when the expectation is added to a server.
+    id: u64,
+    matcher: SharedMatcher,
+    times: (Bound<usize>, Bound<usize>),
+    // shared so that requests can be responded to without holding the server
+    // state lock.
+    responder: Arc<Mutex<ExpectationResponder>>,
+    hit_count: usize,
+    // when each of the requests it matched was received.
+    hit_times: Vec<Instant>,
+    priority: i32,
+    // deactivated expectations no longer match requests but are still verified.
+    active: bool,
+    // added by Server::allow, left out of verification reports and hints.
+    allowed: bool,
+    on_match: Option<OnMatchHook>,
+}
+
+// How an expectation responds to the requests it matches.
+enum ExpectationResponder {
+    Single(Box<dyn Responder>),
+    // The first branch whose matcher matches the request responds.
+    Branches {
+        branches: Vec<Branch>,
+        // number of requests that didn't match any branch.
+        unmatched: usize,
+    },
+}
+
+// A matcher shared between the server state and in-flight requests, so that
+// requests can be matched without holding the server state lock.
+#[derive(Clone)]
+struct SharedMatcher(Arc<Mutex<Box<dyn Matcher<FullRequest>>>>);
+
+impl SharedMatcher {
+    fn new(matcher: Box<dyn Matcher<FullRequest>>) -> SharedMatcher {
+        SharedMatcher(Arc::new(Mutex::new(matcher)))
+    }
+}
+
+impl Matcher<FullRequest> for SharedMatcher {
+    fn matches(&mut self, input: &FullRequest, ctx: &mut ExecutionContext) -> bool {
+        // a panicking matcher poisons the mutex, but it can still be used.
+        let mut matcher = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        matcher.matches(input, ctx)
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matcher = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Matcher::fmt(&**matcher, f)
+    }
+}
+
+struct Branch {
+    matcher: Box<dyn Matcher<FullRequest>>,
+    responder: Box<dyn Responder>,
+    hit_count: usize,
+}
+
+impl Expectation {
+    /// What requests will this expectation match.
+    pub fn matching(matcher: impl Matcher<FullRequest> + 'static) -> ExpectationBuilder {
+        ExpectationBuilder {
+            matcher: Box::new(matcher),
+            // expect exactly one request by default.
+            times: (Bound::Included(1), Bound::Included(1)),
+            priority: 0,
+            on_match: None,
+        }
+    }
+}
+
+impl ExpectationResponder {
+    fn respond<'a>(
+        &mut self,
+        req: &'a FullRequest,
+    ) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'a>> {
+        match self {
+            ExpectationResponder::Single(responder) => responder.respond(req),
+            ExpectationResponder::Branches {
+                branches,
+                unmatched,
+            } => {
+                let branch = branches.iter_mut().find_map(|branch| {
+                    ExecutionContext::evaluate(branch.matcher.as_mut(), req).then_some(branch)
+                });
+                match branch {
+                    Some(branch) => {
+                        branch.hit_count += 1;
+                        branch.responder.respond(req)
+                    }
+                    None => {
+                        log::debug!("no branch found for request: {:?}", req);
+                        *unmatched += 1;
+                        Box::pin(async move {
+                            http::Response::builder()
+                                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                                .body("No branch matched".into())
+                                .unwrap()
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ExpectationResponder {
+    // The number of requests each branch responded to and the number that
+    // matched no branch, if there are branches.
+    fn branch_hits(&self) -> Option<(Vec<usize>, usize)> {
+        match self {
+            ExpectationResponder::Single(_) => None,
+            ExpectationResponder::Branches {
+                branches,
+                unmatched,
+            } => Some((
+                branches.iter().map(|branch| branch.hit_count).collect(),
+                *unmatched,
+            )),
+        }
+    }
+
+    fn set_branch_hits(&mut self, (hit_counts, new_unmatched): (Vec<usize>, usize)) {
+        if let ExpectationResponder::Branches {
+            branches,
+            unmatched,
+        } = self
+        {
+            for (branch, hit_count) in branches.iter_mut().zip(hit_counts) {
+                branch.hit_count = hit_count;
+            }
+            *unmatched = new_unmatched;
+        }
+    }
+}
+
+impl Expectation {
+    fn responder(&self) -> std::sync::MutexGuard<'_, ExpectationResponder> {
+        self.responder.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // A copy of the expectation that shares its matcher and responder.
+    fn share(&self) -> Expectation {
+        Expectation {
+            id: self.id,
+            matcher: self.matcher.clone(),
+            times: self.times,
+            responder: self.responder.clone(),
+            hit_count: self.hit_count,
+            hit_times: self.hit_times.clone(),
+            priority: self.priority,
+            active: self.active,
+            allowed: self.allowed,
+            on_match: self.on_match.clone(),
+        }
+    }
+
+    // Describe why this expectation is not satisfied, or None if it is.
+    fn verification_error(&self) -> Option<String> {
+        if !hit_count_is_valid(self.times, self.hit_count) {
+            return Some(format!(
+                "Unexpected number of requests for matcher '{:?}'; received {}; expected {}",
+                matcher_name(&self.matcher),
+                self.hit_count,
+                RangeDisplay(self.times),
+            ));
+        }
+        if let ExpectationResponder::Branches {
+            branches,
+            unmatched,
+        } = &*self.responder()
+        {
+            if *unmatched > 0 {
+                return Some(format!(
+                    "{} requests matched '{:?}' but none of its branches",
+                    unmatched,
+                    matcher_name(&self.matcher),
+                ));
+            }
+            if let Some(branch) = branches.iter().find(|branch| branch.hit_count == 0) {
+                return Some(format!(
+                    "Branch '{:?}' of matcher '{:?}' did not receive any requests",
+                    matcher_name(&*branch.matcher),
+                    matcher_name(&self.matcher),
+                ));
+            }
+        }
+        None
+    }
+
+    // The number of requests handled by each branch of the responder, if it
+    // has branches.
+    fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        match &*self.responder() {
+            ExpectationResponder::Single(responder) => responder.branch_hit_counts(),
+            ExpectationResponder::Branches { branches, .. } => {
+                Some(branches.iter().map(|branch| branch.hit_count).collect())
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "matcher": format!("{:?}", matcher_name(&self.matcher)),
+            "times": RangeDisplay(self.times).to_string(),
+            "hit_count": self.hit_count,
+            "satisfied": self.verification_error().is_none(),
+            "active": self.active,
+        });
+        if self.allowed {
+            json["allowed"] = true.into();
+        }
+        match &*self.responder() {
+            ExpectationResponder::Branches {
+                branches,
+                unmatched,
+            } => {
+                let branches: Vec<_> = branches
+                    .iter()
+                    .map(|branch| {
+                        serde_json::json!({
+                            "matcher": format!("{:?}", matcher_name(&*branch.matcher)),
+                            "hit_count": branch.hit_count,
+                        })
+                    })
+                    .collect();
+                json["branches"] = branches.into();
+                json["unmatched_branch_requests"] = (*unmatched).into();
+            }
+            ExpectationResponder::Single(responder) => {
+                if let Some(hit_counts) = responder.branch_hit_counts() {
+                    json["branch_hit_counts"] = hit_counts.into();
+                }
+            }
+        }
+        json
+    }
+}
+
+impl fmt::Debug for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("Expectation");
+        f.field("matcher", &matcher_name(&self.matcher))
+            .field("times", &self.times)
+            .field("hit_count", &self.hit_count)
+            .field("priority", &self.priority)
+            .field("active", &self.active)
+            .field("allowed", &self.allowed);
+        match &*self.responder() {
+            ExpectationResponder::Branches { branches, .. } => {
+                let branches: Vec<_> = branches
+                    .iter()
+                    .map(|branch| (matcher_name(&*branch.matcher), branch.hit_count))
+                    .collect();
+                f.field("branches", &branches);
+            }
+            ExpectationResponder::Single(responder) => {
+                if let Some(hit_counts) = responder.branch_hit_counts() {
+                    f.field("branch_hit_counts", &hit_counts);
+                }
+            }
+        }
+        f.finish()
+    }
+}
+
+/// A handle to an expectation that has been added to a server.
+///
+/// Returned by [Server::expect](struct.Server.html#method.expect).
+#[derive(Debug, Clone)]
+pub struct ExpectationHandle {
+    state: ServerState,
+    id: u64,
+    times: (Bound<usize>, Bound<usize>),
+}
+
+impl ExpectationHandle {
+    /// The number of requests the expectation has received so far.
+    ///
+    /// Panics if the expectation has already been cleared from the server.
+    pub fn hit_count(&self) -> usize {
+        self.with_expectation(|expectation| expectation.hit_count)
+    }
+
+    /// When each of the requests the expectation matched was received, in the
+    /// order they were matched.
+    ///
+    /// Panics if the expectation has already been cleared from the server.
+    pub fn hit_times(&self) -> Vec<Instant> {
+        self.with_expectation(|expectation| expectation.hit_times.clone())
+    }
+
+    /// The time between each of the requests the expectation matched and the
+    /// one before it. Useful for checking a client's backoff schedule.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// # use std::time::Duration;
+    /// # async fn example(server: Server) {
+    /// let upload = server.expect(
+    ///     Expectation::matching(request::path("/upload"))
+    ///         .times(2)
+    ///         .respond_with(cycle![status_code(503), status_code(200)]),
+    /// );
+    /// // ... the client uploads, and retries after a failure ...
+    /// assert!(upload.hit_intervals()[0] >= Duration::from_millis(500));
+    /// # }
+    /// ```
+    ///
+    /// Panics if the expectation has already been cleared from the server.
+    pub fn hit_intervals(&self) -> Vec<Duration> {
+        let times = self.hit_times();
+        times
+            .windows(2)
+            .map(|w| w[1].saturating_duration_since(w[0]))
+            .collect()
+    }
+
+    /// The number of requests the expectation was configured to receive.
+    pub fn times(&self) -> (Bound<usize>, Bound<usize>) {
+        self.times
+    }
+
+    /// The number of requests handled by each branch of the expectation's
+    /// responder. Returns None if the responder doesn't have branches.
+    ///
+    /// Branches are created by `respond_with_map` or the `cycle` responder.
+    ///
+    /// Panics if the expectation has already been cleared from the server.
+    pub fn branch_hit_counts(&self) -> Option<Vec<usize>> {
+        self.with_expectation(|expectation| expectation.branch_hit_counts())
+    }
+
+    /// Wait until the expectation has received the minimum number of
+    /// requests it was configured to receive.
+    ///
+    /// Panics if the expectation is cleared from the server while waiting.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// # async fn example(server: Server) {
+    /// let callback = server.expect(
+    ///     Expectation::matching(request::path("/callback")).respond_with(status_code(200)),
+    /// );
+    /// // ... kick off something that eventually calls /callback ...
+    /// callback.wait().await;
+    /// # }
+    /// ```
+    pub async fn wait(&self) {
+        let min = min_hits(self.times);
+        // subscribe before checking the hit count so that a request arriving
+        // in between isn't missed.
+        let mut hits = self.state.hits.subscribe();
+        while self.hit_count() < min {
+            hits.changed()
+                .await
+                .expect("sender is owned by the server state");
+        }
+    }
+
+    /// Like `wait`, but gives up after `timeout`.
+    pub async fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitTimeout> {
+        self.state
+            .runtime
+            .timeout(timeout, self.wait())
+            .await
+            .ok_or_else(|| WaitTimeout {
+                timeout,
+                hit_count: self.hit_count(),
+                times: self.times,
+            })
+    }
+
+    /// Stop the expectation from matching any more requests. The requests it
+    /// has already received are still verified against the number of requests
+    /// it expects.
+    ///
+    /// Panics if the expectation has already been cleared from the server.
+    pub fn deactivate(&self) {
+        log::debug!("expectation deactivated: {}", self.id);
+        self.with_expectation(|expectation| expectation.active = false)
+    }
+
+    /// Deactivate this expectation and add `expectation` in its place. The new
+    /// expectation is matched against requests in the same order as this one
+    /// was, rather than before all other expectations as with
+    /// [Server::expect](struct.Server.html#method.expect).
+    ///
+    /// Panics if this expectation has already been cleared from the server.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// let status = server.expect(
+    ///     Expectation::matching(request::path("/status"))
+    ///         .times(..)
+    ///         .respond_with(status_code(503)),
+    /// );
+    /// // ... phase one: the service is unavailable ...
+    /// let status = status.replace(
+    ///     Expectation::matching(request::path("/status"))
+    ///         .times(..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// ```
+    pub fn replace(&self, expectation: Expectation) -> ExpectationHandle {
+        log::debug!("expectation {} replaced by: {:?}", self.id, expectation);
+        self.state.add_expectation(expectation, Some(self.id))
+    }
+
+    /// Require this expectation to receive a request before `later` receives
+    /// any. Verified along with the number of requests each expectation
+    /// receives; it's not an error if `later` receives no requests at all.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// let token = server.expect(
+    ///     Expectation::matching(request::method_path("POST", "/token"))
+    ///         .times(..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// let data = server.expect(
+    ///     Expectation::matching(request::path("/data"))
+    ///         .times(..)
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// token.happens_before(&data);
+    /// ```
+    pub fn happens_before(&self, later: &ExpectationHandle) {
+        log::debug!("expectation {} happens before {}", self.id, later.id);
+        let mut inner = self.state.lock().expect("mutex poisoned");
+        inner.orderings.push((self.id, later.id));
+    }
+
+    /// Verify just this expectation when the returned guard is dropped,
+    /// rather than when the server is verified. The expectation is removed
+    /// from the server at the same time. This lets a helper function own the
+    /// expectations it creates.
+    ///
+    /// ```
+    /// # use httptest::{ExpectationGuard, Server, Expectation, matchers::*, responders::*};
+    /// fn expect_login(server: &Server) -> ExpectationGuard {
+    ///     server
+    ///         .expect(
+    ///             Expectation::matching(request::method_path("POST", "/login"))
+    ///                 .respond_with(status_code(200)),
+    ///         )
+    ///         .verify_on_drop()
+    /// }
+    /// ```
+    pub fn verify_on_drop(self) -> ExpectationGuard {
+        ExpectationGuard(self)
+    }
+
+    fn with_expectation<T>(&self, f: impl FnOnce(&mut Expectation) -> T) -> T {
+        let mut inner = self.state.lock().expect("mutex poisoned");
+        let expectation = inner
+            .expected
+            .iter_mut()
+            .find(|expectation| expectation.id == self.id)
+            .expect("expectation has been cleared from the server");
+        f(expectation)
+    }
+}
+
+/// Verifies an expectation when dropped.
+///
+/// Created by [ExpectationHandle::verify_on_drop](struct.ExpectationHandle.html#method.verify_on_drop).
+/// Dereferences to the expectation's handle.
+#[derive(Debug)]
+pub struct ExpectationGuard(ExpectationHandle);
+
+impl std::ops::Deref for ExpectationGuard {
+    type Target = ExpectationHandle;
+
+    fn deref(&self) -> &ExpectationHandle {
+        &self.0
+    }
+}
+
+impl Drop for ExpectationGuard {
+    fn drop(&mut self) {
+        let expectation = {
+            let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+            let idx = state
+                .expected
+                .iter()
+                .position(|expectation| expectation.id == self.0.id);
+            idx.map(|idx| state.expected.remove(idx))
+        };
+        if std::thread::panicking() {
+            return;
+        }
+        // the expectation may have been cleared by the server already.
+        let failures = expectation
+            .and_then(|e| e.verification_error())
+            .into_iter()
+            .collect();
+        report_failures(
+            failures,
+            false,
+            self.0.state.on_verification_failure.as_ref(),
+        );
+    }
+}
+
+/// The error returned when
+/// [ExpectationHandle::wait_timeout](struct.ExpectationHandle.html#method.wait_timeout)
+/// times out.
+#[derive(Debug, Clone)]
+pub struct WaitTimeout {
+    timeout: Duration,
+    hit_count: usize,
+    times: (Bound<usize>, Bound<usize>),
+}
+
+impl fmt::Display for WaitTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} waiting for requests; received {}; expected {}",
+            self.timeout,
+            self.hit_count,
+            RangeDisplay(self.times)
+        )
+    }
+}
+
+impl std::error::Error for WaitTimeout {}
+
+/// Define expectations using a builder pattern.
+pub struct ExpectationBuilder {
+    matcher: Box<dyn Matcher<FullRequest>>,
+    times: (Bound<usize>, Bound<usize>),
+    priority: i32,
+    on_match: Option<OnMatchHook>,
+}
+
+impl ExpectationBuilder {
+    /// Expect this many requests.
+    ///
+    /// ```
+    /// # use httptest::{Expectation, matchers::any, responders::status_code};
+    /// // exactly 2 requests
+    /// Expectation::matching(any()).times(2).respond_with(status_code(200));
+    /// // at least 2 requests
+    /// Expectation::matching(any()).times(2..).respond_with(status_code(200));
+    /// // at most 2 requests
+    /// Expectation::matching(any()).times(..=2).respond_with(status_code(200));
+    /// // between 2 and 5 inclusive
+    /// Expectation::matching(any()).times(2..6).respond_with(status_code(200));
+    /// // equivalently
+    /// Expectation::matching(any()).times(2..=5).respond_with(status_code(200));
+    /// ```
+    pub fn times<R>(self, times: R) -> ExpectationBuilder
+    where
+        R: crate::into_times::IntoTimes,
+    {
+        ExpectationBuilder {
+            times: times.into_times(),
+            ..self
+        }
+    }
+
+    /// Set the priority of this expectation. Expectations with a higher
+    /// priority are matched against requests before those with a lower
+    /// priority. Expectations with equal priority are matched most recently
+    /// added first. The default priority is 0.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// // A catch-all that only responds to requests no other expectation
+    /// // matches, even those added before it.
+    /// server.expect(
+    ///     Expectation::matching(any())
+    ///         .times(..)
+    ///         .priority(-1)
+    ///         .respond_with(status_code(404)),
+    /// );
+    /// ```
+    pub fn priority(self, priority: i32) -> ExpectationBuilder {
+        ExpectationBuilder { priority, ..self }
+    }
+
+    /// Call `f` with each request the expectation matches, before it's
+    /// responded to.
+    ///
+    /// ```
+    /// # use httptest::{Server, Expectation, matchers::*, responders::*};
+    /// let server = Server::run();
+    /// let (tx, rx) = std::sync::mpsc::channel();
+    /// let tx = std::sync::Mutex::new(tx);
+    /// server.expect(
+    ///     Expectation::matching(request::path("/events"))
+    ///         .times(..)
+    ///         .on_match(move |req| tx.lock().unwrap().send(req.body().clone()).unwrap())
+    ///         .respond_with(status_code(200)),
+    /// );
+    /// ```
+    pub fn on_match<F>(self, f: F) -> ExpectationBuilder
+    where
+        F: Fn(&FullRequest) + Send + Sync + 'static,
+    {
+        ExpectationBuilder {
+            on_match: Some(OnMatchHook(Arc::new(f))),
+            ..self
+        }
+    }
+
+    /// What should this expectation respond with.
+    pub fn respond_with(self, responder: impl Responder + 'static) -> Expectation {
+        Expectation {
+            id: 0,
+            matcher: SharedMatcher::new(self.matcher),
+            times: self.times,
+            responder: Arc::new(Mutex::new(ExpectationResponder::Single(Box::new(
+                responder,
+            )))),
+            hit_count: 0,
+            hit_times: Vec::new(),
+            priority: self.priority,
+            active: true,
+            allowed: false,
+            on_match: self.on_match,
+        }
+    }
+
+    /// Respond using the first of the provided branches whose matcher matches
+    /// the request. See the `branches!` macro for convenient usage.
+    ///
+    /// Branch matchers are only evaluated against requests that already
+    /// matched this expectation. In addition to the expected number of
+    /// requests, verification requires that every branch responded to at
+    /// least one request and that no request failed to match any branch.
+    /// Requests that don't match any branch receive a 500 response.
+    ///
+    /// ```
+    /// use httptest::{branches, Expectation, matchers::*, responders::*};
+    ///
+    /// // Expect two requests to /search, one for each page.
+    /// Expectation::matching(request::path("/search"))
+    ///     .times(2)
+    ///     .respond_with_map(branches![
+    ///         request::query(url_decoded(contains(("page", "1")))) => status_code(200),
+    ///         request::query(url_decoded(contains(("page", "2")))) => status_code(404),
+    ///     ]);
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn respond_with_map(
+        self,
+        branches: Vec<(Box<dyn Matcher<FullRequest>>, Box<dyn Responder>)>,
+    ) -> Expectation {
+        if branches.is_empty() {
+            panic!("empty vector provided to respond_with_map");
+        }
+        let branches = branches
+            .into_iter()
+            .map(|(matcher, responder)| Branch {
+                matcher,
+                responder,
+                hit_count: 0,
+            })
+            .collect();
+        Expectation {
+            id: 0,
+            matcher: SharedMatcher::new(self.matcher),
+            times: self.times,
+            responder: Arc::new(Mutex::new(ExpectationResponder::Branches {
+                branches,
+                unmatched: 0,
+            })),
+            hit_count: 0,
+            hit_times: Vec::new(),
+            priority: self.priority,
+            active: true,
+            allowed: false,
+            on_match: self.on_match,
+        }
+    }
+}
+
+/// Progress of a request body being received by the server. Passed to the
+/// [upload_progress](struct.ServerBuilder.html#method.upload_progress) hook.
+#[derive(Debug)]
+pub struct UploadProgress<'a> {
+    request: &'a http::request::Parts,
+    bytes_received: usize,
+    chunk_len: usize,
+}
+
+impl UploadProgress<'_> {
+    /// The method, uri and headers of the request being received.
+    pub fn request(&self) -> &http::request::Parts {
+        self.request
+    }
+
+    /// The number of body bytes received so far, including the latest chunk.
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    /// The number of bytes in the latest chunk.
+    pub fn chunk_len(&self) -> usize {
+        self.chunk_len
+    }
+}
+
+/// What the server should do after receiving part of a request body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadAction {
+    /// Keep reading the request.
+    Continue,
+    /// Close the connection without responding.
+    Abort,
+}
+
+type UploadProgressFn = dyn Fn(&UploadProgress) -> UploadAction + Send + Sync;
+
+type OnMatchFn = dyn Fn(&FullRequest) + Send + Sync;
+
+type MapRequestFn = dyn Fn(&mut FullRequest) + Send + Sync;
+
+#[derive(Clone)]
+struct MapRequestHook(Arc<MapRequestFn>);
+
+impl fmt::Debug for MapRequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MapRequestHook")
+    }
+}
+
+type MapResponseFn = dyn Fn(&FullRequest, &mut http::Response<hyper::body::Bytes>) + Send + Sync;
+
+#[derive(Clone)]
+struct MapResponseHook(Arc<MapResponseFn>);
+
+impl fmt::Debug for MapResponseHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MapResponseHook")
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RequestSink(tokio::sync::mpsc::UnboundedSender<FullRequest>);
+
+#[derive(Clone)]
+struct OnMatchHook(Arc<OnMatchFn>);
+
+#[derive(Clone)]
+struct UploadProgressHook(Arc<UploadProgressFn>);
+
+impl fmt::Debug for UploadProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("UploadProgressHook")
+    }
+}
+
+/// A connection accepted by the server. Passed to the
+/// [on_connection_accepted](struct.ServerBuilder.html#method.on_connection_accepted)
+/// and [on_connection_closed](struct.ServerBuilder.html#method.on_connection_closed)
+/// hooks, and added to the extensions of every request received on the
+/// connection so requests can be correlated with their connection.
+///
+/// ```
+/// # use httptest::{ConnectionInfo, Expectation, matchers::*};
+/// // Only match requests from the first connection.
+/// Expectation::matching(|req: &http::Request<bytes::Bytes>| {
+///     req.extensions().get::<ConnectionInfo>().map(|conn| conn.id()) == Some(0)
+/// });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    id: u64,
+    peer_addr: SocketAddr,
+}
+
+impl ConnectionInfo {
+    /// An id for the connection that's unique within the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The address of the client.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+type ConnectionFn = dyn Fn(&ConnectionInfo) + Send + Sync;
+
+#[derive(Clone)]
+struct ConnectionHook(Arc<ConnectionFn>);
+
+impl fmt::Debug for ConnectionHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConnectionHook")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ServerState {
+    inner: Arc<Mutex<ServerStateInner>>,
+    // connections are tracked independently of the expectations so they
+    // survive verify_and_clear.
+    connections: Connections,
+    // ids are never reused so a handle can't refer to a newer expectation
+    // after verify_and_clear.
+    next_expectation_id: Arc<AtomicU64>,
+    upload_progress: Option<UploadProgressHook>,
+    abort_after_bytes: Option<usize>,
+    // bytes of a request body to read per interval.
+    slow_body_read: Option<(usize, Duration)>,
+    #[cfg(feature = "decompress")]
+    decompress_request_bodies: bool,
+    match_before_body: bool,
+    connection_accepted: Option<ConnectionHook>,
+    connection_closed: Option<ConnectionHook>,
+    diagnostics: Option<diagnostics::Sink>,
+    request_sink: Option<RequestSink>,
+    // None keeps every exchange.
+    max_exchanges: Option<usize>,
+    chaos: Option<Chaos>,
+    rng: Arc<Rng>,
+    map_request: Vec<MapRequestHook>,
+    map_response: Vec<MapResponseHook>,
+    default_headers: http::HeaderMap,
+    request_id_header: Option<http::header::HeaderName>,
+    next_request_id: Arc<AtomicU64>,
+    clock: ServerClock,
+    runtime: ServerRuntime,
+    on_verification_failure: Option<FailureHook>,
+    // notified whenever an expectation's hit count changes.
+    hits: Arc<tokio::sync::watch::Sender<()>>,
+    #[cfg(feature = "record")]
+    recorder: Option<crate::record::Recorder>,
+    #[cfg(feature = "openapi")]
+    openapi: Option<Arc<crate::openapi::OpenApi>>,
+}
+
+impl ServerState {
+    fn lock(&self) -> std::sync::LockResult<std::sync::MutexGuard<'_, ServerStateInner>> {
+        self.inner.lock()
+    }
+
+    // Add the configured default headers that the response doesn't already
+    // have.
+    fn add_default_headers(
+        &self,
+        req: &FullRequest,
+        resp: &mut http::Response<hyper::body::Bytes>,
+    ) {
+        let headers = resp.headers_mut();
+        for name in self.default_headers.keys() {
+            if !headers.contains_key(name) {
+                for value in self.default_headers.get_all(name) {
+                    headers.append(name, value.clone());
+                }
+            }
+        }
+        if let Some(name) = &self.request_id_header {
+            if !headers.contains_key(name) {
+                let id = match req.headers().get(name) {
+                    Some(id) => id.clone(),
+                    None => self.next_request_id.fetch_add(1, Ordering::Relaxed).into(),
+                };
+                headers.insert(name, id);
+            }
+        }
+    }
+
+    // Deliver a diagnostic event to the sink, if there is one.
+    fn diagnose(&self, event: impl FnOnce() -> DiagnosticEvent) {
+        if let Some(sink) = &self.diagnostics {
+            sink.emit(event());
+        }
+    }
+
+    // Evaluate the matcher, delivering the evaluation of each of its
+    // sub-matchers to the diagnostics sink.
+    fn evaluate(&self, matcher: &mut SharedMatcher, req: &FullRequest) -> bool {
+        let sink = match &self.diagnostics {
+            Some(sink) => sink,
+            None => return ExecutionContext::evaluate(matcher, req),
+        };
+        let (x, events) = ExecutionContext::evaluate_with_events(matcher, req);
+        for event in events {
+            sink.emit(event);
+        }
+        x
+    }
+
+    // Record that a request was received.
+    fn request_received(&self) -> RequestTiming {
+        let received_at = Instant::now();
+        let mut inner = self.lock().unwrap_or_else(|e| e.into_inner());
+        let since_previous = inner
+            .request_times
+            .last()
+            .map(|previous| received_at.saturating_duration_since(*previous));
+        inner.request_times.push(received_at);
+        RequestTiming {
+            received_at,
+            since_previous,
+        }
+    }
+
+    fn record_unexpected(&self, req: FullRequest) {
+        let request = format!("{} {}", req.method(), req.uri());
+        // user matchers are evaluated without holding the server state lock.
+        let matchers: Vec<SharedMatcher> = self
+            .lock()
+            .expect("mutex poisoned")
+            .expected
+            .iter()
+            .filter(|expectation| expectation.active && !expectation.allowed)
+            .map(|expectation| expectation.matcher.clone())
+            .collect();
+        let closest = closest_expectation(matchers, &req);
+        if let Some(closest) = &closest {
+            log::debug!("closest expectation: {}", closest);
+        }
+        trace::unexpected_request(closest.as_ref().map(|c| c as &dyn fmt::Display));
+        let description = closest.as_ref().map(ClosestMatch::to_string);
+        self.lock()
+            .expect("mutex poisoned")
+            .unexpected_requests
+            .push(UnexpectedRequest {
+                request: req,
+                closest,
+            });
+        self.diagnose(|| DiagnosticEvent::UnexpectedRequest {
+            request,
+            closest: description,
+        });
+    }
+
+    // Determine whether any expectation could match a request with the given
+    // head, regardless of its body.
+    fn head_could_match(&self, head: &http::request::Parts) -> bool {
+        #[cfg(feature = "record")]
+        if self.recorder.is_some() {
+            // unmatched requests are forwarded along with their body.
+            return true;
+        }
+        let mut req = http::Request::new(hyper::body::Bytes::new());
+        *req.method_mut() = head.method.clone();
+        *req.uri_mut() = head.uri.clone();
+        *req.version_mut() = head.version;
+        *req.headers_mut() = head.headers.clone();
+        *req.extensions_mut() = head.extensions.clone();
+        // user matchers are evaluated without holding the server state lock.
+        let matchers: Vec<SharedMatcher> = self
+            .lock()
+            .expect("mutex poisoned")
+            .expected
+            .iter()
+            .filter(|expectation| expectation.active)
+            .map(|expectation| expectation.matcher.clone())
+            .collect();
+        matchers
+            .into_iter()
+            .any(|mut matcher| ExecutionContext::could_match_head(&mut matcher, &req))
+    }
+
+    // Record a panic that occurred on a server thread and return its message.
+    fn record_panic(&self, payload: Box<dyn std::any::Any + Send>) -> String {
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+        let thread = std::thread::current();
+        let msg = format!("thread '{}': {}", thread.name().unwrap_or("<unnamed>"), msg);
+        // A panic while matching may have poisoned the mutex, the panic
+        // message is still worth recording.
+        let mut inner = self.lock().unwrap_or_else(|e| e.into_inner());
+        inner.server_panics.push(msg.clone());
+        msg
+    }
+
+    fn log_exchange(&self, request: FullRequest, response: &http::Response<hyper::body::Bytes>) {
+        let mut builder = http::Response::builder()
+            .status(response.status())
+            .version(response.version());
+        *builder.headers_mut().unwrap() = response.headers().clone();
+        let response = builder.body(response.body().clone()).unwrap();
+        let mut inner = self.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(max) = self.max_exchanges {
+            if max == 0 {
+                return;
+            }
+            while inner.exchanges.len() >= max {
+                inner.exchanges.pop_front();
+            }
+        }
+        inner.exchanges.push_back(Exchange { request, response });
+    }
+
+    // Wait until every active expectation has received the minimum number of
+    // requests it expects.
+    async fn wait_for_min_hits(&self) {
+        let mut hits = self.hits.subscribe();
+        while !self.lock().expect("mutex poisoned").min_hits_reached() {
+            if hits.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn push_expectation(&self, expectation: Expectation) -> ExpectationHandle {
+        self.add_expectation(expectation, None)
+    }
+
+    // Add the expectation. If `replacing` is provided the expectation with
+    // that id is deactivated and the new one takes its place in the order
+    // expectations are matched.
+    fn add_expectation(
+        &self,
+        mut expectation: Expectation,
+        replacing: Option<u64>,
+    ) -> ExpectationHandle {
+        let id = self.next_expectation_id.fetch_add(1, Ordering::Relaxed);
+        expectation.id = id;
+        let handle = ExpectationHandle {
+            state: self.clone(),
+            id,
+            times: expectation.times,
+        };
+        let mut inner = self.lock().expect("mutex poisoned");
+        let idx = match replacing {
+            Some(replacing) => {
+                let idx = inner
+                    .expected
+                    .iter()
+                    .position(|expectation| expectation.id == replacing)
+                    .expect("expectation has been cleared from the server");
+                inner.expected[idx].active = false;
+                idx + 1
+            }
+            None => inner.expected.len(),
+        };
+        inner.expected.insert(idx, expectation);
+        handle
+    }
+}
+
+#[derive(Debug, Default)]
+struct ServerStateInner {
+    unexpected_requests: Vec<UnexpectedRequest>,
+    expected: Vec<Expectation>,
+    server_panics: Vec<String>,
+    // every request received and the response sent, in the order the
+    // responses were produced. Only the most recent max_exchanges are kept.
+    exchanges: VecDeque<Exchange>,
+    // exchanges that don't conform to the OpenAPI spec being validated against.
+    spec_violations: Vec<String>,
+    // when each request was received, in the order they were received.
+    request_times: Vec<Instant>,
+    // pairs of expectation ids where the first must receive a request before
+    // the second receives any.
+    orderings: Vec<(u64, u64)>,
+}
+
+#[derive(Debug)]
+struct Exchange {
+    request: FullRequest,
+    response: http::Response<hyper::body::Bytes>,
+}
+
+impl Exchange {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "request": request_json(&self.request),
+            "response": {
+                "status": self.response.status().as_u16(),
+                "headers": headers_json(self.response.headers()),
+                "body": String::from_utf8_lossy(self.response.body()),
+            },
+        })
+    }
+}
+
+impl ServerStateInner {
+    fn min_hits_reached(&self) -> bool {
+        self.expected
+            .iter()
+            .filter(|expectation| expectation.active)
+            .all(|expectation| expectation.hit_count >= min_hits(expectation.times))
+    }
+
+    // The ids and matchers of the active expectations, in the order they
+    // should be evaluated.
+    fn candidates(&self) -> Vec<(u64, SharedMatcher)> {
+        // Highest priority first, then most recently added first. The sort is
+        // stable so reversing first preserves the insertion order for ties.
+        let mut candidates: Vec<&Expectation> = self
+            .expected
+            .iter()
+            .rev()
+            .filter(|expectation| expectation.active)
+            .collect();
+        candidates.sort_by_key(|expectation| std::cmp::Reverse(expectation.priority));
+        candidates
+            .into_iter()
+            .map(|expectation| (expectation.id, expectation.matcher.clone()))
+            .collect()
+    }
+}
+
+// Find the matcher, of those given in the order their expectations were
+// added, where the most sub-matchers matched the request. Returns None if no
+// matcher matched any part of the request.
+fn closest_expectation(matchers: Vec<SharedMatcher>, req: &FullRequest) -> Option<ClosestMatch> {
+    matchers
+        .into_iter()
+        .map(|mut matcher| {
+            let closeness = ExecutionContext::closeness(&mut matcher, req);
+            (closeness, matcher)
+        })
+        .filter(|(closeness, _)| closeness.matched > 0)
+        // max_by_key returns the last max element, so ties favor the most
+        // recently added expectation just like request matching does.
+        .max_by_key(|(closeness, _)| closeness.matched)
+        .map(|(closeness, matcher)| ClosestMatch {
+            matcher: format!("{:?}", matcher_name(&matcher)),
+            matched: closeness.matched,
+            evaluated: closeness.evaluated,
+            mismatches: closeness.mismatches,
+        })
+}
+
+// Tracks the open connections and the requests in-flight on each of them.
+#[derive(Debug, Clone, Default)]
+struct Connections(Arc<Mutex<ConnectionsInner>>);
+
+#[derive(Debug, Default)]
+struct ConnectionsInner {
+    next_id: u64,
+    open: BTreeMap<u64, OpenConnection>,
+    // the number of requests received on every connection accepted, including
+    // those that have closed.
+    requests: BTreeMap<u64, usize>,
+}
+
+#[derive(Debug)]
+struct OpenConnection {
+    peer_addr: SocketAddr,
+    in_flight: BTreeMap<u64, String>,
+}
+
+impl Connections {
+    // Record a newly accepted connection. The connection is considered open
+    // until the returned guard is dropped.
+    fn opened(&self, peer_addr: SocketAddr) -> ConnectionGuard {
+        let mut inner = self.0.lock().expect("mutex poisoned");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.open.insert(
+            id,
+            OpenConnection {
+                peer_addr,
+                in_flight: BTreeMap::new(),
+            },
+        );
+        inner.requests.insert(id, 0);
+        ConnectionGuard {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    // Record a request received on the connection. The request is considered
+    // in-flight until the returned guard is dropped.
+    fn request_started(&self, conn_id: u64, description: String) -> RequestGuard {
+        let mut inner = self.0.lock().expect("mutex poisoned");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if let Some(conn) = inner.open.get_mut(&conn_id) {
+            conn.in_flight.insert(id, description);
+        }
+        if let Some(requests) = inner.requests.get_mut(&conn_id) {
+            *requests += 1;
+        }
+        RequestGuard {
+            connections: self.clone(),
+            conn_id,
+            id,
+        }
+    }
+
+    fn in_flight_count(&self) -> usize {
+        let inner = self.0.lock().expect("mutex poisoned");
+        inner.open.values().map(|conn| conn.in_flight.len()).sum()
+    }
+
+    fn metrics(&self) -> ConnectionMetrics {
+        let inner = self.0.lock().expect("mutex poisoned");
+        ConnectionMetrics {
+            open: inner.open.len(),
+            requests_per_connection: inner.requests.values().copied().collect(),
+        }
+    }
+}
+
+/// Expectations saved by
+/// [Server::save_expectations](struct.Server.html#method.save_expectations).
+#[derive(Debug)]
+pub struct SavedExpectations {
+    expectations: Vec<Expectation>,
+    // each expectation's branch hit counts and unmatched requests when saved,
+    // if it has branches.
+    branch_hits: Vec<Option<(Vec<usize>, usize)>>,
+    orderings: Vec<(u64, u64)>,
+}
+
+/// Every expectation a server verified and how many requests it received.
+///
+/// Returned by [Server::verify_report](struct.Server.html#method.verify_report).
+/// The `Display` implementation renders one line per expectation, for
+/// logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationReport {
+    expectations: Vec<ExpectationReport>,
+    unexpected_requests: Vec<String>,
+}
+
+impl VerificationReport {
+    fn new(state: &ServerStateInner) -> Self {
+        let expectations = state
+            .expected
+            .iter()
+            .filter(|expectation| !expectation.allowed)
+            .map(|expectation| ExpectationReport {
+                matcher: format!("{:?}", matcher_name(&expectation.matcher)),
+                times: expectation.times,
+                hit_count: expectation.hit_count,
+            })
+            .collect();
+        let unexpected_requests = state
+            .unexpected_requests
+            .iter()
+            .map(|unexpected| {
+                format!(
+                    "{} {}",
+                    unexpected.request.method(),
+                    unexpected.request.uri()
+                )
+            })
+            .collect();
+        VerificationReport {
+            expectations,
+            unexpected_requests,
+        }
+    }
+
+    /// The expectations that were verified, in the order they were added.
+    pub fn expectations(&self) -> &[ExpectationReport] {
+        &self.expectations
+    }
+
+    /// The method and uri of each request that didn't match any expectation.
+    pub fn unexpected_requests(&self) -> &[String] {
+        &self.unexpected_requests
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "verified {} expectations", self.expectations.len())?;
+        for expectation in &self.expectations {
+            write!(
+                f,
+                "\n  {}: received {}; expected {}",
+                expectation.matcher,
+                expectation.hit_count,
+                RangeDisplay(expectation.times)
+            )?;
+        }
+        for request in &self.unexpected_requests {
+            write!(f, "\n  unexpected request: {}", request)?;
+        }
+        Ok(())
+    }
+}
+
+/// An expectation in a [VerificationReport](struct.VerificationReport.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectationReport {
+    matcher: String,
+    times: (Bound<usize>, Bound<usize>),
+    hit_count: usize,
+}
+
+impl ExpectationReport {
+    /// A description of the expectation's matcher.
+    pub fn matcher(&self) -> &str {
+        &self.matcher
+    }
+
+    /// The number of requests the expectation was configured to receive.
+    pub fn times(&self) -> (Bound<usize>, Bound<usize>) {
+        self.times
+    }
+
+    /// The number of requests the expectation received.
+    pub fn hit_count(&self) -> usize {
+        self.hit_count
+    }
+}
+
+/// Counters describing the connections a server has accepted.
+///
+/// Returned by [Server::connection_metrics](struct.Server.html#method.connection_metrics).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    open: usize,
+    requests_per_connection: Vec<usize>,
+}
+
+impl ConnectionMetrics {
+    /// The number of connections accepted since the server started.
+    pub fn accepted(&self) -> usize {
+        self.requests_per_connection.len()
+    }
+
+    /// The number of connections currently open.
+    pub fn open(&self) -> usize {
+        self.open
+    }
+
+    /// The number of requests received on each connection, in the order the
+    /// connections were accepted.
+    pub fn requests_per_connection(&self) -> &[usize] {
+        &self.requests_per_connection
+    }
+}
+
+impl fmt::Display for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.0.lock().expect("mutex poisoned");
+        write!(f, "waiting on {} open connections", inner.open.len())?;
+        for (id, conn) in inner.open.iter() {
+            write!(f, "\n  connection #{} from {}", id, conn.peer_addr)?;
+            for request in conn.in_flight.values() {
+                write!(f, "\n    in-flight request: {}", request)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ConnectionGuard {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut inner = self.connections.0.lock().expect("mutex poisoned");
+        inner.open.remove(&self.id);
+    }
+}
+
+struct RequestGuard {
+    connections: Connections,
+    conn_id: u64,
+    id: u64,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut inner = self.connections.0.lock().expect("mutex poisoned");
+        if let Some(conn) = inner.open.get_mut(&self.conn_id) {
+            conn.in_flight.remove(&self.id);
+        }
+    }
+}
+
+impl ServerStateInner {
+    // Describe every reason verification fails, in the order they are
+    // reported. Empty if verification passes.
+    fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if !self.server_panics.is_empty() {
+            failures.push(format!(
+                "the server panicked in the background:\n{}",
+                self.server_panics.join("\n")
+            ));
+        }
+        failures.extend(
+            self.expected
+                .iter()
+                .filter_map(Expectation::verification_error),
+        );
+        failures.extend(
+            self.orderings
+                .iter()
+                .filter_map(|&(before, after)| self.ordering_error(before, after)),
+        );
+        if !self.spec_violations.is_empty() {
+            failures.push(format!(
+                "the following exchanges violate the OpenAPI spec:\n{}",
+                self.spec_violations.join("\n")
+            ));
+        }
+        if !self.unexpected_requests.is_empty() {
+            let mut msg = String::from("received the following unexpected requests:\n");
+            for unexpected in self.unexpected_requests.iter() {
+                msg.push_str(&unexpected.to_string());
+            }
+            failures.push(msg);
+        }
+        failures
+    }
+
+    // Check that the first request matched by `before` was received prior to
+    // every request matched by `after`.
+    fn ordering_error(&self, before: u64, after: u64) -> Option<String> {
+        let find = |id| {
+            self.expected
+                .iter()
+                .find(|expectation| expectation.id == id)
+        };
+        let (before, after) = (find(before)?, find(after)?);
+        let first_after = after.hit_times.first()?;
+        match before.hit_times.first() {
+            Some(first_before) if first_before < first_after => None,
+            _ => Some(format!(
+                "matcher '{:?}' received a request before matcher '{:?}' had received any",
+                matcher_name(&after.matcher),
+                matcher_name(&before.matcher),
+            )),
+        }
+    }
+
+    // true if nothing has happened since the state was last cleared.
+    fn is_empty(&self) -> bool {
+        self.expected.is_empty()
+            && self.unexpected_requests.is_empty()
+            && self.server_panics.is_empty()
+            && self.spec_violations.is_empty()
+            && self.request_times.is_empty()
+            && self.exchanges.is_empty()
+    }
+
+    // true if verifying this state would not panic.
+    fn passed(&self) -> bool {
+        self.failures().is_empty()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let expectations: Vec<_> = self.expected.iter().map(Expectation::to_json).collect();
+        let unexpected_requests: Vec<_> = self
+            .unexpected_requests
+            .iter()
+            .map(UnexpectedRequest::to_json)
+            .collect();
+        let exchanges: Vec<_> = self.exchanges.iter().map(Exchange::to_json).collect();
+        serde_json::json!({
+            "passed": self.passed(),
+            "expectations": expectations,
+            "unexpected_requests": unexpected_requests,
+            "server_panics": self.server_panics,
+            "spec_violations": self.spec_violations,
+            "exchanges": exchanges,
+        })
+    }
+}
+
+fn request_json(req: &FullRequest) -> serde_json::Value {
+    serde_json::json!({
+        "method": req.method().as_str(),
+        "uri": req.uri().to_string(),
+        "version": format!("{:?}", req.version()),
+        "headers": headers_json(req.headers()),
+        "body": String::from_utf8_lossy(req.body()),
+    })
+}
+
+pub(crate) fn headers_json(headers: &http::HeaderMap) -> serde_json::Value {
+    let headers: Vec<_> = headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes())))
+        .collect();
+    serde_json::json!(headers)
+}
+
+// http::Request isn't Clone because of its extensions. Copy everything else.
+pub(crate) fn copy_request(req: &FullRequest) -> FullRequest {
+    let mut builder = http::Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    *builder.headers_mut().unwrap() = req.headers().clone();
+    builder.body(req.body().clone()).unwrap()
+}
+
+// Destination for the json verification reports.
+struct JsonReportWriter {
+    writer: Box<dyn Write + Send>,
+    // whether any report has been written.
+    written: bool,
+}
+
+impl JsonReportWriter {
+    // Write a single line json document describing the verification of state.
+    fn write(&mut self, addr: &SocketAddr, state: &ServerStateInner) {
+        self.written = true;
+        let mut report = state.to_json();
+        report["addr"] = addr.to_string().into();
+        let result = serde_json::to_writer(&mut self.writer, &report)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"))
+            .and_then(|_| self.writer.flush());
+        if let Err(err) = result {
+            log::error!("failed to write json verification report: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for JsonReportWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("JsonReportWriter")
+    }
+}
+
+// A request that did not match any expectation.
+#[derive(Debug)]
+struct UnexpectedRequest {
+    request: FullRequest,
+    closest: Option<ClosestMatch>,
+}
+
+impl UnexpectedRequest {
+    fn to_json(&self) -> serde_json::Value {
+        let closest = self.closest.as_ref().map(|closest| {
+            serde_json::json!({
+                "matcher": closest.matcher,
+                "matched": closest.matched,
+                "evaluated": closest.evaluated,
+                "mismatches": closest.mismatches,
+            })
+        });
+        serde_json::json!({
+            "request": request_json(&self.request),
+            "closest_expectation": closest,
+        })
+    }
+}
+
+impl fmt::Display for UnexpectedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:#?}", self.request)?;
+        match &self.closest {
+            Some(closest) => writeln!(f, "closest expectation: {}", closest),
+            None => writeln!(f, "closest expectation: none"),
+        }
+    }
+}
+
+// The expectation that came closest to matching an unexpected request.
+#[derive(Debug)]
+struct ClosestMatch {
+    matcher: String,
+    matched: usize,
+    evaluated: usize,
+    mismatches: Vec<String>,
+}
+
+impl fmt::Display for ClosestMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} of {} matchers matched)",
+            self.matcher, self.matched, self.evaluated
+        )?;
+        for mismatch in self.mismatches.iter() {
+            write!(f, "\n  {}", mismatch.replace('\n', "\n  "))?;
+        }
+        Ok(())
+    }
+}
+
+fn times_error(
+    matcher: &dyn Matcher<FullRequest>,
+    times: (Bound<usize>, Bound<usize>),
+    hit_count: usize,
+) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'static>> {
+    let body = hyper::body::Bytes::from(format!(
+        "Unexpected number of requests for matcher '{:?}'; received {}; expected {}",
+        matcher_name(matcher),
+        hit_count,
+        RangeDisplay(times),
+    ));
+    Box::pin(async move {
+        http::Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body)
+            .unwrap()
+    })
+}
+
+struct RangeDisplay((Bound<usize>, Bound<usize>));
+impl fmt::Display for RangeDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // canonicalize the bounds to inclusive or unbounded.
+        enum MyBound {
+            Included(usize),
+            Unbounded,
+        }
+        let inclusive_start = match (self.0).0 {
+            Bound::Included(x) => MyBound::Included(x),
+            Bound::Excluded(x) => MyBound::Included(x + 1),
+            Bound::Unbounded => MyBound::Unbounded,
+        };
+        let inclusive_end = match (self.0).1 {
+            Bound::Included(x) => MyBound::Included(x),
+            Bound::Excluded(x) => MyBound::Included(x - 1),
+            Bound::Unbounded => MyBound::Unbounded,
+        };
+        match (inclusive_start, inclusive_end) {
+            (MyBound::Included(min), MyBound::Unbounded) => write!(f, "AtLeast({})", min),
+            (MyBound::Unbounded, MyBound::Included(max)) => write!(f, "AtMost({})", max),
+            (MyBound::Included(min), MyBound::Included(max)) if min == max => {
+                write!(f, "Exactly({})", max)
+            }
+            (MyBound::Included(min), MyBound::Included(max)) => {
+                write!(f, "Between({}..={})", min, max)
+            }
+            (MyBound::Unbounded, MyBound::Unbounded) => write!(f, "Any"),
+        }
+    }
+}
+
+/// Custom Server Builder.
+pub struct ServerBuilder {
+    bind_addr: Option<SocketAddr>,
+    // the ports to choose from, or why the requested range is invalid.
+    port_range: Option<Result<RangeInclusive<u16>, &'static str>>,
+    shutdown_warning_after: Duration,
+    thread_name: Option<String>,
+    json_report: Option<JsonReportWriter>,
+    snapshot: Option<PathBuf>,
+    upload_progress: Option<UploadProgressHook>,
+    abort_after_bytes: Option<usize>,
+    // bytes of a request body to read per interval.
+    slow_body_read: Option<(usize, Duration)>,
+    #[cfg(feature = "decompress")]
+    decompress_request_bodies: bool,
+    match_before_body: bool,
+    connection_accepted: Option<ConnectionHook>,
+    connection_closed: Option<ConnectionHook>,
+    diagnostics: Option<diagnostics::Sink>,
+    request_sink: Option<RequestSink>,
+    max_exchanges: Option<usize>,
+    chaos: Option<Chaos>,
+    map_request: Vec<MapRequestHook>,
+    map_response: Vec<MapResponseHook>,
+    default_headers: http::HeaderMap,
+    request_id_header: Option<http::header::HeaderName>,
+    clock: Option<ServerClock>,
+    rng_seed: Option<u64>,
+    on_verification_failure: Option<FailureHook>,
+    capture_dir: Option<PathBuf>,
+    report_all_failures: bool,
+    shared_runtime: bool,
+    runtime: Option<ServerRuntime>,
+    hostname: Option<String>,
+    #[cfg(feature = "tls")]
+    tls: bool,
+    #[cfg(feature = "tls")]
+    alpn_protocols: Option<Vec<Vec<u8>>>,
+    #[cfg(feature = "http3")]
+    http3: bool,
+    #[cfg(feature = "http2-push")]
+    http2_server_push: bool,
+    #[cfg(feature = "record")]
+    upstream: Option<http::Uri>,
+    #[cfg(feature = "openapi")]
+    openapi: Option<crate::openapi::OpenApi>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    /// Create a new ServerBuilder. By default the server will listen on ipv6
+    /// loopback if available and fallback to ipv4 loopback if unable to bind to
+    /// ipv6.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            bind_addr: None,
+            port_range: None,
+            shutdown_warning_after: Duration::from_secs(5),
+            thread_name: None,
+            json_report: None,
+            snapshot: None,
+            upload_progress: None,
+            abort_after_bytes: None,
+            slow_body_read: None,
+            #[cfg(feature = "decompress")]
+            decompress_request_bodies: false,
+            match_before_body: false,
+            connection_accepted: None,
+            connection_closed: None,
+            diagnostics: None,
+            request_sink: None,
+            max_exchanges: None,
+            chaos: None,
+            map_request: Vec::new(),
+            map_response: Vec::new(),
+            default_headers: http::HeaderMap::new(),
+            request_id_header: None,
+            clock: None,
+            rng_seed: None,
+            on_verification_failure: None,
+            capture_dir: None,
+            report_all_failures: false,
+            runtime: None,
+            hostname: None,
+            #[cfg(feature = "tls")]
+            tls: false,
+            #[cfg(feature = "tls")]
+            alpn_protocols: None,
+            #[cfg(feature = "http3")]
+            http3: false,
+            #[cfg(feature = "http2-push")]
+            http2_server_push: false,
+            shared_runtime: std::env::var_os(SHARED_RUNTIME_ENV)
+                .filter(|v| v == "1")
+                .is_some(),
+            #[cfg(feature = "record")]
+            upstream: None,
+            #[cfg(feature = "openapi")]
+            openapi: None,
+        }
+    }
+
+    /// Specify the address the server should listen on.
+    pub fn bind_addr(self, bind_addr: SocketAddr) -> ServerBuilder {
+        ServerBuilder {
+            bind_addr: Some(bind_addr),
+            ..self
+        }
+    }
+
+    /// Use `hostname` instead of the server's IP address in the urls returned
+    /// by [Server::url](struct.Server.html#method.url) and
+    /// [Server::url_builder](struct.Server.html#method.url_builder). This is
+    /// useful for clients that route based on the host or check TLS SNI.
+    ///
+    /// The server still listens on its IP address, so the client must
+    /// resolve `hostname` to [Server::addr](struct.Server.html#method.addr),
+    /// e.g. with a resolver override.
+    pub fn hostname(self, hostname: impl Into<String>) -> ServerBuilder {
+        ServerBuilder {
+            hostname: Some(hostname.into()),
+            ..self
+        }
+    }
+
+    /// Listen on a free port within `ports` rather than an ephemeral port
+    /// chosen by the OS. Ports that are already in use are skipped. The port
+    /// of the [bind_addr](#method.bind_addr), if any, is ignored.
+    ///
+    /// Running the server fails if the range is empty or includes port 0.
+    ///
+    /// ```
+    /// # use httptest::ServerBuilder;
+    /// let server = ServerBuilder::new().port_range(40000..41000).run().unwrap();
+    /// assert!((40000..41000).contains(&server.addr().port()));
+    /// ```
+    pub fn port_range<R>(self, ports: R) -> ServerBuilder
+    where
+        R: RangeBounds<u16>,
+    {
+        let start = match ports.start_bound() {
+            Bound::Included(&start) => Some(start),
+            Bound::Excluded(&start) => start.checked_add(1),
+            Bound::Unbounded => Some(1),
+        };
+        let end = match ports.end_bound() {
+            Bound::Included(&end) => Some(end),
+            Bound::Excluded(&end) => end.checked_sub(1),
+            Bound::Unbounded => Some(u16::MAX),
+        };
+        // errors are reported when the server is run.
+        let port_range = match (start, end) {
+            (Some(0), _) => Err("port range includes port 0"),
+            (Some(start), Some(end)) if start <= end => Ok(start..=end),
+            _ => Err("empty port range"),
+        };
+        ServerBuilder {
+            port_range: Some(port_range),
+            ..self
+        }
+    }
+
+    /// How long Drop should wait for the server to shutdown before printing
+    /// the open connections and in-flight requests it's waiting on. Drop
+    /// continues to wait after printing. The default is 5 seconds.
+    ///
+    /// Shutdown waits for in-flight requests to complete, so a hang on Drop
+    /// is typically caused by a client holding a connection open or a slow
+    /// responder.
+    pub fn shutdown_warning_after(self, duration: Duration) -> ServerBuilder {
+        ServerBuilder {
+            shutdown_warning_after: duration,
+            ..self
+        }
+    }
+
+    /// Keep only the `n` most recent requests and responses in the log
+    /// described by [Server::dump_state_json](struct.Server.html#method.dump_state_json)
+    /// and json reports, discarding older ones. By default every exchange is
+    /// kept until the server is verified, which holds every request and
+    /// response body in memory.
+    ///
+    /// [Server::retry_counts](struct.Server.html#method.retry_counts) and
+    /// [snapshot_requests](#method.snapshot_requests) only see the exchanges
+    /// that are kept.
+    pub fn max_logged_exchanges(self, n: usize) -> ServerBuilder {
+        ServerBuilder {
+            max_exchanges: Some(n),
+            ..self
+        }
+    }
+
+    /// Name the threads the server runs on. This name shows up in panic
+    /// messages and debuggers. The default is `httptest-<name of the thread
+    /// that started the server>`, which under the test harness is the name of
+    /// the test.
+    ///
+    /// Panics on the server threads, e.g. from a panicking responder, are
+    /// reported when the server's expectations are verified.
+    pub fn thread_name(self, name: impl Into<String>) -> ServerBuilder {
+        ServerBuilder {
+            thread_name: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Write a machine-readable report to `writer` every time the server's
+    /// expectations are verified, whether or not verification passes. Each
+    /// report is a single line of json containing the expectations with their
+    /// hit counts, the unexpected requests and any panics from the server
+    /// threads.
+    ///
+    /// No report is written when the server is dropped if nothing has
+    /// happened since a report was last written.
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = std::env::temp_dir();
+    /// let server = httptest::ServerBuilder::new()
+    ///     .json_report(std::fs::File::create(dir.join("httptest-report.json"))?)
+    ///     .run()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn json_report(self, writer: impl Write + Send + 'static) -> ServerBuilder {
+        ServerBuilder {
+            json_report: Some(JsonReportWriter {
+                writer: Box::new(writer),
+                written: false,
+            }),
+            ..self
+        }
+    }
+
+    /// Compare the requests the server receives against the snapshot stored
+    /// at `path` when the server is dropped. The snapshot renders each
+    /// request's method, path, headers and body as text, omitting headers
+    /// that change between runs such as `host` and `user-agent`.
+    ///
+    /// If the snapshot is missing or differs, the received requests are
+    /// written next to it with a `.new` extension and the test fails. Review
+    /// the new file and rename it to accept it, or rerun the tests with the
+    /// `HTTPTEST_UPDATE_SNAPSHOTS=1` environment variable to accept all
+    /// changes.
+    pub fn snapshot_requests(self, path: impl Into<PathBuf>) -> ServerBuilder {
+        ServerBuilder {
+            snapshot: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Call `f` every time a chunk of a request body is received. This can be
+    /// used to observe that a client streams a large upload rather than
+    /// buffering it, or to fail the request part way through by returning
+    /// `UploadAction::Abort`, which closes the connection without a response.
+    ///
+    /// The hook runs before the request is matched against any expectations.
+    ///
+    /// ```
+    /// use httptest::{ServerBuilder, UploadAction};
+    ///
+    /// // Drop the connection once the client has sent 1MiB.
+    /// let server = ServerBuilder::new()
+    ///     .upload_progress(|progress| {
+    ///         if progress.bytes_received() >= 1 << 20 {
+    ///             UploadAction::Abort
+    ///         } else {
+    ///             UploadAction::Continue
+    ///         }
+    ///     })
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn upload_progress<F>(self, f: F) -> ServerBuilder
+    where
+        F: Fn(&UploadProgress) -> UploadAction + Send + Sync + 'static,
+    {
+        ServerBuilder {
+            upload_progress: Some(UploadProgressHook(Arc::new(f))),
+            ..self
+        }
+    }
+
+    /// Measure the server's delays with `clock` rather than the system clock.
+    /// See the [clock](clock/index.html) module.
+    pub fn clock(self, clock: impl Clock + 'static) -> ServerBuilder {
+        ServerBuilder {
+            clock: Some(ServerClock(Arc::new(clock))),
+            ..self
+        }
+    }
+
+    /// Add a header to every response the server sends that doesn't already
+    /// have one with the same name, including the error responses to
+    /// unexpected requests.
+    ///
+    /// Responses already include a `date` header, added by hyper, so there's
+    /// no need to add one.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .default_header("server", "httptest")
+    ///     .default_header("cache-control", "no-store")
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn default_header<K, V>(mut self, name: K, value: V) -> ServerBuilder
+    where
+        K: TryInto<http::header::HeaderName>,
+        K::Error: fmt::Debug,
+        V: TryInto<http::header::HeaderValue>,
+        V::Error: fmt::Debug,
+    {
+        let name: http::header::HeaderName = name.try_into().expect("invalid header name");
+        let value: http::header::HeaderValue = value.try_into().expect("invalid header value");
+        self.default_headers.append(name, value);
+        self
+    }
+
+    /// Add a header with the given name identifying the request to every
+    /// response that doesn't already have one. The value is copied from the
+    /// request's header of the same name if it has one, otherwise it's a
+    /// number unique to the server.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .request_id_header("x-request-id")
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn request_id_header<K>(self, name: K) -> ServerBuilder
+    where
+        K: TryInto<http::header::HeaderName>,
+        K::Error: fmt::Debug,
+    {
+        ServerBuilder {
+            request_id_header: Some(name.try_into().expect("invalid header name")),
+            ..self
+        }
+    }
+
+    /// Call `f` to transform every request the server receives before it's
+    /// matched against any expectations, such as to strip a header that
+    /// changes between runs. May be called more than once, in which case the
+    /// functions are applied in the order they were added.
+    ///
+    /// `f` is called once the body has been read, so the head checked by
+    /// [match_before_body](#method.match_before_body) is the one the client
+    /// sent.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .map_request(|req| {
+    ///         req.headers_mut().remove("x-request-id");
+    ///     })
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn map_request<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&mut http::Request<hyper::body::Bytes>) + Send + Sync + 'static,
+    {
+        self.map_request.push(MapRequestHook(Arc::new(f)));
+        self
+    }
+
+    /// Call `f` to transform every response the server sends, after the
+    /// matching expectation has responded. `f` is also passed the request
+    /// being responded to. May be called more than once, in which case the
+    /// functions are applied in the order they were added.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// // Echo the request id back on every response.
+    /// let server = ServerBuilder::new()
+    ///     .map_response(|req, resp| {
+    ///         if let Some(id) = req.headers().get("x-request-id") {
+    ///             resp.headers_mut().insert("x-request-id", id.clone());
+    ///         }
+    ///     })
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn map_response<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&http::Request<hyper::body::Bytes>, &mut http::Response<hyper::body::Bytes>)
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.map_response.push(MapResponseHook(Arc::new(f)));
+        self
+    }
+
+    /// Seed the random number generator behind all of the server's randomized
+    /// behavior, such as [chaos](#method.chaos). Without a seed one is chosen
+    /// at random and logged when the server starts, so a failing run can be
+    /// reproduced by passing the logged seed here.
+    pub fn rng_seed(self, seed: u64) -> ServerBuilder {
+        ServerBuilder {
+            rng_seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// Inject faults into the server's responses at random, on top of
+    /// whatever the matching expectation responded with. Requests still count
+    /// towards the expectation they match. See [Chaos](struct.Chaos.html).
+    pub fn chaos(self, chaos: Chaos) -> ServerBuilder {
+        ServerBuilder {
+            chaos: Some(chaos),
+            ..self
+        }
+    }
+
+    /// Send a copy of every request the server receives to `sink` as soon as
+    /// its body has been read, before it's matched against any expectations.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    /// let server = ServerBuilder::new().request_sink(tx).run().unwrap();
+    /// // ... then in an async task ...
+    /// # async {
+    /// while let Some(req) = rx.recv().await {
+    ///     println!("received {} {}", req.method(), req.uri());
+    /// }
+    /// # };
+    /// ```
+    pub fn request_sink(
+        self,
+        sink: tokio::sync::mpsc::UnboundedSender<http::Request<hyper::body::Bytes>>,
+    ) -> ServerBuilder {
+        ServerBuilder {
+            request_sink: Some(RequestSink(sink)),
+            ..self
+        }
+    }
+
+    /// Call `f` whenever the server accepts a connection, before any requests
+    /// on it are read.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .on_connection_accepted(|conn| {
+    ///         println!("connection #{} from {}", conn.id(), conn.peer_addr())
+    ///     })
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn on_connection_accepted<F>(self, f: F) -> ServerBuilder
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        ServerBuilder {
+            connection_accepted: Some(ConnectionHook(Arc::new(f))),
+            ..self
+        }
+    }
+
+    /// Call `f` whenever a connection is closed, including connections closed
+    /// when the server shuts down.
+    pub fn on_connection_closed<F>(self, f: F) -> ServerBuilder
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        ServerBuilder {
+            connection_closed: Some(ConnectionHook(Arc::new(f))),
+            ..self
+        }
     }
-}
 
-fn times_exceeded(end_bound: Bound<usize>, hit_count: usize) -> bool {
-    match end_bound {
-        Bound::Included(limit) if hit_count > limit => true,
-        Bound::Excluded(limit) if hit_count >= limit => true,
-        _ => false,
+    /// Deliver diagnostics describing how each request was handled to `f`,
+    /// in addition to logging them. This includes the evaluation of every
+    /// matcher and the requests that didn't match any expectation.
+    ///
+    /// The `log` crate's output is easily lost to the test harness's output
+    /// capturing, or to a logger initialized after the server starts. A sink
+    /// can instead collect the diagnostics to print when a test fails.
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .diagnostics(|event| eprintln!("httptest: {}", event))
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn diagnostics<F>(self, f: F) -> ServerBuilder
+    where
+        F: Fn(&DiagnosticEvent) + Send + Sync + 'static,
+    {
+        ServerBuilder {
+            diagnostics: Some(diagnostics::Sink::new(f)),
+            ..self
+        }
     }
-}
 
-fn hit_count_is_valid(bounds: (Bound<usize>, Bound<usize>), hit_count: usize) -> bool {
-    bounds.contains(&hit_count)
-}
+    /// Close the connection, without responding, once `n` bytes of a request
+    /// body have been received. Requests with smaller bodies are unaffected.
+    ///
+    /// Exactly `n` bytes are received, even when they arrive in the same chunk
+    /// as later bytes, so an [upload_progress](#method.upload_progress) hook
+    /// sees `n` bytes received last. Use
+    /// [truncate_after](responders/fn.truncate_after.html) to interrupt
+    /// responses instead.
+    pub fn abort_after_bytes(self, n: usize) -> ServerBuilder {
+        ServerBuilder {
+            abort_after_bytes: Some(n),
+            ..self
+        }
+    }
 
-/// An expectation to be asserted by the server.
-pub struct Expectation {
-    matcher: Box<dyn Matcher<FullRequest>>,
-    times: (Bound<usize>, Bound<usize>),
-    responder: Box<dyn Responder>,
-    hit_count: usize,
-}
+    /// Read request bodies slowly, at most `bytes` bytes every `interval`,
+    /// so the client's writes are held up by backpressure. Useful for
+    /// exercising write timeouts and flow control during large uploads.
+    ///
+    /// Body bytes are received in chunks, so after reading a chunk the server
+    /// waits as many intervals as the chunk is worth before reading more.
+    /// Waits use the server's [clock](#method.clock).
+    ///
+    /// This function will panic if `bytes` is zero.
+    pub fn read_body_slowly(self, bytes: usize, interval: Duration) -> ServerBuilder {
+        assert!(
+            bytes > 0,
+            "read_body_slowly requires a non-zero number of bytes"
+        );
+        ServerBuilder {
+            slow_body_read: Some((bytes, interval)),
+            ..self
+        }
+    }
 
-impl Expectation {
-    /// What requests will this expectation match.
-    pub fn matching(matcher: impl Matcher<FullRequest> + 'static) -> ExpectationBuilder {
-        ExpectationBuilder {
-            matcher: Box::new(matcher),
-            // expect exactly one request by default.
-            times: (Bound::Included(1), Bound::Included(1)),
+    /// Decompress request bodies sent with a `Content-Encoding` of `gzip`,
+    /// `deflate` or `br` before matching them, so body matchers see the
+    /// decompressed body. The `Content-Encoding` header is removed from
+    /// decompressed requests and the original encoding can be matched with
+    /// [request::content_encoding](matchers/request/fn.content_encoding.html).
+    /// Bodies that fail to decompress are left as they were. The default is
+    /// false.
+    #[cfg(feature = "decompress")]
+    pub fn decompress_request_bodies(self, decompress: bool) -> ServerBuilder {
+        ServerBuilder {
+            decompress_request_bodies: decompress,
+            ..self
         }
     }
-}
 
-impl fmt::Debug for Expectation {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("Expectation")
-            .field("matcher", &matcher_name(&*self.matcher))
-            .field("times", &self.times)
-            .field("hit_count", &self.hit_count)
-            .finish()
+    /// Match the request head against the expectations before reading the
+    /// body. If no expectation could match regardless of the body, the server
+    /// responds without reading the body at all, which avoids buffering
+    /// large uploads that would be rejected anyway. The unexpected request is
+    /// reported with an empty body. The default is false.
+    ///
+    /// Body matchers are recognized when built with
+    /// [request::body](matchers/request/fn.body.html). Matchers that read
+    /// the request body directly see an empty body while only the head is
+    /// being matched.
+    pub fn match_before_body(self, match_before_body: bool) -> ServerBuilder {
+        ServerBuilder {
+            match_before_body,
+            ..self
+        }
     }
-}
 
-/// Define expectations using a builder pattern.
-pub struct ExpectationBuilder {
-    matcher: Box<dyn Matcher<FullRequest>>,
-    times: (Bound<usize>, Bound<usize>),
-}
+    /// When verifying expectations, report every unmet expectation and every
+    /// unexpected request together in a single panic rather than panicking on
+    /// the first failure found. The default is false.
+    pub fn report_all_failures(self, report_all_failures: bool) -> ServerBuilder {
+        ServerBuilder {
+            report_all_failures,
+            ..self
+        }
+    }
 
-impl ExpectationBuilder {
-    /// Expect this many requests.
+    /// Call `f` with the reasons verification failed instead of panicking.
+    /// This applies wherever expectations are verified, including when the
+    /// server, a [Scope](struct.Scope.html) or an
+    /// [ExpectationGuard](struct.ExpectationGuard.html) is dropped.
     ///
     /// ```
-    /// # use httptest::{Expectation, matchers::any, responders::status_code};
-    /// // exactly 2 requests
-    /// Expectation::matching(any()).times(2).respond_with(status_code(200));
-    /// // at least 2 requests
-    /// Expectation::matching(any()).times(2..).respond_with(status_code(200));
-    /// // at most 2 requests
-    /// Expectation::matching(any()).times(..=2).respond_with(status_code(200));
-    /// // between 2 and 5 inclusive
-    /// Expectation::matching(any()).times(2..6).respond_with(status_code(200));
-    /// // equivalently
-    /// Expectation::matching(any()).times(2..=5).respond_with(status_code(200));
+    /// use httptest::ServerBuilder;
+    ///
+    /// let server = ServerBuilder::new()
+    ///     .on_verification_failure(|errors| {
+    ///         for failure in errors.failures() {
+    ///             eprintln!("httptest: {}", failure);
+    ///         }
+    ///     })
+    ///     .run()
+    ///     .unwrap();
     /// ```
-    pub fn times<R>(self, times: R) -> ExpectationBuilder
+    pub fn on_verification_failure<F>(self, f: F) -> ServerBuilder
     where
-        R: crate::into_times::IntoTimes,
+        F: Fn(VerificationErrors) + Send + Sync + 'static,
     {
-        ExpectationBuilder {
-            times: times.into_times(),
+        ServerBuilder {
+            on_verification_failure: Some(FailureHook(Arc::new(f))),
             ..self
         }
     }
 
-    /// What should this expectation respond with.
-    pub fn respond_with(self, responder: impl Responder + 'static) -> Expectation {
-        Expectation {
-            matcher: self.matcher,
-            times: self.times,
-            responder: Box::new(responder),
-            hit_count: 0,
+    /// Run the server on a runtime shared by every server in the process
+    /// rather than starting a thread and runtime for each server. This
+    /// reduces the number of threads and the startup cost for large test
+    /// suites. The shared runtime is started the first time it's needed.
+    ///
+    /// Servers on the shared runtime don't use the
+    /// [thread_name](#method.thread_name). The default is false unless the
+    /// `HTTPTEST_SHARED_RUNTIME` environment variable is set to `1`.
+    pub fn shared_runtime(self, shared_runtime: bool) -> ServerBuilder {
+        ServerBuilder {
+            shared_runtime,
+            ..self
         }
     }
-}
-
-#[derive(Debug, Clone)]
-struct ServerState(Arc<Mutex<ServerStateInner>>);
-
-impl ServerState {
-    fn lock(&self) -> std::sync::LockResult<std::sync::MutexGuard<'_, ServerStateInner>> {
-        self.0.lock()
-    }
 
-    fn push_expectation(&self, expectation: Expectation) {
-        let mut inner = self.lock().expect("mutex poisoned");
-        inner.expected.push(expectation);
+    /// Run the server on `runtime` rather than on a tokio runtime. The
+    /// runtime accepts the server's connections, runs its tasks and, unless a
+    /// [clock](#method.clock) is given, measures its delays. This takes
+    /// precedence over [shared_runtime](#method.shared_runtime). See the
+    /// [runtime](runtime/index.html) module.
+    ///
+    /// Servers [forwarding unmatched requests](#method.forward_unmatched_to)
+    /// still need a tokio runtime. Dropping the server blocks until it has
+    /// stopped, so when `runtime` is driven by the thread that drops the
+    /// server, stop it with
+    /// [Server::shutdown_async](struct.Server.html#method.shutdown_async)
+    /// first.
+    pub fn runtime(self, runtime: impl Runtime) -> ServerBuilder {
+        ServerBuilder {
+            runtime: Some(ServerRuntime(Arc::new(runtime))),
+            ..self
+        }
     }
-}
 
-impl Default for ServerState {
-    fn default() -> Self {
-        ServerState(Default::default())
+    /// Serve connections over TLS using a self-signed certificate. Clients
+    /// must trust the certificate from
+    /// [Server::certificate_pem](struct.Server.html#method.certificate_pem),
+    /// and [Server::url](struct.Server.html#method.url) returns https urls.
+    /// The default is false.
+    #[cfg(feature = "tls")]
+    pub fn tls(self, tls: bool) -> ServerBuilder {
+        ServerBuilder { tls, ..self }
     }
-}
-
-#[derive(Debug)]
-struct ServerStateInner {
-    unexpected_requests: Vec<FullRequest>,
-    expected: Vec<Expectation>,
-}
 
-impl ServerStateInner {
-    fn find_expectation(&mut self, req: &FullRequest) -> Option<&mut Expectation> {
-        for expectation in self.expected.iter_mut().rev() {
-            if ExecutionContext::evaluate(expectation.matcher.as_mut(), req) {
-                return Some(expectation);
-            }
+    /// The ALPN protocols offered to TLS clients, in order of preference. The
+    /// default is `["h2", "http/1.1"]`.
+    ///
+    /// The protocol negotiated for a request can be matched with
+    /// [request::alpn_protocol](matchers/request/fn.alpn_protocol.html).
+    ///
+    /// ```
+    /// use httptest::ServerBuilder;
+    ///
+    /// // only allow clients that speak HTTP/1.1.
+    /// let server = ServerBuilder::new()
+    ///     .tls(true)
+    ///     .alpn_protocols(["http/1.1"])
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn alpn_protocols<I, P>(self, protocols: I) -> ServerBuilder
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        ServerBuilder {
+            alpn_protocols: Some(protocols.into_iter().map(Into::into).collect()),
+            ..self
         }
-        None
     }
-}
 
-impl Default for ServerStateInner {
-    fn default() -> Self {
-        ServerStateInner {
-            unexpected_requests: Default::default(),
-            expected: Default::default(),
+    // Whether the server presents a certificate.
+    #[cfg(feature = "tls")]
+    fn needs_certificate(&self) -> bool {
+        #[cfg(feature = "http3")]
+        if self.http3 {
+            return true;
         }
+        self.tls
     }
-}
 
-fn times_error(
-    matcher: &dyn Matcher<FullRequest>,
-    times: (Bound<usize>, Bound<usize>),
-    hit_count: usize,
-) -> Pin<Box<dyn Future<Output = http::Response<hyper::body::Bytes>> + Send + 'static>> {
-    let body = hyper::body::Bytes::from(format!(
-        "Unexpected number of requests for matcher '{:?}'; received {}; expected {}",
-        matcher_name(&*matcher),
-        hit_count,
-        RangeDisplay(times),
-    ));
-    Box::pin(async move {
-        http::Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(body)
-            .unwrap()
-    })
-}
+    /// Also serve HTTP/3 over QUIC, on the same port over UDP when it's free.
+    /// Responses sent over TCP advertise it with an `Alt-Svc` header. The
+    /// default is false.
+    ///
+    /// HTTP/3 is only served over TLS using the self-signed certificate, see
+    /// [Server::certificate_pem](struct.Server.html#method.certificate_pem)
+    /// and [Server::https_url](struct.Server.html#method.https_url). It
+    /// needs a tokio runtime, so the server fails to start if a
+    /// [runtime](#method.runtime) is also given. This is experimental.
+    #[cfg(feature = "http3")]
+    pub fn http3(self, http3: bool) -> ServerBuilder {
+        ServerBuilder { http3, ..self }
+    }
 
-struct RangeDisplay((Bound<usize>, Bound<usize>));
-impl fmt::Display for RangeDisplay {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // canonicalize the bounds to inclusive or unbounded.
-        enum MyBound {
-            Included(usize),
-            Unbounded,
-        }
-        let inclusive_start = match (self.0).0 {
-            Bound::Included(x) => MyBound::Included(x),
-            Bound::Excluded(x) => MyBound::Included(x + 1),
-            Bound::Unbounded => MyBound::Unbounded,
-        };
-        let inclusive_end = match (self.0).1 {
-            Bound::Included(x) => MyBound::Included(x),
-            Bound::Excluded(x) => MyBound::Included(x - 1),
-            Bound::Unbounded => MyBound::Unbounded,
-        };
-        match (inclusive_start, inclusive_end) {
-            (MyBound::Included(min), MyBound::Unbounded) => write!(f, "AtLeast({})", min),
-            (MyBound::Unbounded, MyBound::Included(max)) => write!(f, "AtMost({})", max),
-            (MyBound::Included(min), MyBound::Included(max)) if min == max => {
-                write!(f, "Exactly({})", max)
-            }
-            (MyBound::Included(min), MyBound::Included(max)) => {
-                write!(f, "Between({}..={})", min, max)
-            }
-            (MyBound::Unbounded, MyBound::Unbounded) => write!(f, "Any"),
+    /// Serve HTTP/2 with support for server push, so that responses from
+    /// [responders::push](responders/fn.push.html) are pushed. The default is
+    /// false, which leaves the pushes out.
+    ///
+    /// HTTP/2 connections are then served by the `h2` crate rather than
+    /// hyper, so hyper's `date` header isn't added to their responses and
+    /// requests can't be upgraded. This is experimental.
+    #[cfg(feature = "http2-push")]
+    pub fn http2_server_push(self, http2_server_push: bool) -> ServerBuilder {
+        ServerBuilder {
+            http2_server_push,
+            ..self
         }
     }
-}
 
-/// Custom Server Builder.
-pub struct ServerBuilder {
-    bind_addr: Option<SocketAddr>,
-}
+    /// Write the raw bytes received and sent on every connection to a file in
+    /// `dir`, one file per connection. This is useful for debugging framing
+    /// issues without needing to capture packets on the loopback interface.
+    ///
+    /// Capturing can also be enabled without changing the code by setting the
+    /// `HTTPTEST_CAPTURE_DIR` environment variable to the directory to write
+    /// to.
+    pub fn capture_traffic(self, dir: impl Into<PathBuf>) -> ServerBuilder {
+        ServerBuilder {
+            capture_dir: Some(dir.into()),
+            ..self
+        }
+    }
 
-impl ServerBuilder {
-    /// Create a new ServerBuilder. By default the server will listen on ipv6
-    /// loopback if available and fallback to ipv4 loopback if unable to bind to
-    /// ipv6.
-    pub fn new() -> ServerBuilder {
-        ServerBuilder { bind_addr: None }
+    /// Forward requests that don't match any expectation to `upstream`
+    /// instead of failing verification, and record them. The recordings are
+    /// available from [Server::recordings](struct.Server.html#method.recordings).
+    ///
+    /// The path of the request is appended to the path of `upstream`. Only
+    /// `http` upstreams are supported, the server fails to start with any
+    /// other scheme.
+    #[cfg(feature = "record")]
+    pub fn forward_unmatched_to(self, upstream: http::Uri) -> ServerBuilder {
+        ServerBuilder {
+            upstream: Some(upstream),
+            ..self
+        }
     }
 
-    /// Specify the address the server should listen on.
-    pub fn bind_addr(self, bind_addr: SocketAddr) -> ServerBuilder {
+    /// Validate every request received and every response sent against an
+    /// OpenAPI spec. Violations fail verification. See the
+    /// [openapi](openapi/index.html) module.
+    #[cfg(feature = "openapi")]
+    pub fn validate_against(self, spec: crate::openapi::OpenApi) -> ServerBuilder {
         ServerBuilder {
-            bind_addr: Some(bind_addr),
+            openapi: Some(spec),
+            ..self
         }
     }
 
@@ -367,59 +4245,182 @@ impl ServerBuilder {
     /// The server will run in the background. On Drop it will terminate and
     /// assert it's expectations.
     pub fn run(self) -> std::io::Result<Server> {
-        // And a MakeService to handle each connection...
-        let state = ServerState::default();
-        let service = |state: ServerState| {
-            service_fn(move |req: http::Request<hyper::body::Incoming>| {
-                let state = state.clone();
-                process_request(state, req)
-            })
+        #[cfg(feature = "tls")]
+        let needs_certificate = self.needs_certificate();
+        let rng = match self.rng_seed {
+            Some(seed) => Rng::new(seed),
+            None => Rng::default(),
+        };
+        log::info!("httptest rng seed: {}", rng.seed());
+        // without a runtime the server runs on tokio, and uses whichever tokio
+        // runtime it's called from.
+        let runtime = self.runtime.clone().unwrap_or_default();
+        let clock = match (self.clock, &self.runtime) {
+            (Some(clock), _) => clock,
+            (None, Some(runtime)) => ServerClock(Arc::new(runtime.clone())),
+            (None, None) => ServerClock::default(),
+        };
+        let listener = match self.port_range {
+            Some(port_range) => {
+                let port_range = port_range
+                    .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))?;
+                Self::listener_in_range(self.bind_addr, port_range)?
+            }
+            None => Self::listener(self.bind_addr)?,
         };
-
-        let listener = Self::listener(self.bind_addr)?;
         listener.set_nonblocking(true)?;
 
         let addr = listener.local_addr()?;
+        #[cfg(feature = "tls")]
+        let identity = if needs_certificate {
+            Some(Arc::new(crate::tls::Identity::self_signed(
+                addr,
+                self.hostname.as_deref(),
+            )?))
+        } else {
+            None
+        };
+        #[cfg(feature = "tls")]
+        let tls_acceptor = match &identity {
+            Some(identity) if self.tls => {
+                let alpn = self.alpn_protocols.unwrap_or_else(|| {
+                    crate::tls::DEFAULT_ALPN_PROTOCOLS
+                        .iter()
+                        .map(|protocol| protocol.as_bytes().to_vec())
+                        .collect()
+                });
+                let config = identity.server_config(rustls::ALL_VERSIONS, alpn)?;
+                Some(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+            }
+            _ => None,
+        };
+        #[cfg_attr(not(feature = "http3"), allow(unused_mut))]
+        let mut default_headers = self.default_headers;
+        #[cfg(feature = "http3")]
+        let http3 = match &identity {
+            Some(identity) if self.http3 => {
+                if self.runtime.is_some() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "http3 requires a tokio runtime",
+                    ));
+                }
+                let http3 = crate::http3::Http3::bind(addr, identity)?;
+                let alt_svc = format!("h3=\":{}\"", http3.local_addr()?.port());
+                default_headers
+                    .entry(http::header::ALT_SVC)
+                    .or_insert(alt_svc.try_into().expect("alt-svc is a valid header value"));
+                Some(http3)
+            }
+            _ => None,
+        };
+        #[cfg(feature = "http3")]
+        let http3_addr = match &http3 {
+            Some(http3) => Some(http3.local_addr()?),
+            None => None,
+        };
+
+        // And a MakeService to handle each connection...
+        let state = ServerState {
+            upload_progress: self.upload_progress,
+            abort_after_bytes: self.abort_after_bytes,
+            slow_body_read: self.slow_body_read,
+            #[cfg(feature = "decompress")]
+            decompress_request_bodies: self.decompress_request_bodies,
+            match_before_body: self.match_before_body,
+            connection_accepted: self.connection_accepted,
+            connection_closed: self.connection_closed,
+            diagnostics: self.diagnostics,
+            request_sink: self.request_sink,
+            max_exchanges: self.max_exchanges,
+            chaos: self.chaos,
+            rng: Arc::new(rng),
+            map_request: self.map_request,
+            map_response: self.map_response,
+            default_headers,
+            request_id_header: self.request_id_header,
+            clock,
+            runtime: runtime.clone(),
+            on_verification_failure: self.on_verification_failure,
+            #[cfg(feature = "record")]
+            recorder: self
+                .upstream
+                .map(crate::record::Recorder::new)
+                .transpose()?,
+            #[cfg(feature = "openapi")]
+            openapi: self.openapi.map(Arc::new),
+            ..ServerState::default()
+        };
+        let capture_dir = capture::capture_dir(self.capture_dir);
+        #[cfg(feature = "http2-push")]
+        let http2_server_push = self.http2_server_push;
+        if let Some(dir) = &capture_dir {
+            std::fs::create_dir_all(dir)?;
+        }
 
         // Then bind and serve...
         let (trigger_shutdown, mut shutdown_received) = tokio::sync::watch::channel(false);
         let state_listener = state.clone();
-        let join_handle = std::thread::spawn(move || {
-            let runtime = tokio::runtime::Builder::new_multi_thread()
-                .worker_threads(1)
-                .enable_all()
-                .build()
-                .unwrap();
+        let (thread_exited_tx, thread_exited) = mpsc::channel::<()>();
+        let (exited_tx, exited) = tokio::sync::watch::channel(());
+        let (in_process_tx, mut in_process_rx) = tokio::sync::mpsc::unbounded_channel();
+        let thread_name = self.thread_name.unwrap_or_else(|| {
+            format!(
+                "httptest-{}",
+                std::thread::current().name().unwrap_or("server")
+            )
+        });
+        let state_thread = state.clone();
+        let server_loop = async move {
+            // dropped when the server exits, signaling Drop that shutdown is
+            // complete.
+            let _thread_exited_tx = thread_exited_tx;
+            let _exited_tx = exited_tx;
+
+            let server_loop = AssertUnwindSafe(async move {
+                let mut listener = runtime.0.listen(listener).unwrap();
+                // every connection task holds a sender, so the channel closes
+                // once all of the connections have been served.
+                let (connection_tasks, mut connections_done) = tokio::sync::mpsc::channel::<()>(1);
+                let spawn_connection = |connection: crate::runtime::Task| {
+                    let connection_task = connection_tasks.clone();
+                    runtime.0.spawn(Box::pin(async move {
+                        connection.await;
+                        drop(connection_task);
+                    }));
+                };
+                let serve = ServeConnection {
+                    state: state_listener,
+                    shutdown_received: shutdown_received.clone(),
+                    capture_dir,
+                    addr,
+                    #[cfg(feature = "tls")]
+                    tls: tls_acceptor,
+                    #[cfg(feature = "http2-push")]
+                    http2_server_push,
+                };
 
-            runtime.block_on(async move {
-                let mut connection_tasks = tokio::task::JoinSet::new();
-                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
-                let conn_shutdown_receiver = shutdown_received.clone();
+                #[cfg(feature = "http3")]
+                if let Some(http3) = http3 {
+                    let endpoint = http3.endpoint().unwrap();
+                    spawn_connection(Box::pin(serve.clone().run_http3(endpoint)));
+                }
 
                 let server = async {
                     loop {
-                        let (stream, _addr) = match listener.accept().await {
-                            Ok(a) => a,
-                            Err(e) => {
-                                panic!("listener failed to accept a new connection: {}", e);
+                        tokio::select! {
+                            accepted = listener.accept() => {
+                                let (stream, peer_addr) = accepted.unwrap_or_else(|e| {
+                                    panic!("listener failed to accept a new connection: {}", e)
+                                });
+                                spawn_connection(Box::pin(serve.clone().run(stream, peer_addr)));
                             }
-                        };
-
-                        let state_c = state_listener.clone();
-                        let mut conn_shutdown_receiver_c = conn_shutdown_receiver.clone();
-                        connection_tasks.spawn(async move {
-                            let builder = Builder::new(hyper_util::rt::TokioExecutor::new());
-                            let connection = builder
-                                .serve_connection(TokioIo::new(stream), service(state_c.clone()));
-                            tokio::pin!(connection);
-
-                            tokio::select! {
-                                _ = connection.as_mut() => {}
-                                _ = conn_shutdown_receiver_c.changed().fuse() => {
-                                    connection.as_mut().graceful_shutdown()
-                                }
-                            };
-                        });
+                            Some(stream) = in_process_rx.recv() => {
+                                let stream: Box<dyn Connection> = Box::new(TokioIo::new(stream));
+                                let peer_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+                                spawn_connection(Box::pin(serve.clone().run(stream, peer_addr)));
+                            }
+                        }
                     }
                 };
 
@@ -428,14 +4429,54 @@ impl ServerBuilder {
                     _ = shutdown_received.changed().fuse() => {},
                 }
 
-                while (connection_tasks.join_next().await).is_some() {}
-            });
-        });
+                drop(connection_tasks);
+                let _ = connections_done.recv().await;
+            })
+            .catch_unwind()
+            .await;
+            if let Err(payload) = server_loop {
+                state_thread.record_panic(payload);
+            }
+        };
+
+        let (runtime, join_handle) = if let Some(runtime) = self.runtime {
+            runtime.0.spawn(Box::pin(server_loop));
+            (runtime, None)
+        } else if self.shared_runtime {
+            let runtime = shared_runtime()?;
+            runtime.spawn(server_loop);
+            (ServerRuntime::tokio(runtime.clone()), None)
+        } else {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name(thread_name.clone())
+                .enable_all()
+                .build()?;
+            let runtime_handle = runtime.handle().clone();
+            let thread = std::thread::Builder::new().name(thread_name);
+            let join_handle = thread.spawn(move || runtime.block_on(server_loop))?;
+            (ServerRuntime::tokio(runtime_handle), Some(join_handle))
+        };
 
         Ok(Server {
             trigger_shutdown: Some(trigger_shutdown),
-            join_handle: Some(join_handle),
+            join_handle,
+            thread_exited,
+            exited,
+            shutdown_warning_after: self.shutdown_warning_after,
+            report_all_failures: self.report_all_failures,
+            runtime,
+            in_process: in_process_tx,
+            json_report: self.json_report,
+            snapshot: self.snapshot.map(Snapshot::new),
             addr,
+            hostname: self.hostname,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            #[cfg(feature = "tls")]
+            identity,
+            #[cfg(feature = "http3")]
+            http3_addr,
             state,
         })
     }
@@ -450,4 +4491,46 @@ impl ServerBuilder {
             }
         }
     }
+
+    fn listener_in_range(
+        bind_addr: Option<SocketAddr>,
+        ports: RangeInclusive<u16>,
+    ) -> std::io::Result<TcpListener> {
+        let (start, end) = ports.into_inner();
+        let ips = match bind_addr {
+            Some(addr) => vec![addr.ip()],
+            None => vec![
+                std::net::Ipv6Addr::LOCALHOST.into(),
+                std::net::Ipv4Addr::LOCALHOST.into(),
+            ],
+        };
+        // start at a different port in each process so that concurrent test
+        // binaries don't all contend for the first ports in the range.
+        let len = u32::from(end - start) + 1;
+        let offset = std::process::id() % len;
+        let mut last_err = None;
+        for ip in ips {
+            for i in 0..len {
+                let port = start + ((offset + i) % len) as u16;
+                match TcpListener::bind(SocketAddr::new(ip, port)) {
+                    Ok(listener) => return Ok(listener),
+                    Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => last_err = Some(e),
+                    // the address itself is unusable, e.g. IPv6 is disabled.
+                    Err(e) => {
+                        last_err = Some(e);
+                        break;
+                    }
+                }
+            }
+        }
+        let last_err = last_err.expect("at least one port was tried");
+        log::debug!("no free port in {}..={}: {}", start, end, last_err);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!(
+                "no free port in the range {}..={}: {}",
+                start, end, last_err
+            ),
+        ))
+    }
 }
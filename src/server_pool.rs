@@ -1,8 +1,11 @@
-use crate::Server;
+use crate::{Server, ServerBuilder};
 use once_cell::sync::OnceCell;
-use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
-use std::sync::Mutex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 /// A pool of shared servers.
 ///
@@ -47,10 +50,9 @@ use std::sync::Mutex;
 ///     // server will assert expectations are met on drop.
 /// }
 /// ```
-
-/// A pool of running servers.
+#[allow(clippy::test_attr_in_doctest)]
 #[derive(Debug)]
-pub struct ServerPool(OnceCell<InnerPool>, usize);
+pub struct ServerPool(OnceCell<InnerPool>, usize, fn() -> ServerBuilder);
 
 impl ServerPool {
     /// Create a new pool of servers.
@@ -58,93 +60,183 @@ impl ServerPool {
     /// `max_servers` is the maximum number of servers that will be created.
     /// servers are created on-demand when `get_server` is invoked.
     pub const fn new(max_servers: usize) -> Self {
-        ServerPool(OnceCell::new(), max_servers)
+        ServerPool(OnceCell::new(), max_servers, ServerBuilder::new)
+    }
+
+    /// Create a new pool of servers that are started from the builder returned
+    /// by `make_builder`.
+    ///
+    /// ```
+    /// # use httptest::{ServerBuilder, ServerPool};
+    /// static SERVER_POOL: ServerPool = ServerPool::with_builder(99, || {
+    ///     ServerBuilder::new().bind_addr(([127, 0, 0, 1], 0).into())
+    /// });
+    /// ```
+    pub const fn with_builder(max_servers: usize, make_builder: fn() -> ServerBuilder) -> Self {
+        ServerPool(OnceCell::new(), max_servers, make_builder)
     }
 
     /// Get the next available server from the pool.
-    pub fn get_server(&self) -> ServerHandle {
-        self.0.get_or_init(|| InnerPool::new(self.1)).get_server()
+    ///
+    /// If all servers are in use this waits for one to be returned. Callers
+    /// are given servers in the order they started waiting.
+    pub fn get_server(&self) -> ServerHandle<'_> {
+        self.inner()
+            .get_server(None)
+            .expect("no deadline to exceed")
+    }
+
+    /// Like [get_server](#method.get_server), but gives up if no server becomes
+    /// available within `timeout`.
+    pub fn get_server_timeout(&self, timeout: Duration) -> Result<ServerHandle<'_>, PoolTimeout> {
+        self.inner().get_server(Some(Instant::now() + timeout))
+    }
+
+    fn inner(&self) -> &InnerPool {
+        self.0.get_or_init(|| InnerPool::new(self.1, self.2))
+    }
+}
+
+/// The error returned when
+/// [ServerPool::get_server_timeout](struct.ServerPool.html#method.get_server_timeout)
+/// times out.
+#[derive(Debug, Clone)]
+pub struct PoolTimeout {
+    waited: Duration,
+    max_servers: usize,
+}
+
+impl fmt::Display for PoolTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} waiting for a server; all {} servers in the pool are in use",
+            self.waited, self.max_servers
+        )
     }
 }
 
-#[allow(clippy::mutex_atomic)]
+impl std::error::Error for PoolTimeout {}
+
 #[derive(Debug)]
 struct InnerPool {
-    servers_created: Mutex<usize>,
-    servers_tx: crossbeam_channel::Sender<Server>,
-    servers_rx: crossbeam_channel::Receiver<Server>,
+    make_builder: fn() -> ServerBuilder,
+    max_servers: usize,
+    state: Mutex<PoolState>,
+    // notified when a server is returned or the waiter at the front of the
+    // queue changes.
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    servers_created: usize,
+    idle: Vec<Server>,
+    // tickets of the callers waiting for a server, served in order.
+    waiters: VecDeque<u64>,
+    next_ticket: u64,
 }
 
-#[allow(clippy::mutex_atomic)]
 impl InnerPool {
-    fn new(max_capacity: usize) -> Self {
-        assert!(max_capacity > 0);
-        let (servers_tx, servers_rx) = crossbeam_channel::bounded(max_capacity);
+    fn new(max_servers: usize, make_builder: fn() -> ServerBuilder) -> Self {
+        assert!(max_servers > 0);
         InnerPool {
-            servers_created: Mutex::new(0),
-            servers_tx,
-            servers_rx,
+            make_builder,
+            max_servers,
+            state: Mutex::new(PoolState::default()),
+            changed: Condvar::new(),
         }
     }
 
-    fn get_server(&self) -> ServerHandle {
-        if let Ok(server) = self.servers_rx.try_recv() {
-            return ServerHandle {
-                servers_tx: self.servers_tx.clone(),
-                server: Some(server),
-                lifetime_marker: PhantomData,
-            };
-        }
-        {
-            let mut servers_created = self.servers_created.lock().expect("poisoned mutex");
-            if *servers_created < self.servers_tx.capacity().unwrap() {
-                *servers_created += 1;
-                return ServerHandle {
-                    servers_tx: self.servers_tx.clone(),
-                    server: Some(Server::run()),
-                    lifetime_marker: PhantomData,
-                };
+    fn get_server(&self, deadline: Option<Instant>) -> Result<ServerHandle<'_>, PoolTimeout> {
+        let started = Instant::now();
+        let mut state = self.state.lock().expect("poisoned mutex");
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiters.push_back(ticket);
+        loop {
+            if state.waiters.front() == Some(&ticket) {
+                if let Some(server) = state.idle.pop() {
+                    state.waiters.pop_front();
+                    self.changed.notify_all();
+                    return Ok(self.handle(server));
+                }
+                if state.servers_created < self.max_servers {
+                    state.servers_created += 1;
+                    state.waiters.pop_front();
+                    self.changed.notify_all();
+                    drop(state);
+                    let server = (self.make_builder)().run().unwrap_or_else(|e| {
+                        self.state.lock().expect("poisoned mutex").servers_created -= 1;
+                        panic!("failed to start server: {}", e)
+                    });
+                    return Ok(self.handle(server));
+                }
             }
+            state = match deadline {
+                None => self.changed.wait(state).expect("poisoned mutex"),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.waiters.retain(|waiter| *waiter != ticket);
+                        self.changed.notify_all();
+                        return Err(PoolTimeout {
+                            waited: now - started,
+                            max_servers: self.max_servers,
+                        });
+                    }
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .expect("poisoned mutex")
+                        .0
+                }
+            };
         }
+    }
+
+    fn handle(&self, server: Server) -> ServerHandle<'_> {
         ServerHandle {
-            servers_tx: self.servers_tx.clone(),
-            server: Some(
-                self.servers_rx
-                    .recv()
-                    .expect("all senders unexpectedly dropped"),
-            ),
-            lifetime_marker: PhantomData,
+            pool: self,
+            server: Some(server),
         }
     }
+
+    fn put_server(&self, server: Server) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match server.health_problem() {
+            None => state.idle.push(server),
+            Some(problem) => {
+                // A new server is started in its place the next time one is
+                // needed. Shutting down waits on any in-flight requests so do
+                // it in the background.
+                log::warn!("replacing pooled server at {}: {}", server.addr(), problem);
+                state.servers_created -= 1;
+                std::thread::spawn(move || drop(server));
+            }
+        }
+        self.changed.notify_all();
+    }
 }
 
-#[allow(clippy::mutex_atomic)]
 impl Drop for InnerPool {
     fn drop(&mut self) {
         // wait for all created servers to get returned to the pool.
-        let servers_created = self.servers_created.lock().expect("poisoned mutex");
-        for _ in 0..*servers_created {
-            self.servers_rx
-                .recv()
-                .expect("all senders unexpectedly dropped");
+        let mut state = self.state.lock().expect("poisoned mutex");
+        while state.idle.len() < state.servers_created {
+            state = self.changed.wait(state).expect("poisoned mutex");
         }
     }
 }
 
 /// A handle to a server. Expectations are inserted when the handle is dropped.
+///
+/// When the handle is dropped the server is returned to the pool. If the
+/// server is unhealthy, e.g. requests are still in flight, it's shut down and
+/// replaced by a new server rather than being handed to the next test.
 #[derive(Debug)]
 pub struct ServerHandle<'a> {
-    servers_tx: crossbeam_channel::Sender<Server>,
+    pool: &'a InnerPool,
     server: Option<Server>,
-
-    // We add a lifetime to the ServerHandle just to restrict the ownership
-    // beyond what the implementation currently allows. The public facing API
-    // will appear that the ServerHandle is borrowed from a Pool, which enforces
-    // the desired behavior. No SeverHandle should outlive the underlying Pool.
-    // The current implementation passes around owned values so there is no real
-    // borrowing constraint, but a future implementation may do something more
-    // efficient.
-    lifetime_marker: PhantomData<&'a ()>,
 }
 
 impl Deref for ServerHandle<'_> {
@@ -164,10 +256,12 @@ impl DerefMut for ServerHandle<'_> {
 impl Drop for ServerHandle<'_> {
     fn drop(&mut self) {
         let mut server = self.server.take().unwrap();
-        server.verify_and_clear();
-        self.servers_tx
-            .send(server)
-            .expect("all receivers unexpectedly dropped");
+        // return the server to the pool even if verification fails.
+        let verified = panic::catch_unwind(AssertUnwindSafe(|| server.verify_and_clear()));
+        self.pool.put_server(server);
+        if let Err(payload) = verified {
+            panic::resume_unwind(payload);
+        }
     }
 }
 
@@ -177,6 +271,74 @@ mod tests {
 
     const MAX_SERVERS: usize = 5;
     static POOL: ServerPool = ServerPool::new(MAX_SERVERS);
+    static IPV4_POOL: ServerPool = ServerPool::with_builder(1, || {
+        ServerBuilder::new().bind_addr(([127, 0, 0, 1], 0).into())
+    });
+
+    #[test]
+    fn test_with_builder() {
+        let server = IPV4_POOL.get_server();
+        assert!(server.addr().is_ipv4());
+    }
+
+    #[test]
+    fn test_get_server_timeout() {
+        static POOL: ServerPool = ServerPool::new(1);
+        let _server = POOL.get_server();
+        let err = POOL
+            .get_server_timeout(std::time::Duration::from_millis(10))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("all 1 servers in the pool are in use"));
+    }
+
+    #[test]
+    fn test_waiters_are_served_in_order() {
+        static POOL: ServerPool = ServerPool::new(1);
+        let order = Mutex::new(Vec::new());
+        let server = POOL.get_server();
+        crossbeam_utils::thread::scope(|s| {
+            for i in 0..3 {
+                let order = &order;
+                s.spawn(move |_| {
+                    let _server = POOL.get_server();
+                    order.lock().unwrap().push(i);
+                });
+                // give the thread time to start waiting.
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            drop(server);
+        })
+        .unwrap();
+        assert_eq!(vec![0, 1, 2], *order.lock().unwrap());
+    }
+
+    #[test]
+    fn test_unhealthy_servers_are_replaced() {
+        use crate::{matchers::*, responders::*, Expectation};
+        use std::io::Write;
+        static POOL: ServerPool = ServerPool::new(1);
+
+        let server = POOL.get_server();
+        let addr = server.addr();
+        let slow = server.expect(
+            Expectation::matching(any())
+                .respond_with(delay_and_then(Duration::from_secs(1), status_code(200))),
+        );
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .unwrap();
+        while slow.hit_count() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // the request is still in flight when the server is returned.
+        drop(server);
+
+        let server = POOL.get_server();
+        assert_ne!(addr, server.addr());
+    }
 
     #[test]
     fn test_max_threads() {
@@ -0,0 +1,165 @@
+//! Snapshots of the requests a server received.
+//!
+//! Requests are rendered as text so they can be reviewed and diffed:
+//!
+//! ```text
+//! POST /users?notify=true
+//! content-type: application/json
+//!
+//! {
+//!   "name": "bob"
+//! }
+//! ```
+//!
+//! Headers are sorted by name and headers that change between runs are
+//! omitted. Json bodies are pretty printed with sorted keys.
+
+use crate::identity::Digest;
+use crate::server::FullRequest;
+use std::path::{Path, PathBuf};
+
+/// Set to accept new and changed snapshots instead of failing.
+pub(crate) const UPDATE_ENV: &str = "HTTPTEST_UPDATE_SNAPSHOTS";
+
+// Headers that vary between runs, e.g. the host includes the server's port.
+const IGNORED_HEADERS: &[&str] = &["host", "content-length", "user-agent", "date"];
+
+const SEPARATOR: &str = "---\n";
+
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    path: PathBuf,
+    // the rendered requests received so far.
+    requests: Vec<String>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(path: PathBuf) -> Snapshot {
+        Snapshot {
+            path,
+            requests: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, req: &FullRequest) {
+        self.requests.push(render(req));
+    }
+
+    // Compare the received requests to the stored snapshot. Returns a
+    // description of the failure if they differ.
+    pub(crate) fn check(&self) -> Option<String> {
+        let actual = self.requests.join(SEPARATOR);
+        let new_path = new_path(&self.path);
+        let expected = std::fs::read_to_string(&self.path).ok();
+        if expected.as_deref() == Some(actual.as_str()) {
+            let _ = std::fs::remove_file(&new_path);
+            return None;
+        }
+        let update = std::env::var_os(UPDATE_ENV).filter(|v| !v.is_empty() && v != "0");
+        if update.is_some() {
+            log::info!("updating snapshot {}", self.path.display());
+            if let Err(e) = write(&self.path, &actual) {
+                return Some(format!(
+                    "failed to update snapshot {}: {}",
+                    self.path.display(),
+                    e
+                ));
+            }
+            let _ = std::fs::remove_file(&new_path);
+            return None;
+        }
+
+        let mut msg = match &expected {
+            None => format!("no snapshot found at {}", self.path.display()),
+            Some(expected) => {
+                format!(
+                    "received requests do not match snapshot {}\n{}",
+                    self.path.display(),
+                    crate::diff::line_diff(expected, &actual)
+                )
+            }
+        };
+        match write(&new_path, &actual) {
+            Ok(()) => msg.push_str(&format!(
+                "\nthe received requests were written to {}. Review them and rename the \
+                 file to {} to accept them, or rerun with {}=1.",
+                new_path.display(),
+                self.path.display(),
+                UPDATE_ENV
+            )),
+            Err(e) => msg.push_str(&format!(
+                "\nfailed to write the received requests to {}: {}",
+                new_path.display(),
+                e
+            )),
+        }
+        Some(msg)
+    }
+}
+
+fn new_path(path: &Path) -> PathBuf {
+    let mut new_path = path.as_os_str().to_owned();
+    new_path.push(".new");
+    new_path.into()
+}
+
+fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents)
+}
+
+fn render(req: &FullRequest) -> String {
+    let uri = req.uri();
+    let mut out = format!(
+        "{} {}\n",
+        req.method(),
+        uri.path_and_query()
+            .map_or_else(|| uri.path(), |pq| pq.as_str())
+    );
+    let mut headers: Vec<_> = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| !IGNORED_HEADERS.contains(&name.as_str()))
+        .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes())))
+        .collect();
+    // sort by name only so repeated headers keep their order.
+    headers.sort_by_key(|(name, _)| *name);
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+
+    let body = req.body();
+    if !body.is_empty() {
+        out.push('\n');
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(json) => out.push_str(&serde_json::to_string_pretty(&json).unwrap()),
+            Err(_) => match std::str::from_utf8(body) {
+                Ok(text) => out.push_str(text.trim_end_matches('\n')),
+                Err(_) => out.push_str(&format!("<binary {:?}>", Digest::of(body))),
+            },
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let req = http::Request::post("http://localhost:1234/users?notify=true")
+            .header("x-b", "2")
+            .header("host", "localhost:1234")
+            .header("x-a", "1")
+            .body(bytes::Bytes::from(r#"{"name":"bob","age":3}"#))
+            .unwrap();
+        assert_eq!(
+            "POST /users?notify=true\nx-a: 1\nx-b: 2\n\n{\n  \"age\": 3,\n  \"name\": \"bob\"\n}\n",
+            render(&req)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Match and respond to SOAP requests.
+//!
+//! Requests are matched by their SOAP action and by evaluating XPath
+//! expressions against the body. Responses wrap a payload in a SOAP 1.1
+//! envelope.
+//!
+//! ```
+//! use httptest::{matchers::*, soap, Expectation};
+//!
+//! Expectation::matching(all_of![
+//!     soap::action("urn:GetQuote"),
+//!     soap::body_root("GetQuote", "urn:stocks"),
+//!     soap::xpath("string(//s:Symbol)", "ACME").namespace("s", "urn:stocks"),
+//! ])
+//! .respond_with(soap::envelope(
+//!     r#"<GetQuoteResponse xmlns="urn:stocks"><Price>12.5</Price></GetQuoteResponse>"#,
+//! ));
+//! ```
+
+use crate::matchers::{matcher_name, ExecutionContext, Matcher};
+use crate::responders::{status_code, ResponseBuilder};
+use std::fmt;
+
+/// The namespace of a SOAP 1.1 envelope, bound to the `soap` prefix in XPath
+/// expressions.
+pub const SOAP11_NAMESPACE: &str = "http://schemas.xmlsoap.org/soap/envelope/";
+/// The namespace of a SOAP 1.2 envelope, bound to the `soap12` prefix in XPath
+/// expressions.
+pub const SOAP12_NAMESPACE: &str = "http://www.w3.org/2003/05/soap-envelope";
+
+/// Extract the SOAP action from the request and pass it to the next mapper.
+///
+/// The action is read from the `SOAPAction` header of SOAP 1.1 requests or
+/// the `action` parameter of the content-type of SOAP 1.2 requests, without
+/// the surrounding quotes. It's empty if the request has neither.
+pub fn action<M>(inner: M) -> Action<M> {
+    Action(inner)
+}
+/// The `Action` mapper returned by [action()](fn.action.html)
+#[derive(Debug)]
+pub struct Action<M>(M);
+impl<M, B> Matcher<http::Request<B>> for Action<M>
+where
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        let header = |name| {
+            input
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+        };
+        let action = match input.headers().get("soapaction") {
+            Some(_) => header("soapaction"),
+            None => header("content-type")
+                .split(';')
+                .filter_map(|param| param.trim().strip_prefix("action="))
+                .next()
+                .unwrap_or(""),
+        };
+        ctx.chain(&mut self.0, action.trim_matches('"'))
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Action")
+            .field(&matcher_name(&self.0))
+            .finish()
+    }
+}
+
+/// Evaluate an XPath expression against the request body and pass the string
+/// value of the result to the next mapper.
+///
+/// The `soap` and `soap12` prefixes are bound to the SOAP 1.1 and 1.2
+/// envelope namespaces. Other prefixes can be bound with
+/// [namespace](struct.XPath.html#method.namespace). Requests whose body is
+/// not XML don't match.
+///
+/// This function will panic if the expression is invalid.
+pub fn xpath<M>(expr: &str, inner: M) -> XPath<M> {
+    match sxd_xpath::Factory::new().build(expr) {
+        Ok(Some(_)) => {}
+        Ok(None) => panic!("empty xpath expression"),
+        Err(err) => panic!("invalid xpath expression {:?}: {}", expr, err),
+    }
+    XPath {
+        expr: expr.to_owned(),
+        namespaces: vec![
+            ("soap".to_owned(), SOAP11_NAMESPACE.to_owned()),
+            ("soap12".to_owned(), SOAP12_NAMESPACE.to_owned()),
+        ],
+        inner,
+    }
+}
+/// The `XPath` mapper returned by [xpath()](fn.xpath.html)
+#[derive(Debug)]
+pub struct XPath<M> {
+    expr: String,
+    namespaces: Vec<(String, String)>,
+    inner: M,
+}
+impl<M> XPath<M> {
+    /// Bind a prefix to a namespace for use in the expression.
+    pub fn namespace(mut self, prefix: &str, uri: &str) -> Self {
+        self.namespaces.push((prefix.to_owned(), uri.to_owned()));
+        self
+    }
+}
+impl<M, B> Matcher<http::Request<B>> for XPath<M>
+where
+    B: AsRef<[u8]>,
+    M: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        if ctx.body_unavailable() {
+            return true;
+        }
+        match evaluate(input.body().as_ref(), &self.expr, &self.namespaces) {
+            Some(value) => ctx.chain(&mut self.inner, value.as_str()),
+            None => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("XPath")
+            .field("expr", &self.expr)
+            .field("inner", &matcher_name(&self.inner))
+            .finish()
+    }
+}
+
+/// Match the name and namespace of the first element within the envelope's
+/// body, which identifies the operation being called.
+pub fn body_root<N, NS>(name: N, namespace: NS) -> BodyRoot<N, NS> {
+    BodyRoot { name, namespace }
+}
+/// The `BodyRoot` matcher returned by [body_root()](fn.body_root.html)
+#[derive(Debug)]
+pub struct BodyRoot<N, NS> {
+    name: N,
+    namespace: NS,
+}
+impl<N, NS, B> Matcher<http::Request<B>> for BodyRoot<N, NS>
+where
+    B: AsRef<[u8]>,
+    N: Matcher<str>,
+    NS: Matcher<str>,
+{
+    fn matches(&mut self, input: &http::Request<B>, ctx: &mut ExecutionContext) -> bool {
+        const ROOT: &str = "/*[local-name()='Envelope']/*[local-name()='Body']/*[1]";
+        if ctx.body_unavailable() {
+            return true;
+        }
+        let body = input.body().as_ref();
+        let name = evaluate(body, &format!("local-name({})", ROOT), &[]);
+        let namespace = evaluate(body, &format!("namespace-uri({})", ROOT), &[]);
+        match (name, namespace) {
+            (Some(name), Some(namespace)) => {
+                ctx.chain(&mut self.name, name.as_str())
+                    && ctx.chain(&mut self.namespace, namespace.as_str())
+            }
+            _ => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BodyRoot")
+            .field("name", &matcher_name(&self.name))
+            .field("namespace", &matcher_name(&self.namespace))
+            .finish()
+    }
+}
+
+// Evaluate the expression against the body, returning the string value of
+// the result. Returns None if the body isn't XML.
+fn evaluate(body: &[u8], expr: &str, namespaces: &[(String, String)]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let package = sxd_document::parser::parse(body).ok()?;
+    let document = package.as_document();
+    let xpath = sxd_xpath::Factory::new().build(expr).ok()??;
+    let mut context = sxd_xpath::Context::new();
+    for (prefix, uri) in namespaces {
+        context.set_namespace(prefix, uri);
+    }
+    let value = xpath.evaluate(&context, document.root()).ok()?;
+    Some(value.string())
+}
+
+/// respond with the payload wrapped in a SOAP 1.1 envelope.
+///
+/// The status code will be `200` and the content-type will be
+/// `text/xml; charset=utf-8`.
+pub fn envelope(payload: &str) -> ResponseBuilder<String> {
+    envelope_with_status(200, payload)
+}
+
+fn envelope_with_status(status: u16, payload: &str) -> ResponseBuilder<String> {
+    status_code(status)
+        .append_header("Content-Type", "text/xml; charset=utf-8")
+        .body(format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><soap:Envelope xmlns:soap="{}"><soap:Body>{}</soap:Body></soap:Envelope>"#,
+            SOAP11_NAMESPACE, payload
+        ))
+}
+
+/// respond with a SOAP 1.1 fault with the given code (e.g. `soap:Server`) and
+/// description.
+///
+/// The status code will be `500` and the content-type will be
+/// `text/xml; charset=utf-8`.
+pub fn fault(code: &str, description: &str) -> ResponseBuilder<String> {
+    let payload = format!(
+        "<soap:Fault><faultcode>{}</faultcode><faultstring>{}</faultstring></soap:Fault>",
+        escape(code),
+        escape(description)
+    );
+    envelope_with_status(500, &payload)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = r#"<?xml version="1.0"?>
+<soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/">
+  <soap:Body>
+    <m:GetQuote xmlns:m="urn:stocks"><m:Symbol>ACME</m:Symbol></m:GetQuote>
+  </soap:Body>
+</soap:Envelope>"#;
+
+    fn soap_request() -> http::Request<&'static str> {
+        http::Request::post("/quotes")
+            .header("SOAPAction", "\"urn:GetQuote\"")
+            .body(REQUEST)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_action() {
+        assert!(ExecutionContext::evaluate(
+            &mut action("urn:GetQuote"),
+            &soap_request()
+        ));
+
+        let req = http::Request::post("/quotes")
+            .header(
+                "Content-Type",
+                "application/soap+xml; charset=utf-8; action=\"urn:GetQuote\"",
+            )
+            .body(REQUEST)
+            .unwrap();
+        assert!(ExecutionContext::evaluate(
+            &mut action("urn:GetQuote"),
+            &req
+        ));
+    }
+
+    #[test]
+    fn test_xpath() {
+        let req = soap_request();
+        assert!(ExecutionContext::evaluate(
+            &mut xpath("string(//m:Symbol)", "ACME").namespace("m", "urn:stocks"),
+            &req
+        ));
+        assert!(ExecutionContext::evaluate(
+            &mut xpath("count(/soap:Envelope/soap:Body/*)", "1"),
+            &req
+        ));
+
+        let req = http::Request::post("/quotes").body("not xml").unwrap();
+        assert!(!ExecutionContext::evaluate(
+            &mut xpath("string(/)", ""),
+            &req
+        ));
+    }
+
+    #[test]
+    fn test_body_root() {
+        let req = soap_request();
+        assert!(ExecutionContext::evaluate(
+            &mut body_root("GetQuote", "urn:stocks"),
+            &req
+        ));
+        assert!(!ExecutionContext::evaluate(
+            &mut body_root("GetQuote", "urn:other"),
+            &req
+        ));
+    }
+}
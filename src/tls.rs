@@ -0,0 +1,124 @@
+//! The certificate the server presents over TLS.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::SupportedProtocolVersion;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+// The ALPN protocols offered when none are configured.
+pub(crate) const DEFAULT_ALPN_PROTOCOLS: [&str; 2] = ["h2", "http/1.1"];
+
+// The encodings of the certificate handed out to clients.
+#[derive(Debug, Clone)]
+pub(crate) struct Certificate {
+    pub(crate) der: Vec<u8>,
+    pub(crate) pem: String,
+}
+
+// The certificate and key presented to clients. Every TLS config the server
+// builds resolves the certificate here, so replacing it changes the
+// certificate presented to new connections.
+#[derive(Debug)]
+pub(crate) struct Identity {
+    // the names self-signed certificates are valid for.
+    names: Vec<String>,
+    current: RwLock<(Certificate, Arc<CertifiedKey>)>,
+}
+
+impl Identity {
+    // A certificate valid for localhost, the ip of `addr` and `hostname`.
+    pub(crate) fn self_signed(addr: SocketAddr, hostname: Option<&str>) -> io::Result<Self> {
+        let mut names = vec!["localhost".to_string(), addr.ip().to_string()];
+        names.extend(hostname.map(str::to_string));
+        let current = generate(&names)?;
+        Ok(Identity {
+            names,
+            current: RwLock::new(current),
+        })
+    }
+
+    pub(crate) fn certificate(&self) -> Certificate {
+        self.current.read().expect("lock poisoned").0.clone()
+    }
+
+    // Replace the certificate with a newly generated self-signed one.
+    pub(crate) fn rotate(&self) -> io::Result<()> {
+        let current = generate(&self.names)?;
+        *self.current.write().expect("lock poisoned") = current;
+        Ok(())
+    }
+
+    // Replace the certificate with a PEM encoded chain and private key.
+    pub(crate) fn replace(&self, cert_chain_pem: &str, key_pem: &str) -> io::Result<()> {
+        let chain = CertificateDer::pem_slice_iter(cert_chain_pem.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| invalid_data("no certificate in the chain"))?;
+        let certificate = Certificate {
+            der: leaf.to_vec(),
+            pem: cert_chain_pem.to_string(),
+        };
+        let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).map_err(invalid_data)?;
+        let key = certified_key(chain, &key)?;
+        *self.current.write().expect("lock poisoned") = (certificate, key);
+        Ok(())
+    }
+
+    // A rustls config presenting the certificate and offering `alpn`.
+    // Session resumption is disabled so that every connection is presented
+    // the current certificate.
+    pub(crate) fn server_config(
+        self: &Arc<Self>,
+        versions: &[&'static SupportedProtocolVersion],
+        alpn: Vec<Vec<u8>>,
+    ) -> io::Result<rustls::ServerConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(versions)
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn;
+        config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+        config.send_tls13_tickets = 0;
+        Ok(config)
+    }
+}
+
+impl ResolvesServerCert for Identity {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("lock poisoned").1.clone())
+    }
+}
+
+fn generate(names: &[String]) -> io::Result<(Certificate, Arc<CertifiedKey>)> {
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let certificate = Certificate {
+        der: certified.cert.der().to_vec(),
+        pem: certified.cert.pem(),
+    };
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()).into();
+    let key = certified_key(vec![certified.cert.der().clone()], &key)?;
+    Ok((certificate, key))
+}
+
+fn certified_key(
+    chain: Vec<CertificateDer<'static>>,
+    key: &PrivateKeyDer<'_>,
+) -> io::Result<Arc<CertifiedKey>> {
+    let key = rustls::crypto::ring::sign::any_supported_type(key).map_err(invalid_data)?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
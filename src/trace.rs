@@ -0,0 +1,88 @@
+//! Optional `tracing` instrumentation.
+//!
+//! When the `tracing` feature is enabled the server emits a span for every
+//! request it receives and for every responder it runs, along with events for
+//! matcher evaluation. When the feature is disabled these functions are no-ops
+//! so callers don't need to be littered with `#[cfg]` attributes.
+
+use std::fmt;
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+/// A span covering the handling of a single request. The `expectation` and
+/// `status` fields are recorded once they are known.
+#[cfg(feature = "tracing")]
+pub(crate) fn request_span<B>(conn_id: u64, req: &http::Request<B>) -> Span {
+    tracing::info_span!(
+        "httptest.request",
+        conn_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+        expectation = tracing::field::Empty,
+        status = tracing::field::Empty,
+    )
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn request_span<B>(_conn_id: u64, _req: &http::Request<B>) -> Span {
+    Span
+}
+
+/// A span covering the execution of a responder.
+#[cfg(feature = "tracing")]
+pub(crate) fn respond_span() -> Span {
+    tracing::debug_span!("httptest.respond")
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn respond_span() -> Span {
+    Span
+}
+
+/// Run the future within the span.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(fut: F, span: Span) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(fut, span)
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(fut: F, _span: Span) -> impl Future<Output = F::Output> {
+    fut
+}
+
+/// Record the matcher of the expectation that matched the current request.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_expectation(matcher: &dyn fmt::Debug) {
+    Span::current().record("expectation", tracing::field::debug(matcher));
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_expectation(_matcher: &dyn fmt::Debug) {}
+
+/// Record the status code of the response to the current request.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_status(status: http::StatusCode) {
+    Span::current().record("status", status.as_u16());
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_status(_status: http::StatusCode) {}
+
+/// Emit an event describing the result of evaluating a matcher.
+#[cfg(feature = "tracing")]
+pub(crate) fn matcher_evaluated(depth: usize, matcher: &dyn fmt::Debug, matched: bool) {
+    tracing::trace!(depth, matcher = ?matcher, matched, "matcher evaluated");
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn matcher_evaluated(_depth: usize, _matcher: &dyn fmt::Debug, _matched: bool) {}
+
+/// Emit an event for a request that did not match any expectation.
+#[cfg(feature = "tracing")]
+pub(crate) fn unexpected_request(closest: Option<&dyn fmt::Display>) {
+    match closest {
+        Some(closest) => tracing::warn!(%closest, "no expectation matched request"),
+        None => tracing::warn!("no expectation matched request"),
+    }
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn unexpected_request(_closest: Option<&dyn fmt::Display>) {}
@@ -0,0 +1,433 @@
+//! Load [WireMock](https://wiremock.org) stub mappings as expectations.
+//!
+//! A stub file is either a single mapping or an object with a `mappings`
+//! array. Each mapping becomes an expectation that matches any number of
+//! requests, like a WireMock stub.
+//!
+//! ```no_run
+//! # use httptest::{wiremock, Server};
+//! let server = Server::run();
+//! for expectation in wiremock::load_dir("tests/mappings").unwrap() {
+//!     server.expect(expectation);
+//! }
+//! ```
+//!
+//! Supported request fields are `method`, `url`, `urlPath`, `urlPattern`,
+//! `urlPathPattern`, `queryParameters`, `headers` and `bodyPatterns`, with the
+//! `equalTo`, `contains`, `matches`, `doesNotMatch`, `absent` and
+//! `equalToJson` operators. `caseInsensitive` applies to every string
+//! operator. Supported response fields are `status`, `statusMessage`,
+//! `headers`, `body`, `jsonBody`, `base64Body` and `fixedDelayMilliseconds`.
+//! A mapping's `priority` is honored. Any other request field, operator or
+//! response field is an error rather than being silently ignored.
+
+use crate::matchers::{
+    all_of, contains, eq, json_decoded, key, matches, not, request, url_decoded, ExecutionContext,
+    Matcher,
+};
+use crate::responders::delay_and_then;
+use crate::Expectation;
+use base64::Engine;
+use bytes::Bytes;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+type Request = http::Request<Bytes>;
+
+// WireMock's default priority. Lower numbers take precedence.
+const DEFAULT_PRIORITY: i64 = 5;
+
+/// Read the stub file at `path` and return an expectation for each mapping.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Expectation>> {
+    let path = path.as_ref();
+    parse(&std::fs::read(path)?)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Load every `.json` stub file in `dir`, in file name order.
+pub fn load_dir(dir: impl AsRef<Path>) -> io::Result<Vec<Expectation>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("json".as_ref()) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut expectations = Vec::new();
+    for path in paths {
+        expectations.extend(load(path)?);
+    }
+    Ok(expectations)
+}
+
+/// Parse a stub file and return an expectation for each mapping.
+pub fn parse(stubs: &[u8]) -> io::Result<Vec<Expectation>> {
+    let stubs: Value = serde_json::from_slice(stubs)?;
+    let mappings = match stubs.get("mappings") {
+        Some(mappings) => mappings
+            .as_array()
+            .ok_or_else(|| invalid("mappings is not an array".to_string()))?
+            .iter()
+            .collect(),
+        None => vec![&stubs],
+    };
+    mappings
+        .into_iter()
+        .enumerate()
+        .map(|(i, mapping)| {
+            mapping_expectation(mapping).map_err(|e| invalid(format!("mapping {}: {}", i, e)))
+        })
+        .collect()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid WireMock stub: {}", msg),
+    )
+}
+
+fn mapping_expectation(mapping: &Value) -> Result<Expectation, String> {
+    let priority = match &mapping["priority"] {
+        Value::Null => DEFAULT_PRIORITY,
+        priority => priority.as_i64().ok_or("priority is not an integer")?,
+    };
+    let matcher = request_matcher(&mapping["request"])?;
+    let (response, delay) = response(&mapping["response"])?;
+    let expectation = Expectation::matching(matcher)
+        .times(..)
+        .priority((DEFAULT_PRIORITY - priority) as i32);
+    Ok(match delay {
+        Some(delay) => expectation.respond_with(delay_and_then(delay, response)),
+        None => expectation.respond_with(response),
+    })
+}
+
+// Return an error naming the first field of `value` that isn't supported.
+fn check_fields(value: &Value, supported: &[&str], what: &str) -> Result<(), String> {
+    for field in value.as_object().into_iter().flatten().map(|(k, _)| k) {
+        if !supported.contains(&field.as_str()) {
+            return Err(format!("unsupported {}: {}", what, field));
+        }
+    }
+    Ok(())
+}
+
+fn request_matcher(req: &Value) -> Result<impl Matcher<Request>, String> {
+    const SUPPORTED: &[&str] = &[
+        "method",
+        "url",
+        "urlPath",
+        "urlPattern",
+        "urlPathPattern",
+        "queryParameters",
+        "headers",
+        "bodyPatterns",
+    ];
+    check_fields(req, SUPPORTED, "request field")?;
+
+    let mut matchers: Vec<Box<dyn Matcher<Request>>> = Vec::new();
+    match req["method"].as_str() {
+        None | Some("ANY") => {}
+        Some(method) => matchers.push(Box::new(request::method(method.to_string()))),
+    }
+
+    if let Some(url) = req["url"].as_str() {
+        let url = url.to_string();
+        matchers.push(Box::new(move |req: &Request| path_and_query(req) == url));
+    }
+    if let Some(path) = req["urlPath"].as_str() {
+        matchers.push(Box::new(request::path(path.to_string())));
+    }
+    if let Some(pattern) = req["urlPattern"].as_str() {
+        let regex = full_match_regex(pattern)?;
+        matchers.push(Box::new(move |req: &Request| {
+            regex.is_match(path_and_query(req).as_bytes())
+        }));
+    }
+    if let Some(pattern) = req["urlPathPattern"].as_str() {
+        matchers.push(Box::new(request::path(matches(full_match_regex(pattern)?))));
+    }
+
+    for (name, spec) in req["queryParameters"].as_object().into_iter().flatten() {
+        let name = name.clone();
+        matchers.push(match Operator::parse(spec)? {
+            None => Box::new(request::query(url_decoded(not(contains(key(name)))))),
+            Some(op) => Box::new(request::query(url_decoded(contains((name, op))))),
+        });
+    }
+    for (name, spec) in req["headers"].as_object().into_iter().flatten() {
+        let name = name.to_lowercase();
+        matchers.push(match Operator::parse(spec)? {
+            None => Box::new(request::headers(not(contains(key(name))))),
+            Some(op) => Box::new(request::headers(contains((name, op)))),
+        });
+    }
+    for spec in req["bodyPatterns"].as_array().into_iter().flatten() {
+        match spec.get("equalToJson") {
+            Some(json) => {
+                check_fields(spec, &["equalToJson"], "equalToJson option")?;
+                // equalToJson may be given as json or as a string of json.
+                let json = match json {
+                    Value::String(s) => serde_json::from_str(s)
+                        .map_err(|e| format!("invalid equalToJson: {}", e))?,
+                    json => json.clone(),
+                };
+                matchers.push(Box::new(request::body(json_decoded(eq(json)))));
+            }
+            None => match Operator::parse(spec)? {
+                Some(op) => matchers.push(Box::new(request::body(op))),
+                None => return Err("absent is not supported in bodyPatterns".to_string()),
+            },
+        }
+    }
+    Ok(all_of(matchers))
+}
+
+// A WireMock string operator such as `{"equalTo": "foo"}`, compiled to a
+// regex.
+#[derive(Debug)]
+struct Operator {
+    spec: Value,
+    regex: regex::bytes::Regex,
+    negate: bool,
+}
+
+impl Operator {
+    // Parse the operator. `absent` is returned as None because it applies to
+    // the key rather than the value.
+    fn parse(spec: &Value) -> Result<Option<Operator>, String> {
+        const SUPPORTED: &[&str] = &[
+            "equalTo",
+            "contains",
+            "matches",
+            "doesNotMatch",
+            "absent",
+            "caseInsensitive",
+        ];
+        check_fields(spec, SUPPORTED, "match operator")?;
+        if spec["absent"] == Value::Bool(true) {
+            return Ok(None);
+        }
+        let flags = if spec["caseInsensitive"] == Value::Bool(true) {
+            "(?i)"
+        } else {
+            ""
+        };
+        let operand = |op: &str| spec[op].as_str();
+        let (regex, negate) = if let Some(expected) = operand("equalTo") {
+            (format!("{}^{}$", flags, regex::escape(expected)), false)
+        } else if let Some(expected) = operand("contains") {
+            (format!("{}{}", flags, regex::escape(expected)), false)
+        } else if let Some(pattern) = operand("matches") {
+            (format!("{}^(?:{})$", flags, pattern), false)
+        } else if let Some(pattern) = operand("doesNotMatch") {
+            (format!("{}^(?:{})$", flags, pattern), true)
+        } else {
+            return Err(format!("unsupported match operator: {}", spec));
+        };
+        Ok(Some(Operator {
+            spec: spec.clone(),
+            regex: regex::bytes::Regex::new(&regex).map_err(|e| e.to_string())?,
+            negate,
+        }))
+    }
+}
+
+impl<IN> Matcher<IN> for Operator
+where
+    IN: AsRef<[u8]> + ?Sized,
+{
+    fn matches(&mut self, input: &IN, _ctx: &mut ExecutionContext) -> bool {
+        self.regex.is_match(input.as_ref()) != self.negate
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WireMock({})", self.spec)
+    }
+}
+
+fn full_match_regex(pattern: &str) -> Result<regex::bytes::Regex, String> {
+    regex::bytes::Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())
+}
+
+fn path_and_query(req: &Request) -> String {
+    req.uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string())
+}
+
+fn response(resp: &Value) -> Result<(http::Response<Bytes>, Option<Duration>), String> {
+    const SUPPORTED: &[&str] = &[
+        "status",
+        "statusMessage",
+        "headers",
+        "body",
+        "jsonBody",
+        "base64Body",
+        "fixedDelayMilliseconds",
+    ];
+    check_fields(resp, SUPPORTED, "response field")?;
+
+    let status = resp["status"].as_u64().unwrap_or(200);
+    let mut builder = http::Response::builder().status(status as u16);
+    if let Some(message) = resp.get("statusMessage") {
+        let message = message.as_str().ok_or("statusMessage is not a string")?;
+        let reason = hyper::ext::ReasonPhrase::try_from(message.to_string())
+            .map_err(|_| format!("invalid statusMessage: {:?}", message))?;
+        builder = builder.extension(reason);
+    }
+    for (name, values) in resp["headers"].as_object().into_iter().flatten() {
+        // a header may have a single value or a list of values.
+        let values = match values {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = value.as_str().ok_or("header values must be strings")?;
+            builder = builder.header(name.as_str(), value);
+        }
+    }
+    let body = if let Some(body) = resp["body"].as_str() {
+        Bytes::from(body.to_string())
+    } else if let Some(json) = resp.get("jsonBody") {
+        Bytes::from(json.to_string())
+    } else if let Some(body) = resp["base64Body"].as_str() {
+        base64::engine::general_purpose::STANDARD
+            .decode(body)
+            .map_err(|e| format!("invalid base64Body: {}", e))?
+            .into()
+    } else {
+        Bytes::new()
+    };
+    let delay = resp["fixedDelayMilliseconds"]
+        .as_u64()
+        .map(Duration::from_millis);
+    let response = builder.body(body).map_err(|e| e.to_string())?;
+    Ok((response, delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(req: Value) -> impl Matcher<Request> {
+        request_matcher(&req).unwrap()
+    }
+
+    #[test]
+    fn test_request_matcher() {
+        let req = http::Request::post("/api/users?page=2&sort=name")
+            .header("content-type", "application/json; charset=utf-8")
+            .body(Bytes::from(r#"{"name": "bob"}"#))
+            .unwrap();
+        let mut m = matcher(serde_json::json!({
+            "method": "POST",
+            "urlPathPattern": "/api/[a-z]+",
+            "queryParameters": {
+                "page": {"equalTo": "2"},
+                "debug": {"absent": true},
+            },
+            "headers": {
+                "Content-Type": {"contains": "application/json"},
+            },
+            "bodyPatterns": [{"equalToJson": {"name": "bob"}}],
+        }));
+        assert!(ExecutionContext::evaluate(&mut m, &req));
+
+        let mut m = matcher(serde_json::json!({"url": "/api/users?page=2&sort=name"}));
+        assert!(ExecutionContext::evaluate(&mut m, &req));
+        let mut m = matcher(serde_json::json!({"url": "/api/users"}));
+        assert!(!ExecutionContext::evaluate(&mut m, &req));
+        let mut m = matcher(serde_json::json!({
+            "headers": {"content-type": {"equalTo": "APPLICATION/JSON; CHARSET=UTF-8", "caseInsensitive": true}},
+        }));
+        assert!(ExecutionContext::evaluate(&mut m, &req));
+        let mut m = matcher(serde_json::json!({
+            "queryParameters": {"page": {"doesNotMatch": "[0-9]+"}},
+        }));
+        assert!(!ExecutionContext::evaluate(&mut m, &req));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err =
+            parse(br#"{"mappings": [{"request": {}, "response": {"bodyFileName": "a.json"}}]}"#)
+                .err()
+                .unwrap();
+        assert_eq!(
+            "invalid WireMock stub: mapping 0: unsupported response field: bodyFileName",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_unsupported_request_fields() {
+        for (request, field) in [
+            (
+                r#"{"urlPathTemplate": "/users/{id}"}"#,
+                "request field: urlPathTemplate",
+            ),
+            (
+                r#"{"cookies": {"session": {"equalTo": "a"}}}"#,
+                "request field: cookies",
+            ),
+            (
+                r#"{"headers": {"accept": {"equalToXml": "<a/>"}}}"#,
+                "match operator: equalToXml",
+            ),
+            (
+                r#"{"bodyPatterns": [{"equalToJson": {}, "ignoreArrayOrder": true}]}"#,
+                "equalToJson option: ignoreArrayOrder",
+            ),
+        ] {
+            let stub = format!(r#"{{"request": {}, "response": {{}}}}"#, request);
+            let err = parse(stub.as_bytes()).err().unwrap();
+            assert_eq!(
+                format!("invalid WireMock stub: mapping 0: unsupported {}", field),
+                err.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_patterns() {
+        let req = http::Request::get("/")
+            .header("accept", "Application/JSON")
+            .body(Bytes::new())
+            .unwrap();
+        let mut m = matcher(serde_json::json!({
+            "headers": {"accept": {"matches": "application/[a-z]+", "caseInsensitive": true}},
+        }));
+        assert!(ExecutionContext::evaluate(&mut m, &req));
+        let mut m = matcher(serde_json::json!({
+            "headers": {"accept": {"matches": "application/[a-z]+"}},
+        }));
+        assert!(!ExecutionContext::evaluate(&mut m, &req));
+        let mut m = matcher(serde_json::json!({
+            "headers": {"accept": {"doesNotMatch": "application/json", "caseInsensitive": true}},
+        }));
+        assert!(!ExecutionContext::evaluate(&mut m, &req));
+    }
+
+    #[test]
+    fn test_status_message() {
+        let (resp, _) = response(&serde_json::json!({
+            "status": 299,
+            "statusMessage": "Custom Warning",
+        }))
+        .unwrap();
+        let reason = resp.extensions().get::<hyper::ext::ReasonPhrase>().unwrap();
+        assert_eq!(reason.as_bytes(), b"Custom Warning");
+
+        let err = response(&serde_json::json!({"statusMessage": "a\nb"}))
+            .err()
+            .unwrap();
+        assert_eq!(err, r#"invalid statusMessage: "a\nb""#);
+    }
+}
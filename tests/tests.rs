@@ -1,7 +1,7 @@
 use http_body_util::{BodyExt, Full};
 use httptest::{matchers::*, responders::*, Expectation, ExpectationBuilder, ServerPool};
 use hyper_util::client::legacy::{connect::HttpConnector, Client, Error};
-use std::{future::Future, net::SocketAddr};
+use std::{future::Future, net::SocketAddr, ops::Bound};
 
 fn create_test_client() -> Client<HttpConnector, Full<hyper::body::Bytes>> {
     Client::builder(hyper_util::rt::TokioExecutor::new()).build_http()
@@ -65,6 +65,195 @@ async fn test_expectation_cardinality_not_reached_explicit_verify() {
     server.verify_and_clear();
 }
 
+#[tokio::test]
+async fn test_verify_report() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::path("/foo"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+    server.expect(
+        Expectation::matching(request::path("/bar"))
+            .times(..)
+            .respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    for _ in 0..2 {
+        read_response_body(client.get(server.url("/foo"))).await;
+    }
+
+    let report = server.verify_report();
+    let expectations = report.expectations();
+    assert_eq!(2, expectations.len());
+    assert_eq!("Path(\"/foo\")", expectations[0].matcher());
+    assert_eq!(
+        (Bound::Included(1), Bound::Unbounded),
+        expectations[0].times()
+    );
+    assert_eq!(2, expectations[0].hit_count());
+    assert_eq!(0, expectations[1].hit_count());
+    assert!(report.unexpected_requests().is_empty());
+    assert_eq!(
+        "verified 2 expectations\n  Path(\"/foo\"): received 2; expected AtLeast(1)\n  Path(\"/bar\"): received 0; expected Any",
+        report.to_string()
+    );
+}
+
+#[tokio::test]
+async fn test_allow() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let favicon = server.allow(request::path("/favicon.ico"), status_code(404));
+    server.allow(request::path("/healthz"), status_code(200));
+    server.expect(Expectation::matching(request::path("/foo")).respond_with(status_code(200)));
+    let client = create_test_client();
+    for path in &["/favicon.ico", "/favicon.ico", "/foo"] {
+        read_response_body(client.get(server.url(path))).await;
+    }
+    assert_eq!(2, favicon.hit_count());
+
+    let report = server.verify_report();
+    assert_eq!(1, report.expectations().len());
+    assert_eq!("Path(\"/foo\")", report.expectations()[0].matcher());
+}
+
+#[tokio::test]
+async fn test_health_checks() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let probes = httptest::presets::health_checks()
+        .path("/livez")
+        .install(&server);
+    let client = create_test_client();
+    for path in &["/healthz", "/readyz", "/livez"] {
+        let resp = read_response_body(client.get(server.url(path))).await;
+        assert_eq!(200, resp.status().as_u16());
+        assert_eq!("ok", resp.body());
+    }
+    assert_eq!(3, probes.hit_count());
+    server.verify_and_clear();
+
+    let probes = httptest::presets::health_checks()
+        .paths(vec!["/ping"])
+        .install(&server);
+    let resp = read_response_body(client.get(server.url("/ping"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(1, probes.hit_count());
+    // probes never fail verification.
+    server.verify_and_clear();
+}
+
+#[tokio::test]
+async fn test_save_and_restore_expectations() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let health = server.expect(
+        Expectation::matching(request::path("/health"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+    let baseline = server.save_expectations();
+
+    let client = create_test_client();
+    for case in ["/one", "/two"] {
+        server.expect(Expectation::matching(request::path(case)).respond_with(status_code(201)));
+        for path in ["/health", case] {
+            let resp = read_response_body(client.get(server.url(path))).await;
+            assert!(resp.status().is_success());
+        }
+        assert_eq!(1, health.hit_count());
+        server.verify_and_clear();
+        server.restore_expectations(&baseline);
+        assert_eq!(0, health.hit_count());
+    }
+
+    read_response_body(client.get(server.url("/health"))).await;
+    let saved = server.save_expectations();
+    server.restore_expectations_with_hits(&saved);
+    assert_eq!(1, health.hit_count());
+}
+
+#[tokio::test]
+async fn test_restore_expectations_with_branches() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let search = server.expect(
+        Expectation::matching(request::path("/search"))
+            .times(2)
+            .respond_with_map(httptest::branches![
+                request::query(url_decoded(contains(("page", "1")))) => status_code(200),
+                request::query(url_decoded(contains(("page", "2")))) => status_code(404),
+            ]),
+    );
+    let baseline = server.save_expectations();
+
+    let client = create_test_client();
+    let get = |url| read_response_body(client.get(url));
+
+    // a request that matches no branch is forgotten once restored.
+    assert_eq!(
+        500,
+        get(server.url("/search?page=3")).await.status().as_u16()
+    );
+
+    for case in 0..2 {
+        server.restore_expectations(&baseline);
+        assert_eq!(Some(vec![0, 0]), search.branch_hit_counts());
+        get(server.url("/search?page=1")).await;
+        get(server.url("/search?page=2")).await;
+        assert_eq!(Some(vec![1, 1]), search.branch_hit_counts());
+        if case == 1 {
+            let saved = server.save_expectations();
+            server.restore_expectations_with_hits(&saved);
+            assert_eq!(Some(vec![1, 1]), search.branch_hit_counts());
+        }
+        server.verify_and_clear();
+    }
+}
+
+fn expect_login(server: &httptest::Server) -> httptest::ExpectationGuard {
+    server
+        .expect(
+            Expectation::matching(request::method_path("POST", "/login"))
+                .respond_with(status_code(200)),
+        )
+        .verify_on_drop()
+}
+
+#[tokio::test]
+async fn test_verify_on_drop() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let client = create_test_client();
+    {
+        let login = expect_login(&server);
+        let req = hyper::Request::post(server.url("/login"))
+            .body(Full::default())
+            .unwrap();
+        read_response_body(client.request(req)).await;
+        assert_eq!(1, login.hit_count());
+    }
+    // the expectation was removed from the server along with the guard.
+    assert!(server.verify_report().expectations().is_empty());
+}
+
+#[tokio::test]
+#[should_panic(expected = "Unexpected number of requests for matcher")]
+async fn test_verify_on_drop_not_met() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let _login = expect_login(&server);
+}
+
 #[tokio::test]
 #[should_panic]
 async fn test_expectation_cardinality_exceeded() {
@@ -93,6 +282,32 @@ async fn test_expectation_cardinality_exceeded() {
     // Should panic on Server drop.
 }
 
+#[tokio::test]
+#[should_panic(
+    expected = r#"closest expectation: AllOf[Method("GET"), Path("/foo")] (2 of 4 matchers matched)"#
+)]
+async fn test_unexpected_request_reports_closest_expectation() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![request::method("POST"), request::path("/bar")])
+            .times(..)
+            .respond_with(status_code(200)),
+    );
+    server.expect(
+        Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
+            .times(..)
+            .respond_with(status_code(200)),
+    );
+
+    // Issue a GET /fo with a typo in the path. No expectation matches so a 500
+    // is returned and the server panics on Drop pointing at GET /foo.
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/fo"))).await;
+    assert_eq!(500, resp.status().as_u16());
+}
+
 #[tokio::test]
 async fn test_json() {
     let _ = pretty_env_logger::try_init();
@@ -124,206 +339,3682 @@ async fn test_json() {
 }
 
 #[tokio::test]
-async fn test_cycle() {
+async fn test_reason_phrase() {
     let _ = pretty_env_logger::try_init();
 
-    // Setup a server to expect a single GET /foo request and respond with a
-    // json encoding of my_data.
     let server = httptest::Server::run();
     server.expect(
-        Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
-            .times(4)
-            .respond_with(cycle![status_code(200), status_code(404),]),
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(status_code(299).reason_phrase("Custom Warning")),
     );
 
-    // Issue multiple GET /foo to the server and verify it alternates between 200 and 404 codes.
     let client = create_test_client();
     let resp = read_response_body(client.get(server.url("/foo"))).await;
-    assert_eq!(200, resp.status().as_u16());
-    let resp = read_response_body(client.get(server.url("/foo"))).await;
-    assert_eq!(404, resp.status().as_u16());
-    let resp = read_response_body(client.get(server.url("/foo"))).await;
-    assert_eq!(200, resp.status().as_u16());
-    let resp = read_response_body(client.get(server.url("/foo"))).await;
-    assert_eq!(404, resp.status().as_u16());
+    assert_eq!(299, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"Custom Warning"[..]),
+        resp.extensions()
+            .get::<hyper::ext::ReasonPhrase>()
+            .map(|reason| reason.as_bytes())
+    );
 }
 
 #[tokio::test]
-async fn test_url_encoded() {
+async fn test_conditional() {
+    use std::time::{Duration, SystemTime};
     let _ = pretty_env_logger::try_init();
 
-    // Setup a server to expect a single GET /foo request and respond with a
-    // json response.
     let server = httptest::Server::run();
     server.expect(
-        Expectation::matching(all_of![
-            request::method("GET"),
-            request::path("/foo"),
-            request::query(url_decoded(contains(("key", "value")))),
-        ])
-        .respond_with(url_encoded(&[("key", "value"), ("k", "v")])),
+        Expectation::matching(request::path("/foo"))
+            .times(6)
+            .respond_with(
+                conditional(
+                    status_code(200)
+                        .insert_header("cache-control", "max-age=60")
+                        .insert_header("content-type", "text/plain")
+                        .body("hello"),
+                )
+                .last_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+            ),
     );
 
-    // Issue the GET /foo?key=value to the server and verify it returns a 200 with an
-    // application/x-www-form-urlencoded body of key=value.
     let client = create_test_client();
-    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
+    let get = |name: &'static str, value: &str| {
+        let req = hyper::Request::get(server.url("/foo"));
+        let req = match name {
+            "" => req,
+            name => req.header(name, value),
+        };
+        client.request(req.body(Full::default()).unwrap())
+    };
+
+    // the full response carries the validators.
+    let resp = read_response_body(get("", "")).await;
     assert_eq!(200, resp.status().as_u16());
+    assert_eq!("hello", resp.body());
     assert_eq!(
-        Some(&b"application/x-www-form-urlencoded"[..]),
-        resp.headers().get("content-type").map(|x| x.as_bytes())
+        "Sun, 13 Sep 2020 12:26:40 GMT",
+        resp.headers()["last-modified"]
     );
-    assert_eq!("key=value&k=v", resp.body());
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
 
-    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
-}
+    let resp = read_response_body(get("if-none-match", &format!("\"other\", W/{}", etag))).await;
+    assert_eq!(304, resp.status().as_u16());
+    assert_eq!("", resp.body());
+    assert_eq!(etag, resp.headers()["etag"]);
+    assert_eq!("max-age=60", resp.headers()["cache-control"]);
+    assert!(!resp.headers().contains_key("content-type"));
 
-#[tokio::test]
-async fn test_respond_with_fn() {
-    let _ = pretty_env_logger::try_init();
+    let resp = read_response_body(get("if-none-match", "\"other\"")).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("hello", resp.body());
 
-    let server = httptest::Server::run();
-    let delay = std::time::Duration::from_millis(100);
-    server.expect(Expectation::matching(any()).respond_with(move || {
-        std::thread::sleep(delay);
-        status_code(200)
-    }));
+    let resp = read_response_body(get("if-modified-since", "Sun, 13 Sep 2020 12:26:40 GMT")).await;
+    assert_eq!(304, resp.status().as_u16());
 
-    // Issue the GET /foo?key=value to the server and verify it returns a 200 with an
-    // application/x-www-form-urlencoded body of key=value.
-    let client = create_test_client();
-    let now = std::time::Instant::now();
-    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
-    let elapsed = now.elapsed();
+    let resp = read_response_body(get("if-modified-since", "Sun, 13 Sep 2020 12:26:39 GMT")).await;
     assert_eq!(200, resp.status().as_u16());
-    assert!(elapsed >= delay);
 
-    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+    // other methods fail the precondition.
+    let req = hyper::Request::put(server.url("/foo"))
+        .header("if-none-match", "*")
+        .body(Full::default())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(412, resp.status().as_u16());
 }
 
 #[tokio::test]
-async fn test_delay_and_then() {
+async fn test_cache_control() {
+    use std::time::{Duration, SystemTime};
     let _ = pretty_env_logger::try_init();
 
     let server = httptest::Server::run();
-    let delay = std::time::Duration::from_millis(100);
-    server
-        .expect(Expectation::matching(any()).respond_with(delay_and_then(delay, status_code(200))));
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(
+            status_code(200)
+                .cache_control(
+                    CacheControl::new()
+                        .public()
+                        .max_age(Duration::from_secs(60))
+                        .stale_while_revalidate(Duration::from_secs(30))
+                        .directive("max-stale=10"),
+                )
+                .expires_in(Duration::from_secs(3600)),
+        ),
+    );
 
-    // Issue the GET /foo?key=value to the server and verify it returns a 200
     let client = create_test_client();
-    let now = std::time::Instant::now();
-    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
-    let elapsed = now.elapsed();
-    assert_eq!(200, resp.status().as_u16());
-    assert!(elapsed >= delay);
-
-    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(
+        "public, max-age=60, stale-while-revalidate=30, max-stale=10",
+        resp.headers()["cache-control"]
+    );
+    // the expiry is relative to when the response was sent.
+    let expires = httpdate::parse_http_date(resp.headers()["expires"].to_str().unwrap()).unwrap();
+    let expected = SystemTime::now() + Duration::from_secs(3600);
+    let skew = match expected.duration_since(expires) {
+        Ok(skew) => skew,
+        Err(err) => err.duration(),
+    };
+    assert!(skew < Duration::from_secs(5), "{:?}", skew);
 }
 
 #[tokio::test]
-async fn test_custom_json() {
-    use httptest::{matchers::*, responders::*, Expectation, Server};
-    use serde_json::json;
+async fn test_force_framing() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     let _ = pretty_env_logger::try_init();
 
-    let server = Server::run();
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/length")).respond_with(force_framing(
+            Framing::ContentLength,
+            ndjson_stream(vec![1, 2]),
+        )),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/chunked")).respond_with(force_framing(
+            Framing::Chunked,
+            status_code(200).body("hello"),
+        )),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/empty"))
+            .respond_with(force_framing(Framing::Chunked, status_code(200))),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/close")).respond_with(force_framing(
+            Framing::CloseDelimited,
+            status_code(200)
+                .insert_header("content-type", "text/plain")
+                .body("hello"),
+        )),
+    );
 
-    #[derive(serde::Deserialize, Debug, PartialEq)]
-    struct PostBody {
-        msg: Option<String>,
-    }
+    let addr = server.addr();
+    let get = |path: &'static str, connection: &'static str| async move {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: {}\r\n\r\n",
+                    path, connection
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        String::from_utf8(resp).unwrap()
+    };
 
-    server.expect(
-        Expectation::matching(all_of![
-            request::method("POST"),
-            request::path("/bar"),
-            request::body(json_decoded(|b: &PostBody| { b.msg.is_some() }))
-        ])
-        .respond_with(json_encoded(json!({"result": "success"}))),
+    let resp = get("/length", "close").await;
+    assert!(resp.contains("content-length: 4\r\n"), "{}", resp);
+    assert!(!resp.contains("transfer-encoding"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\n1\n2\n"), "{}", resp);
+
+    let resp = get("/chunked", "close").await;
+    assert!(resp.contains("transfer-encoding: chunked\r\n"), "{}", resp);
+    assert!(!resp.contains("content-length"), "{}", resp);
+    assert!(
+        resp.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"),
+        "{}",
+        resp
     );
 
-    // Now test your http client against the server.
-    let client = create_test_client();
-    // Issue the GET /foo to the server.
+    let resp = get("/empty", "close").await;
+    assert!(resp.contains("transfer-encoding: chunked\r\n"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\n0\r\n\r\n"), "{}", resp);
 
-    // Issue a POST /bar with {'foo': 'bar'} json body.
-    let post_req = http::Request::post(server.url("/bar"))
-        .body(json!({"msg": "foo"}).to_string().into())
-        .unwrap();
-    // Read the entire response body into a Vec<u8> to allow using the body
-    // response matcher.
-    let resp = read_response_body(client.request(post_req)).await;
-    // Assert the response was a 200 with a json body of {'result': 'success'}
-    assert_eq!(200, resp.status().as_u16());
+    // the connection is closed after the body, without the client asking.
+    let resp = get("/close", "keep-alive").await;
     assert_eq!(
-        json!({"result": "success"}),
-        serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()
+        "HTTP/1.1 200 OK\r\n\
+         content-type: text/plain\r\n\
+         connection: close\r\n\
+         \r\n\
+         hello",
+        resp
     );
-
-    // on Drop the server will assert all expectations have been met and will
-    // panic if not.
 }
 
-// verify that the server can be started even if not run within a tokio context.
-#[test]
-fn test_outside_of_tokio_context() {
+#[tokio::test]
+async fn test_verbatim_headers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     let _ = pretty_env_logger::try_init();
-    let _server = httptest::Server::run();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(2)
+            .respond_with(
+                status_code(200)
+                    .insert_header("content-type", "text/plain")
+                    .verbatim_header("Set-Cookie", "a=1")
+                    .verbatim_header("Vary", "Accept")
+                    .verbatim_header("Set-Cookie", "b=2")
+                    .body("hello"),
+            ),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    // the connection is kept alive after a response with verbatim headers.
+    stream
+        .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let expected = "HTTP/1.1 200 OK\r\n\
+                    content-type: text/plain\r\n\
+                    Set-Cookie: a=1\r\n\
+                    Vary: Accept\r\n\
+                    Set-Cookie: b=2\r\n\
+                    content-length: 5\r\n\
+                    \r\n\
+                    hello";
+    let mut resp = vec![0; expected.len()];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(expected, String::from_utf8(resp).unwrap());
+
+    stream
+        .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    assert_eq!(expected, String::from_utf8(resp).unwrap());
 }
 
 #[tokio::test]
-async fn test_server_custom() {
+async fn test_cycle() {
     let _ = pretty_env_logger::try_init();
 
-    let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
-
-    // Setup a server to expect a single GET /foo request.
-    let server = httptest::ServerBuilder::new()
-        .bind_addr(bind_addr)
-        .run()
-        .unwrap();
+    // Setup a server to expect a single GET /foo request and respond with a
+    // json encoding of my_data.
+    let server = httptest::Server::run();
     server.expect(
         Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
-            .respond_with(status_code(200)),
+            .times(4)
+            .respond_with(cycle![status_code(200), status_code(404),]),
     );
 
-    // Issue the GET /foo to the server and verify it returns a 200.
+    // Issue multiple GET /foo to the server and verify it alternates between 200 and 404 codes.
     let client = create_test_client();
     let resp = read_response_body(client.get(server.url("/foo"))).await;
     assert_eq!(200, resp.status().as_u16());
-
-    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(404, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(404, resp.status().as_u16());
 }
 
-// compile test to ensure users of the library can write wrappers for
-// ExpectationBuilder that is generic over IntoTimes.
-#[allow(unused)]
-fn times_wrapper(times: impl httptest::IntoTimes) -> ExpectationBuilder {
-    Expectation::matching(request::method("POST")).times(times)
-}
+#[tokio::test]
+async fn test_respond_with_map() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/search"))
+            .times(3)
+            .respond_with_map(httptest::branches![
+                request::query(url_decoded(contains(("page", "1")))) => status_code(200),
+                request::query(url_decoded(contains(("page", "2")))) => status_code(404),
+            ]),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/search?page=1"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/search?page=2"))).await;
+    assert_eq!(404, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/search?page=1"))).await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_expectation_handle() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let token = server.expect(
+        Expectation::matching(request::method_path("POST", "/token"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+    let foo = server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(3)
+            .respond_with(cycle![status_code(200), status_code(404)]),
+    );
+    assert_eq!((Bound::Included(3), Bound::Included(3)), foo.times());
+    assert_eq!(0, token.hit_count());
+    assert_eq!(None, token.branch_hit_counts());
+
+    let client = create_test_client();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/token"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(1, token.hit_count());
+    assert_eq!(0, foo.hit_count());
+
+    for _ in 0..3 {
+        read_response_body(client.get(server.url("/foo"))).await;
+    }
+    assert_eq!(1, token.hit_count());
+    assert_eq!(3, foo.hit_count());
+    assert_eq!(Some(vec![2, 1]), foo.branch_hit_counts());
+}
+
+#[tokio::test]
+async fn test_expectation_handle_wait() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let foo = server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(2..)
+            .respond_with(status_code(200)),
+    );
+    let bar = server.expect(
+        Expectation::matching(request::method_path("GET", "/bar")).respond_with(status_code(200)),
+    );
+
+    let err = bar
+        .wait_timeout(std::time::Duration::from_millis(10))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        "timed out after 10ms waiting for requests; received 0; expected Exactly(1)",
+        err.to_string()
+    );
+
+    let client = create_test_client();
+    let (foo_url, bar_url) = (server.url("/foo"), server.url("/bar"));
+    tokio::spawn(async move {
+        for url in [foo_url.clone(), bar_url, foo_url] {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            read_response_body(client.get(url)).await;
+        }
+    });
+    foo.wait_timeout(std::time::Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(2, foo.hit_count());
+    bar.wait().await;
+}
+
+#[tokio::test]
+async fn test_expectation_handle_replace() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    // Matches anything the other expectations don't.
+    server.expect(
+        Expectation::matching(any())
+            .times(1)
+            .respond_with(status_code(418)),
+    );
+    let status = server.expect(
+        Expectation::matching(request::path("/status"))
+            .times(1..)
+            .respond_with(status_code(503)),
+    );
+    // Added after status, so it would take precedence if replacements were
+    // added to the end.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/status"))
+            .times(1)
+            .respond_with(status_code(404)),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/status"))).await;
+    assert_eq!(404, resp.status().as_u16());
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/status"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(503, resp.status().as_u16());
+
+    let new_status = status.replace(
+        Expectation::matching(request::path("/status"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/status"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(1, status.hit_count());
+    assert_eq!(1, new_status.hit_count());
+
+    new_status.deactivate();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/status"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(418, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_scope() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let health = server.expect(
+        Expectation::matching(request::path("/health"))
+            .times(2)
+            .respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    {
+        let phase_one = server.scope();
+        phase_one
+            .expect(Expectation::matching(request::path("/login")).respond_with(status_code(200)));
+        read_response_body(client.get(server.url("/login"))).await;
+        read_response_body(client.get(server.url("/health"))).await;
+    }
+    {
+        let phase_two = server.scope();
+        phase_two
+            .expect(Expectation::matching(request::path("/login")).respond_with(status_code(403)));
+        let resp = read_response_body(client.get(server.url("/login"))).await;
+        assert_eq!(403, resp.status().as_u16());
+        read_response_body(client.get(server.url("/health"))).await;
+    }
+    assert_eq!(2, health.hit_count());
+}
+
+#[tokio::test]
+#[should_panic(expected = "Unexpected number of requests for matcher 'Path(\"/login\")'")]
+async fn test_scope_verified_on_drop() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let scope = server.scope();
+    scope.expect(Expectation::matching(request::path("/login")).respond_with(status_code(200)));
+    drop(scope);
+}
+
+#[tokio::test]
+async fn test_retry_counts() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/upload"))
+            .times(4)
+            .respond_with(cycle![
+                status_code(503),
+                status_code(503),
+                status_code(200),
+                status_code(200),
+            ]),
+    );
+
+    let client = create_test_client();
+    for body in ["a", "a", "a", "b"] {
+        read_response_body(
+            client.request(
+                hyper::Request::post(server.url("/upload"))
+                    .body(Full::from(body))
+                    .unwrap(),
+            ),
+        )
+        .await;
+    }
+    assert_eq!(
+        vec![
+            (hyper::body::Bytes::from("a"), 2),
+            (hyper::body::Bytes::from("b"), 0)
+        ],
+        server.retry_counts(|req: &http::Request<hyper::body::Bytes>| req.body().clone())
+    );
+    assert_eq!(
+        vec![(hyper::Method::POST, 3)],
+        server.retry_counts(httptest::identity::method())
+    );
+}
+
+#[tokio::test]
+async fn test_verify_with_timeout() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(2..)
+            .respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    let url = server.url("/foo");
+    tokio::spawn(async move {
+        for _ in 0..2 {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            read_response_body(client.get(url.clone())).await;
+        }
+    });
+    server
+        .verify_with_timeout(std::time::Duration::from_secs(5))
+        .await;
+}
+
+#[test]
+fn test_verify_with_timeout_blocking() {
+    use std::io::{Read, Write};
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    let addr = server.addr();
+    let client = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    });
+    server.verify_with_timeout_blocking(std::time::Duration::from_secs(5));
+    assert!(client.join().unwrap().starts_with("HTTP/1.1 200"));
+}
+
+#[tokio::test]
+#[should_panic(expected = "Unexpected number of requests for matcher")]
+async fn test_verify_with_timeout_fails_after_timeout() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    server
+        .verify_with_timeout(std::time::Duration::from_millis(10))
+        .await;
+}
+
+#[tokio::test]
+async fn test_report_all_failures() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::ServerBuilder::new()
+        .report_all_failures(true)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/bar")).respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    read_response_body(client.get(server.url("/baz"))).await;
+
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| server.verify_and_clear()))
+        .unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.starts_with("3 verification failures:\n1. "), "{}", msg);
+    assert!(msg.contains("\n2. Unexpected number of requests for matcher 'MethodPath { method: \"GET\", path: \"/bar\" }'"), "{}", msg);
+    assert!(
+        msg.contains("\n3. received the following unexpected requests:\n"),
+        "{}",
+        msg
+    );
+}
+
+#[tokio::test]
+async fn test_on_verification_failure() {
+    use std::sync::{Arc, Mutex};
+    let _ = pretty_env_logger::try_init();
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let handler_errors = errors.clone();
+    let server = httptest::ServerBuilder::new()
+        .on_verification_failure(move |e| handler_errors.lock().unwrap().push(e))
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    {
+        let _guard = server
+            .expect(
+                Expectation::matching(request::method_path("GET", "/bar"))
+                    .respond_with(status_code(200)),
+            )
+            .verify_on_drop();
+    }
+    drop(server);
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(2, errors.len());
+    assert!(errors[0].to_string().contains("\"/bar\""), "{}", errors[0]);
+    assert_eq!(1, errors[1].failures().len());
+    assert!(
+        errors[1].failures()[0].contains("\"/foo\""),
+        "{}",
+        errors[1]
+    );
+}
+
+#[tokio::test]
+async fn test_expectation_priority() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::path("/foo"))
+            .priority(1)
+            .respond_with(status_code(200)),
+    );
+    server.expect(Expectation::matching(request::path("/bar")).respond_with(status_code(201)));
+    // Added last but matched last.
+    server.expect(
+        Expectation::matching(any())
+            .times(1)
+            .priority(-1)
+            .respond_with(status_code(404)),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/bar"))).await;
+    assert_eq!(201, resp.status().as_u16());
+    let resp = read_response_body(client.get(server.url("/baz"))).await;
+    assert_eq!(404, resp.status().as_u16());
+}
+
+#[tokio::test]
+#[should_panic(expected = "did not receive any requests")]
+async fn test_respond_with_map_branch_not_exercised() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/search"))
+            .times(1..)
+            .respond_with_map(httptest::branches![
+                request::query(url_decoded(contains(("page", "1")))) => status_code(200),
+                request::query(url_decoded(contains(("page", "2")))) => status_code(404),
+            ]),
+    );
+
+    // Only request the first page. Should panic on Drop.
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/search?page=1"))).await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+#[allow(clippy::needless_borrows_for_generic_args)]
+async fn test_url_encoded() {
+    let _ = pretty_env_logger::try_init();
+
+    // Setup a server to expect a single GET /foo request and respond with a
+    // json response.
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method("GET"),
+            request::path("/foo"),
+            request::query(url_decoded(contains(("key", "value")))),
+        ])
+        .respond_with(url_encoded(&[("key", "value"), ("k", "v")])),
+    );
+
+    // Issue the GET /foo?key=value to the server and verify it returns a 200 with an
+    // application/x-www-form-urlencoded body of key=value.
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"application/x-www-form-urlencoded"[..]),
+        resp.headers().get("content-type").map(|x| x.as_bytes())
+    );
+    assert_eq!("key=value&k=v", resp.body());
+
+    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+}
+
+#[tokio::test]
+async fn test_request_timing() {
+    use std::time::Duration;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    // only requests that backed off for at least 50ms succeed.
+    let too_soon = server.expect(
+        Expectation::matching(request::path("/upload"))
+            .times(1)
+            .respond_with(status_code(503)),
+    );
+    let backed_off = server.expect(
+        Expectation::matching(all_of![
+            request::path("/upload"),
+            request::since_previous(|d: &Duration| *d >= Duration::from_millis(50)),
+        ])
+        .times(1)
+        .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/upload"))).await;
+    assert_eq!(503, resp.status().as_u16());
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let resp = read_response_body(client.get(server.url("/upload"))).await;
+    assert_eq!(200, resp.status().as_u16());
+
+    let times = server.request_times();
+    assert_eq!(2, times.len());
+    assert!(times[1] - times[0] >= Duration::from_millis(50));
+    assert_eq!(vec![times[0]], too_soon.hit_times());
+    assert_eq!(vec![times[1]], backed_off.hit_times());
+    assert!(too_soon.hit_intervals().is_empty());
+}
+
+#[tokio::test]
+async fn test_on_match() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let count = Arc::new(AtomicUsize::new(0));
+    let hook_count = count.clone();
+    server.expect(
+        Expectation::matching(request::path(matches("^/events/")))
+            .times(2)
+            .on_match(move |req| {
+                hook_count.fetch_add(1, Ordering::SeqCst);
+                tx.send(req.uri().path().to_owned()).unwrap();
+            })
+            .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    for path in ["/events/1", "/events/2"] {
+        let resp = read_response_body(client.get(server.url(path))).await;
+        assert_eq!(200, resp.status());
+    }
+    assert_eq!(2, count.load(Ordering::SeqCst));
+    assert_eq!(Some("/events/1".to_owned()), rx.recv().await);
+    assert_eq!(Some("/events/2".to_owned()), rx.recv().await);
+}
+
+#[tokio::test]
+async fn test_middleware() {
+    use httptest::ServerBuilder;
+    let _ = pretty_env_logger::try_init();
+
+    let server = ServerBuilder::new()
+        .map_request(|req| {
+            req.headers_mut().remove("x-request-id");
+        })
+        .map_request(|req| {
+            req.headers_mut()
+                .insert("x-seen", http::HeaderValue::from_static("1"));
+        })
+        .map_response(|req, resp| {
+            let path = http::HeaderValue::from_str(req.uri().path()).unwrap();
+            resp.headers_mut().insert("x-path", path);
+        })
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(all_of![
+            request::path("/foo"),
+            request::headers(not(contains(key("x-request-id")))),
+            request::headers(contains(("x-seen", "1"))),
+        ])
+        .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    let req = hyper::Request::get(server.url("/foo"))
+        .header("x-request-id", "1234")
+        .body(Full::default())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(200, resp.status());
+    assert_eq!("/foo", resp.headers()["x-path"]);
+}
+
+#[tokio::test]
+async fn test_default_headers() {
+    use httptest::ServerBuilder;
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = ServerBuilder::new()
+        .default_header("server", "httptest")
+        .default_header("cache-control", "no-store")
+        .request_id_header("x-request-id")
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::path("/foo"))
+            .times(2)
+            .respond_with(status_code(200).insert_header("cache-control", "max-age=60")),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!("httptest", resp.headers()["server"]);
+    assert_eq!("max-age=60", resp.headers()["cache-control"]);
+    assert!(resp.headers().contains_key("x-request-id"));
+    assert!(resp.headers().contains_key("date"));
+
+    let req = hyper::Request::get(server.url("/foo"))
+        .header("x-request-id", "abc")
+        .body(Full::default())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!("abc", resp.headers()["x-request-id"]);
+
+    // unexpected requests get them too.
+    let resp = read_response_body(client.get(server.url("/bar"))).await;
+    assert_eq!(500, resp.status());
+    assert_eq!("httptest", resp.headers()["server"]);
+    assert_eq!("no-store", resp.headers()["cache-control"]);
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| server.verify_and_clear()));
+}
+
+#[tokio::test]
+async fn test_manual_clock() {
+    use httptest::{clock::ManualClock, ServerBuilder};
+    use std::time::{Duration, Instant};
+    let _ = pretty_env_logger::try_init();
+
+    let clock = ManualClock::new();
+    let server = ServerBuilder::new().clock(clock.clone()).run().unwrap();
+    server.expect(
+        Expectation::matching(request::path("/slow"))
+            .respond_with(delay_and_then(Duration::from_secs(60), status_code(200))),
+    );
+
+    let start = Instant::now();
+    let client = create_test_client();
+    let resp = tokio::spawn(read_response_body(client.get(server.url("/slow"))));
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(1, clock.sleepers());
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(200, resp.await.unwrap().status());
+    assert_eq!(0, clock.sleepers());
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn test_chaos() {
+    use httptest::{Chaos, ServerBuilder};
+    let _ = pretty_env_logger::try_init();
+
+    // the same seed fails the same requests.
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let server = ServerBuilder::new()
+            .rng_seed(1234)
+            .chaos(Chaos::new().error_rate(0.5))
+            .run()
+            .unwrap();
+        assert_eq!(1234, server.rng_seed());
+        server.expect(
+            Expectation::matching(request::path("/flaky"))
+                .times(20)
+                .respond_with(status_code(200)),
+        );
+        let client = create_test_client();
+        let mut statuses = Vec::new();
+        for _ in 0..20 {
+            let resp = read_response_body(client.get(server.url("/flaky"))).await;
+            statuses.push(resp.status().as_u16());
+        }
+        assert!(statuses.contains(&200));
+        assert!(statuses.contains(&500));
+        runs.push(statuses);
+    }
+    assert_eq!(runs[0], runs[1]);
+
+    // resets close the connection without a response.
+    let server = ServerBuilder::new()
+        .chaos(Chaos::new().reset_rate(1.0))
+        .run()
+        .unwrap();
+    server.expect(Expectation::matching(request::path("/flaky")).respond_with(status_code(200)));
+    let client = create_test_client();
+    assert!(client.get(server.url("/flaky")).await.is_err());
+}
+
+#[tokio::test]
+async fn test_request_sink() {
+    let _ = pretty_env_logger::try_init();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let server = httptest::ServerBuilder::new()
+        .request_sink(tx)
+        .run()
+        .unwrap();
+    server.expect(Expectation::matching(request::path("/foo")).respond_with(status_code(200)));
+
+    let client = create_test_client();
+    let req = hyper::Request::post(server.url("/foo"))
+        .body(Full::from("hello"))
+        .unwrap();
+    let resp = tokio::spawn(read_response_body(client.request(req)));
+    let received = rx.recv().await.unwrap();
+    assert_eq!("/foo", received.uri().path());
+    assert_eq!(&b"hello"[..], received.body());
+    assert_eq!(200, resp.await.unwrap().status());
+}
+
+#[tokio::test]
+async fn test_happens_before() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let token = server.expect(
+        Expectation::matching(request::method_path("POST", "/token"))
+            .respond_with(status_code(200)),
+    );
+    let data = server.expect(
+        Expectation::matching(request::path("/data"))
+            .times(2)
+            .respond_with(status_code(200)),
+    );
+    token.happens_before(&data);
+
+    let client = create_test_client();
+    let token_req = hyper::Request::post(server.url("/token"))
+        .body(Full::default())
+        .unwrap();
+    assert_eq!(
+        200,
+        read_response_body(client.request(token_req)).await.status()
+    );
+    for _ in 0..2 {
+        let resp = read_response_body(client.get(server.url("/data"))).await;
+        assert_eq!(200, resp.status());
+    }
+}
+
+#[tokio::test]
+#[should_panic(expected = "before matcher")]
+async fn test_happens_before_violated() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    let token = server.expect(
+        Expectation::matching(request::method_path("POST", "/token"))
+            .respond_with(status_code(200)),
+    );
+    let data =
+        server.expect(Expectation::matching(request::path("/data")).respond_with(status_code(200)));
+    token.happens_before(&data);
+
+    let client = create_test_client();
+    read_response_body(client.get(server.url("/data"))).await;
+    let token_req = hyper::Request::post(server.url("/token"))
+        .body(Full::default())
+        .unwrap();
+    read_response_body(client.request(token_req)).await;
+    server.verify_and_clear();
+}
+
+#[tokio::test]
+async fn test_generated_body() {
+    let _ = pretty_env_logger::try_init();
+
+    // a length that's not a multiple of the pattern or the frame size.
+    const LEN: u64 = 10 * 1024 * 1024 + 7;
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/download"))
+            .respond_with(generated_body(LEN, "abc")),
+    );
+
+    let client = create_test_client();
+    let resp = client.get(server.url("/download")).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(LEN.to_string().as_bytes()),
+        resp.headers().get("content-length").map(|x| x.as_bytes())
+    );
+
+    // check the pattern continues across frames without buffering the body.
+    let mut body = resp.into_body();
+    let mut received = 0u64;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.unwrap().into_data() {
+            for (i, b) in data.iter().enumerate() {
+                assert_eq!(b"abc"[((received + i as u64) % 3) as usize], *b);
+            }
+            received += data.len() as u64;
+        }
+    }
+    assert_eq!(LEN, received);
+}
+
+#[tokio::test]
+async fn test_ndjson_stream() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/watch")).respond_with(
+            ndjson_stream(vec![
+                serde_json::json!({"type": "ADDED"}),
+                serde_json::json!({"type": "DELETED"}),
+            ])
+            .line_delay(std::time::Duration::from_millis(50)),
+        ),
+    );
+
+    let client = create_test_client();
+    let resp = client.get(server.url("/watch")).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"application/x-ndjson"[..]),
+        resp.headers().get("content-type").map(|x| x.as_bytes())
+    );
+    assert_eq!(
+        Some(&b"chunked"[..]),
+        resp.headers()
+            .get("transfer-encoding")
+            .map(|x| x.as_bytes())
+    );
+
+    // each line arrives in its own frame, after its delay.
+    let start = std::time::Instant::now();
+    let mut body = resp.into_body();
+    let mut lines = Vec::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.unwrap().into_data() {
+            lines.push(String::from_utf8(data.to_vec()).unwrap());
+        }
+    }
+    assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+    assert_eq!(
+        vec!["{\"type\":\"ADDED\"}\n", "{\"type\":\"DELETED\"}\n"],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn test_ndjson_stream_body_changed() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::ServerBuilder::new()
+        .map_response(|_, resp| *resp.body_mut() = "shortened".into())
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/watch")).respond_with(ndjson_stream(
+            vec![
+                serde_json::json!({"type": "ADDED"}),
+                serde_json::json!({"type": "DELETED"}),
+            ],
+        )),
+    );
+
+    // the body is sent whole rather than in the chunks of the original body.
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/watch"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("shortened", resp.body());
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_webhook() {
+    let _ = pretty_env_logger::try_init();
+
+    // the client under test receives callbacks on its own server.
+    let callbacks = httptest::Server::run();
+    let done = callbacks.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/done"),
+            request::headers(contains(("content-type", "application/json"))),
+            request::body(json_decoded(eq(serde_json::json!({"status": "done"})))),
+        ])
+        .respond_with(status_code(200)),
+    );
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/jobs")).respond_with(
+            webhook(callback_url_from_json("/callback_url"), status_code(202))
+                .json_payload(serde_json::json!({"status": "done"}))
+                .delay(std::time::Duration::from_millis(10)),
+        ),
+    );
+
+    let client = create_test_client();
+    let body = serde_json::json!({"callback_url": callbacks.url_str("/done")}).to_string();
+    let req = hyper::Request::post(server.url("/jobs"))
+        .body(body.into())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(202, resp.status().as_u16());
+
+    done.wait_timeout(std::time::Duration::from_secs(5))
+        .await
+        .unwrap();
+}
+
+#[cfg(feature = "yaml")]
+#[tokio::test]
+async fn test_yaml_encoded() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(yaml_encoded(serde_json::json!({"result": "success"}))),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"application/yaml"[..]),
+        resp.headers().get("content-type").map(|x| x.as_bytes())
+    );
+    assert_eq!("result: success\n", resp.body());
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn test_msgpack_encoded() {
+    let _ = pretty_env_logger::try_init();
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct MyData {
+        foo: String,
+        bar: u32,
+    }
+    let my_data = MyData {
+        foo: "hello".to_string(),
+        bar: 7,
+    };
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(msgpack_encoded(&my_data)),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"application/msgpack"[..]),
+        resp.headers().get("content-type").map(|x| x.as_bytes())
+    );
+    let decoded: MyData = rmp_serde::from_slice(resp.body()).unwrap();
+    assert_eq!(my_data, decoded);
+}
+
+#[cfg(feature = "cbor")]
+#[tokio::test]
+async fn test_cbor() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/foo"),
+            request::body(cbor_decoded(eq(serde_json::json!({"temp": 21})))),
+        ])
+        .respond_with(cbor_encoded(serde_json::json!({"result": "success"}))),
+    );
+
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(&serde_json::json!({"temp": 21}), &mut body).unwrap();
+    let req = hyper::Request::post(server.url("/foo"))
+        .body(body.into())
+        .unwrap();
+    let client = create_test_client();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        Some(&b"application/cbor"[..]),
+        resp.headers().get("content-type").map(|x| x.as_bytes())
+    );
+    let decoded: serde_json::Value = ciborium::de::from_reader(&resp.body()[..]).unwrap();
+    assert_eq!(serde_json::json!({"result": "success"}), decoded);
+}
+
+#[tokio::test]
+async fn test_respond_with_fn() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let delay = std::time::Duration::from_millis(100);
+    server.expect(Expectation::matching(any()).respond_with(move || {
+        std::thread::sleep(delay);
+        status_code(200)
+    }));
+
+    // Issue the GET /foo?key=value to the server and verify it returns a 200 with an
+    // application/x-www-form-urlencoded body of key=value.
+    let client = create_test_client();
+    let now = std::time::Instant::now();
+    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
+    let elapsed = now.elapsed();
+    assert_eq!(200, resp.status().as_u16());
+    assert!(elapsed >= delay);
+
+    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+}
+
+#[tokio::test]
+async fn test_delay_and_then() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let delay = std::time::Duration::from_millis(100);
+    server
+        .expect(Expectation::matching(any()).respond_with(delay_and_then(delay, status_code(200))));
+
+    // Issue the GET /foo?key=value to the server and verify it returns a 200
+    let client = create_test_client();
+    let now = std::time::Instant::now();
+    let resp = read_response_body(client.get(server.url("/foo?key=value"))).await;
+    let elapsed = now.elapsed();
+    assert_eq!(200, resp.status().as_u16());
+    assert!(elapsed >= delay);
+
+    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+}
+
+#[tokio::test]
+async fn test_custom_json() {
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+    use serde_json::json;
+    let _ = pretty_env_logger::try_init();
+
+    let server = Server::run();
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct PostBody {
+        msg: Option<String>,
+    }
+
+    server.expect(
+        Expectation::matching(all_of![
+            request::method("POST"),
+            request::path("/bar"),
+            request::body(json_decoded(|b: &PostBody| { b.msg.is_some() }))
+        ])
+        .respond_with(json_encoded(json!({"result": "success"}))),
+    );
+
+    // Now test your http client against the server.
+    let client = create_test_client();
+    // Issue the GET /foo to the server.
+
+    // Issue a POST /bar with {'foo': 'bar'} json body.
+    let post_req = http::Request::post(server.url("/bar"))
+        .body(json!({"msg": "foo"}).to_string().into())
+        .unwrap();
+    // Read the entire response body into a Vec<u8> to allow using the body
+    // response matcher.
+    let resp = read_response_body(client.request(post_req)).await;
+    // Assert the response was a 200 with a json body of {'result': 'success'}
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(
+        json!({"result": "success"}),
+        serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()
+    );
+
+    // on Drop the server will assert all expectations have been met and will
+    // panic if not.
+}
+
+// verify that the server can be started even if not run within a tokio context.
+#[test]
+fn test_outside_of_tokio_context() {
+    let _ = pretty_env_logger::try_init();
+    let _server = httptest::Server::run();
+}
+
+#[tokio::test]
+async fn test_server_custom() {
+    let _ = pretty_env_logger::try_init();
+
+    let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    // Setup a server to expect a single GET /foo request.
+    let server = httptest::ServerBuilder::new()
+        .bind_addr(bind_addr)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
+            .respond_with(status_code(200)),
+    );
+
+    // Issue the GET /foo to the server and verify it returns a 200.
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+
+    // The Drop impl of the server will assert that all expectations were satisfied or else it will panic.
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_drop_waits_for_in_flight_requests() {
+    let _ = pretty_env_logger::try_init();
+
+    // Warn almost immediately so the diagnostics are printed while the slow
+    // request is still in-flight.
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server = httptest::ServerBuilder::new()
+        .shutdown_warning_after(std::time::Duration::from_millis(10))
+        .diagnostics({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event.clone())
+        })
+        .run()
+        .unwrap();
+    let delay = std::time::Duration::from_millis(200);
+    server
+        .expect(Expectation::matching(any()).respond_with(delay_and_then(delay, status_code(200))));
+
+    let client = create_test_client();
+    let resp = tokio::spawn(read_response_body(client.get(server.url("/slow"))));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // Dropping the server blocks until the in-flight request completes.
+    let now = std::time::Instant::now();
+    tokio::task::block_in_place(|| drop(server));
+    assert!(now.elapsed() >= std::time::Duration::from_millis(100));
+    assert_eq!(200, resp.await.unwrap().status().as_u16());
+
+    // The delayed shutdown was reported along with the in-flight request.
+    let events = events.lock().unwrap();
+    let connections = events
+        .iter()
+        .find_map(|event| match event {
+            httptest::DiagnosticEvent::ShutdownDelayed { connections, .. } => Some(connections),
+            _ => None,
+        })
+        .expect("shutdown delay not reported");
+    assert!(connections.contains("/slow"), "{}", connections);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_expect_while_matching() {
+    use std::sync::{mpsc, Mutex};
+    let _ = pretty_env_logger::try_init();
+
+    // A matcher that blocks until released.
+    let (entered_tx, entered_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let (entered_tx, release_rx) = (Mutex::new(entered_tx), Mutex::new(release_rx));
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(move |_: &http::Request<bytes::Bytes>| {
+            entered_tx.lock().unwrap().send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            true
+        })
+        .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    let resp = tokio::spawn(read_response_body(client.get(server.url("/slow"))));
+    tokio::task::block_in_place(|| entered_rx.recv().unwrap());
+    // expectations can be added while the matcher is being evaluated.
+    let other = server.expect(
+        Expectation::matching(request::path("/other"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    release_tx.send(()).unwrap();
+    assert_eq!(200, resp.await.unwrap().status().as_u16());
+    assert_eq!(0, other.hit_count());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_expect_while_describing_unexpected_request() {
+    use std::sync::{mpsc, Mutex};
+    let _ = pretty_env_logger::try_init();
+
+    // A matcher that never matches and blocks until released.
+    let (entered_tx, entered_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let (entered_tx, release_rx) = (Mutex::new(entered_tx), Mutex::new(release_rx));
+    let (failures_tx, failures_rx) = mpsc::channel();
+    let failures_tx = Mutex::new(failures_tx);
+    let server = httptest::ServerBuilder::new()
+        .on_verification_failure(move |errors| {
+            failures_tx
+                .lock()
+                .unwrap()
+                .send(errors.failures().len())
+                .unwrap();
+        })
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(move |_: &http::Request<bytes::Bytes>| {
+            entered_tx.lock().unwrap().send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            false
+        })
+        .times(0)
+        .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    let resp = tokio::spawn(read_response_body(client.get(server.url("/miss"))));
+    // the first evaluation looks for a match.
+    tokio::task::block_in_place(|| entered_rx.recv().unwrap());
+    release_tx.send(()).unwrap();
+    // the second finds the closest expectation to describe the request.
+    tokio::task::block_in_place(|| entered_rx.recv().unwrap());
+    let other = server.expect(
+        Expectation::matching(request::path("/other"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    release_tx.send(()).unwrap();
+    assert_eq!(500, resp.await.unwrap().status().as_u16());
+    assert_eq!(0, other.hit_count());
+
+    drop(server);
+    assert_eq!(Ok(1), failures_rx.try_recv());
+}
+
+#[tokio::test]
+#[should_panic(
+    expected = "the server panicked in the background:\nthread 'my-server': responder failed"
+)]
+async fn test_responder_panic_is_reported() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::ServerBuilder::new()
+        .thread_name("my-server")
+        .shared_runtime(false)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(any())
+            .respond_with(|| -> ResponseBuilder<&'static str> { panic!("responder failed") }),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(500, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_json_report() {
+    let _ = pretty_env_logger::try_init();
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let report = SharedBuf::default();
+    let mut server = httptest::ServerBuilder::new()
+        .json_report(report.clone())
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    server.verify_and_clear();
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&report.0.lock().unwrap()).expect("report is valid json");
+    assert_eq!(
+        serde_json::json!({
+            "addr": server.addr().to_string(),
+            "passed": true,
+            "expectations": [{
+                "matcher": r#"MethodPath { method: "GET", path: "/foo" }"#,
+                "times": "AtLeast(1)",
+                "hit_count": 1,
+                "satisfied": true,
+                "active": true,
+            }],
+            "unexpected_requests": [],
+            "server_panics": [],
+            "spec_violations": [],
+            "exchanges": [{
+                "request": {
+                    "method": "GET",
+                    "uri": "/foo",
+                    "version": "HTTP/1.1",
+                    "headers": [["host", server.addr().to_string()]],
+                    "body": "",
+                },
+                "response": {
+                    "status": 200,
+                    "headers": [],
+                    "body": "",
+                },
+            }],
+        }),
+        report
+    );
+}
+
+#[tokio::test]
+async fn test_json_report_after_verify() {
+    let _ = pretty_env_logger::try_init();
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let reports = |buf: &SharedBuf| -> Vec<serde_json::Value> {
+        let buf = buf.0.lock().unwrap();
+        serde_json::Deserializer::from_slice(&buf)
+            .into_iter()
+            .map(|report| report.expect("report is valid json"))
+            .collect()
+    };
+
+    // nothing happened after the explicit verification, so dropping the
+    // server doesn't write another report.
+    let report = SharedBuf::default();
+    let mut server = httptest::ServerBuilder::new()
+        .json_report(report.clone())
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    server.verify_and_clear();
+    drop(server);
+    let written = reports(&report);
+    assert_eq!(1, written.len());
+    assert_eq!(1, written[0]["expectations"].as_array().unwrap().len());
+
+    // expectations added after verifying are reported on drop.
+    let report = SharedBuf::default();
+    let mut server = httptest::ServerBuilder::new()
+        .json_report(report.clone())
+        .run()
+        .unwrap();
+    server.verify_and_clear();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    drop(server);
+    let written = reports(&report);
+    assert_eq!(2, written.len());
+    assert_eq!(1, written[1]["expectations"].as_array().unwrap().len());
+
+    // without an explicit verification the report is written on drop.
+    let report = SharedBuf::default();
+    let server = httptest::ServerBuilder::new()
+        .json_report(report.clone())
+        .run()
+        .unwrap();
+    drop(server);
+    assert_eq!(1, reports(&report).len());
+}
+
+#[tokio::test]
+async fn test_url_builder() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::path("/search"),
+            request::query(url_decoded(contains(("q", "a&b=c d")))),
+            request::query(url_decoded(contains(("page", "2")))),
+            request::query(url_decoded(contains(("sort", "asc")))),
+        ])
+        .respond_with(status_code(200)),
+    );
+    let url = server
+        .url_builder("/search?sort=asc")
+        .query("q", "a&b=c d")
+        .query("page", 2)
+        .build();
+    assert_eq!(Some("sort=asc&q=a%26b%3Dc+d&page=2"), url.query());
+    let client = create_test_client();
+    let resp = read_response_body(client.get(url)).await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_hostname() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::ServerBuilder::new()
+        .hostname("api.test.local")
+        .run()
+        .unwrap();
+    let url = server.url("/foo");
+    let authority = format!("api.test.local:{}", server.addr().port());
+    assert_eq!(
+        Some(authority.as_str()),
+        url.authority().map(|a| a.as_str())
+    );
+    assert_eq!(
+        format!("http://{}/bar?q=1", authority),
+        server.url_builder("/bar").query("q", 1).build().to_string()
+    );
+
+    // the server is reached at its address with the hostname in the host
+    // header, as a client with a resolver override would.
+    server.expect(
+        Expectation::matching(all_of![
+            request::path("/foo"),
+            request::headers(contains(("host", authority.clone()))),
+        ])
+        .respond_with(status_code(200)),
+    );
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(format!("GET /foo HTTP/1.1\r\nhost: {}\r\n\r\n", authority).as_bytes())
+        .await
+        .unwrap();
+    let mut resp = [0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(b"HTTP/1.1 200", &resp);
+}
+
+#[test]
+fn test_port_range() {
+    let _ = pretty_env_logger::try_init();
+
+    // occupy one port in the range so the server has to skip it.
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let ports = port - 1..=port;
+    let server = httptest::ServerBuilder::new()
+        .bind_addr(([127, 0, 0, 1], 0).into())
+        .port_range(ports.clone())
+        .run()
+        .unwrap();
+    assert_eq!(port - 1, server.addr().port());
+
+    let err = httptest::ServerBuilder::new()
+        .bind_addr(([127, 0, 0, 1], 0).into())
+        .port_range(ports)
+        .run()
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::AddrInUse, err.kind());
+
+    let err = httptest::ServerBuilder::new()
+        .port_range(port..port)
+        .run()
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn test_port_range_includes_zero() {
+    let _ = pretty_env_logger::try_init();
+
+    // port 0 asks the OS for an ephemeral port, so it can't be in a range.
+    let err = httptest::ServerBuilder::new()
+        .port_range(0..=40000)
+        .run()
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[tokio::test]
+async fn test_connection_metrics() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::path("/foo"))
+            .times(4)
+            .respond_with(status_code(200)),
+    );
+
+    // a pooling client reuses its connection.
+    let client = create_test_client();
+    for _ in 0..3 {
+        let resp = read_response_body(client.get(server.url("/foo"))).await;
+        assert_eq!(200, resp.status().as_u16());
+    }
+    let metrics = server.connection_metrics();
+    assert_eq!(1, metrics.accepted());
+    assert_eq!(1, metrics.open());
+    assert_eq!(&[3], metrics.requests_per_connection());
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let metrics = server.connection_metrics();
+    assert_eq!(2, metrics.accepted());
+    assert_eq!(&[3, 1], metrics.requests_per_connection());
+}
+
+#[tokio::test]
+async fn test_connection_hooks() {
+    use std::sync::{mpsc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let (events_tx, events_rx) = mpsc::channel();
+    let accepted_tx = Mutex::new(events_tx.clone());
+    let closed_tx = Mutex::new(events_tx);
+    let server = httptest::ServerBuilder::new()
+        .on_connection_accepted(move |conn| {
+            let _ = accepted_tx.lock().unwrap().send(("accepted", *conn));
+        })
+        .on_connection_closed(move |conn| {
+            let _ = closed_tx.lock().unwrap().send(("closed", *conn));
+        })
+        .run()
+        .unwrap();
+    let (conn_tx, conn_rx) = mpsc::channel();
+    let conn_tx = Mutex::new(conn_tx);
+    server.expect(
+        Expectation::matching(move |req: &http::Request<bytes::Bytes>| {
+            let conn = req.extensions().get::<httptest::ConnectionInfo>();
+            conn_tx.lock().unwrap().send(conn.copied()).unwrap();
+            true
+        })
+        .respond_with(status_code(200)),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+
+    let recv = || {
+        events_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap()
+    };
+    let (event, accepted) = recv();
+    assert_eq!("accepted", event);
+    assert_eq!(stream.local_addr().unwrap(), accepted.peer_addr());
+    assert_eq!(Some(accepted), conn_rx.recv().unwrap());
+    assert_eq!(("closed", accepted), recv());
+}
+
+#[tokio::test]
+async fn test_diagnostics() {
+    use httptest::DiagnosticEvent;
+    use std::sync::{Arc, Mutex};
+    let _ = pretty_env_logger::try_init();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let mut server = httptest::ServerBuilder::new()
+        .diagnostics(move |event| sink.lock().unwrap().push(event.clone()))
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(
+        vec![
+            DiagnosticEvent::RequestReceived {
+                request: "GET /foo".to_string()
+            },
+            DiagnosticEvent::MatcherEvaluated {
+                depth: 0,
+                matcher: r#"MethodPath { method: "GET", path: "/foo" }"#.to_string(),
+                matched: true,
+            },
+            DiagnosticEvent::MatcherEvaluated {
+                depth: 1,
+                matcher: r#""GET""#.to_string(),
+                matched: true,
+            },
+            DiagnosticEvent::MatcherEvaluated {
+                depth: 1,
+                matcher: r#""/foo""#.to_string(),
+                matched: true,
+            },
+            DiagnosticEvent::ExpectationMatched {
+                matcher: r#"MethodPath { method: "GET", path: "/foo" }"#.to_string(),
+            },
+        ],
+        std::mem::take(&mut *events.lock().unwrap())
+    );
+
+    read_response_body(client.get(server.url("/bar"))).await;
+    let events = std::mem::take(&mut *events.lock().unwrap());
+    match events.last() {
+        Some(DiagnosticEvent::UnexpectedRequest { request, closest }) => {
+            assert_eq!("GET /bar", request);
+            assert!(closest.as_ref().unwrap().contains("/foo"), "{:?}", closest);
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| server.verify_and_clear()));
+}
+
+#[tokio::test]
+async fn test_expect_macro() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let foo = httptest::expect!(server, GET "/foo?x=1"
+        with header "Accept" == "application/json"
+        => 200 json {"ok": true});
+    httptest::expect!(server, POST "/upload" with body "hello" => 201 body "created");
+    httptest::expect!(server, DELETE "/foo" => 204);
+
+    let client = create_test_client();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::get(server.url("/foo?y=2&x=1"))
+                .header("accept", "application/json")
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("application/json", resp.headers()["content-type"]);
+    assert_eq!(&b"{\"ok\":true}"[..], resp.body());
+    assert_eq!(1, foo.hit_count());
+
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/upload"))
+                .body(Full::from("hello"))
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(201, resp.status().as_u16());
+    assert_eq!(&b"created"[..], resp.body());
+
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::delete(server.url("/foo"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(204, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert!(server.is_running());
+
+    server.shutdown();
+    assert!(!server.is_running());
+    assert!(client.get(server.url("/foo")).await.is_err());
+    // the state is still available after shutdown.
+    assert_eq!(server.dump_state_json()["expectations"][0]["hit_count"], 1);
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn test_dump_state_json() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/foo"))
+            .times(1..)
+            .respond_with(json_encoded(serde_json::json!({"id": 1}))),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/bar"))
+            .times(..)
+            .respond_with(status_code(404)),
+    );
+
+    let client = create_test_client();
+    read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/foo"))
+                .body(Full::from("hello"))
+                .unwrap(),
+        ),
+    )
+    .await;
+
+    let state = server.dump_state_json();
+    assert_eq!(state["passed"], true);
+    assert_eq!(state["expectations"][0]["hit_count"], 1);
+    assert_eq!(state["expectations"][1]["hit_count"], 0);
+    let exchanges = state["exchanges"].as_array().unwrap();
+    assert_eq!(1, exchanges.len());
+    assert_eq!(exchanges[0]["request"]["uri"], "/foo");
+    assert_eq!(exchanges[0]["request"]["body"], "hello");
+    assert_eq!(exchanges[0]["response"]["status"], 200);
+    assert_eq!(exchanges[0]["response"]["body"], r#"{"id":1}"#);
+}
+
+#[tokio::test]
+async fn test_max_logged_exchanges() {
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::ServerBuilder::new()
+        .max_logged_exchanges(2)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method("GET"))
+            .times(3)
+            .respond_with(status_code(200)),
+    );
+
+    let client = create_test_client();
+    for path in &["/a", "/b", "/c"] {
+        read_response_body(client.get(server.url(path))).await;
+    }
+
+    // Only the most recent exchanges are kept.
+    let state = server.dump_state_json();
+    let uris: Vec<_> = state["exchanges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|exchange| exchange["request"]["uri"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(vec!["/b", "/c"], uris);
+}
+
+#[tokio::test]
+async fn test_upload_progress() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let (progress_tx, progress_rx) = std::sync::mpsc::channel();
+    let progress_tx = std::sync::Mutex::new(progress_tx);
+    let server = httptest::ServerBuilder::new()
+        .upload_progress(move |progress| {
+            let _ = progress_tx.lock().unwrap().send(progress.bytes_received());
+            if progress.request().uri.path() == "/abort" && progress.bytes_received() >= 5 {
+                httptest::UploadAction::Abort
+            } else {
+                httptest::UploadAction::Continue
+            }
+        })
+        .run()
+        .unwrap();
+    let upload = server.expect(
+        Expectation::matching(request::method_path("POST", "/upload"))
+            .respond_with(status_code(200)),
+    );
+
+    // Send the body in two parts, waiting for the server to observe the
+    // first before sending the second.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello")
+        .await
+        .unwrap();
+    let recv_progress = || progress_rx.recv_timeout(std::time::Duration::from_secs(5));
+    assert_eq!(Ok(5), recv_progress());
+    assert_eq!(0, upload.hit_count());
+    stream.write_all(b"world").await.unwrap();
+    assert_eq!(Ok(10), recv_progress());
+    let mut resp = [0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(b"HTTP/1.1 200", &resp);
+    assert_eq!(1, upload.hit_count());
+
+    // Aborting closes the connection without a response.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"POST /abort HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello")
+        .await
+        .unwrap();
+    assert_eq!(Ok(5), recv_progress());
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    assert!(resp.is_empty());
+}
+
+#[tokio::test]
+async fn test_match_before_body() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let mut server = httptest::ServerBuilder::new()
+        .match_before_body(true)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/upload"),
+            request::body("data"),
+        ])
+        .times(..)
+        .respond_with(status_code(200)),
+    );
+
+    // No expectation matches the path, so the server responds without
+    // waiting for the rest of the body.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(
+            b"POST /other HTTP/1.1\r\nhost: localhost\r\ncontent-length: 1000000\r\n\r\nhello",
+        )
+        .await
+        .unwrap();
+    let mut resp = [0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(b"HTTP/1.1 500", &resp);
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| server.verify_and_clear()))
+        .unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains("uri: /other"), "{}", msg);
+
+    // Requests that might match have their body read as usual.
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/upload"),
+            request::body("data"),
+        ])
+        .respond_with(status_code(200)),
+    );
+    let client = create_test_client();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/upload"))
+                .body(Full::from("data"))
+                .unwrap(),
+        ),
+    )
+    .await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_abort_after_bytes() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::ServerBuilder::new()
+        .abort_after_bytes(5)
+        .run()
+        .unwrap();
+    let upload = server.expect(
+        Expectation::matching(request::method_path("POST", "/upload"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    assert!(resp.is_empty());
+    assert_eq!(0, upload.hit_count());
+}
+
+#[tokio::test]
+async fn test_abort_after_bytes_within_chunk() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let (progress_tx, progress_rx) = std::sync::mpsc::channel();
+    let progress_tx = std::sync::Mutex::new(progress_tx);
+    let server = httptest::ServerBuilder::new()
+        .abort_after_bytes(3)
+        .upload_progress(move |progress| {
+            let _ = progress_tx.lock().unwrap().send(progress.bytes_received());
+            httptest::UploadAction::Continue
+        })
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/upload"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+
+    // The whole body arrives in a single chunk, but only 3 bytes are read.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 5\r\n\r\nhello")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    assert!(resp.is_empty());
+    assert_eq!(vec![3], progress_rx.try_iter().collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_read_body_slowly() {
+    use httptest::clock::ManualClock;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let clock = ManualClock::new();
+    let server = httptest::ServerBuilder::new()
+        .clock(clock.clone())
+        .read_body_slowly(5, Duration::from_secs(1))
+        .run()
+        .unwrap();
+    let upload = server.expect(
+        Expectation::matching(request::method_path("POST", "/upload"))
+            .respond_with(status_code(200)),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhelloworld",
+        )
+        .await
+        .unwrap();
+    // 10 bytes take two intervals to read.
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(0, upload.hit_count());
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(1));
+
+    let mut resp = [0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(b"HTTP/1.1 200", &resp);
+    assert_eq!(1, upload.hit_count());
+}
+
+#[tokio::test]
+async fn test_truncate_after() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/generated"))
+            .respond_with(truncate_after(5, generated_body(100_000, "0123456789"))),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/ndjson"))
+            .respond_with(truncate_after(3, ndjson_stream(vec![1, 2, 3]))),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/short"))
+            .respond_with(truncate_after(5, status_code(200).body("hello"))),
+    );
+
+    async fn get(server: &httptest::Server, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        String::from_utf8(resp).unwrap()
+    }
+
+    let resp = get(&server, "/generated").await;
+    assert!(resp.contains("content-length: 100000\r\n"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\n01234"), "{}", resp);
+
+    // the chunked body is cut off without its final chunk.
+    let resp = get(&server, "/ndjson").await;
+    assert!(resp.contains("transfer-encoding: chunked\r\n"), "{}", resp);
+    assert!(resp.ends_with("2\r\n1\n\r\n1\r\n2\r\n"), "{}", resp);
+
+    let resp = get(&server, "/short").await;
+    assert!(resp.ends_with("\r\n\r\nhello"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_declare_content_length() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/longer"))
+            .respond_with(declare_content_length(10, status_code(200).body("hello"))),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/shorter")).respond_with(
+            declare_content_length(5, status_code(200).body("helloworld")),
+        ),
+    );
+
+    // a body shorter than declared is cut off by closing the connection.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET /longer HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.contains("content-length: 10\r\n"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nhello"), "{}", resp);
+
+    // the bytes beyond the declared length are left on the connection, ahead
+    // of the next response.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET /shorter HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.contains("content-length: 5\r\n"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nhelloworld"), "{}", resp);
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_truncate_body_at() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/download"))
+            .respond_with(truncate_body_at(5, status_code(200).body("helloworld"))),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET /download HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.contains("content-length: 10\r\n"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nhello"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_shared_runtime() {
+    let _ = pretty_env_logger::try_init();
+
+    let servers: Vec<_> = (0..3)
+        .map(|i| {
+            let server = httptest::ServerBuilder::new()
+                .shared_runtime(true)
+                .run()
+                .unwrap();
+            server.expect(
+                Expectation::matching(request::method_path("GET", "/foo"))
+                    .respond_with(status_code(200).body(format!("server {}", i))),
+            );
+            server
+        })
+        .collect();
+    let client = create_test_client();
+    for (i, server) in servers.iter().enumerate() {
+        let resp = read_response_body(client.get(server.url("/foo"))).await;
+        assert_eq!(format!("server {}", i).as_bytes(), &resp.body()[..]);
+    }
+    drop(servers);
+
+    // the shared runtime outlives the servers that used it.
+    let server = httptest::ServerBuilder::new()
+        .shared_runtime(true)
+        .run()
+        .unwrap();
+    server.expect(Expectation::matching(any()).respond_with(status_code(204)));
+    let resp = read_response_body(client.get(server.url("/bar"))).await;
+    assert_eq!(204, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_capture_traffic() {
+    let _ = pretty_env_logger::try_init();
+
+    let dir = std::env::temp_dir().join(format!("httptest-capture-{}", std::process::id()));
+    let server = httptest::ServerBuilder::new()
+        .capture_traffic(&dir)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(status_code(200).body("captured")),
+    );
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let port = server.addr().port();
+    drop(server);
+
+    let capture =
+        std::fs::read_to_string(dir.join(format!("httptest-{}-conn0.log", port))).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let received = capture.find(">>> ").unwrap();
+    let sent = capture.find("<<< ").unwrap();
+    assert!(received < sent, "{}", capture);
+    assert!(
+        capture[received..sent].contains("GET /foo HTTP/1.1\r\n"),
+        "{}",
+        capture
+    );
+    assert!(
+        capture[sent..].contains("HTTP/1.1 200 OK\r\n"),
+        "{}",
+        capture
+    );
+    assert!(capture[sent..].contains("captured"), "{}", capture);
+}
+
+// compile test to ensure users of the library can write wrappers for
+// ExpectationBuilder that is generic over IntoTimes.
+#[allow(unused)]
+fn times_wrapper(times: impl httptest::IntoTimes) -> ExpectationBuilder {
+    Expectation::matching(request::method("POST")).times(times)
+}
+
+// ensure that the state of a server in a ServerPool is always reset when the handle is dropped.
+// Even when panicking.
+#[test]
+fn test_server_pool_expectations_are_cleared_on_panic() {
+    let _ = pretty_env_logger::try_init();
+
+    // Create a pool with only 1 server.
+    static SERVER_POOL: ServerPool = ServerPool::new(1);
+
+    // Add an expectation to the server and then panic. The panic should cause the server handle to
+    // be dropped and that should clear the expecations on the server.
+    let _ = std::panic::catch_unwind(|| {
+        let server = SERVER_POOL.get_server();
+        server.expect(
+            Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
+                .respond_with(status_code(200)),
+        );
+        panic!("panic without server expectation being met");
+    });
+
+    // Retrieve another handle to the same underlying server. The implicit drop of this handle
+    // should succeed because the expectation added above was cleared by the panic.
+    let _server = SERVER_POOL.get_server();
+}
+
+#[cfg(feature = "decompress")]
+#[tokio::test]
+async fn test_decompress_request_bodies() {
+    use httptest::ServerBuilder;
+    use std::io::Write;
+    let _ = pretty_env_logger::try_init();
+
+    let server = ServerBuilder::new()
+        .decompress_request_bodies(true)
+        .run()
+        .unwrap();
+    for encoding in ["gzip", "deflate", "br"] {
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/upload"),
+                request::content_encoding(encoding),
+                request::headers(not(contains(key("content-encoding")))),
+                request::headers(contains(("content-length", "5"))),
+                request::body("hello"),
+            ])
+            .respond_with(status_code(200)),
+        );
+    }
+    // bodies that can't be decompressed are matched as they were sent.
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/upload"),
+            request::headers(contains(("content-encoding", "gzip"))),
+            request::body("not gzip"),
+        ])
+        .respond_with(status_code(400)),
+    );
+
+    let gzip = {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"hello").unwrap();
+        encoder.finish().unwrap()
+    };
+    let deflate = {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"hello").unwrap();
+        encoder.finish().unwrap()
+    };
+    let br = {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(b"hello").unwrap();
+        encoder.into_inner()
+    };
+
+    let client = create_test_client();
+    for (encoding, body, status) in [
+        ("gzip", gzip, 200),
+        ("deflate", deflate, 200),
+        ("br", br, 200),
+        ("gzip", b"not gzip".to_vec(), 400),
+    ] {
+        let req = hyper::Request::post(server.url("/upload"))
+            .header("content-encoding", encoding)
+            .body(Full::from(body))
+            .unwrap();
+        let resp = read_response_body(client.request(req)).await;
+        assert_eq!(status, resp.status().as_u16(), "{}", encoding);
+    }
+}
+
+#[cfg(feature = "digest-auth")]
+#[tokio::test]
+async fn test_digest_auth() {
+    use httptest::presets::digest::DigestAuth;
+    use md5::{Digest, Md5};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let digest = DigestAuth::new("example", "alice", "secret");
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/protected"),
+            digest.authorized(),
+        ])
+        .respond_with(status_code(200)),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/protected"),
+            not(digest.authorized()),
+        ])
+        .respond_with(digest.challenge()),
+    );
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/protected"))).await;
+    assert_eq!(401, resp.status().as_u16());
+    let challenge = resp.headers()["www-authenticate"].to_str().unwrap();
+    let nonce = challenge
+        .split("nonce=\"")
+        .nth(1)
+        .and_then(|s| s.split('"').next())
+        .unwrap();
+
+    let hex = |s: String| -> String {
+        Md5::digest(s.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    };
+    let ha1 = hex("alice:example:secret".to_string());
+    let ha2 = hex("GET:/protected".to_string());
+    let response = hex(format!("{}:{}:00000001:abc:auth:{}", ha1, nonce, ha2));
+    let authorization = format!(
+        r#"Digest username="alice", realm="example", nonce="{}", uri="/protected", qop=auth, nc=00000001, cnonce="abc", response="{}""#,
+        nonce, response
+    );
+    let req = hyper::Request::get(server.url("/protected"))
+        .header("authorization", authorization)
+        .body(Full::default())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[cfg(feature = "oidc")]
+#[tokio::test]
+async fn test_oidc() {
+    use base64::Engine;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let provider = httptest::presets::oidc()
+        .subject("alice")
+        .claim("email", "alice@example.com")
+        .install(&server);
+
+    let client = create_test_client();
+    let resp =
+        read_response_body(client.get(server.url("/.well-known/openid-configuration"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let discovery: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(provider.issuer(), discovery["issuer"]);
+    assert_eq!(server.url_str("/jwks"), discovery["jwks_uri"]);
+
+    let resp = read_response_body(client.get(server.url("/jwks"))).await;
+    let jwks: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!("httptest", jwks["keys"][0]["kid"]);
+
+    let req = hyper::Request::post(server.url("/token"))
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body("grant_type=client_credentials&client_id=my-client".into())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(200, resp.status().as_u16());
+    let token: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!("Bearer", token["token_type"]);
+    let claims = token["access_token"]
+        .as_str()
+        .unwrap()
+        .split('.')
+        .nth(1)
+        .unwrap();
+    let claims: serde_json::Value = serde_json::from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(claims)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(provider.issuer(), claims["iss"]);
+    assert_eq!("alice", claims["sub"]);
+    assert_eq!("my-client", claims["aud"]);
+    assert_eq!("alice@example.com", claims["email"]);
+}
+
+#[cfg(feature = "jwt")]
+#[tokio::test]
+async fn test_jwt_token() {
+    use base64::Engine;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/token"))
+            .respond_with(jwt_token().claim("sub", "alice").hs256("s3cr3t")),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/rs256"))
+            .respond_with(jwt_token().expires_in(std::time::Duration::from_secs(60))),
+    );
+    server.expect(Expectation::matching(request::path("/jwks")).respond_with(jwks()));
+
+    let client = create_test_client();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/token"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!("Bearer", body["token_type"]);
+    assert_eq!(3600, body["expires_in"]);
+    let token = body["access_token"].as_str().unwrap();
+    let mut m = jwt(|claims: &serde_json::Value| {
+        claims["sub"] == "alice"
+            && claims["exp"].as_u64().unwrap() == claims["iat"].as_u64().unwrap() + 3600
+    })
+    .verify_hs256("s3cr3t");
+    assert!(ExecutionContext::evaluate(&mut m, token));
+
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/rs256"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(60, body["expires_in"]);
+    let resp = read_response_body(client.get(server.url("/jwks"))).await;
+    let jwks: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let component = |name: &str| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(jwks["keys"][0][name].as_str().unwrap())
+            .unwrap()
+    };
+    let mut m = jwt(any())
+        .header(|header: &serde_json::Value| header["alg"] == "RS256")
+        .verify_rs256(component("n"), component("e"));
+    assert!(ExecutionContext::evaluate(
+        &mut m,
+        body["access_token"].as_str().unwrap()
+    ));
+}
+
+#[cfg(feature = "jwt")]
+#[tokio::test]
+async fn test_jwt_token_rs256_key() {
+    use base64::Engine;
+    let _ = pretty_env_logger::try_init();
+
+    let pem = include_str!("../src/insecure_test_jwt_key.pem");
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/token"))
+            .respond_with(jwt_token().claim("sub", "alice").rs256_key(pem)),
+    );
+    server.expect(Expectation::matching(request::path("/jwks")).respond_with(jwks_for(pem)));
+
+    let client = create_test_client();
+    let resp = read_response_body(
+        client.request(
+            hyper::Request::post(server.url("/token"))
+                .body(Full::default())
+                .unwrap(),
+        ),
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let resp = read_response_body(client.get(server.url("/jwks"))).await;
+    let jwks: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let component = |name: &str| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(jwks["keys"][0][name].as_str().unwrap())
+            .unwrap()
+    };
+    let mut m = jwt(|claims: &serde_json::Value| claims["sub"] == "alice")
+        .verify_rs256(component("n"), component("e"));
+    assert!(ExecutionContext::evaluate(
+        &mut m,
+        body["access_token"].as_str().unwrap()
+    ));
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+async fn test_s3() {
+    use httptest::presets::s3::Operation;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    let store = httptest::presets::s3().install(&server);
+
+    let client = create_test_client();
+    let req = hyper::Request::put(server.url("/bucket/dir/file.txt"))
+        .header("content-type", "text/plain")
+        .body("hello".into())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert!(resp.headers().contains_key("etag"));
+
+    let resp = read_response_body(client.get(server.url("/bucket/dir/file.txt"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("text/plain", resp.headers()["content-type"]);
+    assert_eq!("hello", resp.body());
+
+    let resp = read_response_body(client.get(server.url("/bucket?list-type=2&prefix=dir/"))).await;
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.contains("<Key>dir/file.txt</Key>"));
+
+    let resp = read_response_body(client.get(server.url("/bucket/missing"))).await;
+    assert_eq!(404, resp.status().as_u16());
+
+    assert_eq!(Some("hello".into()), store.object("bucket", "dir/file.txt"));
+    assert_eq!(
+        vec![
+            Operation::PutObject {
+                bucket: "bucket".to_string(),
+                key: "dir/file.txt".to_string()
+            },
+            Operation::GetObject {
+                bucket: "bucket".to_string(),
+                key: "dir/file.txt".to_string()
+            },
+            Operation::ListObjects {
+                bucket: "bucket".to_string(),
+                prefix: "dir/".to_string()
+            },
+            Operation::GetObject {
+                bucket: "bucket".to_string(),
+                key: "missing".to_string()
+            },
+        ],
+        store.operations()
+    );
+}
+
+#[cfg(feature = "grpc")]
+// The client's HTTP/2 connection has to acknowledge the server's shutdown,
+// which it can't do if the runtime is blocked dropping the server.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_grpc() {
+    use httptest::grpc;
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            grpc::call("helloworld.Greeter", "SayHello"),
+            grpc::message(&b"world"[..]),
+        ])
+        .respond_with(grpc::unary(&b"hello world"[..]).metadata("x-greeting", "1")),
+    );
+    server.expect(
+        Expectation::matching(grpc::call("helloworld.Greeter", "SayGoodbye"))
+            .respond_with(grpc::status(grpc::Code::Unimplemented, "not here")),
+    );
+
+    let client: Client<HttpConnector, Full<hyper::body::Bytes>> =
+        Client::builder(hyper_util::rt::TokioExecutor::new())
+            .http2_only(true)
+            .build_http();
+    let call = |method: &str| {
+        hyper::Request::post(server.url(&format!("/helloworld.Greeter/{}", method)))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(hyper::body::Bytes::from_static(b"\x00\x00\x00\x00\x05world").into())
+            .unwrap()
+    };
+
+    let resp = client.request(call("SayHello")).await.unwrap();
+    assert_eq!(hyper::Version::HTTP_2, resp.version());
+    assert_eq!("1", resp.headers()["x-greeting"]);
+    let body = resp.into_body().collect().await.unwrap();
+    assert_eq!("0", body.trailers().unwrap()["grpc-status"]);
+    assert_eq!(
+        &b"\x00\x00\x00\x00\x0bhello world"[..],
+        &body.to_bytes()[..]
+    );
+
+    let resp = client.request(call("SayGoodbye")).await.unwrap();
+    assert_eq!("12", resp.headers()["grpc-status"]);
+    assert_eq!("not%20here", resp.headers()["grpc-message"]);
+}
+
+#[cfg(feature = "connector")]
+#[tokio::test]
+async fn test_connector() {
+    let _ = pretty_env_logger::try_init();
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(status_code(200).body("in process")),
+    );
+
+    let client: Client<_, Full<hyper::body::Bytes>> =
+        Client::builder(hyper_util::rt::TokioExecutor::new()).build(server.connector());
+    // the host is never resolved.
+    let resp = read_response_body(client.get("http://example.invalid/foo".parse().unwrap())).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("in process", resp.body());
+    server.verify_and_clear();
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_reqwest() {
+    let _ = pretty_env_logger::try_init();
+    let mut server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .times(2)
+            .respond_with(status_code(200)),
+    );
+
+    let resp = reqwest::get(server.reqwest_url("/foo")).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+
+    let client = server.reqwest_client("api.example.com");
+    let url = format!("http://api.example.com:{}/foo", server.addr().port());
+    let resp = client.get(url).send().await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    server.verify_and_clear();
+}
+
+#[cfg(all(feature = "reqwest", feature = "tls"))]
+#[tokio::test]
+async fn test_reqwest_trusted_client() {
+    let _ = pretty_env_logger::try_init();
+    let server = httptest::ServerBuilder::new()
+        .tls(true)
+        .hostname("api.example.com")
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(status_code(200).body("secure")),
+    );
+
+    let client = server.trusted_client("api.example.com");
+    let resp = client.get(server.url_str("/foo")).send().await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("secure", resp.text().await.unwrap());
+}
+
+#[cfg(feature = "record")]
+#[tokio::test]
+async fn test_record_and_replay() {
+    let _ = pretty_env_logger::try_init();
+    let upstream = httptest::Server::run();
+    upstream.expect(
+        Expectation::matching(request::method_path("POST", "/api/foo"))
+            .respond_with(status_code(201).body("created")),
+    );
+    let recorder = httptest::ServerBuilder::new()
+        .forward_unmatched_to(upstream.url("/api"))
+        .run()
+        .unwrap();
+    recorder
+        .expect(Expectation::matching(request::path("/matched")).respond_with(status_code(200)));
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(recorder.url("/matched"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    let req = hyper::Request::post(recorder.url("/foo?x=1"))
+        .body("payload".into())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(201, resp.status().as_u16());
+    assert_eq!("created", resp.body());
+
+    // only the unmatched request is recorded.
+    let recordings = recorder.recordings();
+    assert_eq!(1, recordings.len());
+    let path = std::env::temp_dir().join(format!("httptest-record-{}.json", std::process::id()));
+    httptest::record::save(&path, &recordings).unwrap();
+    let recordings = httptest::record::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let replay = httptest::Server::run();
+    replay.expect(recordings[0].to_expectation());
+    let req = hyper::Request::post(replay.url("/foo?x=1"))
+        .body("payload".into())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(201, resp.status().as_u16());
+    assert_eq!("created", resp.body());
+}
+
+#[tokio::test]
+async fn test_expect_har() {
+    let _ = pretty_env_logger::try_init();
+    let har = serde_json::json!({
+        "log": {
+            "entries": [{
+                "request": {"method": "GET", "url": "https://example.com/foo?page=2"},
+                "response": {
+                    "status": 200,
+                    "headers": [{"name": "content-type", "value": "text/plain"}],
+                    "content": {"text": "from har"},
+                },
+            }],
+        },
+    });
+    let path = std::env::temp_dir().join(format!("httptest-{}.har", std::process::id()));
+    std::fs::write(&path, har.to_string()).unwrap();
+    let server = httptest::Server::run();
+    server.expect_har(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/foo?page=2"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("text/plain", resp.headers()["content-type"]);
+    assert_eq!("from har", resp.body());
+}
+
+#[cfg(feature = "wiremock")]
+#[tokio::test]
+async fn test_wiremock_stubs() {
+    let _ = pretty_env_logger::try_init();
+    let dir = std::env::temp_dir().join(format!("httptest-wiremock-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let stubs = serde_json::json!({
+        "mappings": [
+            {
+                "request": {"method": "GET", "urlPathPattern": "/users/[0-9]+"},
+                "response": {"status": 200, "jsonBody": {"name": "bob"}},
+            },
+            {
+                "priority": 1,
+                "request": {"method": "GET", "urlPath": "/users/0"},
+                "response": {"status": 404},
+            },
+        ],
+    });
+    std::fs::write(dir.join("users.json"), stubs.to_string()).unwrap();
+    let expectations = httptest::wiremock::load_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let server = httptest::Server::run();
+    for expectation in expectations {
+        server.expect(expectation);
+    }
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/users/1"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!(r#"{"name":"bob"}"#, resp.body());
+    let resp = read_response_body(client.get(server.url("/users/0"))).await;
+    assert_eq!(404, resp.status().as_u16());
+}
+
+#[cfg(feature = "openapi")]
+#[tokio::test]
+async fn test_openapi_validation() {
+    let _ = pretty_env_logger::try_init();
+    let spec = serde_json::json!({
+        "openapi": "3.0.3",
+        "paths": {
+            "/users/{id}": {
+                "get": {
+                    "parameters": [{
+                        "name": "id", "in": "path", "required": true,
+                        "schema": {"type": "integer"},
+                    }],
+                    "responses": {
+                        "200": {
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["name"],
+                                        "properties": {"name": {"type": "string"}},
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    });
+    let spec = httptest::openapi::OpenApi::from_json(spec.to_string().as_bytes()).unwrap();
+    let mut server = httptest::ServerBuilder::new()
+        .validate_against(spec)
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(request::path(matches("^/users/")))
+            .times(2)
+            .respond_with(json_encoded(serde_json::json!({"name": 1}))),
+    );
+    let client = create_test_client();
+    read_response_body(client.get(server.url("/users/1"))).await;
+    read_response_body(client.get(server.url("/users/bob"))).await;
+
+    let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        server.verify_and_clear();
+    }))
+    .unwrap_err();
+    assert_eq!(
+        Some(
+            &"the following exchanges violate the OpenAPI spec:\n\
+              GET /users/1: response body.name: expected string, got 1\n\
+              GET /users/bob: path parameter id: expected integer, got \"bob\"\n\
+              GET /users/bob: response body.name: expected string, got 1"
+                .to_string()
+        ),
+        panic.downcast_ref::<String>()
+    );
+}
+
+#[tokio::test]
+async fn test_fixture_dir() {
+    let _ = pretty_env_logger::try_init();
+    let dir = std::env::temp_dir().join(format!("httptest-fixtures-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("GET__users__42.json"), r#"{"id": 42}"#).unwrap();
+    std::fs::write(dir.join("DELETE__users__42@204.txt"), "").unwrap();
+    let expectations = httptest::fixtures::load_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let server = httptest::Server::run();
+    for expectation in expectations {
+        server.expect(expectation);
+    }
+    let client = create_test_client();
+    let resp = read_response_body(client.get(server.url("/users/42"))).await;
+    assert_eq!(200, resp.status().as_u16());
+    assert_eq!("application/json", resp.headers()["content-type"]);
+    assert_eq!(r#"{"id": 42}"#, resp.body());
+    let req = hyper::Request::delete(server.url("/users/42"))
+        .body(Full::default())
+        .unwrap();
+    let resp = read_response_body(client.request(req)).await;
+    assert_eq!(204, resp.status().as_u16());
+}
 
-// ensure that the state of a server in a ServerPool is always reset when the handle is dropped.
-// Even when panicking.
 #[test]
-fn test_server_pool_expectations_are_cleared_on_panic() {
+fn test_fixture_invalid_status() {
+    let dir =
+        std::env::temp_dir().join(format!("httptest-fixtures-invalid-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("GET__users@1000.json"), "{}").unwrap();
+    let err = httptest::fixtures::load_dir(&dir).map(|_| ()).unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    assert!(err.to_string().contains("GET__users@1000.json"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_snapshot_requests() {
     let _ = pretty_env_logger::try_init();
+    let dir = std::env::temp_dir().join(format!("httptest-snapshot-{}", std::process::id()));
+    let path = dir.join("requests.snap");
+    let new_path = dir.join("requests.snap.new");
 
-    // Create a pool with only 1 server.
-    static SERVER_POOL: ServerPool = ServerPool::new(1);
+    async fn run(path: &std::path::Path, body: &'static str) -> std::thread::Result<()> {
+        let server = httptest::ServerBuilder::new()
+            .snapshot_requests(path)
+            .run()
+            .unwrap();
+        server.expect(Expectation::matching(any()).respond_with(status_code(200)));
+        let req = hyper::Request::post(server.url("/users"))
+            .header("content-type", "application/json")
+            .body(body.into())
+            .unwrap();
+        read_response_body(create_test_client().request(req)).await;
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(server)))
+    }
 
-    // Add an expectation to the server and then panic. The panic should cause the server handle to
-    // be dropped and that should clear the expecations on the server.
-    let _ = std::panic::catch_unwind(|| {
-        let server = SERVER_POOL.get_server();
-        server.expect(
-            Expectation::matching(all_of![request::method("GET"), request::path("/foo")])
+    // the first run has no snapshot to compare to.
+    let err = run(&path, r#"{"name":"bob"}"#).await.unwrap_err();
+    assert!(err
+        .downcast_ref::<String>()
+        .unwrap()
+        .starts_with("no snapshot found at"));
+    assert_eq!(
+        "POST /users\ncontent-type: application/json\n\n{\n  \"name\": \"bob\"\n}\n",
+        std::fs::read_to_string(&new_path).unwrap()
+    );
+
+    // accept the snapshot.
+    std::fs::rename(&new_path, &path).unwrap();
+    run(&path, r#"{ "name": "bob" }"#).await.unwrap();
+
+    let err = run(&path, r#"{"name":"alice"}"#).await.unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains("-  \"name\": \"bob\"\n+  \"name\": \"alice\""));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_body_framing() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![request::is_chunked(), request::body("hello world"),])
+            .respond_with(status_code(201)),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::content_length(eq(11)),
+            request::body("hello world"),
+        ])
+        .respond_with(status_code(200)),
+    );
+
+    let requests: [(&[u8], &str); 2] = [
+        (
+            b"POST /upload HTTP/1.1\r\nhost: localhost\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+            "HTTP/1.1 201",
+        ),
+        (
+            b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 11\r\nconnection: close\r\n\r\nhello world",
+            "HTTP/1.1 200",
+        ),
+    ];
+    for (request, status) in requests {
+        let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with(status), "{}", resp);
+    }
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_alpn() {
+    use httptest::ServerBuilder;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    let _ = pretty_env_logger::try_init();
+
+    // connect offering h2 and http/1.1, returning the protocol the server
+    // chose and the status of a GET /foo.
+    async fn get(server: &httptest::Server) -> (Option<Vec<u8>>, hyper::StatusCode) {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(server.certificate_der().unwrap().to_vec().into())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let protocol = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+        let req = hyper::Request::get(server.url("/foo"))
+            .body(Full::<hyper::body::Bytes>::default())
+            .unwrap();
+        let resp = if protocol.as_deref() == Some(b"h2") {
+            let (mut sender, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(conn);
+            sender.send_request(req).await.unwrap()
+        } else {
+            let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                .await
+                .unwrap();
+            tokio::spawn(conn);
+            sender.send_request(req).await.unwrap()
+        };
+        (protocol, resp.status())
+    }
+
+    let server = ServerBuilder::new().tls(true).run().unwrap();
+    assert_eq!(Some("https"), server.url("/foo").scheme_str());
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/foo"),
+            request::alpn_protocol("h2"),
+        ])
+        .respond_with(status_code(200)),
+    );
+    assert_eq!(
+        (Some(b"h2".to_vec()), hyper::StatusCode::OK),
+        get(&server).await
+    );
+
+    let server = ServerBuilder::new()
+        .tls(true)
+        .alpn_protocols(["http/1.1"])
+        .run()
+        .unwrap();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/foo"),
+            request::alpn_protocol("http/1.1"),
+        ])
+        .respond_with(status_code(200)),
+    );
+    assert_eq!(
+        (Some(b"http/1.1".to_vec()), hyper::StatusCode::OK),
+        get(&server).await
+    );
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_certificate_rotation() {
+    use httptest::ServerBuilder;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    let _ = pretty_env_logger::try_init();
+
+    // complete a TLS handshake with the server trusting only `cert`.
+    async fn handshake(server: &httptest::Server, cert: &[u8]) -> std::io::Result<()> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.to_vec().into()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(server.addr()).await?;
+        tokio_rustls::TlsConnector::from(Arc::new(tls))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .map(drop)
+    }
+
+    let server = ServerBuilder::new().tls(true).run().unwrap();
+    let old = server.certificate_der().unwrap();
+    handshake(&server, &old).await.unwrap();
+
+    server.rotate_certificate();
+    let new = server.certificate_der().unwrap();
+    assert_ne!(old, new);
+    assert!(handshake(&server, &old).await.is_err());
+    handshake(&server, &new).await.unwrap();
+
+    let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+    server
+        .set_certificate(&certified.cert.pem(), &certified.key_pair.serialize_pem())
+        .unwrap();
+    assert_eq!(Some(certified.cert.pem()), server.certificate_pem());
+    assert!(handshake(&server, &new).await.is_err());
+    handshake(&server, certified.cert.der()).await.unwrap();
+
+    assert!(server.set_certificate("not a certificate", "").is_err());
+}
+
+#[cfg(feature = "http3")]
+#[tokio::test]
+async fn test_http3() {
+    use bytes::{Buf, Bytes};
+    use httptest::ServerBuilder;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    let _ = pretty_env_logger::try_init();
+
+    let server = ServerBuilder::new().http3(true).run().unwrap();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/foo"),
+            request::body("hello"),
+        ])
+        .respond_with(status_code(200).body("bar")),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/alt")).respond_with(status_code(200)),
+    );
+
+    // responses sent over TCP advertise HTTP/3.
+    let resp = read_response_body(create_test_client().get(server.url("/alt"))).await;
+    let h3_addr = server.http3_addr().unwrap();
+    assert_eq!(
+        format!("h3=\":{}\"", h3_addr.port()),
+        resp.headers()["alt-svc"]
+    );
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(server.certificate_der().unwrap().to_vec().into())
+        .unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let tls = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+    let bind_ip: std::net::IpAddr = match h3_addr {
+        SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut endpoint = quinn::Endpoint::client(SocketAddr::new(bind_ip, 0)).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+    let conn = endpoint
+        .connect(h3_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(conn))
+        .await
+        .unwrap();
+    tokio::spawn(async move { futures::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let req = http::Request::post(server.https_url("/foo"))
+        .body(())
+        .unwrap();
+    let mut stream = send_request.send_request(req).await.unwrap();
+    stream.send_data(Bytes::from("hello")).await.unwrap();
+    stream.finish().await.unwrap();
+    let resp = stream.recv_response().await.unwrap();
+    assert_eq!(200, resp.status());
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    assert_eq!(b"bar", &body[..]);
+}
+
+#[cfg(feature = "http2-push")]
+#[tokio::test]
+async fn test_http2_server_push() {
+    use httptest::ServerBuilder;
+    let _ = pretty_env_logger::try_init();
+
+    let server = ServerBuilder::new().http2_server_push(true).run().unwrap();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/page"))
+            .times(3)
+            .respond_with(push(
+                "/style.css",
+                status_code(200)
+                    .insert_header("content-type", "text/css")
+                    .body("body {}"),
+                status_code(200).body("<html></html>"),
+            )),
+    );
+
+    // HTTP/1 clients only receive the response.
+    let resp = read_response_body(create_test_client().get(server.url("/page"))).await;
+    assert_eq!("<html></html>", resp.body());
+
+    let addr = server.addr();
+    // the connections are closed before the server is dropped, which waits
+    // for them.
+    let connect = |enable_push: bool| async move {
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, conn) = h2::client::Builder::new()
+            .enable_push(enable_push)
+            .handshake::<_, bytes::Bytes>(tcp)
+            .await
+            .unwrap();
+        (client, tokio::spawn(conn))
+    };
+    let req = || http::Request::get(server.url("/page")).body(()).unwrap();
+
+    let (mut client, conn) = connect(true).await;
+    let (mut resp, _) = client.send_request(req(), true).unwrap();
+    drop(client);
+    let mut pushes = resp.push_promises();
+    let mut resp = resp.await.unwrap();
+    assert_eq!(200, resp.status());
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(b"<html></html>", &body[..]);
+    let promise = pushes.push_promise().await.unwrap().unwrap();
+    let (pushed_req, pushed) = promise.into_parts();
+    assert_eq!("GET", pushed_req.method());
+    assert_eq!(server.url("/style.css"), *pushed_req.uri());
+    let mut pushed = pushed.await.unwrap();
+    assert_eq!(200, pushed.status());
+    assert_eq!("text/css", pushed.headers()["content-type"]);
+    let mut body = Vec::new();
+    while let Some(chunk) = pushed.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(b"body {}", &body[..]);
+    assert!(pushes.push_promise().await.is_none());
+    drop((resp, pushes, pushed));
+    conn.await.unwrap().unwrap();
+
+    // clients that disable push only receive the response.
+    let (mut client, conn) = connect(false).await;
+    let (resp, _) = client.send_request(req(), true).unwrap();
+    drop(client);
+    let mut resp = resp.await.unwrap();
+    assert_eq!(200, resp.status());
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(b"<html></html>", &body[..]);
+    drop(resp);
+    conn.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn test_smol_runtime() {
+    use httptest::{runtime::SmolRuntime, ServerBuilder};
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use std::time::Duration;
+    let _ = pretty_env_logger::try_init();
+
+    // no tokio runtime is running, the server and the test both run on smol.
+    let server = ServerBuilder::new()
+        .runtime(SmolRuntime::new())
+        .run()
+        .unwrap();
+    let handle = server.expect(
+        Expectation::matching(request::method_path("GET", "/foo"))
+            .respond_with(delay_and_then(Duration::from_millis(10), status_code(200))),
+    );
+    smol::block_on(async {
+        let mut stream = smol::net::TcpStream::connect(server.addr()).await.unwrap();
+        stream
+            .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        handle.wait_timeout(Duration::from_secs(5)).await.unwrap();
+    });
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn test_smol_runtime_with_executor() {
+    use httptest::{runtime::SmolRuntime, ServerBuilder};
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::Arc;
+    use std::time::Duration;
+    let _ = pretty_env_logger::try_init();
+
+    // the executor only runs on the test thread, which also drops the server.
+    let executor = Arc::new(smol::Executor::new());
+    smol::block_on(executor.run(async {
+        let mut server = ServerBuilder::new()
+            .runtime(SmolRuntime::with_executor(executor.clone()))
+            .run()
+            .unwrap();
+        let handle = server.expect(
+            Expectation::matching(request::method_path("GET", "/foo"))
                 .respond_with(status_code(200)),
         );
-        panic!("panic without server expectation being met");
+        let mut stream = smol::net::TcpStream::connect(server.addr()).await.unwrap();
+        stream
+            .write_all(b"GET /foo HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        handle.wait_timeout(Duration::from_secs(5)).await.unwrap();
+
+        // dropping the server without blocking the executor.
+        server.shutdown_async().await;
+        assert!(!server.is_running());
+        drop(server);
+    }));
+}
+
+#[cfg(all(feature = "smol", feature = "webhook"))]
+#[test]
+fn test_webhook_smol_runtime() {
+    use httptest::{runtime::SmolRuntime, ServerBuilder};
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use std::time::Duration;
+    let _ = pretty_env_logger::try_init();
+
+    // no tokio runtime is running, the callback is sent on smol.
+    let callbacks = ServerBuilder::new()
+        .runtime(SmolRuntime::new())
+        .run()
+        .unwrap();
+    let done = callbacks.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/done"),
+            request::body("done"),
+        ])
+        .respond_with(status_code(200)),
+    );
+
+    let server = ServerBuilder::new()
+        .runtime(SmolRuntime::new())
+        .run()
+        .unwrap();
+    let callback_url = callbacks.url_str("/done");
+    server.expect(
+        Expectation::matching(request::method_path("POST", "/jobs")).respond_with(
+            webhook(move |_: &_| Some(callback_url.clone()), status_code(202))
+                .payload("done")
+                .delay(Duration::from_millis(10)),
+        ),
+    );
+    smol::block_on(async {
+        let mut stream = smol::net::TcpStream::connect(server.addr()).await.unwrap();
+        stream
+            .write_all(b"POST /jobs HTTP/1.1\r\nhost: localhost\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 202"), "{}", resp);
+        done.wait_timeout(Duration::from_secs(5)).await.unwrap();
     });
+}
 
-    // Retrieve another handle to the same underlying server. The implicit drop of this handle
-    // should succeed because the expectation added above was cleared by the panic.
-    let _server = SERVER_POOL.get_server();
+#[tokio::test]
+async fn test_forward_proxy_scheme_and_absolute_uri() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::scheme("http"),
+            request::absolute_uri("http://example.com/foo?bar"),
+        ])
+        .respond_with(status_code(200).body("proxied")),
+    );
+
+    // absolute-form request target.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET http://example.com/foo?bar HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nproxied"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_forward_proxy() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let server = httptest::Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::proxy_target("example.com"),
+            request::path("/foo"),
+        ])
+        .respond_with(status_code(200).body("proxied")),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::method("CONNECT"),
+            request::proxy_target("example.com:80"),
+            request::headers(contains(("proxy-authorization", "Basic dXNlcjpwYXNz"))),
+        ])
+        .respond_with(status_code(200)),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::proxy_target("example.com:80"),
+            request::path("/tunneled"),
+        ])
+        .respond_with(status_code(200).body("tunneled")),
+    );
+
+    // absolute-form request target.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"GET http://example.com/foo HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nproxied"), "{}", resp);
+
+    // CONNECT tunnel.
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:80 HTTP/1.1\r\nhost: example.com:80\r\nproxy-authorization: Basic dXNlcjpwYXNz\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    while !resp.ends_with(b"\r\n\r\n") {
+        resp.push(stream.read_u8().await.unwrap());
+    }
+    assert!(resp.starts_with(b"HTTP/1.1 200"));
+    stream
+        .write_all(b"GET /tunneled HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\ntunneled"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_forward_proxy_tunnel_closed_on_shutdown() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = pretty_env_logger::try_init();
+
+    let closed = Arc::new(AtomicUsize::new(0));
+    let closed_hook = closed.clone();
+    let server = httptest::ServerBuilder::new()
+        .on_connection_closed(move |_| {
+            closed_hook.fetch_add(1, Ordering::SeqCst);
+        })
+        .run()
+        .unwrap();
+    server.expect(Expectation::matching(request::method("CONNECT")).respond_with(status_code(200)));
+    server.expect(
+        Expectation::matching(request::path("/tunneled"))
+            .respond_with(status_code(200).body("tunneled")),
+    );
+
+    let mut stream = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:80 HTTP/1.1\r\nhost: example.com:80\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    while !resp.ends_with(b"\r\n\r\n") {
+        resp.push(stream.read_u8().await.unwrap());
+    }
+    stream
+        .write_all(b"GET /tunneled HTTP/1.1\r\nhost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = Vec::new();
+    while !resp.ends_with(b"tunneled") {
+        resp.push(stream.read_u8().await.unwrap());
+    }
+
+    // the tunnel keeps the connection open until the server shuts down.
+    assert_eq!(0, closed.load(Ordering::SeqCst));
+    drop(server);
+    assert_eq!(1, closed.load(Ordering::SeqCst));
+    assert_eq!(0, stream.read(&mut [0; 16]).await.unwrap());
 }
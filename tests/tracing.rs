@@ -0,0 +1,57 @@
+#![cfg(feature = "tracing")]
+
+use http_body_util::Full;
+use httptest::{matchers::*, responders::*, Expectation, Server};
+use hyper_util::client::legacy::Client;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_request_span() {
+    let output = SharedBuf::default();
+    let writer = output.clone();
+    // The server runs on it's own thread so the subscriber needs to be global.
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .init();
+
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/foo")).respond_with(status_code(200)),
+    );
+    let client =
+        Client::builder(hyper_util::rt::TokioExecutor::new()).build_http::<Full<bytes::Bytes>>();
+    let resp = client.get(server.url("/foo")).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    drop(server);
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let request_span = output
+        .lines()
+        .find(|line| line.contains(" INFO httptest.request{") && line.contains(": close"))
+        .expect("request span closed");
+    assert!(request_span.contains("method=GET"), "{}", request_span);
+    assert!(request_span.contains("path=/foo"), "{}", request_span);
+    assert!(
+        request_span.contains(r#"expectation=MethodPath { method: "GET", path: "/foo" }"#),
+        "{}",
+        request_span
+    );
+    assert!(request_span.contains("status=200"), "{}", request_span);
+    assert!(output.contains("httptest.respond"), "{}", output);
+    assert!(output.contains("matcher evaluated"), "{}", output);
+}